@@ -0,0 +1,5 @@
+#!/bin/sh
+# Copy the module to $MX_TEST_MODULE_DIR, from which mx-tester installs it
+# in the image of Synapse with `pip install`.
+set -e
+cp -r pyproject.toml {{module}} "$MX_TEST_MODULE_DIR/"
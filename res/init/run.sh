@@ -0,0 +1,10 @@
+#!/bin/sh
+# Run the tests against the homeserver brought up by `mx-tester up`.
+#
+# Available environment variables include:
+# - MX_TEST_HOMESERVER_URL, the URL of the homeserver;
+# - MX_TEST_SERVER_NAME, its server name;
+# - MX_TEST_CWD, the directory in which mx-tester was launched.
+set -e
+echo "Testing against $MX_TEST_HOMESERVER_URL"
+# e.g. python -m pytest tests
@@ -0,0 +1,445 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Abusive traffic, as declared in `abuse`: `mx-tester abuse`.
+//!
+//! Each scenario registers a set of throwaway users, then has them perform
+//! one kind of abuse, e.g. flooding a room with messages, at a given rate for
+//! a given duration, so that anti-abuse modules may be tested against
+//! realistic attack shapes. Requests rejected by the homeserver, e.g. because
+//! a module has banned the abuser, are counted rather than treated as failures.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::registration::User;
+
+#[cfg(feature = "matrix-client")]
+pub use self::client::{run, run_scenario};
+
+/// The highest `rate`, in actions per second, i.e. one action per millisecond.
+pub const MAX_RATE: f64 = 1000.;
+
+/// A kind of abuse.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Pattern {
+    /// Each throwaway user creates rooms and invites `targets` to them, one
+    /// invite per action, creating a new room once all targets are invited.
+    #[serde(rename = "mass_invites")]
+    MassInvites,
+
+    /// The throwaway users join `room`, then send messages, one per action.
+    #[serde(rename = "message_flood")]
+    MessageFlood,
+
+    /// The throwaway users repeatedly join and leave `room`, one join or
+    /// leave per action.
+    #[serde(rename = "join_leave")]
+    JoinLeave,
+}
+
+/// An abusive traffic pattern, as declared in `abuse`.
+#[derive(Clone, Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct Scenario {
+    /// A name for the scenario, unique within this test.
+    ///
+    /// Also used as a prefix for the localnames of the throwaway users.
+    pub name: String,
+
+    /// The kind of abuse.
+    pub pattern: Pattern,
+
+    /// The room targeted by `message_flood` and `join_leave`, as a room id or
+    /// an alias. Throwaway users must be allowed to join it, e.g. it is public.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub room: Option<String>,
+
+    /// The users invited by `mass_invites`, by localname, as declared in `users`.
+    ///
+    /// Defaults to all the users declared in `users`.
+    #[serde(default)]
+    #[builder(default)]
+    pub targets: Vec<String>,
+
+    /// The body of the messages of `message_flood`.
+    ///
+    /// Defaults to "spam".
+    #[serde(default = "Scenario::default_body")]
+    #[builder(default = Scenario::default_body())]
+    pub body: String,
+
+    /// How many throwaway users perform the abuse, in turn.
+    ///
+    /// Defaults to 5.
+    #[serde(default = "Scenario::default_users")]
+    #[builder(default = Scenario::default_users())]
+    pub users: usize,
+
+    /// How many actions per second, across all throwaway users.
+    ///
+    /// This is an upper bound: actions are performed one at a time, so a slow
+    /// homeserver lowers the actual rate.
+    ///
+    /// At most `MAX_RATE`. Defaults to 10.
+    #[serde(default = "Scenario::default_rate")]
+    #[builder(default = Scenario::default_rate())]
+    pub rate: f64,
+
+    /// How long the abuse lasts, in seconds, not counting the registration of
+    /// throwaway users.
+    ///
+    /// Defaults to 10.
+    #[serde(default = "Scenario::default_duration_sec")]
+    #[builder(default = Scenario::default_duration_sec())]
+    pub duration_sec: u64,
+}
+
+impl Scenario {
+    fn default_body() -> String {
+        "spam".to_string()
+    }
+    fn default_users() -> usize {
+        5
+    }
+    fn default_rate() -> f64 {
+        10.
+    }
+    fn default_duration_sec() -> u64 {
+        10
+    }
+
+    /// The localname of the throwaway user `index`.
+    ///
+    /// Throwaway users are specific to this run of mx-tester, so that they
+    /// haven't been banned or deactivated by a previous run.
+    pub fn localname(&self, index: usize) -> String {
+        let run: String = crate::run_id().chars().take(8).collect();
+        format!("{}-{}-{}", self.name, run, index)
+    }
+}
+
+/// Check that the scenarios of `abuse` are consistent, e.g. that their
+/// targets are declared in `users`, adding a description of each problem
+/// to `problems`.
+pub fn check(scenarios: &[Scenario], users: &[User], problems: &mut Vec<String>) {
+    let mut names = HashSet::new();
+    for scenario in scenarios {
+        let what = format!("Abuse scenario {}", scenario.name);
+        if !names.insert(scenario.name.as_str()) {
+            problems.push(format!("{} is declared more than once", what));
+        }
+        // The name ends up in localnames.
+        if scenario.name.is_empty()
+            || !scenario
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        {
+            problems.push(format!(
+                "{}: invalid name, expected lowercase letters, digits, `-` and `_`",
+                what
+            ));
+        }
+        if scenario.users == 0 {
+            problems.push(format!("{}: `users` must be at least 1", what));
+        }
+        if !scenario.rate.is_finite() || scenario.rate <= 0. || scenario.rate > MAX_RATE {
+            problems.push(format!(
+                "{}: `rate` must be positive and at most {}",
+                what, MAX_RATE
+            ));
+        }
+        match scenario.pattern {
+            Pattern::MassInvites => {
+                if scenario.room.is_some() {
+                    problems.push(format!(
+                        "{}: `room` is not meaningful for `mass_invites`, which creates its own rooms",
+                        what
+                    ));
+                }
+                if scenario.targets.is_empty() && users.is_empty() {
+                    problems.push(format!(
+                        "{}: `mass_invites` needs `targets` or users declared in `users`",
+                        what
+                    ));
+                }
+                for target in &scenario.targets {
+                    if !users.iter().any(|user| &user.localname == target) {
+                        problems.push(format!(
+                            "{}: target {} is not declared in `users`",
+                            what, target
+                        ));
+                    }
+                }
+            }
+            Pattern::MessageFlood | Pattern::JoinLeave => {
+                if scenario.room.is_none() {
+                    problems.push(format!(
+                        "{}: `room` is required for `message_flood` and `join_leave`",
+                        what
+                    ));
+                }
+                if !scenario.targets.is_empty() {
+                    problems.push(format!(
+                        "{}: `targets` is only meaningful for `mass_invites`",
+                        what
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// The outcome of a scenario.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Report {
+    /// The name of the scenario.
+    pub scenario: String,
+
+    /// The throwaway users, e.g. to check that a module has banned or
+    /// deactivated them.
+    pub users: Vec<String>,
+
+    /// The actions accepted by the homeserver.
+    pub accepted: usize,
+
+    /// The actions rejected by the homeserver, e.g. because of a module.
+    pub rejected: usize,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} actions accepted, {} rejected, by {} throwaway users",
+            self.scenario,
+            self.accepted,
+            self.rejected,
+            self.users.len()
+        )
+    }
+}
+
+#[cfg(feature = "matrix-client")]
+mod client {
+    use super::*;
+
+    use anyhow::{anyhow, Context, Error};
+    use log::debug;
+
+    use crate::Config;
+
+    /// The state of a throwaway user during a scenario.
+    struct Abuser {
+        client: matrix_sdk::Client,
+
+        /// For `mass_invites`, the room being filled with invites and the number of
+        /// targets invited to it so far. For `join_leave`, whether the user is in the room.
+        room_id: Option<matrix_sdk::ruma::OwnedRoomId>,
+        invited: usize,
+    }
+
+    /// Perform one action of `scenario` as `abuser`.
+    async fn act(
+        scenario: &Scenario,
+        abuser: &mut Abuser,
+        room_id: Option<&matrix_sdk::ruma::RoomId>,
+        targets: &[matrix_sdk::ruma::OwnedUserId],
+    ) -> Result<(), Error> {
+        use matrix_sdk::ruma::{
+            api::client::{
+                membership::{
+                    invite_user::{self, v3::InvitationRecipient},
+                    leave_room,
+                },
+                room::create_room,
+            },
+            events::room::message::RoomMessageEventContent,
+            UserId,
+        };
+        match scenario.pattern {
+            Pattern::MassInvites => {
+                if abuser.room_id.is_none() || abuser.invited == targets.len() {
+                    let response = abuser
+                        .client
+                        .create_room(create_room::v3::Request::new())
+                        .await
+                        .context("Could not create room")?;
+                    abuser.room_id = Some(response.room_id);
+                    abuser.invited = 0;
+                }
+                let room_id = abuser.room_id.as_ref().unwrap();
+                let user_id: &UserId = &targets[abuser.invited];
+                // Move on to the next target even if this invite is rejected.
+                abuser.invited += 1;
+                abuser
+                    .client
+                    .send(
+                        invite_user::v3::Request::new(
+                            room_id,
+                            InvitationRecipient::UserId { user_id },
+                        ),
+                        None,
+                    )
+                    .await
+                    .with_context(|| format!("Could not invite {} to {}", user_id, room_id))?;
+            }
+            Pattern::MessageFlood => {
+                let room_id = room_id.unwrap();
+                crate::helpers::send_message_event(
+                    &abuser.client,
+                    room_id,
+                    &RoomMessageEventContent::text_plain(&scenario.body),
+                )
+                .await?;
+            }
+            Pattern::JoinLeave => {
+                let room_id = room_id.unwrap();
+                if abuser.room_id.take().is_some() {
+                    abuser
+                        .client
+                        .send(leave_room::v3::Request::new(room_id), None)
+                        .await
+                        .with_context(|| format!("Could not leave {}", room_id))?;
+                } else {
+                    abuser
+                        .client
+                        .join_room_by_id(room_id)
+                        .await
+                        .with_context(|| format!("Could not join {}", room_id))?;
+                    abuser.room_id = Some(room_id.to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `scenario` against the homeserver of `config`, which must be up.
+    pub async fn run_scenario(config: &Config, scenario: &Scenario) -> Result<Report, Error> {
+        let mut problems = vec![];
+        check(std::slice::from_ref(scenario), &config.users, &mut problems);
+        if !problems.is_empty() {
+            return Err(anyhow!(problems.join("\n")));
+        }
+        println!(
+            "** abuse scenario {}: registering {} throwaway users",
+            scenario.name, scenario.users
+        );
+        let mut abusers = Vec::with_capacity(scenario.users);
+        let mut report = Report {
+            scenario: scenario.name.clone(),
+            ..Report::default()
+        };
+        for index in 0..scenario.users {
+            let client =
+                crate::registration::throwaway_client(config, &scenario.localname(index)).await?;
+            let user_id = client
+                .user_id()
+                .ok_or_else(|| anyhow!("Throwaway user is not logged in"))?;
+            report.users.push(user_id.to_string());
+            abusers.push(Abuser {
+                client,
+                room_id: None,
+                invited: 0,
+            });
+        }
+
+        let room_id = match scenario.room {
+            Some(ref room) => Some(crate::helpers::resolve_room(&abusers[0].client, room).await?),
+            None => None,
+        };
+        if scenario.pattern == Pattern::MessageFlood {
+            let room_id = room_id.as_ref().unwrap();
+            for abuser in &abusers {
+                abuser
+                    .client
+                    .join_room_by_id(room_id)
+                    .await
+                    .with_context(|| format!("Throwaway users could not join {}", room_id))?;
+            }
+        }
+        let server_name = abusers[0]
+            .client
+            .user_id()
+            .ok_or_else(|| anyhow!("Throwaway user is not logged in"))?
+            .server_name()
+            .to_owned();
+        let targets = if scenario.targets.is_empty() {
+            config
+                .users
+                .iter()
+                .map(|user| user.localname.as_str())
+                .collect::<Vec<_>>()
+        } else {
+            scenario.targets.iter().map(String::as_str).collect()
+        }
+        .into_iter()
+        .map(|localname| {
+            matrix_sdk::ruma::UserId::parse_with_server_name(localname, &server_name)
+                .with_context(|| format!("Invalid localname {}", localname))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+        println!(
+            "** abuse scenario {}: {:?} at up to {} actions per second for {}s",
+            scenario.name, scenario.pattern, scenario.rate, scenario.duration_sec
+        );
+        let deadline =
+            tokio::time::Instant::now() + std::time::Duration::from_secs(scenario.duration_sec);
+        // `interval` panics with a zero period.
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs_f64(
+            1. / scenario.rate.min(MAX_RATE),
+        ));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        for turn in 0.. {
+            interval.tick().await;
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            let abuser = &mut abusers[turn % scenario.users];
+            match act(scenario, abuser, room_id.as_deref(), &targets).await {
+                Ok(()) => report.accepted += 1,
+                Err(err) => {
+                    debug!("Abuse scenario {}: {:#}", scenario.name, err);
+                    report.rejected += 1;
+                }
+            }
+        }
+        println!("** abuse scenario {}", report);
+        Ok(report)
+    }
+
+    /// Run the scenarios of `abuse` called `names`, in order, or all of them if
+    /// `names` is empty.
+    pub async fn run(config: &Config, names: &[&str]) -> Result<Vec<Report>, Error> {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !config.abuse.iter().any(|scenario| &scenario.name == *name))
+        {
+            return Err(anyhow!("No abuse scenario {} in `abuse`", name));
+        }
+        let mut reports = vec![];
+        for scenario in config
+            .abuse
+            .iter()
+            .filter(|scenario| names.is_empty() || names.contains(&scenario.name.as_str()))
+        {
+            reports.push(run_scenario(config, scenario).await?);
+        }
+        Ok(reports)
+    }
+}
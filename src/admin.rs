@@ -0,0 +1,266 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Administrative operations on a homeserver that is already up.
+
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::Docker;
+use log::debug;
+
+use crate::{
+    helpers, lifecycle::DockerExt, manifest::Manifest, registration::admin_client, synapse_admin,
+    util::with_heartbeat, Config,
+};
+
+/// How long we're willing to wait for Synapse to come back after a restart.
+const TIMEOUT_RESTART: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// How long we're willing to wait for background updates to complete.
+const TIMEOUT_BACKGROUND_UPDATES: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// A change to apply to homeserver.yaml, e.g. `presence.enabled=false`.
+#[derive(Clone, Debug)]
+pub struct ConfigChange {
+    /// The path to the key, e.g. `["presence", "enabled"]`.
+    pub path: Vec<String>,
+
+    /// The new value.
+    pub value: serde_yaml::Value,
+}
+
+impl FromStr for ConfigChange {
+    type Err = Error;
+
+    /// Parse a change from `key=value` or `key.subkey=value`.
+    ///
+    /// `value` is parsed as YAML, so `false` is a boolean, `10` is a number
+    /// and `"10"` is a string.
+    fn from_str(source: &str) -> Result<Self, Error> {
+        let (key, value) = source
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid change `{}`, expected `key=value`", source))?;
+        let path: Vec<String> = key.split('.').map(str::to_string).collect();
+        if path.iter().any(String::is_empty) {
+            return Err(anyhow!("Invalid key `{}` in change `{}`", key, source));
+        }
+        let value = serde_yaml::from_str(value)
+            .with_context(|| format!("Invalid value `{}` in change `{}`", value, source))?;
+        Ok(ConfigChange { path, value })
+    }
+}
+
+impl ConfigChange {
+    /// Apply this change to a homeserver config, creating intermediate mappings if necessary.
+    pub fn apply(&self, config: &mut serde_yaml::Mapping) -> Result<(), Error> {
+        let (last, intermediate) = self
+            .path
+            .split_last()
+            .ok_or_else(|| anyhow!("Empty key in change"))?;
+        let mut current = config;
+        for key in intermediate {
+            let entry = current
+                .entry(key.as_str().into())
+                .or_insert_with(|| yaml!({}));
+            if entry.is_null() {
+                *entry = yaml!({});
+            }
+            current = entry.as_mapping_mut().ok_or_else(|| {
+                anyhow!(
+                    "Cannot apply change to `{}`: `{}` is not a mapping",
+                    self.path.join("."),
+                    key
+                )
+            })?;
+        }
+        current.insert(last.as_str().into(), self.value.clone());
+        Ok(())
+    }
+}
+
+/// Apply changes to a yaml file, returning the new content.
+fn patch_file(path: &Path, changes: &[ConfigChange]) -> Result<serde_yaml::Mapping, Error> {
+    debug!("Patching {:?} with {:?}", path, changes);
+    let file = std::fs::File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    let mut content: serde_yaml::Mapping =
+        serde_yaml::from_reader(file).with_context(|| format!("Could not parse {:?}", path))?;
+    for change in changes {
+        change.apply(&mut content)?;
+    }
+    serde_yaml::to_writer(std::fs::File::create(path)?, &content)
+        .with_context(|| format!("Could not write {:?}", path))?;
+    Ok(content)
+}
+
+/// Patch the configuration of a homeserver that is already up and restart it
+/// to take the changes into account.
+///
+/// Note that sending SIGHUP is not sufficient, as Synapse only reloads its logging
+/// configuration upon SIGHUP.
+pub async fn reload_config(
+    docker: &Docker,
+    config: &Config,
+    changes: &[ConfigChange],
+) -> Result<(), Error> {
+    let run_container_name = config.run_container_name();
+    if !docker.is_container_healthy(&run_container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running or is unhealthy, please run `mx-tester up` first",
+            run_container_name
+        ));
+    }
+    println!("\n* reload-config: starting");
+    let content = patch_file(&config.homeserver_config_path(), changes)?;
+    Manifest::record_homeserver_config(config, &content)?;
+    if config.workers.enabled {
+        // In workers mode, shared.yaml is loaded after homeserver.yaml,
+        // so it would override our changes.
+        patch_file(&config.synapse_workers_dir().join("shared.yaml"), changes)?;
+    }
+    restart(docker, config).await?;
+    println!("* reload-config: success");
+    Ok(())
+}
+
+/// Restart Synapse and wait until it is ready to accept requests.
+///
+/// This is useful e.g. to take into account changes to modules installed
+/// in editable mode. Restarting the container keeps the data directory,
+/// so users and rooms are preserved.
+pub async fn restart(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let run_container_name = config.run_container_name();
+    if !docker.is_container_running(&run_container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running, please run `mx-tester up` first",
+            run_container_name
+        ));
+    }
+    println!("** restarting Synapse");
+    docker
+        .restart_container(&run_container_name, None)
+        .await
+        .context("Could not restart Synapse")?;
+    wait_until_ready(docker, config).await?;
+    println!("** restarting Synapse success");
+    Ok(())
+}
+
+/// Wait until Synapse, once (re)started, is ready to accept requests.
+pub(crate) async fn wait_until_ready(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let run_container_name = config.run_container_name();
+    let versions_url = format!(
+        "{}/_matrix/client/versions",
+        config.homeserver.public_baseurl
+    );
+    let waiting = async {
+        let client = reqwest::Client::new();
+        loop {
+            match client.get(&versions_url).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => debug!("Synapse not ready yet: {}", response.status()),
+                Err(err) => debug!("Synapse not ready yet: {}", err),
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    };
+    tokio::time::timeout(
+        TIMEOUT_RESTART,
+        with_heartbeat(
+            "Synapse to restart",
+            || crate::lifecycle::describe_container(docker, &run_container_name),
+            waiting,
+        ),
+    )
+    .await
+    .map_err(|_| anyhow!("Synapse did not come back within {:?}", TIMEOUT_RESTART))
+    .and_then(|result: Result<(), Error>| result)
+}
+
+/// Wait until Synapse has completed all its background updates.
+///
+/// On a fresh database, Synapse keeps running background updates for a
+/// little while after startup, which can make some admin APIs flaky.
+pub async fn wait_for_background_updates(config: &Config) -> Result<(), Error> {
+    let admin = admin_client(config).await?;
+    let waiting = async {
+        loop {
+            let status = admin
+                .send(
+                    synapse_admin::background_updates_status::Request::new(),
+                    None,
+                )
+                .await
+                .context("Could not get status of background updates")?;
+            if status.current_updates.is_empty() {
+                return Ok(());
+            }
+            debug!(
+                "Background updates in progress: {:?}",
+                status.current_updates
+            );
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    };
+    tokio::time::timeout(
+        TIMEOUT_BACKGROUND_UPDATES,
+        with_heartbeat("background updates", || async { None }, waiting),
+    )
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "Background updates did not complete within {:?}",
+            TIMEOUT_BACKGROUND_UPDATES
+        )
+    })
+    .and_then(|result: Result<(), Error>| result)
+}
+
+/// Rebuild the user directory from scratch and wait until this is complete.
+pub async fn rebuild_user_directory(config: &Config) -> Result<(), Error> {
+    println!("** rebuilding user directory");
+    let admin = admin_client(config).await?;
+    admin
+        .send(
+            synapse_admin::start_background_job::Request::new("regenerate_directory"),
+            None,
+        )
+        .await
+        .context("Could not start rebuilding the user directory")?;
+    wait_for_background_updates(config).await?;
+    println!("** rebuilding user directory success");
+    Ok(())
+}
+
+/// Print the event reports as JSON, most recent first, optionally only those
+/// about events of `room`, a room id or alias.
+pub async fn print_event_reports(config: &Config, room: Option<&str>) -> Result<(), Error> {
+    let admin = admin_client(config).await?;
+    let room_id = match room {
+        Some(room) => Some(helpers::resolve_room(&admin, room).await?),
+        None => None,
+    };
+    let reports = helpers::event_reports(&admin, room_id.as_deref()).await?;
+    println!("{}", serde_json::to_string_pretty(&reports)?);
+    Ok(())
+}
+
+/// Print the current state of `room`, a room id or alias, as JSON.
+pub async fn print_room_state(config: &Config, room: &str) -> Result<(), Error> {
+    let admin = admin_client(config).await?;
+    let room_id = helpers::resolve_room(&admin, room).await?;
+    let state = helpers::room_state(&admin, &room_id).await?;
+    println!("{}", serde_json::to_string_pretty(&state)?);
+    Ok(())
+}
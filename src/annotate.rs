@@ -0,0 +1,163 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Annotating the output of mx-tester for CI environments, e.g. with
+//! GitHub Actions workflow commands, so that failures show up in the UI.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+use crate::Config;
+
+/// The number of lines of script logs to include in a failure report.
+const SCRIPT_LOG_EXCERPT_LINES: usize = 20;
+
+/// The flavor of annotations to emit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Annotations {
+    /// No annotations, just the regular output.
+    #[default]
+    None,
+
+    /// GitHub Actions workflow commands, i.e. `::group::`, `::error ...::`.
+    GitHub,
+}
+
+/// A group of output lines, closed when dropped.
+pub struct Group {
+    annotations: Annotations,
+}
+impl Drop for Group {
+    fn drop(&mut self) {
+        if let Annotations::GitHub = self.annotations {
+            println!("::endgroup::");
+        }
+    }
+}
+
+impl Annotations {
+    /// Start a group of output lines, e.g. a step. The group is closed when the result is dropped.
+    pub fn group(&self, title: &str) -> Group {
+        if let Annotations::GitHub = self {
+            println!("::group::{}", escape_data(title));
+        }
+        Group { annotations: *self }
+    }
+
+    /// Report the failure of a step, with excerpts of the relevant logs.
+    pub fn report<T>(&self, config: &Config, step: &str, result: &Result<T, Error>) {
+        let err = match (self, result) {
+            (Annotations::GitHub, Err(err)) => err,
+            _ => return,
+        };
+        let mut message = format!("{:#}\n\nmx-tester run {}", err, crate::run_id());
+        let script_log = config.scripts_logs_dir().join(format!("{}.log", step));
+        if let Some(excerpt) = tail(&script_log, SCRIPT_LOG_EXCERPT_LINES) {
+            message.push_str(&format!(
+                "\n\nLast lines of {}:\n{}",
+                script_log.display(),
+                excerpt
+            ));
+        }
+        println!(
+            "::error title={}::{}",
+            escape_property(&format!("mx-tester {} failed", step)),
+            escape_data(&message)
+        );
+
+        // Synapse tracebacks are typically the most useful information.
+        let synapse_log = config.logs_dir().join("docker").join(if step == "build" {
+            "build.log"
+        } else {
+            "up-run-down.log"
+        });
+        if let Some((line, traceback)) = last_traceback(&synapse_log) {
+            match workspace_path(&synapse_log) {
+                Some(path) => println!(
+                    "::error file={},line={},title={}::{}",
+                    escape_property(&path.to_string_lossy()),
+                    line,
+                    escape_property("Synapse traceback"),
+                    escape_data(&traceback)
+                ),
+                // GitHub would drop the annotation, so mention the file in the message instead.
+                None => println!(
+                    "::error title={}::{}",
+                    escape_property("Synapse traceback"),
+                    escape_data(&format!(
+                        "{}, line {}:\n{}",
+                        synapse_log.display(),
+                        line,
+                        traceback
+                    ))
+                ),
+            }
+        }
+    }
+}
+
+/// `path`, relative to the workspace, i.e. `$GITHUB_WORKSPACE` or, by default, the
+/// current directory, if it is within the workspace.
+///
+/// GitHub only attaches annotations to files of the workspace, by relative path.
+pub fn workspace_path(path: &Path) -> Option<PathBuf> {
+    let workspace = match std::env::var_os("GITHUB_WORKSPACE") {
+        Some(workspace) => PathBuf::from(workspace),
+        None => std::env::current_dir().ok()?,
+    };
+    let workspace = workspace.canonicalize().unwrap_or(workspace);
+    let path = path.canonicalize().ok()?;
+    path.strip_prefix(&workspace).ok().map(Path::to_path_buf)
+}
+
+/// The last `count` lines of a file, if it exists and is not empty.
+fn tail(path: &Path, count: usize) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.is_empty() {
+        return None;
+    }
+    Some(lines[lines.len().saturating_sub(count)..].join("\n"))
+}
+
+/// The last Python traceback in a log file, with its (1-based) line number.
+fn last_traceback(path: &Path) -> Option<(usize, String)> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let lines: Vec<&str> = content.lines().collect();
+    let start = lines
+        .iter()
+        .rposition(|line| line.contains("Traceback (most recent call last)"))?;
+    // The traceback consists of indented lines, followed by the (unindented) exception.
+    let mut end = start + 1;
+    while end < lines.len() && lines[end].starts_with(char::is_whitespace) {
+        end += 1;
+    }
+    let end = std::cmp::min(end + 1, lines.len());
+    Some((start + 1, lines[start..end].join("\n")))
+}
+
+/// Escape the message of a workflow command.
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape the value of a property of a workflow command.
+fn escape_property(property: &str) -> String {
+    escape_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
@@ -0,0 +1,345 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Application services under test, as declared in `appservices`.
+//!
+//! During `up`, mx-tester writes a registration file for each application
+//! service, with fresh `as_token`/`hs_token` unless specified, and declares
+//! it in the configuration of the homeserver. The tokens are then handed to
+//! the bot running the application service, if any, and to scripts.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "matrix-client")]
+pub use self::client::{register_ghosts, write_registrations};
+
+/// The port on which the bot running an application service is expected to
+/// listen, if `url` is not specified.
+const DEFAULT_APPSERVICE_PORT: u16 = 9000;
+
+/// The directory in which registration files are written, within the homeserver container.
+pub(crate) const GUEST_APPSERVICES_DIR: &str = "/data/appservices";
+
+/// The path of the registration file, within the container of the bot running
+/// the application service.
+pub const BOT_REGISTRATION_PATH: &str = "/mx-tester/registration.yaml";
+
+/// An application service under test.
+#[derive(Clone, Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct AppService {
+    /// A unique id for the application service.
+    pub id: String,
+
+    /// The URL at which the homeserver reaches the application service.
+    ///
+    /// If unspecified, `bot` must be specified and the URL is
+    /// `http://<container of the bot>:<port>`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub url: Option<String>,
+
+    /// The name of a bot declared in `bots` running this application service.
+    ///
+    /// The bot receives the tokens and the registration file.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub bot: Option<String>,
+
+    /// The port on which `bot` listens, if `url` is unspecified.
+    ///
+    /// Defaults to 9000.
+    #[serde(default = "AppService::default_port")]
+    #[builder(default = AppService::default_port())]
+    pub port: u16,
+
+    /// The localpart of the user of the application service itself.
+    pub sender_localpart: String,
+
+    /// The users, aliases and rooms claimed by the application service.
+    #[serde(default)]
+    #[builder(default)]
+    pub namespaces: Namespaces,
+
+    /// Whether requests from the application service are rate-limited.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub rate_limited: bool,
+
+    /// The third-party protocols of the application service, if it is a bridge.
+    #[serde(default)]
+    #[builder(default)]
+    pub protocols: Vec<String>,
+
+    /// The token used by the application service to talk to the homeserver.
+    ///
+    /// If unspecified, a new token is generated during each `up`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub as_token: Option<String>,
+
+    /// The token used by the homeserver to talk to the application service.
+    ///
+    /// If unspecified, a new token is generated during each `up`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub hs_token: Option<String>,
+
+    /// If specified, set up the application service as a bridge.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub bridge: Option<BridgePreset>,
+}
+
+impl AppService {
+    fn default_port() -> u16 {
+        DEFAULT_APPSERVICE_PORT
+    }
+
+    /// The id of the registration used for double-puppeting, if any.
+    pub fn double_puppet_id(&self) -> Option<String> {
+        match self.bridge {
+            Some(BridgePreset {
+                double_puppet: true,
+                ..
+            }) => Some(format!("{}-doublepuppet", self.id)),
+            _ => None,
+        }
+    }
+}
+
+/// Setting up an application service as a bridge.
+///
+/// - The users and aliases starting with `prefix` are claimed by the bridge.
+/// - If `double_puppet` is set, a second registration lets the bridge act
+///   as any user of the homeserver, and login with an existing session
+///   and appservice login are enabled.
+/// - The ghost users in `ghosts` are registered by the bridge during `up`.
+#[derive(Clone, Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct BridgePreset {
+    /// The prefix of the localparts of ghost users and of aliases of portal rooms,
+    /// e.g. `telegram_`.
+    pub prefix: String,
+
+    /// Whether to set up double-puppeting.
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "crate::util::true_")]
+    #[builder(default = true)]
+    pub double_puppet: bool,
+
+    /// Ghost users to register during `up`, by localpart, without `prefix`.
+    #[serde(default)]
+    #[builder(default)]
+    pub ghosts: Vec<String>,
+}
+
+/// The namespaces claimed by an application service.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Namespaces {
+    #[serde(default)]
+    pub users: Vec<Namespace>,
+    #[serde(default)]
+    pub aliases: Vec<Namespace>,
+    #[serde(default)]
+    pub rooms: Vec<Namespace>,
+}
+
+/// A namespace, i.e. a regex of user ids, aliases or room ids.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Namespace {
+    /// A regex, e.g. `@_irc_.*:localhost:9999`.
+    pub regex: String,
+
+    /// Whether only the application service may use this namespace.
+    #[serde(default = "crate::util::true_")]
+    pub exclusive: bool,
+}
+
+/// The contents of a registration file, as expected by the homeserver.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Registration {
+    pub id: String,
+    /// `None` for registrations that are only used for their `as_token`, e.g. double-puppeting.
+    pub url: Option<String>,
+    pub as_token: String,
+    pub hs_token: String,
+    pub sender_localpart: String,
+    pub namespaces: Namespaces,
+    pub rate_limited: bool,
+    pub protocols: Vec<String>,
+}
+
+/// Read the registration file of an application service, as written during `up`.
+pub fn load_registration(config: &Config, appservice: &AppService) -> Result<Registration, Error> {
+    load(config.appservice_registration_path(appservice))
+}
+
+/// Read the registration file used for double-puppeting by a bridge, as written during `up`.
+pub fn load_double_puppet_registration(
+    config: &Config,
+    appservice: &AppService,
+) -> Result<Option<Registration>, Error> {
+    config
+        .double_puppet_registration_path(appservice)
+        .map(load)
+        .transpose()
+}
+
+fn load(path: PathBuf) -> Result<Registration, Error> {
+    let file = std::fs::File::open(&path).with_context(|| format!("Could not open {:?}", path))?;
+    serde_yaml::from_reader(file).with_context(|| format!("Could not parse {:?}", path))
+}
+
+#[cfg(feature = "matrix-client")]
+mod client {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    /// Write the registration file of each application service, generating tokens as needed.
+    pub fn write_registrations(config: &Config) -> Result<(), Error> {
+        use rand::{distributions::Alphanumeric, Rng};
+        let token = || -> String {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect()
+        };
+        let dir = config.appservices_dir();
+        let _ = std::fs::remove_dir_all(&dir);
+        if config.appservices.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:#?}", dir))?;
+        // In regexes, the server name must match literally.
+        let server_name = config.homeserver.server_name.replace('.', "\\.");
+        let write = |path: PathBuf, registration: &Registration| -> Result<(), Error> {
+            serde_yaml::to_writer(std::fs::File::create(&path)?, registration)
+                .with_context(|| format!("Could not write {:?}", path))
+        };
+        for appservice in &config.appservices {
+            let mut namespaces = appservice.namespaces.clone();
+            if let Some(ref bridge) = appservice.bridge {
+                namespaces.users.push(Namespace {
+                    regex: format!("@{}.*:{}", bridge.prefix, server_name),
+                    exclusive: true,
+                });
+                namespaces.aliases.push(Namespace {
+                    regex: format!("#{}.*:{}", bridge.prefix, server_name),
+                    exclusive: true,
+                });
+            }
+            let registration = Registration {
+                id: appservice.id.clone(),
+                url: Some(config.appservice_url(appservice).with_context(|| {
+                    format!(
+                        "Application service {} needs either `url` or `bot`",
+                        appservice.id
+                    )
+                })?),
+                as_token: appservice.as_token.clone().unwrap_or_else(token),
+                hs_token: appservice.hs_token.clone().unwrap_or_else(token),
+                sender_localpart: appservice.sender_localpart.clone(),
+                namespaces,
+                rate_limited: appservice.rate_limited,
+                protocols: appservice.protocols.clone(),
+            };
+            write(
+                config.appservice_registration_path(appservice),
+                &registration,
+            )?;
+
+            // Double-puppeting uses a registration that claims all users, non-exclusively,
+            // and is never contacted by the homeserver.
+            if let (Some(id), Some(path)) = (
+                appservice.double_puppet_id(),
+                config.double_puppet_registration_path(appservice),
+            ) {
+                let registration = Registration {
+                    id: id.clone(),
+                    url: None,
+                    as_token: token(),
+                    hs_token: token(),
+                    sender_localpart: id,
+                    namespaces: Namespaces {
+                        users: vec![Namespace {
+                            regex: format!("@.*:{}", server_name),
+                            exclusive: false,
+                        }],
+                        ..Namespaces::default()
+                    },
+                    rate_limited: false,
+                    protocols: vec![],
+                };
+                write(path, &registration)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register the ghost users of bridges, as declared in `bridge.ghosts`.
+    ///
+    /// Ghost users that already exist are left untouched.
+    pub async fn register_ghosts(config: &Config) -> Result<(), Error> {
+        let client = reqwest::Client::new();
+        let url = format!(
+            "{}/_matrix/client/v3/register",
+            config.homeserver.public_baseurl
+        );
+        for appservice in &config.appservices {
+            let bridge = match appservice.bridge {
+                Some(ref bridge) if !bridge.ghosts.is_empty() => bridge,
+                _ => continue,
+            };
+            let registration = load_registration(config, appservice)?;
+            for ghost in &bridge.ghosts {
+                let localpart = format!("{}{}", bridge.prefix, ghost);
+                let response = client
+                    .post(&url)
+                    .bearer_auth(&registration.as_token)
+                    .json(&serde_json::json!({
+                        "type": "m.login.application_service",
+                        "username": localpart,
+                    }))
+                    .send()
+                    .await
+                    .with_context(|| format!("Could not register ghost {}", localpart))?;
+                if response.status().is_success() {
+                    continue;
+                }
+                let status = response.status();
+                let body: serde_json::Value = response.json().await.unwrap_or_default();
+                if body["errcode"] == "M_USER_IN_USE" {
+                    continue;
+                }
+                return Err(anyhow!(
+                    "Could not register ghost {} of application service {}: {} {}",
+                    localpart,
+                    appservice.id,
+                    status,
+                    body
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Uploading the logs and manifest of a test to a bucket, for CI environments
+//! in which the local disk does not persist.
+//!
+//! Uploads are delegated to the `aws` (for `s3://` urls) or `gsutil` (for `gs://` urls)
+//! command-line tools, which pick their credentials from the environment.
+
+use std::{path::Path, process::Stdio};
+
+use anyhow::{anyhow, Context, Error};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use typed_builder::TypedBuilder;
+
+use crate::{coverage::CoverageConfig, exec::CommandExt, manifest::Manifest, Config, Status};
+
+/// Configuring what happens to the artifacts of a test.
+#[derive(Debug, Default, TypedBuilder, Deserialize, Serialize)]
+pub struct ArtifactsConfig {
+    /// If specified, upload artifacts at the end of `down`.
+    #[serde(default)]
+    #[builder(default)]
+    pub upload: Option<UploadConfig>,
+
+    /// If specified, collect the coverage of Python code running in Synapse.
+    ///
+    /// Data files are stored in `logs/coverage`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub coverage: Option<CoverageConfig>,
+}
+
+/// Where and when to upload artifacts.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct UploadConfig {
+    /// The destination, e.g. `s3://my-bucket/mx-tester` or `gs://my-bucket/mx-tester`.
+    ///
+    /// Artifacts are uploaded to a subdirectory `$NAME/$TIMESTAMP`.
+    pub url: String,
+
+    /// When to upload artifacts.
+    ///
+    /// May be overridden from the command-line.
+    #[serde(default)]
+    #[builder(default)]
+    pub on: UploadWhen,
+}
+
+/// When to upload artifacts.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum UploadWhen {
+    /// Upload artifacts after every `down`.
+    #[default]
+    #[serde(rename = "always")]
+    Always,
+
+    /// Upload artifacts only if `run` has failed.
+    #[serde(rename = "failure-only")]
+    FailureOnly,
+}
+
+/// Check that the options of `artifacts` are consistent.
+pub fn check(artifacts: &ArtifactsConfig, problems: &mut Vec<String>) {
+    if let Some(ref upload) = artifacts.upload {
+        if !upload.url.starts_with("s3://") && !upload.url.starts_with("gs://") {
+            problems.push(format!(
+                "Invalid `artifacts.upload.url` {}, expected `s3://...` or `gs://...`",
+                upload.url
+            ));
+        }
+    }
+}
+
+/// Upload the logs and the manifest of a test, if so configured.
+pub async fn upload(config: &Config, status: Status) -> Result<(), Error> {
+    let upload = match config.artifacts.upload {
+        None => return Ok(()),
+        Some(ref upload) => upload,
+    };
+    if upload.on == UploadWhen::FailureOnly && status != Status::Failure {
+        debug!("Not uploading artifacts, the test has not failed");
+        return Ok(());
+    }
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Invalid system time")?
+        .as_secs();
+    let dest = format!(
+        "{}/{}/{}",
+        upload.url.trim_end_matches('/'),
+        config.name,
+        timestamp
+    );
+    println!("** uploading artifacts to {}", dest);
+    let logs_dest = format!("{}/logs", dest);
+    copy(config, &config.logs_dir(), &logs_dest, true).await?;
+    let manifest = Manifest::path(config);
+    if manifest.exists() {
+        copy(config, &manifest, &format!("{}/manifest.json", dest), false).await?;
+    }
+    println!("** uploading artifacts success");
+    Ok(())
+}
+
+/// Copy a file or directory to a bucket.
+async fn copy(config: &Config, source: &Path, dest: &str, recursive: bool) -> Result<(), Error> {
+    let mut command = if dest.starts_with("s3://") {
+        let mut command = Command::new("aws");
+        command.args(["s3", "cp", "--only-show-errors"]);
+        if recursive {
+            command.arg("--recursive");
+        }
+        command
+    } else if dest.starts_with("gs://") {
+        let mut command = Command::new("gsutil");
+        command.args(["-q", "-m", "cp"]);
+        if recursive {
+            command.arg("-r");
+        }
+        command
+    } else {
+        return Err(anyhow!(
+            "Invalid artifacts url {}, expected `s3://...` or `gs://...`",
+            dest
+        ));
+    };
+    command
+        .arg(source)
+        .arg(dest)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+        .spawn_logged(
+            &config.scripts_logs_dir(),
+            "upload",
+            &format!("{:?} -> {}", source, dest),
+        )
+        .await
+        .with_context(|| format!("Could not upload {:?} to {}", source, dest))
+}
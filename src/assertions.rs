@@ -0,0 +1,192 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Built-in checks run at the end of `run`, as configured in `assertions`.
+//!
+//! A module may break in ways that the `run` script doesn't notice, e.g. a
+//! callback that raises on events the script doesn't look at. Synapse logs
+//! such failures at level ERROR, so we can catch them by inspecting its logs.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+/// How many offending lines to show when failing.
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// The start of the header written by mx-tester at the top of its log files,
+/// see `crate::log_header`.
+const LOG_HEADER_PREFIX: &str = "--- mx-tester ";
+
+/// Checks run at the end of `run`, in addition to the `run` script.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct AssertionsConfig {
+    /// If `true`, fail `run` if Synapse, or any of its workers, logged
+    /// anything at level ERROR or CRITICAL since the last `up`.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub no_synapse_errors: bool,
+
+    /// Regexes of log lines that don't count as errors for
+    /// `no_synapse_errors`, e.g. known issues of Synapse.
+    #[serde(default)]
+    #[builder(default)]
+    pub allowed_errors: Vec<String>,
+}
+impl Default for AssertionsConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// A line logged by Synapse at level ERROR or CRITICAL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SynapseError {
+    pub path: PathBuf,
+
+    /// The line number, starting at 1.
+    pub line: usize,
+    pub content: String,
+}
+
+/// Check the configuration of assertions, pushing any problem to `problems`.
+pub fn check(assertions: &AssertionsConfig, problems: &mut Vec<String>) {
+    if !assertions.allowed_errors.is_empty() && !assertions.no_synapse_errors {
+        problems.push(
+            "`assertions.allowed_errors` requires `assertions.no_synapse_errors`".to_string(),
+        );
+    }
+    for pattern in &assertions.allowed_errors {
+        if let Err(err) = Regex::new(pattern) {
+            problems.push(format!(
+                "Invalid regex {:?} in `assertions.allowed_errors`: {}",
+                pattern, err
+            ));
+        }
+    }
+}
+
+/// Whether a line of the logs of Synapse has level ERROR or CRITICAL.
+///
+/// Synapse formats log lines as `<time> - <logger> - <line> - <level> - <request> - <message>`.
+pub fn is_error_line(line: &str) -> bool {
+    line.contains(" - ERROR - ") || line.contains(" - CRITICAL - ")
+}
+
+/// The errors of the logs of Synapse, except for those matching `allowed`.
+///
+/// If mx-tester wrote a header at the top of the log, i.e. at each `up`, we
+/// only look at lines after the last header.
+pub fn find_errors(path: &Path, content: &str, allowed: &[Regex]) -> Vec<SynapseError> {
+    let lines: Vec<&str> = content.lines().collect();
+    let start = lines
+        .iter()
+        .rposition(|line| line.starts_with(LOG_HEADER_PREFIX))
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    lines
+        .iter()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, line)| is_error_line(line))
+        .filter(|(_, line)| !allowed.iter().any(|regex| regex.is_match(line)))
+        .map(|(index, line)| SynapseError {
+            path: path.to_path_buf(),
+            line: index + 1,
+            content: line.to_string(),
+        })
+        .collect()
+}
+
+/// The log files of Synapse: the output of the container and, in workers
+/// mode, the log of each worker.
+fn synapse_logs(config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![config.logs_dir().join("docker").join("up-run-down.log")];
+    if config.workers.enabled {
+        let workers_dir = config.logs_dir().join("workers");
+        match std::fs::read_dir(&workers_dir) {
+            Ok(entries) => {
+                let mut workers_logs = vec![];
+                for entry in entries {
+                    let path = entry
+                        .with_context(|| format!("Could not read directory {:?}", workers_dir))?
+                        .path();
+                    if path.extension() == Some(std::ffi::OsStr::new("log")) {
+                        workers_logs.push(path);
+                    }
+                }
+                workers_logs.sort();
+                paths.extend(workers_logs);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Could not read directory {:?}", workers_dir))
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// If `assertions.no_synapse_errors`, fail if the logs of Synapse contain errors.
+pub fn check_synapse_logs(config: &Config) -> Result<(), Error> {
+    if !config.assertions.no_synapse_errors {
+        return Ok(());
+    }
+    let allowed = config
+        .assertions
+        .allowed_errors
+        .iter()
+        .map(|pattern| Regex::new(pattern))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid regex in `assertions.allowed_errors`")?;
+    let mut errors: Vec<SynapseError> = vec![];
+    for path in synapse_logs(config)? {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", path)),
+        };
+        for error in find_errors(&path, &content, &allowed) {
+            // In workers mode, the output of the container repeats the logs of workers.
+            if !errors.iter().any(|known| known.content == error.content) {
+                errors.push(error);
+            }
+        }
+    }
+    if errors.is_empty() {
+        println!("** no errors in the logs of Synapse");
+        return Ok(());
+    }
+    for error in errors.iter().take(MAX_REPORTED_ERRORS) {
+        eprintln!(
+            "** {}:{}: {}",
+            error.path.display(),
+            error.line,
+            error.content
+        );
+    }
+    if errors.len() > MAX_REPORTED_ERRORS {
+        eprintln!("** ... and {} more", errors.len() - MAX_REPORTED_ERRORS);
+    }
+    Err(anyhow!(
+        "Synapse logged {} error(s), see above. To ignore some of them, add regexes to `assertions.allowed_errors`",
+        errors.len()
+    ))
+}
@@ -0,0 +1,337 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarking Synapse and modules: `mx-tester bench`.
+//!
+//! Runs `bench.script` (or `run`) several times against a homeserver that is
+//! up, measures the latency of the Client-Server API calls listed in
+//! `bench.endpoints` from the access log of Synapse, and compares percentiles
+//! against a baseline stored in a JSON file.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::{Config, Script};
+
+/// The marker of access log entries in the logs of Synapse.
+const PROCESSED_REQUEST: &str = "Processed request: ";
+
+/// Give the log watcher time to write the last entries of an iteration.
+const LOG_SETTLE_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Configuring `mx-tester bench`.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct BenchConfig {
+    /// The script generating load, executed once per iteration.
+    ///
+    /// If unspecified, use `run`.
+    #[serde(default)]
+    #[builder(default)]
+    pub script: Option<Script>,
+
+    /// How many times to execute the script.
+    ///
+    /// Defaults to 5. May be overridden from the command-line.
+    #[serde(default = "BenchConfig::default_iterations")]
+    #[builder(default = BenchConfig::default_iterations())]
+    pub iterations: u32,
+
+    /// The calls to measure, e.g. `GET /_matrix/client/v3/sync`.
+    ///
+    /// The query string is ignored and `*` matches any single path segment,
+    /// e.g. `PUT /_matrix/client/*/rooms/*/send/*/*`.
+    #[serde(default)]
+    #[builder(default)]
+    pub endpoints: Vec<String>,
+
+    /// The file storing the baseline, relative to the current directory.
+    ///
+    /// Defaults to `mx-tester-bench.json`.
+    #[serde(default = "BenchConfig::default_baseline")]
+    #[builder(default = BenchConfig::default_baseline())]
+    pub baseline: PathBuf,
+
+    /// By how much, in percent, a percentile may exceed its baseline before
+    /// it is considered a regression.
+    ///
+    /// Defaults to 10.
+    #[serde(default = "BenchConfig::default_threshold_percent")]
+    #[builder(default = BenchConfig::default_threshold_percent())]
+    pub threshold_percent: f64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl BenchConfig {
+    fn default_iterations() -> u32 {
+        5
+    }
+    fn default_baseline() -> PathBuf {
+        PathBuf::from("mx-tester-bench.json")
+    }
+    fn default_threshold_percent() -> f64 {
+        10.
+    }
+}
+
+/// The latency of calls to an endpoint, in milliseconds.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Stats {
+    /// The number of calls measured, across all iterations.
+    pub count: usize,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Stats {
+    /// Compute percentiles from durations, in seconds.
+    ///
+    /// Returns `None` if there are no durations.
+    pub fn from_durations(durations: &[f64]) -> Option<Stats> {
+        if durations.is_empty() {
+            return None;
+        }
+        let mut sorted = durations.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // Nearest-rank percentiles.
+        let percentile = |p: f64| {
+            let rank = ((p / 100.) * sorted.len() as f64).ceil() as usize;
+            sorted[rank.max(1) - 1] * 1000.
+        };
+        Some(Stats {
+            count: sorted.len(),
+            p50_ms: percentile(50.),
+            p90_ms: percentile(90.),
+            p99_ms: percentile(99.),
+        })
+    }
+}
+
+/// The result of a benchmark, indexed by endpoint, as stored in the baseline.
+pub type Report = BTreeMap<String, Stats>;
+
+/// Parse an entry of the access log of Synapse, e.g.
+///
+/// `... Processed request: 0.003sec/-0.000sec (...) 1133B 200 "GET /_matrix/client/v3/sync?timeout=0 HTTP/1.1" ...`
+///
+/// into the method, the path (without query string) and the duration, in seconds.
+pub fn parse_access_log_line(line: &str) -> Option<(&str, &str, f64)> {
+    let entry = &line[line.find(PROCESSED_REQUEST)? + PROCESSED_REQUEST.len()..];
+    let duration = entry[..entry.find("sec")?].parse().ok()?;
+    let start = entry.find('"')? + 1;
+    let end = start + entry[start..].find('"')?;
+    let mut request = entry[start..end].split(' ');
+    let method = request.next()?;
+    let uri = request.next()?;
+    let path = uri.split('?').next()?;
+    Some((method, path, duration))
+}
+
+/// Whether a call matches an endpoint of `bench.endpoints`.
+pub fn matches_endpoint(endpoint: &str, method: &str, path: &str) -> bool {
+    let (expected_method, expected_path) = match endpoint.split_once(' ') {
+        Some(split) => split,
+        None => return false,
+    };
+    if !expected_method.eq_ignore_ascii_case(method) {
+        return false;
+    }
+    let expected: Vec<&str> = expected_path.trim_end_matches('/').split('/').collect();
+    let actual: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual.iter())
+            .all(|(expected, actual)| *expected == "*" || expected == actual)
+}
+
+/// Compare a report against a baseline.
+///
+/// Returns a description of each regression, i.e. each percentile that exceeds
+/// its baseline by more than `threshold_percent`, and of each endpoint of the
+/// baseline that was not measured.
+pub fn compare(baseline: &Report, report: &Report, threshold_percent: f64) -> Vec<String> {
+    let mut regressions = vec![];
+    for (endpoint, expected) in baseline {
+        let actual = match report.get(endpoint) {
+            Some(actual) => actual,
+            None => {
+                regressions.push(format!("{}: no calls measured", endpoint));
+                continue;
+            }
+        };
+        for (name, expected, actual) in [
+            ("p50", expected.p50_ms, actual.p50_ms),
+            ("p90", expected.p90_ms, actual.p90_ms),
+            ("p99", expected.p99_ms, actual.p99_ms),
+        ] {
+            if actual > expected * (1. + threshold_percent / 100.) {
+                regressions.push(format!(
+                    "{}: {} is {:.1}ms, baseline {:.1}ms (+{:.0}%)",
+                    endpoint,
+                    name,
+                    actual,
+                    expected,
+                    (actual / expected - 1.) * 100.
+                ));
+            }
+        }
+    }
+    regressions
+}
+
+/// The file storing the report of the latest benchmark, as part of the artifacts.
+pub fn report_path(config: &Config) -> PathBuf {
+    config.logs_dir().join("bench.json")
+}
+
+/// The files in which Synapse writes its access log: the logs of the container
+/// and, with workers, the logs of each worker.
+fn log_files(config: &Config) -> Vec<PathBuf> {
+    let mut files = vec![config.logs_dir().join("docker").join("up-run-down.log")];
+    if let Ok(entries) = std::fs::read_dir(config.logs_dir().join("workers")) {
+        files.extend(entries.filter_map(|entry| entry.ok().map(|entry| entry.path())));
+    }
+    files
+}
+
+/// The current size of each log file, to read only what an iteration writes.
+fn log_offsets(config: &Config) -> HashMap<PathBuf, u64> {
+    log_files(config)
+        .into_iter()
+        .filter_map(|path| {
+            let len = std::fs::metadata(&path).ok()?.len();
+            Some((path, len))
+        })
+        .collect()
+}
+
+/// The contents of a log file past `offset`.
+fn read_from(path: &Path, offset: u64) -> Result<String, Error> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)
+        .with_context(|| format!("Could not read {:?}", path))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Run the benchmark against a homeserver that is up and compare against the baseline.
+///
+/// If there is no baseline yet, or if `update_baseline` is `true`, store the
+/// result as the new baseline instead.
+pub async fn bench(config: &Config, update_baseline: bool) -> Result<(), Error> {
+    println!("\n* bench step: starting");
+    let script = config
+        .bench
+        .script
+        .as_ref()
+        .or(config.run.as_ref())
+        .ok_or_else(|| anyhow!("Nothing to benchmark, please specify `bench.script` or `run`"))?;
+    if config.bench.endpoints.is_empty() {
+        return Err(anyhow!(
+            "No calls to measure, please specify `bench.endpoints`"
+        ));
+    }
+    let env = config.shared_env_variables()?;
+
+    let mut durations: HashMap<&str, Vec<f64>> = HashMap::new();
+    for iteration in 1..=config.bench.iterations {
+        println!(
+            "** bench iteration {}/{}",
+            iteration, config.bench.iterations
+        );
+        let offsets = log_offsets(config);
+        script
+            .run("bench", &config.scripts_logs_dir(), &env)
+            .await
+            .with_context(|| format!("Error running bench script, iteration {}", iteration))?;
+        tokio::time::sleep(LOG_SETTLE_DELAY).await;
+        for path in log_files(config) {
+            let offset = offsets.get(&path).copied().unwrap_or(0);
+            let content = read_from(&path, offset)?;
+            for (method, path, duration) in content.lines().filter_map(parse_access_log_line) {
+                if let Some(endpoint) = config
+                    .bench
+                    .endpoints
+                    .iter()
+                    .find(|endpoint| matches_endpoint(endpoint, method, path))
+                {
+                    durations.entry(endpoint).or_default().push(duration);
+                }
+            }
+        }
+    }
+    let report: Report = config
+        .bench
+        .endpoints
+        .iter()
+        .filter_map(|endpoint| {
+            let stats = Stats::from_durations(durations.get(endpoint.as_str())?)?;
+            Some((endpoint.clone(), stats))
+        })
+        .collect();
+    for (endpoint, stats) in &report {
+        println!(
+            "** {}: {} calls, p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms",
+            endpoint, stats.count, stats.p50_ms, stats.p90_ms, stats.p99_ms
+        );
+    }
+    let serialized = serde_json::to_string_pretty(&report)?;
+    let report_path = report_path(config);
+    std::fs::write(&report_path, &serialized)
+        .with_context(|| format!("Could not write {:?}", report_path))?;
+
+    let baseline_path = &config.bench.baseline;
+    if update_baseline || !baseline_path.exists() {
+        std::fs::write(baseline_path, &serialized)
+            .with_context(|| format!("Could not write baseline {:?}", baseline_path))?;
+        println!(
+            "* bench step: success, baseline stored at {:?}",
+            baseline_path
+        );
+        return Ok(());
+    }
+    let baseline: Report = serde_json::from_str(
+        &std::fs::read_to_string(baseline_path)
+            .with_context(|| format!("Could not read baseline {:?}", baseline_path))?,
+    )
+    .with_context(|| format!("Invalid baseline {:?}", baseline_path))?;
+    let regressions = compare(&baseline, &report, config.bench.threshold_percent);
+    if !regressions.is_empty() {
+        return Err(anyhow!(
+            "Performance regressions against baseline {:?}:\n{}",
+            baseline_path,
+            regressions.join("\n")
+        ));
+    }
+    println!(
+        "* bench step: success, no regression against {:?}",
+        baseline_path
+    );
+    Ok(())
+}
@@ -0,0 +1,273 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the bots under test, as declared in `bots`.
+//!
+//! Each bot runs in its own container, on the same Docker network as Synapse.
+//! Bots are started once Synapse is up and users are registered, so that they
+//! can be handed the access token of a fixture user, and stopped during `down`.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{
+    container::{Config as BollardContainerConfig, CreateContainerOptions},
+    models::HostConfig,
+    Docker,
+};
+use log::debug;
+
+use crate::{
+    appservice::{self, BOT_REGISTRATION_PATH},
+    environment::Environment,
+    lifecycle::{build_image, networking_config, pull_image_if_missing, write_container_logs},
+    Bot, Config, HARDCODED_GUEST_PORT,
+};
+
+/// Environment variable: the URL of the homeserver, as seen from the bot's container.
+const MX_TEST_HOMESERVER_URL: &str = "MX_TEST_HOMESERVER_URL";
+
+/// Environment variable: the server name of the homeserver.
+const MX_TEST_SERVER_NAME: &str = "MX_TEST_SERVER_NAME";
+
+/// Environment variable: the user id of the bot, if `user` is specified.
+const MX_TEST_BOT_USER_ID: &str = "MX_TEST_BOT_USER_ID";
+
+/// Environment variable: the access token of the bot, if `user` is specified.
+const MX_TEST_BOT_ACCESS_TOKEN: &str = "MX_TEST_BOT_ACCESS_TOKEN";
+
+/// Environment variable: the token used by the application service to talk to the homeserver,
+/// if the bot runs an application service.
+const MX_TEST_AS_TOKEN: &str = "MX_TEST_AS_TOKEN";
+
+/// Environment variable: the token used by the homeserver to talk to the application service,
+/// if the bot runs an application service.
+const MX_TEST_HS_TOKEN: &str = "MX_TEST_HS_TOKEN";
+
+/// Environment variable: the path of the registration file within the container,
+/// if the bot runs an application service.
+const MX_TEST_AS_REGISTRATION: &str = "MX_TEST_AS_REGISTRATION";
+
+/// Environment variable: the token to use for double-puppeting, if the bot runs
+/// a bridge with `double_puppet`.
+const MX_TEST_DOUBLE_PUPPET_TOKEN: &str = "MX_TEST_DOUBLE_PUPPET_TOKEN";
+
+/// Build the images of bots that specify `build`.
+pub async fn build(docker: &Docker, config: &Config) -> Result<(), Error> {
+    for bot in &config.bots {
+        let context_dir = match bot.build {
+            Some(ref dir) => dir,
+            None => continue,
+        };
+        let tag = config.bot_image(bot);
+        let _ = docker.remove_image(&tag, None, None).await;
+        let logs_path = config
+            .logs_dir()
+            .join("docker")
+            .join(format!("bot-{}-build.log", bot.name));
+        println!(
+            "** building bot {}. Logs will be stored at {:?}",
+            bot.name, logs_path
+        );
+        build_image(
+            docker,
+            config,
+            context_dir,
+            &tag,
+            &format!("bot-{}.tar", bot.name),
+            &logs_path,
+        )
+        .await
+        .with_context(|| format!("Could not build bot {}", bot.name))?;
+    }
+    Ok(())
+}
+
+/// Start all bots, handing them the access token of their user, if any.
+pub async fn start(
+    docker: &Docker,
+    config: &Config,
+    clients: &HashMap<String, matrix_sdk::Client>,
+) -> Result<(), Error> {
+    for bot in &config.bots {
+        start_bot(docker, config, clients, bot)
+            .await
+            .with_context(|| format!("Could not start bot {}", bot.name))?;
+    }
+    Ok(())
+}
+
+async fn start_bot(
+    docker: &Docker,
+    config: &Config,
+    clients: &HashMap<String, matrix_sdk::Client>,
+    bot: &Bot,
+) -> Result<(), Error> {
+    let container_name = config.bot_container_name(bot);
+    let image = config.bot_image(bot);
+    let runtime = config.docker.runtime.container_runtime();
+
+    let mut env = vec![
+        format!(
+            "{}=http://{}:{}",
+            MX_TEST_HOMESERVER_URL,
+            config.run_container_name(),
+            HARDCODED_GUEST_PORT
+        ),
+        format!("{}={}", MX_TEST_SERVER_NAME, config.homeserver.server_name),
+    ];
+    if let Some(ref localname) = bot.user {
+        let client = clients.get(localname).ok_or_else(|| {
+            anyhow!(
+                "No client for user {}, users must be registered during `up` to be handed to bots",
+                localname
+            )
+        })?;
+        let user_id = client
+            .user_id()
+            .ok_or_else(|| anyhow!("Cannot determine full user id for user {}", localname))?;
+        let access_token = client
+            .access_token()
+            .ok_or_else(|| anyhow!("User {} is not logged in", localname))?;
+        env.push(format!("{}={}", MX_TEST_BOT_USER_ID, user_id));
+        env.push(format!("{}={}", MX_TEST_BOT_ACCESS_TOKEN, access_token));
+    }
+    let mut binds = vec![];
+    if let Some(appservice) = config
+        .appservices
+        .iter()
+        .find(|appservice| appservice.bot.as_ref() == Some(&bot.name))
+    {
+        let registration = appservice::load_registration(config, appservice)?;
+        env.push(format!("{}={}", MX_TEST_AS_TOKEN, registration.as_token));
+        env.push(format!("{}={}", MX_TEST_HS_TOKEN, registration.hs_token));
+        env.push(format!(
+            "{}={}",
+            MX_TEST_AS_REGISTRATION, BOT_REGISTRATION_PATH
+        ));
+        if let Some(registration) = appservice::load_double_puppet_registration(config, appservice)?
+        {
+            env.push(format!(
+                "{}={}",
+                MX_TEST_DOUBLE_PUPPET_TOKEN, registration.as_token
+            ));
+        }
+        let host_path = Environment::detect(docker)
+            .await?
+            .host_path(&config.appservice_registration_path(appservice))?;
+        binds.push(format!(
+            "{}:{}:ro",
+            host_path.to_string_lossy(),
+            BOT_REGISTRATION_PATH
+        ));
+    }
+    env.extend(
+        bot.env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value)),
+    );
+
+    // Images built during `build` are always available, others may need to be pulled.
+    pull_image_if_missing(docker, config, &image).await?;
+
+    // Remove any leftover from a previous run.
+    let _ = docker.stop_container(&container_name, None).await;
+    let _ = docker.remove_container(&container_name, None).await;
+
+    debug!("Creating container {}", container_name);
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: container_name.as_str(),
+            }),
+            BollardContainerConfig {
+                env: Some(env),
+                image: Some(image.clone()),
+                cmd: bot.command.clone(),
+                labels: Some(config.docker_labels()),
+                networking_config: networking_config(config, &bot.aliases),
+                host_config: Some(HostConfig {
+                    network_mode: Some(config.network()),
+                    binds: Some(binds),
+                    // Enable access to host as `host.docker.internal`, as for Synapse.
+                    extra_hosts: runtime.extra_hosts(),
+                    userns_mode: runtime.userns_mode(),
+                    ..HostConfig::default()
+                }),
+                ..BollardContainerConfig::default()
+            },
+        )
+        .await
+        .context("Failed to create container")?;
+    docker
+        .start_container::<String>(&container_name, None)
+        .await
+        .context("Failed to start container")?;
+
+    // Write logs to the logs directory.
+    let logs_path = config
+        .logs_dir()
+        .join("docker")
+        .join(format!("bot-{}.log", bot.name));
+    println!(
+        "** started bot {}. Logs will be stored at {:?}",
+        bot.name, logs_path
+    );
+    write_container_logs(docker, &container_name, &logs_path).await
+}
+
+/// Stop and remove all bots.
+///
+/// Bots that have stopped on their own with an error are reported, as this
+/// generally indicates a crash.
+pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let mut result = Ok(());
+    for bot in &config.bots {
+        let container_name = config.bot_container_name(bot);
+        match docker.inspect_container(&container_name, None).await {
+            Ok(response) => {
+                let state = response.state.unwrap_or_default();
+                if state.running != Some(true) && state.exit_code.unwrap_or(0) != 0 {
+                    println!(
+                        "** bot {} had stopped with exit code {}, see {:?}",
+                        bot.name,
+                        state.exit_code.unwrap_or(0),
+                        config
+                            .logs_dir()
+                            .join("docker")
+                            .join(format!("bot-{}.log", bot.name))
+                    );
+                }
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                debug!(target: "mx-tester-down", "Bot container {} not found", container_name);
+                continue;
+            }
+            Err(err) => {
+                result = result.and(Err(err).context("Error inspecting bot container"));
+                continue;
+            }
+        }
+        debug!(target: "mx-tester-down", "Taking down bot {}", bot.name);
+        let _ = docker.stop_container(&container_name, None).await;
+        if let Err(err) = docker.remove_container(&container_name, None).await {
+            result = result.and(
+                Err(err).with_context(|| format!("Error removing container of bot {}", bot.name)),
+            );
+        }
+    }
+    result
+}
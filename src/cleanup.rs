@@ -1,4 +1,6 @@
-use crate::Config;
+use crate::{Config, Runtime};
+use anyhow::Error;
+use bollard::Docker;
 use log::warn;
 use std::sync::Arc;
 
@@ -21,12 +23,28 @@ pub struct Cleanup {
     /// The container name used during `up` and `run`.
     run_container_name: Arc<str>,
 
+    /// The containers of bots, started during `up`.
+    bot_container_names: Vec<Arc<str>>,
+
+    /// The containers of homeservers declared in `homeservers`, started during `up`.
+    federated_container_names: Vec<Arc<str>>,
+
+    /// The containers of the TURN server, MAS and the identity server, if any,
+    /// started during `up`.
+    sidecar_container_names: Vec<Arc<str>>,
+
     /// The network to which this container is attached.
     network_name: Arc<str>,
 
     /// If `true`, during cleanup, also take down the network.
     /// `false` by default.
     cleanup_network: bool,
+
+    /// The container runtime running the containers.
+    runtime: Runtime,
+
+    /// A client for the container runtime, as configured in `config.docker`.
+    docker: Result<Docker, Error>,
 }
 impl Cleanup {
     pub fn new(config: &Config) -> Self {
@@ -34,8 +52,82 @@ impl Cleanup {
             is_armed: true,
             setup_container_name: config.setup_container_name().into(),
             run_container_name: config.run_container_name().into(),
+            bot_container_names: config
+                .bots
+                .iter()
+                .map(|bot| config.bot_container_name(bot).into())
+                .collect(),
+            federated_container_names: config
+                .homeservers
+                .iter()
+                .filter_map(|peer| config.federated(peer).ok())
+                .flat_map(|peer_config| {
+                    [
+                        peer_config.setup_container_name().into(),
+                        peer_config.run_container_name().into(),
+                    ]
+                })
+                .collect(),
+            sidecar_container_names: config
+                .turn
+                .as_ref()
+                .map(|_| crate::turn::container_name(config))
+                .into_iter()
+                .chain(config.mas.as_ref().into_iter().flat_map(|_| {
+                    [
+                        crate::mas::container_name(config),
+                        crate::mas::database_container_name(config),
+                    ]
+                }))
+                .chain(
+                    config
+                        .identity_server
+                        .as_ref()
+                        .map(|_| crate::identity::container_name(config)),
+                )
+                .chain(config.push.as_ref().into_iter().flat_map(|_| {
+                    [
+                        crate::push::container_name(config),
+                        crate::push::receiver_container_name(config),
+                    ]
+                }))
+                .chain(
+                    config
+                        .email
+                        .as_ref()
+                        .map(|_| crate::email::container_name(config)),
+                )
+                .chain(
+                    config
+                        .jaeger
+                        .as_ref()
+                        .map(|_| crate::jaeger::container_name(config)),
+                )
+                .chain(
+                    config
+                        .sso
+                        .as_ref()
+                        .map(|_| crate::sso::container_name(config)),
+                )
+                .chain(
+                    config
+                        .postgres
+                        .as_ref()
+                        .filter(|postgres| !postgres.is_external())
+                        .map(|_| crate::postgres::container_name(config)),
+                )
+                .chain(
+                    config
+                        .services
+                        .iter()
+                        .map(|service| crate::services::container_name(config, service)),
+                )
+                .map(Into::into)
+                .collect(),
             network_name: config.network().into(),
             cleanup_network: false,
+            runtime: config.docker.runtime,
+            docker: config.docker.runtime.container_runtime().connect(config),
         }
     }
 
@@ -60,21 +152,43 @@ impl Drop for Cleanup {
         if !self.is_armed {
             return;
         }
-        let docker = bollard::Docker::connect_with_local_defaults()
-            .expect("Failed to connect to Docker daemon");
+        let docker = match self.docker {
+            Ok(ref docker) => docker.clone(),
+            Err(ref err) => {
+                warn!(
+                    "Auto-cleanup: could not connect to the container runtime: {:#}",
+                    err
+                );
+                return;
+            }
+        };
+        let runtime = self.runtime.container_runtime();
         let setup_container_name = self.setup_container_name.clone();
         let run_container_name = self.run_container_name.clone();
+        let bot_container_names = self.bot_container_names.clone();
+        let federated_container_names = self.federated_container_names.clone();
+        let sidecar_container_names = self.sidecar_container_names.clone();
         let network_name = self.network_name.clone();
         let cleanup_network = self.cleanup_network;
         tokio::task::block_in_place(move || {
             tokio::runtime::Handle::current().block_on(async move {
                 warn!("Auto-cleanup...");
-                let _ = docker.stop_container(&setup_container_name, None).await;
-                let _ = docker.remove_container(&setup_container_name, None).await;
-                let _ = docker.stop_container(&run_container_name, None).await;
-                let _ = docker.remove_container(&run_container_name, None).await;
+                for container_name in bot_container_names
+                    .into_iter()
+                    .chain(federated_container_names)
+                    .chain(sidecar_container_names)
+                {
+                    let _ = runtime.stop_container(&docker, &container_name).await;
+                    let _ = runtime.remove_container(&docker, &container_name).await;
+                }
+                let _ = runtime.stop_container(&docker, &setup_container_name).await;
+                let _ = runtime
+                    .remove_container(&docker, &setup_container_name)
+                    .await;
+                let _ = runtime.stop_container(&docker, &run_container_name).await;
+                let _ = runtime.remove_container(&docker, &run_container_name).await;
                 if cleanup_network {
-                    let _ = docker.remove_network(&network_name).await;
+                    let _ = runtime.remove_network(&docker, &network_name).await;
                 }
                 warn!("Auto-cleanup... DONE");
             });
@@ -0,0 +1,144 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The commands of a test, i.e. `build`, `up`, `run` and `down`, and how the
+//! command-line selects them.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use itertools::Itertools;
+
+use crate::Status;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Build,
+    Up,
+    Run,
+    Down,
+}
+
+impl Command {
+    /// The name of the command, as on the command-line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Build => "build",
+            Command::Up => "up",
+            Command::Run => "run",
+            Command::Down => "down",
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = Error;
+    fn from_str(source: &str) -> Result<Self, Error> {
+        match source {
+            "up" => Ok(Command::Up),
+            "down" => Ok(Command::Down),
+            "run" => Ok(Command::Run),
+            "build" => Ok(Command::Build),
+            _ => Err(anyhow!(
+                "Invalid command `{}`, expected `build`, `up`, `run` or `down`",
+                source
+            )),
+        }
+    }
+}
+
+/// Apply `--only`, `--skip-build` and `--skip-registration` to the list of commands,
+/// keeping their order.
+///
+/// Fails on combinations that would not do what the user expects, e.g. skipping
+/// a command that isn't going to run anyway.
+pub fn select_commands(
+    commands: Vec<Command>,
+    only: Option<Vec<Command>>,
+    skip_build: bool,
+    skip_registration: bool,
+) -> Result<Vec<Command>, Error> {
+    let mut commands = commands;
+    if let Some(only) = only {
+        if let Some(missing) = only.iter().find(|command| !commands.contains(command)) {
+            return Err(anyhow!(
+                "`--only {}` but `{}` is not among the commands {}",
+                missing.name(),
+                missing.name(),
+                commands
+                    .iter()
+                    .map(|command| format!("`{}`", command.name()))
+                    .join(", ")
+            ));
+        }
+        commands.retain(|command| only.contains(command));
+    }
+    if skip_build {
+        if !commands.contains(&Command::Build) {
+            return Err(anyhow!("`--skip-build` but there is no `build` to skip"));
+        }
+        commands.retain(|command| *command != Command::Build);
+    }
+    if skip_registration && !commands.contains(&Command::Up) {
+        return Err(anyhow!("`--skip-registration` only makes sense with `up`"));
+    }
+    Ok(commands)
+}
+
+/// The progress of the commands of a test.
+///
+/// Once a command has failed, every further command but `down` is skipped, so
+/// that we don't leave the environment up, and `down` takes the failure path.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// The status of the last `run`, until `down` consumes it.
+    run_status: Option<Status>,
+
+    /// The commands that have failed so far, with their error.
+    pub failures: Vec<(Command, Error)>,
+}
+
+impl Progress {
+    /// Whether `command` should be skipped because of a previous failure.
+    pub fn skips(&self, command: Command) -> bool {
+        !self.failures.is_empty() && command != Command::Down
+    }
+
+    /// The status with which to bring things `down`.
+    pub fn down_status(&self) -> Status {
+        match self.run_status {
+            _ if !self.failures.is_empty() => Status::Failure,
+            None => Status::Manual,
+            Some(status) => status,
+        }
+    }
+
+    /// Record the result of `command`.
+    pub fn record(&mut self, command: Command, result: Result<(), Error>) {
+        match command {
+            Command::Run => {
+                self.run_status = Some(if result.is_ok() {
+                    Status::Success
+                } else {
+                    Status::Failure
+                })
+            }
+            Command::Down => self.run_status = None,
+            Command::Build | Command::Up => {}
+        }
+        if let Err(err) = result {
+            self.failures.push((command, err));
+        }
+    }
+}
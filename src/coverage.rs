@@ -0,0 +1,123 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collecting the coverage of Python code running in Synapse, as specified
+//! in `artifacts.coverage`.
+//!
+//! During `build`, `coverage.py` is installed in the image and started in every
+//! Python process through `COVERAGE_PROCESS_START`, which also covers workers.
+//! Data files are written to the data directory and moved to `logs/coverage`
+//! during `down`, so that they are part of the artifacts.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::{util::move_file, Config};
+
+/// The directory in which data files are written, within the Synapse container.
+const GUEST_COVERAGE_DIR: &str = "/data/coverage";
+
+/// The name of the configuration file of `coverage.py`, in the build context.
+pub const COVERAGERC: &str = "coveragerc";
+
+/// Configuring coverage collection.
+#[derive(Debug, Default, TypedBuilder, Deserialize, Serialize)]
+pub struct CoverageConfig {
+    /// The Python packages to measure, e.g. the packages of the modules under test.
+    ///
+    /// If unspecified, measure all the code executed, including Synapse itself.
+    #[serde(default)]
+    #[builder(default)]
+    pub packages: Vec<String>,
+}
+
+/// The contents of the configuration file of `coverage.py`.
+pub fn coveragerc(coverage: &CoverageConfig) -> String {
+    let mut rc = format!(
+        "[run]
+# One data file per process, as Synapse may run several processes.
+parallel = true
+# Docker stops Synapse with SIGTERM, save data before exiting.
+sigterm = true
+data_file = {}/.coverage
+",
+        GUEST_COVERAGE_DIR
+    );
+    if !coverage.packages.is_empty() {
+        rc.push_str(&format!("source_pkgs = {}\n", coverage.packages.join(", ")));
+    }
+    rc
+}
+
+/// The Dockerfile instructions installing `coverage.py` and starting it
+/// in every Python process.
+pub fn dockerfile() -> String {
+    format!("
+# Collect coverage, as per `artifacts.coverage`.
+RUN /usr/local/bin/python -m pip install coverage
+COPY {coveragerc} /mx-tester/{coveragerc}
+RUN echo \"import coverage; coverage.process_startup()\" > $(/usr/local/bin/python -c \"import sysconfig; print(sysconfig.get_paths()['purelib'])\")/mx-tester-coverage.pth
+ENV COVERAGE_PROCESS_START=/mx-tester/{coveragerc}
+",
+        coveragerc = COVERAGERC
+    )
+}
+
+/// The directory in which data files are written, on the host.
+fn host_coverage_dir(config: &Config) -> PathBuf {
+    config.synapse_data_dir().join("coverage")
+}
+
+/// The directory in which data files are stored once Synapse is down.
+pub fn coverage_dir(config: &Config) -> PathBuf {
+    config.logs_dir().join("coverage")
+}
+
+/// Prepare a fresh directory for data files, before Synapse is started.
+pub fn prepare(config: &Config) -> Result<(), Error> {
+    for dir in [host_coverage_dir(config), coverage_dir(config)] {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    let dir = host_coverage_dir(config);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Could not create directory {:?}", dir))
+}
+
+/// Move data files to the logs directory, once Synapse is down.
+///
+/// Data files may be combined with `coverage combine`, using a `[paths]` section
+/// to map the paths within the container to the source of the modules.
+pub fn collect(config: &Config) -> Result<(), Error> {
+    let source = host_coverage_dir(config);
+    if !source.exists() {
+        return Ok(());
+    }
+    let dest = coverage_dir(config);
+    std::fs::create_dir_all(&dest)
+        .with_context(|| format!("Could not create directory {:?}", dest))?;
+    let mut count = 0;
+    for entry in
+        std::fs::read_dir(&source).with_context(|| format!("Could not read {:?}", source))?
+    {
+        let path = entry?.path();
+        if let Some(name) = path.file_name() {
+            move_file(&path, &dest.join(name))?;
+            count += 1;
+        }
+    }
+    println!("** collected {} coverage data file(s) in {:?}", count, dest);
+    Ok(())
+}
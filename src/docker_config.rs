@@ -0,0 +1,271 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The registry credentials of the Docker CLI, as stored in
+//! `~/.docker/config.json`.
+//!
+//! CI runners are typically provisioned with `docker login` or with a
+//! credential helper, e.g. `docker-credential-ecr-login` or
+//! `docker-credential-osxkeychain`, rather than with a username and password.
+//! Whenever neither `credentials` nor `registry_profiles` match a registry,
+//! we fall back to the credentials of the Docker CLI for this registry.
+
+use std::{collections::HashMap, path::PathBuf, process::Stdio};
+
+use anyhow::{anyhow, Context, Error};
+use data_encoding::BASE64;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{Credentials, DOCKER_HUB};
+
+/// The server address of Docker Hub, as used by `docker login`.
+pub const DOCKER_HUB_SERVER_ADDRESS: &str = "https://index.docker.io/v1/";
+
+/// The username returned by credential helpers for identity tokens.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// The message of credential helpers that don't know a registry.
+const CREDENTIALS_NOT_FOUND: &str = "credentials not found";
+
+/// An entry of `auths`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthEntry {
+    /// `username:password`, in base64.
+    #[serde(default)]
+    pub auth: Option<String>,
+
+    #[serde(default)]
+    pub identitytoken: Option<String>,
+}
+
+/// The part of `~/.docker/config.json` dealing with registries.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DockerConfigFile {
+    /// Credentials stored in the file itself, indexed by server address.
+    ///
+    /// With a credential helper, `docker login` still records an empty entry.
+    #[serde(default)]
+    pub auths: HashMap<String, AuthEntry>,
+
+    /// The default credential helper, e.g. `osxkeychain` for
+    /// `docker-credential-osxkeychain`.
+    #[serde(default, rename = "credsStore")]
+    pub creds_store: Option<String>,
+
+    /// Credential helpers for specific registries, e.g. `ecr-login` for
+    /// `123456789012.dkr.ecr.eu-west-1.amazonaws.com`.
+    #[serde(default, rename = "credHelpers")]
+    pub cred_helpers: HashMap<String, String>,
+}
+
+/// The host of the registry of a server address, e.g. `docker.io` for
+/// `https://index.docker.io/v1/`.
+pub fn registry_of(server: &str) -> String {
+    Credentials {
+        serveraddress: Some(server.to_string()),
+        ..Credentials::default()
+    }
+    .registry()
+    .to_string()
+}
+
+impl DockerConfigFile {
+    /// The path of the file, i.e. `$DOCKER_CONFIG/config.json`, by default
+    /// `~/.docker/config.json`.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+            return Some(PathBuf::from(dir).join("config.json"));
+        }
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+    }
+
+    /// Load the file, if it exists.
+    pub fn load() -> Result<Self, Error> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", path)),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid Docker configuration {:?}", path))
+    }
+
+    /// The server addresses of all the registries the Docker CLI may have
+    /// credentials for, sorted.
+    pub fn servers(&self) -> Vec<&str> {
+        let mut servers: Vec<&str> = self
+            .auths
+            .keys()
+            .chain(self.cred_helpers.keys())
+            .map(String::as_str)
+            .collect();
+        servers.sort_unstable();
+        servers.dedup();
+        servers
+    }
+
+    /// The credential helper for a registry, if any: the one specified in
+    /// `credHelpers`, otherwise `credsStore` if `docker login` recorded the
+    /// registry in `auths`.
+    pub fn helper(&self, registry: &str) -> Option<&str> {
+        if let Some((_, helper)) = self
+            .cred_helpers
+            .iter()
+            .find(|(server, _)| registry_of(server) == registry)
+        {
+            return Some(helper);
+        }
+        if self
+            .auths
+            .keys()
+            .any(|server| registry_of(server) == registry)
+        {
+            return self.creds_store.as_deref();
+        }
+        None
+    }
+
+    /// The credentials stored in `auths` for a registry, if any.
+    pub fn stored_credentials(&self, registry: &str) -> Result<Option<Credentials>, Error> {
+        let (server, entry) = match self
+            .auths
+            .iter()
+            .find(|(server, _)| registry_of(server) == registry)
+        {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let mut credentials = Credentials {
+            serveraddress: Some(server.clone()),
+            identitytoken: entry.identitytoken.clone(),
+            ..Credentials::default()
+        };
+        if let Some(ref auth) = entry.auth {
+            let decoded = BASE64
+                .decode(auth.as_bytes())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| anyhow!("Invalid `auth` for {} in Docker configuration", server))?;
+            let (username, password) = decoded.split_once(':').ok_or_else(|| {
+                anyhow!(
+                    "Invalid `auth` for {} in Docker configuration, expected `username:password`",
+                    server
+                )
+            })?;
+            credentials.username = Some(username.to_string());
+            credentials.password = Some(password.to_string());
+        }
+        Ok(if credentials.is_empty() {
+            None
+        } else {
+            Some(credentials)
+        })
+    }
+
+    /// The credentials of the Docker CLI for a registry, e.g. `ghcr.io`, if any.
+    ///
+    /// If the registry has a credential helper, this runs the helper.
+    pub async fn credentials(&self, registry: &str) -> Result<Option<Credentials>, Error> {
+        if let Some(helper) = self.helper(registry) {
+            let server = match self
+                .servers()
+                .into_iter()
+                .find(|server| registry_of(server) == registry)
+            {
+                Some(server) => server.to_string(),
+                // Helpers know Docker Hub by its legacy address.
+                None if registry == DOCKER_HUB => DOCKER_HUB_SERVER_ADDRESS.to_string(),
+                None => registry.to_string(),
+            };
+            return get_from_helper(helper, &server).await;
+        }
+        self.stored_credentials(registry)
+    }
+}
+
+/// The output of `docker-credential-* get`.
+#[derive(Debug, Deserialize)]
+pub struct HelperCredentials {
+    #[serde(rename = "Username")]
+    pub username: String,
+
+    #[serde(rename = "Secret")]
+    pub secret: String,
+}
+
+impl HelperCredentials {
+    /// Convert into credentials for a server address.
+    pub fn into_credentials(self, server: &str) -> Credentials {
+        let mut credentials = Credentials {
+            serveraddress: Some(server.to_string()),
+            ..Credentials::default()
+        };
+        if self.username == IDENTITY_TOKEN_USERNAME {
+            credentials.identitytoken = Some(self.secret);
+        } else {
+            credentials.username = Some(self.username);
+            credentials.password = Some(self.secret);
+        }
+        credentials
+    }
+}
+
+/// Ask credential helper `docker-credential-{helper}` for the credentials of
+/// a server address.
+///
+/// Returns `None` if the helper doesn't know this server.
+pub async fn get_from_helper(helper: &str, server: &str) -> Result<Option<Credentials>, Error> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = tokio::process::Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run credential helper {}", program))?;
+    {
+        let mut stdin = child.stdin.take().expect("We have just piped stdin");
+        stdin
+            .write_all(server.as_bytes())
+            .await
+            .with_context(|| format!("Could not write to credential helper {}", program))?;
+        // Dropping stdin closes it, so that the helper stops waiting for input.
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("Could not run credential helper {}", program))?;
+    if !output.status.success() {
+        // Helpers report errors on stdout.
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains(CREDENTIALS_NOT_FOUND) {
+            return Ok(None);
+        }
+        return Err(anyhow!(
+            "Credential helper {} failed for {}: {}{}",
+            program,
+            server,
+            message.trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid output of credential helper {}", program))?;
+    Ok(Some(credentials.into_credentials(server)))
+}
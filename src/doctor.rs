@@ -0,0 +1,475 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Diagnosing the environment of mx-tester: `mx-tester doctor`.
+//!
+//! Most failures of a first run are caused by the environment rather than by
+//! the test, e.g. a Docker daemon that isn't reachable, a port already in use
+//! or a test root that the current user cannot write to, and surface as opaque
+//! errors of the Docker API. Each check prints what it found and, if something
+//! is wrong, how to fix it.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use bollard::Docker;
+
+use crate::environment::Environment;
+use crate::Config;
+
+/// Below this much free disk space in the test root, building images and
+/// running Synapse are likely to fail.
+const MIN_FREE_DISK_BYTES: u64 = 5 * GIB;
+
+/// The memory needed to run Synapse, without and with workers.
+const MIN_MEMORY_BYTES: u64 = 2 * GIB;
+const MIN_MEMORY_WORKERS_BYTES: u64 = 4 * GIB;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// The problems found by the checks.
+#[derive(Default)]
+struct Diagnosis {
+    /// Problems that will cause mx-tester to fail.
+    errors: usize,
+
+    /// Problems that may cause mx-tester to fail, depending on the test.
+    warnings: usize,
+}
+impl Diagnosis {
+    fn ok(&self, message: &str) {
+        println!("** ok: {}", message);
+    }
+    fn error(&mut self, message: &str, fix: &str) {
+        self.errors += 1;
+        println!("** error: {}\n   fix: {}", message, fix);
+    }
+    fn warning(&mut self, message: &str, fix: &str) {
+        self.warnings += 1;
+        println!("** warning: {}\n   fix: {}", message, fix);
+    }
+}
+
+/// Check the environment of mx-tester for `config`, printing what is found
+/// and how to fix problems.
+///
+/// Fails if mx-tester cannot work in this environment. Unlike other commands,
+/// this connects to the Docker daemon itself, to diagnose connection problems.
+pub async fn doctor(config: &Config) -> Result<(), Error> {
+    let mut diagnosis = Diagnosis::default();
+
+    println!("* doctor: container runtime");
+    let docker = check_connection(config, &mut diagnosis).await;
+
+    println!("* doctor: environment");
+    let environment = match docker {
+        Some(ref docker) => Environment::detect(docker).await?,
+        None => Environment::Host,
+    };
+    match environment {
+        Environment::Host => {
+            println!("** running directly on the Docker host (or Docker-in-Docker), paths are used as is")
+        }
+        Environment::Container { ref id, ref mounts } => {
+            println!(
+                "** running in container {} of the Docker host (Docker-outside-of-Docker), bind-mount paths are translated through its mounts:",
+                id
+            );
+            for (destination, source) in mounts {
+                println!("*** {:?} -> {:?}", destination, source);
+            }
+        }
+    }
+
+    println!("* doctor: bind-mounts");
+    check_bind_mounts(config, &environment, &mut diagnosis);
+
+    println!("* doctor: test root");
+    check_test_root(config, &mut diagnosis);
+
+    println!("* doctor: ports");
+    check_ports(config, &mut diagnosis);
+
+    if let Some(ref docker) = docker {
+        println!("* doctor: resources and user namespaces");
+        check_daemon(docker, config, &mut diagnosis).await;
+    }
+    check_cgroup_memory(config, &mut diagnosis);
+
+    if diagnosis.errors > 0 {
+        return Err(anyhow!(
+            "{} error(s), {} warning(s), see above",
+            diagnosis.errors,
+            diagnosis.warnings
+        ));
+    }
+    println!("* doctor: success, {} warning(s)", diagnosis.warnings);
+    Ok(())
+}
+
+/// Connect to the daemon, explaining why this fails if it does.
+async fn check_connection(config: &Config, diagnosis: &mut Diagnosis) -> Option<Docker> {
+    let runtime = config.docker.runtime.container_runtime();
+    let err = match crate::connect(config).await {
+        Ok(docker) => {
+            match docker.version().await {
+                Ok(version) => diagnosis.ok(&format!(
+                    "connected to {} {}, API {}",
+                    runtime.name(),
+                    version.version.as_deref().unwrap_or("?"),
+                    version.api_version.as_deref().unwrap_or("?")
+                )),
+                Err(err) => diagnosis.warning(
+                    &format!(
+                        "could not determine the version of {}: {}",
+                        runtime.name(),
+                        err
+                    ),
+                    "check that the daemon is a recent Docker or Podman",
+                ),
+            }
+            return Some(docker);
+        }
+        Err(err) => err,
+    };
+    let message = format!("cannot connect to the {} daemon: {:#}", runtime.name(), err);
+    let start = match config.docker.runtime {
+        crate::Runtime::Docker => "start Docker, e.g. `sudo systemctl start docker`",
+        crate::Runtime::Podman => {
+            "start the Podman API service, e.g. `systemctl --user start podman.socket`"
+        }
+    };
+    match runtime.socket() {
+        None => diagnosis.error(
+            &message,
+            "check that `DOCKER_HOST` points to a running daemon",
+        ),
+        Some(socket) => match std::os::unix::net::UnixStream::connect(&socket) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => diagnosis.error(
+                &format!("{}: socket {:?} does not exist", message, socket),
+                start,
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => diagnosis.error(
+                &format!("{}: no permission to use socket {:?}", message, socket),
+                "add the current user to group `docker`, e.g. `sudo usermod -aG docker $USER`, then log in again",
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => diagnosis.error(
+                &format!("{}: nothing listens on socket {:?}", message, socket),
+                start,
+            ),
+            _ => diagnosis.error(
+                &message,
+                "check that the daemon is healthy, e.g. with `docker info`, or increase `--docker-timeout`",
+            ),
+        },
+    }
+    None
+}
+
+/// Check that the directories bind-mounted in containers are visible to the daemon.
+fn check_bind_mounts(config: &Config, environment: &Environment, diagnosis: &mut Diagnosis) {
+    let fix = "mount this directory (or one of its parents) in the container of mx-tester, or use `--root`";
+    for path in [
+        config.synapse_data_dir(),
+        config.synapse_workers_dir(),
+        config.etc_dir(),
+        config.logs_dir(),
+    ] {
+        match environment.host_path(&path) {
+            Ok(host_path) => diagnosis.ok(&format!("{:?} -> {:?}", path, host_path)),
+            Err(err) => diagnosis.error(&format!("{:?}: {}", path, err), fix),
+        }
+    }
+    for module in &config.modules {
+        if let crate::InstallMode::Editable = module.install_mode {
+            match module
+                .host_path()
+                .and_then(|path| Ok((environment.host_path(&path)?, path)))
+            {
+                Ok((host_path, path)) => diagnosis.ok(&format!("{:?} -> {:?}", path, host_path)),
+                Err(err) => diagnosis.error(&format!("module {}: {}", module.name, err), fix),
+            }
+        }
+    }
+}
+
+/// Check that the test root is writable and has enough free space.
+fn check_test_root(config: &Config, diagnosis: &mut Diagnosis) {
+    let root = config.test_root();
+    let probe = root.join(".mx-tester-doctor");
+    match std::fs::create_dir_all(&root).and_then(|_| std::fs::write(&probe, b"")) {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            diagnosis.ok(&format!("{:?} is writable", root));
+        }
+        Err(err) => diagnosis.error(
+            &format!("cannot write to {:?}: {}", root, err),
+            "if files were left behind by containers running as another user, remove them with `mx-tester clean`, otherwise pick another directory with `--root`",
+        ),
+    }
+    match free_disk_space(&root) {
+        Ok(free) if free < MIN_FREE_DISK_BYTES => diagnosis.warning(
+            &format!(
+                "only {} of free disk space for {:?}",
+                format_bytes(free),
+                root
+            ),
+            "free some space, e.g. with `mx-tester clean --all` or `docker system prune`",
+        ),
+        Ok(free) => diagnosis.ok(&format!("{} of free disk space", format_bytes(free))),
+        Err(err) => diagnosis.warning(
+            &format!(
+                "could not determine free disk space for {:?}: {}",
+                root, err
+            ),
+            "check the disk space manually, e.g. with `df -h`",
+        ),
+    }
+}
+
+/// The free disk space, in bytes, on the file system of `path` or of its
+/// closest existing ancestor.
+fn free_disk_space(path: &Path) -> Result<u64, Error> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("/"));
+    let stat = nix::sys::statvfs::statvfs(existing)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// The ports that mx-tester publishes on the host, with what they are for.
+///
+/// This is the list checked both by `doctor` and before `up`.
+pub(crate) fn host_ports(config: &Config) -> Vec<(String, u64)> {
+    let mut ports = vec![("homeserver".to_string(), config.homeserver.host_port)];
+    ports.extend(
+        config
+            .docker
+            .port_mapping
+            .iter()
+            .map(|mapping| ("docker.port_mapping".to_string(), mapping.host)),
+    );
+    for peer in &config.homeservers {
+        ports.push((
+            format!("homeserver {}", peer.name),
+            peer.homeserver.host_port,
+        ));
+    }
+    for service in &config.services {
+        ports.extend(
+            service
+                .ports
+                .iter()
+                .map(|mapping| (format!("service {}", service.name), mapping.host)),
+        );
+    }
+    let optional = [
+        (
+            "debug.python",
+            config.debug.python.as_ref().map(|debug| debug.port),
+        ),
+        ("turn", config.turn.as_ref().and_then(|turn| turn.host_port)),
+        (
+            "identity_server",
+            config
+                .identity_server
+                .as_ref()
+                .and_then(|identity| identity.host_port),
+        ),
+        ("push", config.push.as_ref().and_then(|push| push.host_port)),
+        (
+            "postgres",
+            config
+                .postgres
+                .as_ref()
+                .and_then(|postgres| postgres.host_port),
+        ),
+        ("email", config.email.as_ref().map(|email| email.host_port)),
+        (
+            "jaeger",
+            config.jaeger.as_ref().map(|jaeger| jaeger.host_port),
+        ),
+        ("mas", config.mas.as_ref().map(|mas| mas.host_port)),
+        ("sso", config.sso.as_ref().map(|sso| sso.host_port)),
+        (
+            "recording",
+            config.recording.as_ref().map(|recording| recording.port),
+        ),
+    ];
+    for (what, port) in optional {
+        if let Some(port) = port {
+            ports.push((what.to_string(), port.into()));
+        }
+    }
+    ports
+}
+
+/// Check that the ports published on the host are free.
+fn check_ports(config: &Config, diagnosis: &mut Diagnosis) {
+    for (what, port) in host_ports(config) {
+        let port = match u16::try_from(port) {
+            Ok(port) => port,
+            Err(_) => {
+                diagnosis.error(
+                    &format!("{}: invalid port {}", what, port),
+                    "use a port between 1 and 65535",
+                );
+                continue;
+            }
+        };
+        match std::net::TcpListener::bind(("0.0.0.0", port)) {
+            Ok(_) => diagnosis.ok(&format!("port {} ({}) is free", port, what)),
+            Err(err) => diagnosis.warning(
+                &format!("port {} ({}) is not available: {}", port, what, err),
+                "if the homeserver of this test is up, this is expected, otherwise stop the program using this port, e.g. found with `ss -ltnp`, or pick another port, e.g. with `--instance`",
+            ),
+        }
+    }
+}
+
+/// Check the memory available to the daemon and whether it remaps user ids.
+async fn check_daemon(docker: &Docker, config: &Config, diagnosis: &mut Diagnosis) {
+    let info = match docker.info().await {
+        Ok(info) => info,
+        Err(err) => {
+            diagnosis.warning(
+                &format!("could not fetch information on the daemon: {}", err),
+                "check that the daemon is healthy, e.g. with `docker info`",
+            );
+            return;
+        }
+    };
+    let min_memory = if config.workers.enabled {
+        MIN_MEMORY_WORKERS_BYTES
+    } else {
+        MIN_MEMORY_BYTES
+    };
+    match info.mem_total {
+        Some(total) if (total as u64) < min_memory => diagnosis.warning(
+            &format!(
+                "the daemon has {} of memory, Synapse{} may need {}",
+                format_bytes(total as u64),
+                if config.workers.enabled {
+                    " with workers"
+                } else {
+                    ""
+                },
+                format_bytes(min_memory)
+            ),
+            "give the daemon more memory, e.g. in the settings of Docker Desktop or of the virtual machine running it",
+        ),
+        Some(total) => diagnosis.ok(&format!(
+            "the daemon has {} of memory",
+            format_bytes(total as u64)
+        )),
+        None => {}
+    }
+    let security_options = info.security_options.unwrap_or_default();
+    let has_option = |name: &str| {
+        security_options.iter().any(|option| {
+            option
+                .split(',')
+                .any(|part| part == format!("name={}", name))
+        })
+    };
+    if has_option("userns") {
+        diagnosis.warning(
+            "the daemon remaps user ids (`userns-remap`), so files written by Synapse in the test root belong to a subordinate uid of the host",
+            "if mx-tester cannot remove them, use `mx-tester clean`, or disable `userns-remap` for this daemon",
+        );
+    }
+    if has_option("rootless") {
+        match config.docker.runtime {
+            crate::Runtime::Podman => {
+                diagnosis.ok("rootless Podman, containers keep the uid of the current user")
+            }
+            crate::Runtime::Docker => diagnosis.warning(
+                "rootless Docker, so files written by Synapse in the test root belong to a subordinate uid of the host",
+                "if mx-tester cannot remove them, use `mx-tester clean`, or `rootlesskit rm -rf` them",
+            ),
+        }
+        check_subordinate_ids(diagnosis);
+    }
+}
+
+/// With a rootless daemon, check that the current user has subordinate uids,
+/// without which containers with several users, e.g. Synapse, cannot start.
+fn check_subordinate_ids(diagnosis: &mut Diagnosis) {
+    let uid = nix::unistd::getuid();
+    let name = match nix::unistd::User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => return,
+    };
+    let has_entry = std::fs::read_to_string("/etc/subuid")
+        .map(|content| {
+            content.lines().any(|line| {
+                let owner = line.split(':').next().unwrap_or_default();
+                owner == name || owner == uid.to_string()
+            })
+        })
+        .unwrap_or(false);
+    if has_entry {
+        diagnosis.ok(&format!("user {} has subordinate uids", name));
+    } else {
+        diagnosis.error(
+            &format!("user {} has no subordinate uids in /etc/subuid", name),
+            &format!(
+                "add some, e.g. `sudo usermod --add-subuids 100000-165535 --add-subgids 100000-165535 {}`",
+                name
+            ),
+        );
+    }
+}
+
+/// If mx-tester runs in a cgroup with a memory limit, e.g. in a CI container
+/// running Docker-in-Docker, check that the limit leaves room for Synapse.
+fn check_cgroup_memory(config: &Config, diagnosis: &mut Diagnosis) {
+    // cgroups v2, then v1.
+    let limit = [
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ]
+    .iter()
+    .filter_map(|path| std::fs::read_to_string(path).ok())
+    .find_map(|content| content.trim().parse::<u64>().ok());
+    let limit = match limit {
+        // cgroups v1 represent "no limit" with a huge number.
+        Some(limit) if limit < u64::MAX / 2 => limit,
+        _ => return,
+    };
+    let min_memory = if config.workers.enabled {
+        MIN_MEMORY_WORKERS_BYTES
+    } else {
+        MIN_MEMORY_BYTES
+    };
+    if limit < min_memory {
+        diagnosis.warning(
+            &format!(
+                "mx-tester runs in a cgroup limited to {} of memory, which is shared with Synapse if the daemon runs in the same container",
+                format_bytes(limit)
+            ),
+            &format!(
+                "raise the memory limit of the container or CI job to at least {}",
+                format_bytes(min_memory)
+            ),
+        );
+    } else {
+        diagnosis.ok(&format!("cgroup memory limit of {}", format_bytes(limit)));
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / GIB as f64)
+}
@@ -0,0 +1,308 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capturing the emails sent by Synapse (MailHog), as specified in `email`.
+//!
+//! MailHog runs in its own container on the same Docker network as Synapse,
+//! which sends its emails there instead of an actual SMTP server. Captured
+//! emails may be fetched with `emails`, e.g. to follow the link of a
+//! registration or password reset email.
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{clear_emails, decode_body, emails, start, stop};
+
+/// The SMTP port of MailHog, within its container.
+const SMTP_PORT: u16 = 1025;
+
+/// Configuring the capture of emails.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct EmailConfig {
+    /// The Docker image of MailHog.
+    ///
+    /// Defaults to `mailhog/mailhog:latest`.
+    #[serde(default = "EmailConfig::default_image")]
+    #[builder(default = EmailConfig::default_image())]
+    pub image: String,
+
+    /// The port of the host on which the HTTP API of MailHog is published.
+    ///
+    /// Defaults to 9925.
+    #[serde(default = "EmailConfig::default_host_port")]
+    #[builder(default = EmailConfig::default_host_port())]
+    pub host_port: u16,
+
+    /// The sender of the emails of Synapse.
+    ///
+    /// Defaults to `mx-tester <noreply@mx-tester.test>`.
+    #[serde(default = "EmailConfig::default_notif_from")]
+    #[builder(default = EmailConfig::default_notif_from())]
+    pub notif_from: String,
+
+    /// Additional hostnames of MailHog on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl EmailConfig {
+    fn default_image() -> String {
+        "mailhog/mailhog:latest".to_string()
+    }
+    fn default_host_port() -> u16 {
+        9925
+    }
+    fn default_notif_from() -> String {
+        "mx-tester <noreply@mx-tester.test>".to_string()
+    }
+
+    /// The URL of the HTTP API of MailHog, on the host.
+    pub fn api_url(&self) -> String {
+        format!("http://localhost:{}", self.host_port)
+    }
+}
+
+/// The name of the container running MailHog.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-mailhog-{}", config.name)
+}
+
+/// Patch the `email` section of homeserver.yaml to send emails to MailHog.
+///
+/// Fields of `email` specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    email: &EmailConfig,
+    content: &mut serde_yaml::Mapping,
+) -> Result<(), Error> {
+    let section = content
+        .entry("email".into())
+        .or_insert_with(|| serde_yaml::Value::Mapping(serde_yaml::Mapping::new()));
+    if section.is_null() {
+        *section = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let section = section
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("In homeserver.yaml, expected a mapping for key `email`"))?;
+    for (key, value) in [
+        ("smtp_host", yaml!(container_name(config))),
+        ("smtp_port", yaml!(SMTP_PORT)),
+        // MailHog doesn't support TLS.
+        ("force_tls", yaml!(false)),
+        ("require_transport_security", yaml!(false)),
+        ("enable_tls", yaml!(false)),
+        ("notif_from", yaml!(email.notif_from.clone())),
+    ] {
+        if !section.contains_key(key) {
+            section.insert(key.into(), value);
+        }
+    }
+    Ok(())
+}
+
+/// An email captured by MailHog.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Email {
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+
+    /// The `text/plain` part, decoded.
+    pub text: Option<String>,
+
+    /// The `text/html` part, decoded.
+    pub html: Option<String>,
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::Docker;
+    use data_encoding::BASE64;
+
+    use crate::services::{stop_container, Container, ContainerPort};
+
+    /// The port of the HTTP API of MailHog, within its container.
+    const API_PORT: u16 = 8025;
+
+    impl Email {
+        /// Parse a message, as returned by the API of MailHog.
+        pub fn from_mailhog(message: &serde_json::Value) -> Result<Email, Error> {
+            let content = &message["Content"];
+            let header = |headers: &serde_json::Value, name: &str| -> Option<String> {
+                headers[name][0].as_str().map(str::to_string)
+            };
+            let mut email = Email {
+                from: header(&content["Headers"], "From").unwrap_or_default(),
+                to: message["Raw"]["To"]
+                    .as_array()
+                    .map(|to| {
+                        to.iter()
+                            .filter_map(|to| to.as_str().map(str::to_string))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                subject: header(&content["Headers"], "Subject").unwrap_or_default(),
+                ..Email::default()
+            };
+            // A multipart message has its parts in `MIME`, otherwise the message
+            // is its own single part.
+            let parts = match message["MIME"]["Parts"].as_array() {
+                Some(parts) => parts.iter().collect(),
+                None => vec![content],
+            };
+            for part in parts {
+                let content_type = header(&part["Headers"], "Content-Type").unwrap_or_default();
+                let body = decode_body(
+                    part["Body"].as_str().unwrap_or_default(),
+                    header(&part["Headers"], "Content-Transfer-Encoding").as_deref(),
+                )?;
+                if content_type.starts_with("text/plain") && email.text.is_none() {
+                    email.text = Some(body);
+                } else if content_type.starts_with("text/html") && email.html.is_none() {
+                    email.html = Some(body);
+                }
+            }
+            Ok(email)
+        }
+    }
+
+    /// Decode the body of a part of an email, as per its `Content-Transfer-Encoding`.
+    pub fn decode_body(body: &str, encoding: Option<&str>) -> Result<String, Error> {
+        match encoding.map(str::to_ascii_lowercase).as_deref() {
+            Some("base64") => {
+                let compact: String = body.split_whitespace().collect();
+                let bytes = BASE64
+                    .decode(compact.as_bytes())
+                    .context("Invalid base64 in email")?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned())
+            }
+            Some("quoted-printable") => Ok(decode_quoted_printable(body)),
+            _ => Ok(body.to_string()),
+        }
+    }
+
+    /// Decode quoted-printable text, e.g. `a=3Db=\r\nc` => `a=bc`.
+    fn decode_quoted_printable(body: &str) -> String {
+        let bytes = body.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'=' {
+                // Soft line break.
+                if bytes[i + 1..].starts_with(b"\r\n") {
+                    i += 3;
+                    continue;
+                }
+                if bytes[i + 1..].starts_with(b"\n") {
+                    i += 2;
+                    continue;
+                }
+                if let Some(byte) = body
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    /// The emails captured so far, newest first.
+    pub async fn emails(config: &Config) -> Result<Vec<Email>, Error> {
+        let email = config
+            .email
+            .as_ref()
+            .ok_or_else(|| anyhow!("Emails are only captured with `email`"))?;
+        let response: serde_json::Value =
+            reqwest::get(format!("{}/api/v2/messages", email.api_url()))
+                .await
+                .context("Could not fetch emails from MailHog")?
+                .error_for_status()
+                .context("Could not fetch emails from MailHog")?
+                .json()
+                .await
+                .context("Invalid response from MailHog")?;
+        response["items"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Invalid response from MailHog, expected `items`"))?
+            .iter()
+            .map(Email::from_mailhog)
+            .collect()
+    }
+
+    /// Delete the emails captured so far.
+    pub async fn clear_emails(config: &Config) -> Result<(), Error> {
+        let email = config
+            .email
+            .as_ref()
+            .ok_or_else(|| anyhow!("Emails are only captured with `email`"))?;
+        reqwest::Client::new()
+            .delete(format!("{}/api/v1/messages", email.api_url()))
+            .send()
+            .await
+            .context("Could not delete emails from MailHog")?
+            .error_for_status()
+            .context("Could not delete emails from MailHog")?;
+        Ok(())
+    }
+
+    /// Start MailHog, if `email` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let email = match config.email {
+            None => return Ok(()),
+            Some(ref email) => email,
+        };
+        Container {
+            label: "MailHog".to_string(),
+            name: container_name(config),
+            image: email.image.clone(),
+            ports: vec![
+                ContainerPort::tcp(SMTP_PORT, None),
+                ContainerPort::tcp(API_PORT, Some(u64::from(email.host_port))),
+            ],
+            aliases: email.aliases.clone(),
+            log_name: "mailhog.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await
+    }
+
+    /// Stop and remove MailHog, if `email` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.email.is_none() {
+            return Ok(());
+        }
+        stop_container(docker, config, &container_name(config), "MailHog").await
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding out where mx-tester itself is running.
+//!
+//! In CI, mx-tester often runs inside a container with the socket of the host's
+//! Docker daemon mounted ("Docker-outside-of-Docker"). In that case, the paths we
+//! bind-mount into the Synapse container are resolved by the daemon on the host,
+//! so they need to be translated through the mounts of our own container.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::Docker;
+use log::debug;
+
+/// Where mx-tester is running, as far as bind-mounts are concerned.
+#[derive(Debug)]
+pub enum Environment {
+    /// On the same machine as the Docker daemon, either directly or in a
+    /// container that also runs the daemon ("Docker-in-Docker").
+    ///
+    /// Paths need no translation.
+    Host,
+
+    /// In a container managed by the Docker daemon we're talking to.
+    Container {
+        /// The id of our container.
+        id: String,
+
+        /// Our mounts, as (path in our container, path on the host).
+        mounts: Vec<(PathBuf, PathBuf)>,
+    },
+}
+
+impl Environment {
+    /// Find out where we're running.
+    pub async fn detect(docker: &Docker) -> Result<Self, Error> {
+        if !is_in_container() {
+            return Ok(Environment::Host);
+        }
+        for id in own_container_ids() {
+            let response = match docker.inspect_container(&id, None).await {
+                Ok(response) => response,
+                Err(err) => {
+                    debug!(
+                        "Container {} is not known to the Docker daemon: {}",
+                        id, err
+                    );
+                    continue;
+                }
+            };
+            let mounts = response
+                .mounts
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|mount| {
+                    Some((
+                        PathBuf::from(mount.destination?),
+                        PathBuf::from(mount.source?),
+                    ))
+                })
+                .collect();
+            return Ok(Environment::Container {
+                id: response.id.unwrap_or(id),
+                mounts,
+            });
+        }
+        // Either Docker-in-Docker or a daemon on another machine, in either case
+        // we cannot do better than using paths as they are.
+        debug!("Running in a container unknown to the Docker daemon");
+        Ok(Environment::Host)
+    }
+
+    /// The path on the Docker host corresponding to a local path, for use in a bind-mount.
+    pub fn host_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()
+                .context("Could not determine current directory")?
+                .join(path)
+        };
+        let mounts = match self {
+            Environment::Host => return Ok(path),
+            Environment::Container { ref mounts, .. } => mounts,
+        };
+        // If several mounts match, the most specific one wins.
+        let (destination, source) = mounts
+            .iter()
+            .filter(|(destination, _)| path.starts_with(destination))
+            .max_by_key(|(destination, _)| destination.components().count())
+            .ok_or_else(|| {
+                anyhow!(
+                    "mx-tester is running in a container but {:?} is not in a volume or bind-mount, so the Docker daemon cannot access it. Mount this directory (or one of its parents) in the container, e.g. with `-v {}:{}` or use `--root`",
+                    path,
+                    path.display(),
+                    path.display()
+                )
+            })?;
+        let suffix = path
+            .strip_prefix(destination)
+            .expect("We have just checked that the path starts with the destination");
+        Ok(source.join(suffix))
+    }
+}
+
+/// Whether we're running inside a container, Docker or otherwise.
+fn is_in_container() -> bool {
+    if Path::new("/.dockerenv").exists() {
+        return true;
+    }
+    match std::fs::read_to_string("/proc/self/cgroup") {
+        Ok(cgroup) => ["docker", "kubepods", "containerd"]
+            .iter()
+            .any(|needle| cgroup.contains(needle)),
+        Err(_) => false,
+    }
+}
+
+/// The ids by which the Docker daemon may know our container, most reliable first.
+fn own_container_ids() -> Vec<String> {
+    let mut ids = vec![];
+    // With cgroups v2, our id typically only shows in the paths mounted from the
+    // host, e.g. `/var/lib/docker/containers/<id>/hostname`.
+    if let Ok(mountinfo) = std::fs::read_to_string("/proc/self/mountinfo") {
+        for line in mountinfo.lines() {
+            if let Some((_, rest)) = line.split_once("/containers/") {
+                let id: String = rest.chars().take_while(|c| c.is_ascii_hexdigit()).collect();
+                if id.len() == 64 && !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+    // By default, Docker sets the hostname to the short container id.
+    if let Ok(hostname) = std::fs::read_to_string("/etc/hostname") {
+        let hostname = hostname.trim().to_string();
+        if !hostname.is_empty() && !ids.contains(&hostname) {
+            ids.push(hostname);
+        }
+    }
+    ids
+}
@@ -58,7 +58,7 @@ where
     T: 'static + Send,
 {
     debug!("Storing {} logs in {:?}", name, dest);
-    let command = format!("\ncommand: {}\n", command);
+    let command = format!("\n{}command: {}\n", crate::log_header(name), command);
     tokio::task::spawn(async move {
         let mut file = OpenOptions::new()
             .create(true)
@@ -0,0 +1,193 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Federation between the homeserver under test and the homeservers declared
+//! in `homeservers`.
+//!
+//! Each federated homeserver runs the same image as the main homeserver, in
+//! its own container on the same Docker network, with its own data directory
+//! under `federation/<name>` and its own users. Homeservers reach each other
+//! through their server name, which is an alias on the network, and a
+//! federation listener with a self-signed certificate on port 8448.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{registration::User, util::YamlExt, HomeserverConfig};
+
+#[cfg(feature = "docker")]
+pub use self::docker::{down, up};
+
+/// The port of the federation listener, within each container.
+pub const FEDERATION_PORT: u64 = 8448;
+
+/// The self-signed certificate of the federation listener, within the image.
+pub(crate) const TLS_CERTIFICATE_PATH: &str = "/mx-tester/federation.crt";
+
+/// The private key of the federation listener, within the image.
+pub(crate) const TLS_PRIVATE_KEY_PATH: &str = "/mx-tester/federation.key";
+
+/// A homeserver federating with the homeserver under test.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FederatedHomeserver {
+    /// A unique name, used for containers and directories.
+    pub name: String,
+
+    /// The configuration of this homeserver, as `homeserver` for the main homeserver.
+    ///
+    /// `server_name`, `host_port` and `public_baseurl` must differ from those
+    /// of other homeservers.
+    pub homeserver: HomeserverConfig,
+
+    /// Users to register on this homeserver, as `users` for the main homeserver.
+    #[serde(default)]
+    pub users: Vec<User>,
+}
+
+/// The Dockerfile instructions generating the self-signed certificate of
+/// the federation listener.
+pub fn dockerfile() -> String {
+    format!(
+        "
+# A self-signed certificate for federation, as per `homeservers`.
+RUN apt-get update && apt-get install -y openssl
+RUN openssl req -x509 -newkey rsa:2048 -nodes -days 3650 -subj /CN=mx-tester -keyout {key} -out {cert} && chmod a+r {key}
+",
+        key = TLS_PRIVATE_KEY_PATH,
+        cert = TLS_CERTIFICATE_PATH,
+    )
+}
+
+/// Patch homeserver.yaml to federate with other homeservers on the Docker network.
+pub fn patch_homeserver_config(
+    config: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    config
+        .entry("listeners".into())
+        .or_insert(serde_yaml::Value::Null)
+        .to_seq_mut()
+        .ok_or_else(|| anyhow!("In homeserver.yaml, expected a sequence for key `listeners`"))?
+        .push(yaml!({
+            "port" => FEDERATION_PORT,
+            "tls" => true,
+            "type" => "http",
+            "bind_addresses" => yaml!(["::"]),
+            "x_forwarded" => false,
+            "resources" => yaml!([yaml!({
+                "names" => yaml!(["federation"]),
+                "compress" => false
+            })])
+        }));
+    // Homeservers run on a private network, with self-signed certificates,
+    // and should not talk to the outside world. Don't override mx-tester.yml.
+    for (key, value) in [
+        ("tls_certificate_path", yaml!(TLS_CERTIFICATE_PATH)),
+        ("tls_private_key_path", yaml!(TLS_PRIVATE_KEY_PATH)),
+        ("federation_verify_certificates", yaml!(false)),
+        ("ip_range_blacklist", yaml!([])),
+        ("trusted_key_servers", yaml!([])),
+    ] {
+        if !extra_fields.contains_key(key) {
+            config.insert(key.into(), value);
+        }
+    }
+    Ok(())
+}
+
+/// The host part of a server name, i.e. its alias on the Docker network.
+pub fn network_alias(server_name: &str) -> &str {
+    match server_name.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => server_name,
+    }
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::Docker;
+    use log::debug;
+
+    use crate::{
+        lifecycle::{create_synapse_dirs, register_users, start_homeserver},
+        Config,
+    };
+
+    /// Bring up the homeservers declared in `homeservers` and register their users.
+    pub async fn up(docker: &Docker, config: &Config) -> Result<(), Error> {
+        for peer in &config.homeservers {
+            let peer_config = config.federated(peer)?;
+            println!(
+                "** starting federated homeserver {}. Logs will be stored at {:?}",
+                peer.name,
+                peer_config
+                    .logs_dir()
+                    .join("docker")
+                    .join("up-run-down.log")
+            );
+            let _ = std::fs::remove_dir_all(peer_config.test_root());
+            create_synapse_dirs(&peer_config)?;
+            start_homeserver(docker, &peer_config)
+                .await
+                .with_context(|| format!("Failed to start federated homeserver {}", peer.name))?;
+            if !config.skip_registration {
+                register_users(docker, &peer_config, &peer_config.run_container_name())
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to register users on federated homeserver {}",
+                            peer.name
+                        )
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take down the homeservers declared in `homeservers`.
+    pub async fn down(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let mut result = Ok(());
+        for peer in &config.homeservers {
+            let peer_config = config.federated(peer)?;
+            for container_name in [
+                peer_config.setup_container_name(),
+                peer_config.run_container_name(),
+            ] {
+                debug!(target: "mx-tester-down", "Taking down {}", container_name);
+                let _ = docker.stop_container(&container_name, None).await;
+                match docker.remove_container(&container_name, None).await {
+                    Ok(_)
+                    | Err(bollard::errors::Error::DockerResponseServerError {
+                        status_code: 404,
+                        ..
+                    }) => {}
+                    Err(err) => {
+                        result = result.and(Err(err).with_context(|| {
+                            format!(
+                                "Error removing container of federated homeserver {}",
+                                peer.name
+                            )
+                        }))
+                    }
+                }
+            }
+        }
+        result
+    }
+}
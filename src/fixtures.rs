@@ -0,0 +1,164 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Placeholders in `users` and their rooms, e.g. `alice-${random}`.
+//!
+//! Placeholders let fixtures avoid collisions with the users and rooms of
+//! previous runs, e.g. against a database that isn't reset. Random values are
+//! derived from a seed, recorded in the manifest, so that a failing run may be
+//! reproduced with `--seed`.
+//!
+//! The same template always expands to the same value, so that e.g. a member
+//! `alice-${random}` of a room, the `user` of a bot or a target of an abuse
+//! scenario refers to user `alice-${random}`.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hasher},
+};
+
+use anyhow::{Context, Error};
+
+use crate::Config;
+
+/// Replaced with 8 random lowercase hexadecimal digits.
+pub const RANDOM: &str = "${random}";
+
+/// Replaced with 1 in the first template, 2 in the second, etc.
+pub const SEQ: &str = "${seq}";
+
+/// A new random seed.
+pub fn new_seed() -> u64 {
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// Expands templates, deterministically for a given seed.
+pub struct Expander {
+    /// The state of a SplitMix64 generator. We don't use `rand`, as we want
+    /// the same seed to give the same names with any version of mx-tester.
+    state: u64,
+    seq: u64,
+    expanded: HashMap<String, String>,
+}
+
+impl Expander {
+    pub fn new(seed: u64) -> Self {
+        Expander {
+            state: seed,
+            seq: 0,
+            expanded: HashMap::new(),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Expand the placeholders of a template, if any.
+    pub fn expand(&mut self, template: &str) -> String {
+        if !template.contains(RANDOM) && !template.contains(SEQ) {
+            return template.to_string();
+        }
+        if let Some(expanded) = self.expanded.get(template) {
+            return expanded.clone();
+        }
+        let mut expanded = template.to_string();
+        while expanded.contains(RANDOM) {
+            let random = format!("{:08x}", self.next_random() as u32);
+            expanded = expanded.replacen(RANDOM, &random, 1);
+        }
+        if expanded.contains(SEQ) {
+            self.seq += 1;
+            expanded = expanded.replace(SEQ, &self.seq.to_string());
+        }
+        self.expanded.insert(template.to_string(), expanded.clone());
+        expanded
+    }
+
+    /// Expand the placeholders of all the strings of a yaml value.
+    fn expand_value(&mut self, value: &mut serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::String(string) => *string = self.expand(string),
+            serde_yaml::Value::Sequence(sequence) => {
+                for item in sequence {
+                    self.expand_value(item);
+                }
+            }
+            serde_yaml::Value::Mapping(mapping) => {
+                for (_, item) in mapping.iter_mut() {
+                    self.expand_value(item);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `users`, the `users` of `homeservers`, or the fields that refer
+/// to users, contain placeholders.
+pub fn has_placeholders(config: &Config) -> bool {
+    let is_template = |name: &str| name.contains(RANDOM) || name.contains(SEQ);
+    let users =
+        std::iter::once(&config.users).chain(config.homeservers.iter().map(|peer| &peer.users));
+    users
+        .filter_map(|users| serde_yaml::to_string(users).ok())
+        .any(|yaml| is_template(&yaml))
+        || config
+            .bots
+            .iter()
+            .filter_map(|bot| bot.user.as_deref())
+            .any(is_template)
+        || config
+            .abuse
+            .iter()
+            .flat_map(|scenario| &scenario.targets)
+            .any(|target| is_template(target))
+}
+
+/// Expand the placeholders of `users`, of the `users` of `homeservers` and
+/// of the fields that refer to users, i.e. the `user` of `bots` and the
+/// `targets` of `abuse`, and remember the seed in `config.fixtures_seed`.
+pub fn expand(config: &mut Config, seed: u64) -> Result<(), Error> {
+    fn expand_users<T>(expander: &mut Expander, users: &T) -> Result<T, Error>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let mut value = serde_yaml::to_value(users).context("Could not serialize users")?;
+        expander.expand_value(&mut value);
+        serde_yaml::from_value(value).context("Invalid users after expanding placeholders")
+    }
+    let mut expander = Expander::new(seed);
+    config.users = expand_users(&mut expander, &config.users)?;
+    for peer in &mut config.homeservers {
+        peer.users = expand_users(&mut expander, &peer.users)?;
+    }
+    for bot in &mut config.bots {
+        if let Some(ref mut user) = bot.user {
+            *user = expander.expand(user);
+        }
+    }
+    for scenario in &mut config.abuse {
+        for target in &mut scenario.targets {
+            *target = expander.expand(target);
+        }
+    }
+    config.fixtures_seed = Some(seed);
+    Ok(())
+}
@@ -0,0 +1,280 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the test against variations of homeserver.yaml: `mx-tester fuzz-config`.
+//!
+//! Experimental. Modules frequently make implicit assumptions about the
+//! configuration of the homeserver, e.g. that rooms are not encrypted or that
+//! presence is enabled. For each combination of the values of `fuzz_config.axes`,
+//! the homeserver is brought up with these values in homeserver.yaml, `run` is
+//! executed, then the homeserver is brought down, and the combinations that
+//! broke are reported.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::fuzz_config;
+
+/// A key of homeserver.yaml and the values to try.
+#[derive(Clone, Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct Axis {
+    /// A top-level key of homeserver.yaml, e.g. `presence`.
+    pub key: String,
+
+    /// The values to try, e.g. `{enabled: true}` and `{enabled: false}`.
+    pub values: Vec<serde_yaml::Value>,
+}
+
+/// Configuring `mx-tester fuzz-config`.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct FuzzConfig {
+    /// The keys of homeserver.yaml to vary. Every combination of their
+    /// values is tried.
+    ///
+    /// Defaults to encryption by default on and off, presence on and off,
+    /// and room versions 9, 10 and 11.
+    #[serde(default = "FuzzConfig::default_axes")]
+    #[builder(default = FuzzConfig::default_axes())]
+    pub axes: Vec<Axis>,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl FuzzConfig {
+    fn default_axes() -> Vec<Axis> {
+        let yaml = |source: &str| -> serde_yaml::Value { serde_yaml::from_str(source).unwrap() };
+        vec![
+            Axis {
+                key: "encryption_enabled_by_default_for_room_type".to_string(),
+                values: vec![yaml("'off'"), yaml("all")],
+            },
+            Axis {
+                key: "presence".to_string(),
+                values: vec![yaml("{enabled: true}"), yaml("{enabled: false}")],
+            },
+            Axis {
+                key: "default_room_version".to_string(),
+                values: vec![yaml("'9'"), yaml("'10'"), yaml("'11'")],
+            },
+        ]
+    }
+
+    /// Every combination of the values of `axes`, as values of homeserver.yaml
+    /// by key, varying the last axis first.
+    pub fn combinations(&self) -> Vec<Vec<(String, serde_yaml::Value)>> {
+        let mut combinations = vec![vec![]];
+        for axis in &self.axes {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination: Vec<(String, serde_yaml::Value)>| {
+                    axis.values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((axis.key.clone(), value.clone()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        combinations
+    }
+}
+
+/// Check that `fuzz_config` is consistent, adding a description of each
+/// problem to `problems`.
+pub fn check(fuzz: &FuzzConfig, problems: &mut Vec<String>) {
+    let mut keys = std::collections::HashSet::new();
+    for axis in &fuzz.axes {
+        if !keys.insert(axis.key.as_str()) {
+            problems.push(format!(
+                "`fuzz_config.axes`: key {} is declared more than once",
+                axis.key
+            ));
+        }
+        if axis.values.is_empty() {
+            problems.push(format!(
+                "`fuzz_config.axes`: key {} needs at least one value",
+                axis.key
+            ));
+        }
+    }
+}
+
+/// A human-readable description of a combination, e.g.
+/// `presence={"enabled":false}, default_room_version="10"`.
+pub fn describe(combination: &[(String, serde_yaml::Value)]) -> String {
+    combination
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                key,
+                serde_json::to_string(value).unwrap_or_else(|_| format!("{:?}", value))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The outcome of the test for one combination.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Outcome {
+    /// The values of homeserver.yaml, by key.
+    #[serde(serialize_with = "crate::util::serialize_sorted")]
+    pub homeserver: HashMap<String, serde_yaml::Value>,
+
+    /// The step that failed, if any, i.e. `up`, `run` or `down`.
+    pub failed_step: Option<String>,
+
+    /// The error, if any.
+    pub error: Option<String>,
+
+    /// The logs of this combination, e.g. those of Synapse.
+    pub logs: std::path::PathBuf,
+}
+
+/// The file in which `fuzz_config` writes its report.
+pub fn report_path(config: &Config) -> std::path::PathBuf {
+    config.test_root().join("fuzz-config.json")
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::{anyhow, Context, Error};
+    use bollard::Docker;
+
+    /// Bring the homeserver up, execute `run` then bring the homeserver down for
+    /// each combination of `fuzz_config.axes`, against the image built by `build`.
+    ///
+    /// The logs of each combination are moved to `fuzz-config/<index>` in the test
+    /// root, the outcomes are written to `fuzz-config.json`. Fails if any combination
+    /// broke.
+    pub async fn fuzz_config(docker: &Docker, config: &mut Config) -> Result<Vec<Outcome>, Error> {
+        let combinations = config.fuzz_config.combinations();
+        let logs_root = config.test_root().join("fuzz-config");
+        if logs_root.exists() {
+            std::fs::remove_dir_all(&logs_root)
+                .with_context(|| format!("Could not clean up {:?}", logs_root))?;
+        }
+        std::fs::create_dir_all(&logs_root)
+            .with_context(|| format!("Could not create directory {:?}", logs_root))?;
+        let mut outcomes = vec![];
+        for (index, combination) in combinations.iter().enumerate() {
+            println!(
+                "\n* fuzz-config {}/{}: {}",
+                index + 1,
+                combinations.len(),
+                describe(combination)
+            );
+            // Apply the combination, remembering the values it replaces.
+            let previous: Vec<(&String, Option<serde_yaml::Value>)> = combination
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key,
+                        config
+                            .homeserver
+                            .extra_fields
+                            .insert(key.clone(), value.clone()),
+                    )
+                })
+                .collect();
+
+            let mut failure = match crate::up(docker, config).await {
+                Ok(()) => match crate::run(docker, config).await {
+                    Ok(()) => None,
+                    Err(err) => Some(("run", err)),
+                },
+                Err(err) => Some(("up", err)),
+            };
+            let status = if failure.is_some() {
+                crate::Status::Failure
+            } else {
+                crate::Status::Success
+            };
+            if let Err(err) = crate::down(docker, config, status).await {
+                failure.get_or_insert(("down", err));
+            }
+
+            for (key, value) in previous {
+                match value {
+                    Some(value) => config.homeserver.extra_fields.insert(key.clone(), value),
+                    None => config.homeserver.extra_fields.remove(key),
+                };
+            }
+            let logs = logs_root.join(format!("{}", index));
+            if config.logs_dir().exists() {
+                std::fs::rename(config.logs_dir(), &logs)
+                    .with_context(|| format!("Could not move logs to {:?}", logs))?;
+            }
+            match failure {
+                None => println!(
+                    "* fuzz-config {}/{}: success",
+                    index + 1,
+                    combinations.len()
+                ),
+                Some((step, ref err)) => println!(
+                    "* fuzz-config {}/{}: {} failed: {:#}",
+                    index + 1,
+                    combinations.len(),
+                    step,
+                    err
+                ),
+            }
+            outcomes.push(Outcome {
+                homeserver: combination.iter().cloned().collect(),
+                failed_step: failure.as_ref().map(|(step, _)| step.to_string()),
+                error: failure.map(|(_, err)| format!("{:#}", err)),
+                logs,
+            });
+        }
+
+        let report_path = report_path(config);
+        std::fs::write(&report_path, serde_json::to_string_pretty(&outcomes)?)
+            .with_context(|| format!("Could not write {:?}", report_path))?;
+        let broken: Vec<&Outcome> = outcomes
+            .iter()
+            .filter(|outcome| outcome.failed_step.is_some())
+            .collect();
+        println!(
+            "\n* fuzz-config: {}/{} combinations broke, see {:?}",
+            broken.len(),
+            outcomes.len(),
+            report_path
+        );
+        for (outcome, combination) in outcomes.iter().zip(&combinations) {
+            if let Some(ref step) = outcome.failed_step {
+                println!("** {} ({} failed)", describe(combination), step);
+            }
+        }
+        if !broken.is_empty() {
+            return Err(anyhow!(
+                "{} combinations of homeserver.yaml broke, see {:?}",
+                broken.len(),
+                report_path
+            ));
+        }
+        Ok(outcomes)
+    }
+}
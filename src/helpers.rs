@@ -0,0 +1,789 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for test code running against a homeserver brought up by mx-tester.
+//!
+//! Most of these helpers need to wait for the homeserver to take changes into
+//! account, hence the `wait_for_*` functions, which fail after a timeout.
+
+use std::{convert::TryFrom, time::Duration};
+
+use anyhow::{anyhow, Context, Error};
+use log::debug;
+use matrix_sdk::ruma::{
+    api::client::{
+        knock::knock_room,
+        membership::invite_user::{self, v3::InvitationRecipient},
+        message::send_message_event,
+        presence::set_presence,
+        receipt::create_receipt::{self, v3::ReceiptType},
+        redact::redact_event,
+        room::get_room_event,
+        state::{get_state_events_for_key, send_state_event},
+        typing::create_typing_event::{self, v3::Typing},
+        user_directory::search_users,
+    },
+    events::{
+        call::{
+            answer::CallAnswerEventContent,
+            candidates::{CallCandidatesEventContent, Candidate},
+            hangup::CallHangupEventContent,
+            invite::CallInviteEventContent,
+            AnswerSessionDescription, OfferSessionDescription,
+        },
+        AnyStateEvent, MessageLikeEventContent,
+    },
+    presence::PresenceState,
+    serde::Raw,
+    EventId, OwnedEventId, OwnedRoomId, OwnedUserId, OwnedVoipId, RoomAliasId, RoomId,
+    RoomOrAliasId, TransactionId, UInt, UserId, VoipId, VoipVersionId,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::synapse_admin::{self, EventReport};
+
+/// The delay between two attempts of a `wait_for_*` helper.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Wait until `user_id` appears in the results of a user directory search
+/// for `term`, as performed by `client`.
+pub async fn wait_for_user_in_directory(
+    client: &matrix_sdk::Client,
+    user_id: &UserId,
+    term: &str,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            let response = client
+                .send(search_users::v3::Request::new(term), None)
+                .await
+                .context("Could not search user directory")?;
+            if response.results.iter().any(|user| user.user_id == user_id) {
+                return Ok(());
+            }
+            debug!(
+                "User {} not found in directory search for {:?} yet",
+                user_id, term
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "User {} did not appear in directory search for {:?} within {:?}",
+                user_id,
+                term,
+                timeout
+            )
+        })
+        .and_then(|result: Result<(), Error>| result)
+}
+
+/// List the rooms currently published in the public room directory, as seen by `client`.
+pub async fn public_rooms(client: &matrix_sdk::Client) -> Result<Vec<OwnedRoomId>, Error> {
+    let mut result = vec![];
+    let mut since = None;
+    loop {
+        let response = client
+            .public_rooms(None, since.as_deref(), None)
+            .await
+            .context("Could not list public rooms")?;
+        result.extend(response.chunk.into_iter().map(|chunk| chunk.room_id));
+        match response.next_batch {
+            Some(next_batch) => since = Some(next_batch),
+            None => return Ok(result),
+        }
+    }
+}
+
+/// Wait until `room_id` appears in the public room directory, as seen by `client`.
+pub async fn wait_for_room_in_directory(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    timeout: Duration,
+) -> Result<(), Error> {
+    wait_for_room_directory_status(client, room_id, true, timeout).await
+}
+
+/// Wait until `room_id` does not appear in the public room directory, as seen by `client`.
+///
+/// Typically used to check that a module filters the room directory.
+pub async fn wait_for_room_not_in_directory(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    timeout: Duration,
+) -> Result<(), Error> {
+    wait_for_room_directory_status(client, room_id, false, timeout).await
+}
+
+async fn wait_for_room_directory_status(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    expected: bool,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            let rooms = public_rooms(client).await?;
+            if rooms.iter().any(|candidate| candidate == room_id) == expected {
+                return Ok(());
+            }
+            debug!(
+                "Room {} in directory: {}, waiting for {}",
+                room_id, !expected, expected
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Room {} {} the public room directory after {:?}",
+                room_id,
+                if expected {
+                    "is still not in"
+                } else {
+                    "is still in"
+                },
+                timeout
+            )
+        })
+        .and_then(|result: Result<(), Error>| result)
+}
+
+/// Resolve a room from either a room id (`!room:server`) or an alias (`#alias:server`).
+pub async fn resolve_room(client: &matrix_sdk::Client, room: &str) -> Result<OwnedRoomId, Error> {
+    if let Ok(room_id) = RoomId::parse(room) {
+        return Ok(room_id);
+    }
+    let alias = <&RoomAliasId>::try_from(room)
+        .with_context(|| format!("Invalid room `{}`, expected a room id or an alias", room))?;
+    let response = client
+        .resolve_room_alias(alias)
+        .await
+        .with_context(|| format!("Could not resolve alias {}", alias))?;
+    Ok(response.room_id)
+}
+
+/// Set the presence of the user of `client`, with an optional status message.
+pub async fn set_presence(
+    client: &matrix_sdk::Client,
+    presence: PresenceState,
+    status_msg: Option<&str>,
+) -> Result<(), Error> {
+    let user_id = client.user_id().context("Client is not logged in")?;
+    let mut request = set_presence::v3::Request::new(user_id, presence);
+    request.status_msg = status_msg;
+    client
+        .send(request, None)
+        .await
+        .context("Could not set presence")?;
+    Ok(())
+}
+
+/// Start (or stop) a typing notification by the user of `client` in `room_id`.
+///
+/// Pass `Some(duration)` to start typing for `duration`, `None` to stop typing.
+pub async fn send_typing(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    duration: Option<Duration>,
+) -> Result<(), Error> {
+    let user_id = client.user_id().context("Client is not logged in")?;
+    let typing = match duration {
+        Some(duration) => Typing::Yes(duration),
+        None => Typing::No,
+    };
+    client
+        .send(
+            create_typing_event::v3::Request::new(user_id, room_id, typing),
+            None,
+        )
+        .await
+        .with_context(|| format!("Could not send typing notification in {}", room_id))?;
+    Ok(())
+}
+
+/// Send a read receipt by the user of `client` for `event_id` in `room_id`.
+pub async fn send_read_receipt(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<(), Error> {
+    client
+        .send(
+            create_receipt::v3::Request::new(room_id, ReceiptType::Read, event_id),
+            None,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Could not send read receipt for {} in {}",
+                event_id, room_id
+            )
+        })?;
+    Ok(())
+}
+
+/// Send a message-like event to `room_id` as the user of `client`.
+///
+/// Unlike `matrix_sdk::room::Joined::send`, this does not require `client` to have synced.
+pub async fn send_message_event<C>(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    content: &C,
+) -> Result<OwnedEventId, Error>
+where
+    C: MessageLikeEventContent,
+{
+    let event_type = content.event_type().to_string();
+    let content = serde_json::to_value(content).context("Could not serialize event")?;
+    send_raw_message_event(client, room_id, &event_type, &content).await
+}
+
+/// Send a message-like event of type `event_type` with arbitrary `content`,
+/// e.g. with relations that ruma doesn't support, to `room_id` as the user of `client`.
+pub async fn send_raw_message_event(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_type: &str,
+    content: &serde_json::Value,
+) -> Result<OwnedEventId, Error> {
+    let txn_id = TransactionId::new();
+    let request = message_event_request(room_id, &txn_id, event_type, content)?;
+    let response = client
+        .send(request, None)
+        .await
+        .with_context(|| format!("Could not send {} to {}", event_type, room_id))?;
+    Ok(response.event_id)
+}
+
+/// The request sending a message-like event of type `event_type` with `content` to `room_id`.
+pub fn message_event_request<'a>(
+    room_id: &'a RoomId,
+    txn_id: &'a TransactionId,
+    event_type: &str,
+    content: &serde_json::Value,
+) -> Result<send_message_event::v3::Request<'a>, Error> {
+    let body = Raw::new(content)
+        .with_context(|| format!("Invalid content for event {}", event_type))?
+        .cast();
+    Ok(send_message_event::v3::Request::new_raw(
+        room_id,
+        txn_id,
+        event_type.into(),
+        body,
+    ))
+}
+
+/// An ICE candidate, as sent in `m.call.candidates`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CallCandidate {
+    /// The SDP "a" line of the candidate.
+    pub candidate: String,
+
+    /// The SDP media type this candidate is intended for.
+    pub sdp_mid: String,
+
+    /// The index of the SDP "m" line this candidate is intended for.
+    pub sdp_m_line_index: u32,
+}
+
+impl From<&CallCandidate> for Candidate {
+    fn from(candidate: &CallCandidate) -> Self {
+        Candidate::new(
+            candidate.candidate.clone(),
+            candidate.sdp_mid.clone(),
+            candidate.sdp_m_line_index.into(),
+        )
+    }
+}
+
+/// The SDP fixtures describing a call, typically loaded from a yaml file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CallFixture {
+    /// The SDP offer sent by the caller in `m.call.invite`.
+    pub offer: String,
+
+    /// The SDP answer sent by the callee in `m.call.answer`.
+    pub answer: String,
+
+    /// ICE candidates sent by the caller, if any.
+    #[serde(default)]
+    pub caller_candidates: Vec<CallCandidate>,
+
+    /// ICE candidates sent by the callee, if any.
+    #[serde(default)]
+    pub callee_candidates: Vec<CallCandidate>,
+
+    /// How long the invite is valid, in milliseconds.
+    #[serde(default = "CallFixture::default_lifetime")]
+    pub lifetime: u32,
+}
+
+impl CallFixture {
+    fn default_lifetime() -> u32 {
+        60_000
+    }
+}
+
+async fn send_call_candidates(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    call_id: &VoipId,
+    candidates: &[CallCandidate],
+) -> Result<(), Error> {
+    if candidates.is_empty() {
+        return Ok(());
+    }
+    let content = CallCandidatesEventContent::version_0(
+        call_id.to_owned(),
+        candidates.iter().map(Candidate::from).collect(),
+    );
+    send_message_event(client, room_id, &content).await?;
+    Ok(())
+}
+
+/// Simulate a complete VoIP call between the users of `caller` and `callee` in `room_id`.
+///
+/// This sends, in order, `m.call.invite` and `m.call.candidates` from the caller,
+/// `m.call.answer` and `m.call.candidates` from the callee and finally `m.call.hangup`
+/// from the caller. Both users must already be members of the room.
+///
+/// Returns the id of the call.
+pub async fn simulate_call(
+    caller: &matrix_sdk::Client,
+    callee: &matrix_sdk::Client,
+    room_id: &RoomId,
+    fixture: &CallFixture,
+) -> Result<OwnedVoipId, Error> {
+    let call_id = VoipId::new();
+    let invite = CallInviteEventContent::version_0(
+        call_id.clone(),
+        UInt::from(fixture.lifetime),
+        OfferSessionDescription::new(fixture.offer.clone()),
+    );
+    send_message_event(caller, room_id, &invite).await?;
+    send_call_candidates(caller, room_id, &call_id, &fixture.caller_candidates).await?;
+
+    let answer = CallAnswerEventContent::version_0(
+        AnswerSessionDescription::new(fixture.answer.clone()),
+        call_id.clone(),
+    );
+    send_message_event(callee, room_id, &answer).await?;
+    send_call_candidates(callee, room_id, &call_id, &fixture.callee_candidates).await?;
+
+    let hangup = CallHangupEventContent::new(call_id.clone(), VoipVersionId::V0);
+    send_message_event(caller, room_id, &hangup).await?;
+    Ok(call_id)
+}
+
+/// Redact `event_id` in `room_id` as the user of `client`.
+///
+/// Returns the id of the redaction event.
+pub async fn redact(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    reason: Option<&str>,
+) -> Result<OwnedEventId, Error> {
+    let txn_id = TransactionId::new();
+    let mut request = redact_event::v3::Request::new(room_id, event_id, &txn_id);
+    request.reason = reason;
+    let response = client
+        .send(request, None)
+        .await
+        .with_context(|| format!("Could not redact {} in {}", event_id, room_id))?;
+    Ok(response.event_id)
+}
+
+/// Wait until `event_id` appears as redacted to `client`.
+pub async fn wait_for_redaction(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            let response = client
+                .send(get_room_event::v3::Request::new(room_id, event_id), None)
+                .await
+                .with_context(|| format!("Could not fetch {} in {}", event_id, room_id))?;
+            let event: serde_json::Value =
+                response.event.deserialize_as().context("Invalid event")?;
+            if !event["unsigned"]["redacted_because"].is_null() {
+                return Ok(());
+            }
+            debug!("Event {} not redacted yet", event_id);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Event {} was still not redacted after {:?}",
+                event_id,
+                timeout
+            )
+        })
+        .and_then(|result: Result<(), Error>| result)
+}
+
+/// Send a state event with arbitrary content as the user of `client`.
+pub async fn send_state_event(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+    content: &serde_json::Value,
+) -> Result<OwnedEventId, Error> {
+    let body = Raw::new(content)
+        .with_context(|| format!("Invalid content for state event {}", event_type))?
+        .cast();
+    let request =
+        send_state_event::v3::Request::new_raw(room_id, event_type.into(), state_key, body);
+    let response = client
+        .send(request, None)
+        .await
+        .with_context(|| format!("Could not send state event {}", event_type))?;
+    Ok(response.event_id)
+}
+
+/// Fetch the content of a state event, as seen by `client`.
+pub async fn get_state_event(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+) -> Result<serde_json::Value, Error> {
+    let response = client
+        .send(
+            get_state_events_for_key::v3::Request::new(room_id, event_type.into(), state_key),
+            None,
+        )
+        .await
+        .with_context(|| format!("Could not fetch state event {} in {}", event_type, room_id))?;
+    response
+        .content
+        .deserialize_as()
+        .with_context(|| format!("Invalid state event {}", event_type))
+}
+
+/// The state over which users race in `race_state`.
+#[derive(Clone, Copy, Debug)]
+pub enum StateRace {
+    /// Each user repeatedly changes the topic of the room.
+    Topic,
+
+    /// Each user repeatedly changes the power level required to send
+    /// a custom event type. This requires users to be allowed to change
+    /// power levels.
+    PowerLevels,
+}
+
+/// Make several users send conflicting state events to `room_id` concurrently,
+/// `rounds` times each, e.g. to exercise state resolution in a module.
+///
+/// Returns the content of the state event once all users are done, as seen
+/// by the first user.
+pub async fn race_state(
+    clients: &[matrix_sdk::Client],
+    room_id: &RoomId,
+    race: StateRace,
+    rounds: usize,
+) -> Result<serde_json::Value, Error> {
+    let first = clients
+        .first()
+        .ok_or_else(|| anyhow!("Cannot race without users"))?;
+    let event_type = match race {
+        StateRace::Topic => "m.room.topic",
+        StateRace::PowerLevels => "m.room.power_levels",
+    };
+    let power_levels = match race {
+        StateRace::Topic => None,
+        StateRace::PowerLevels => Some(get_state_event(first, room_id, event_type, "").await?),
+    };
+    let races = clients.iter().enumerate().map(|(index, client)| {
+        let power_levels = power_levels.clone();
+        async move {
+            for round in 0..rounds {
+                let content = match power_levels {
+                    None => json!({ "topic": format!("Topic {} by racer {}", round, index) }),
+                    Some(ref power_levels) => {
+                        let mut content = power_levels.clone();
+                        content["events"]["org.matrix.mx-tester.race"] =
+                            json!(round * clients.len() + index);
+                        content
+                    }
+                };
+                send_state_event(client, room_id, event_type, "", &content).await?;
+            }
+            Ok::<(), Error>(())
+        }
+    });
+    futures_util::future::try_join_all(races).await?;
+    get_state_event(first, room_id, event_type, "").await
+}
+
+/// Knock on `room` (a room id or an alias) as the user of `client`, i.e. request an invite.
+///
+/// Returns the id of the room.
+pub async fn knock(
+    client: &matrix_sdk::Client,
+    room: &str,
+    reason: Option<&str>,
+) -> Result<OwnedRoomId, Error> {
+    let room = <&RoomOrAliasId>::try_from(room)
+        .with_context(|| format!("Invalid room `{}`, expected a room id or an alias", room))?;
+    let mut request = knock_room::v3::Request::new(room);
+    request.reason = reason;
+    let response = client
+        .send(request, None)
+        .await
+        .with_context(|| format!("Could not knock on {}", room))?;
+    Ok(response.room_id)
+}
+
+/// Approve a knock by `user_id` on `room_id`, i.e. invite them, as the user of `client`.
+pub async fn approve_knock(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<(), Error> {
+    client
+        .send(
+            invite_user::v3::Request::new(room_id, InvitationRecipient::UserId { user_id }),
+            None,
+        )
+        .await
+        .with_context(|| format!("Could not approve knock by {} on {}", user_id, room_id))?;
+    Ok(())
+}
+
+/// List the event reports, most recent first, optionally only those about
+/// events of `room_id`.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn event_reports(
+    admin: &matrix_sdk::Client,
+    room_id: Option<&RoomId>,
+) -> Result<Vec<EventReport>, Error> {
+    let mut result = vec![];
+    let mut from = None;
+    loop {
+        let mut request = synapse_admin::event_reports::Request::new();
+        request.room_id = room_id;
+        request.from = from;
+        let response = admin
+            .send(request, None)
+            .await
+            .context("Could not list event reports")?;
+        result.extend(response.event_reports);
+        match response.next_token {
+            Some(next_token) => from = Some(next_token),
+            None => return Ok(result),
+        }
+    }
+}
+
+/// Wait until `event_id` has been reported, e.g. by a moderation module,
+/// and return the most recent report.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn wait_for_event_report(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    timeout: Duration,
+) -> Result<EventReport, Error> {
+    let waiting = async {
+        loop {
+            let reports = event_reports(admin, Some(room_id)).await?;
+            if let Some(report) = reports
+                .into_iter()
+                .find(|report| report.event_id == event_id)
+            {
+                return Ok(report);
+            }
+            debug!("Event {} not reported yet", event_id);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| anyhow!("Event {} was not reported within {:?}", event_id, timeout))
+        .and_then(|result: Result<EventReport, Error>| result)
+}
+
+/// Fetch the current state of `room_id`, whether or not `admin` is a member.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn room_state(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+) -> Result<Vec<Raw<AnyStateEvent>>, Error> {
+    let response = admin
+        .send(synapse_admin::room_state::Request::new(room_id), None)
+        .await
+        .with_context(|| format!("Could not fetch the state of {}", room_id))?;
+    Ok(response.state)
+}
+
+/// Whether `err` is a 404 from the homeserver.
+fn is_not_found(err: &matrix_sdk::HttpError) -> bool {
+    match err {
+        matrix_sdk::HttpError::Server(ref code) => code.as_u16() == 404,
+        _ => matches!(err.as_ruma_error(), Some(err) if err.status_code.as_u16() == 404),
+    }
+}
+
+/// Whether `room_id` is blocked, i.e. local users may not join it.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn is_room_blocked(admin: &matrix_sdk::Client, room_id: &RoomId) -> Result<bool, Error> {
+    let response = admin
+        .send(
+            synapse_admin::room_block_status::Request::new(room_id),
+            None,
+        )
+        .await
+        .with_context(|| format!("Could not fetch the block status of {}", room_id))?;
+    Ok(response.block)
+}
+
+/// The users currently joined to `room_id`, whether or not `admin` is a member.
+///
+/// If the room has been purged, e.g. by a shutdown, there are no members.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn room_members(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+) -> Result<Vec<OwnedUserId>, Error> {
+    match admin
+        .send(synapse_admin::room_members::Request::new(room_id), None)
+        .await
+    {
+        Ok(response) => Ok(response.members),
+        Err(err) if is_not_found(&err) => Ok(vec![]),
+        Err(err) => Err(err).with_context(|| format!("Could not fetch the members of {}", room_id)),
+    }
+}
+
+/// Wait until `room_id` is blocked, e.g. by a moderation module.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn wait_for_room_blocked(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            if is_room_blocked(admin, room_id).await? {
+                return Ok(());
+            }
+            debug!("Room {} not blocked yet", room_id);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| anyhow!("Room {} was not blocked within {:?}", room_id, timeout))
+        .and_then(|result: Result<(), Error>| result)
+}
+
+/// Wait until none of `user_ids` is a member of `room_id`, e.g. once a
+/// moderation module has kicked or banned them.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn wait_for_members_removed(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+    user_ids: &[&UserId],
+    timeout: Duration,
+) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            let members = room_members(admin, room_id).await?;
+            let remaining: Vec<&OwnedUserId> = members
+                .iter()
+                .filter(|member| user_ids.contains(&member.as_ref()))
+                .collect();
+            if remaining.is_empty() {
+                return Ok(());
+            }
+            debug!("Users {:?} still members of {}", remaining, room_id);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Users {:?} were still members of {} after {:?}",
+                user_ids,
+                room_id,
+                timeout
+            )
+        })
+        .and_then(|result: Result<(), Error>| result)
+}
+
+/// Wait until `room_id` has been shut down, e.g. by a moderation module, i.e.
+/// it is blocked and no user of the homeserver of `admin` is a member anymore.
+///
+/// Members of other homeservers remain in the room, as seen by their homeservers.
+///
+/// `admin` must be the client of an admin, e.g. `registration::admin_client`.
+pub async fn wait_for_room_shut_down(
+    admin: &matrix_sdk::Client,
+    room_id: &RoomId,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let server_name = admin
+        .user_id()
+        .ok_or_else(|| anyhow!("The admin client is not logged in"))?
+        .server_name()
+        .to_owned();
+    let waiting = async {
+        loop {
+            let blocked = is_room_blocked(admin, room_id).await?;
+            let local_members: Vec<OwnedUserId> = room_members(admin, room_id)
+                .await?
+                .into_iter()
+                .filter(|member| member.server_name() == server_name)
+                .collect();
+            if blocked && local_members.is_empty() {
+                return Ok(());
+            }
+            debug!(
+                "Room {} not shut down yet, blocked: {}, local members: {:?}",
+                room_id, blocked, local_members
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, waiting)
+        .await
+        .map_err(|_| anyhow!("Room {} was not shut down within {:?}", room_id, timeout))
+        .and_then(|result: Result<(), Error>| result)
+}
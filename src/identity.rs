@@ -0,0 +1,161 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An identity server (Sydent) for 3PID flows, as specified in `identity_server`.
+//!
+//! Sydent runs in its own container on the same Docker network as Synapse.
+//! Synapse trusts it and talks to it over plain HTTP, so that 3PID invites
+//! and lookups may be exercised in `run` scripts.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+
+/// The port on which Sydent listens, within its container.
+const SYDENT_PORT: u16 = 8090;
+
+/// Configuring the identity server.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct IdentityServerConfig {
+    /// The Docker image of Sydent.
+    ///
+    /// Defaults to `matrixdotorg/sydent:latest`.
+    #[serde(default = "IdentityServerConfig::default_image")]
+    #[builder(default = IdentityServerConfig::default_image())]
+    pub image: String,
+
+    /// If specified, publish the port of Sydent on this port of the host,
+    /// e.g. for clients running on the host.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub host_port: Option<u16>,
+
+    /// Additional environment variables for Sydent, e.g. `SYDENT_PID_FILE`.
+    #[serde(default, serialize_with = "crate::util::serialize_sorted")]
+    #[builder(default)]
+    pub env: HashMap<String, String>,
+
+    /// Additional hostnames of Sydent on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl IdentityServerConfig {
+    fn default_image() -> String {
+        "matrixdotorg/sydent:latest".to_string()
+    }
+}
+
+/// The name of the container running Sydent.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-sydent-{}", config.name)
+}
+
+/// The address of Sydent, as seen from the Docker network, e.g. `id_server`
+/// in 3PID invites.
+pub fn server_address(config: &Config) -> String {
+    format!("{}:{}", container_name(config), SYDENT_PORT)
+}
+
+/// The URL of Sydent, as seen from the Docker network.
+pub fn url(config: &Config) -> String {
+    format!("http://{}", server_address(config))
+}
+
+/// Patch homeserver.yaml to trust Sydent and talk to it over HTTP.
+///
+/// Fields specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    for (key, value) in [
+        (
+            "trusted_third_party_id_servers",
+            yaml!([server_address(config)]),
+        ),
+        // Synapse no longer supports delegating emails, only phone numbers.
+        (
+            "account_threepid_delegates",
+            yaml!({ "msisdn" => url(config) }),
+        ),
+        // Sydent doesn't serve HTTPS.
+        (
+            "use_insecure_ssl_client_just_for_testing_do_not_use",
+            yaml!(true),
+        ),
+    ] {
+        if !extra_fields.contains_key(key) {
+            content.insert(key.into(), value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use bollard::Docker;
+
+    use crate::services::{stop_container, Container, ContainerPort};
+
+    /// Start Sydent, if `identity_server` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let identity_server = match config.identity_server {
+            None => return Ok(()),
+            Some(ref identity_server) => identity_server,
+        };
+        let container_name = container_name(config);
+        let mut env = vec![format!("SYDENT_SERVER_NAME={}", container_name)];
+        env.extend(
+            identity_server
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+        Container {
+            label: "Sydent".to_string(),
+            name: container_name,
+            image: identity_server.image.clone(),
+            env,
+            ports: vec![ContainerPort::tcp(
+                SYDENT_PORT,
+                identity_server.host_port.map(u64::from),
+            )],
+            aliases: identity_server.aliases.clone(),
+            log_name: "sydent.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await
+    }
+
+    /// Stop and remove Sydent, if `identity_server` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.identity_server.is_none() {
+            return Ok(());
+        }
+        stop_container(docker, config, &container_name(config), "Sydent").await
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scaffolding a new project with `mx-tester init`.
+//!
+//! Writes a starter `mx-tester.yml` for a Synapse module, a bot or an
+//! application service, along with example scripts in directory `mx-tester`
+//! next to it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+
+/// The directory of the example scripts, relative to `mx-tester.yml`.
+pub const SCRIPTS_DIR: &str = "mx-tester";
+
+/// The kind of project to scaffold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Template {
+    /// A Synapse module, installed in the image of Synapse.
+    Module,
+
+    /// A bot, running in its own container.
+    Bot,
+
+    /// An application service, running in its own container.
+    Appservice,
+}
+impl std::str::FromStr for Template {
+    type Err = Error;
+    fn from_str(source: &str) -> Result<Self, Error> {
+        match source {
+            "module" => Ok(Template::Module),
+            "bot" => Ok(Template::Bot),
+            "appservice" => Ok(Template::Appservice),
+            _ => Err(anyhow!(
+                "Invalid template `{}`, expected `module`, `bot` or `appservice`",
+                source
+            )),
+        }
+    }
+}
+impl Template {
+    /// The files of the template, as (path relative to `mx-tester.yml`, content).
+    ///
+    /// `mx-tester.yml` itself is represented by an empty path.
+    fn files(&self) -> Vec<(&'static str, &'static str)> {
+        let config = match self {
+            Template::Module => include_str!("../res/init/module.yml"),
+            Template::Bot => include_str!("../res/init/bot.yml"),
+            Template::Appservice => include_str!("../res/init/appservice.yml"),
+        };
+        let mut files = vec![("", config)];
+        if *self == Template::Module {
+            files.push(("build.sh", include_str!("../res/init/build.sh")));
+        }
+        files.push(("run.sh", include_str!("../res/init/run.sh")));
+        files
+    }
+}
+
+/// A name suitable for the test, i.e. for Docker containers and aliases,
+/// derived from e.g. the name of the directory of the project.
+///
+/// `dir` doesn't need to exist yet.
+pub fn default_name(dir: &Path) -> String {
+    let name: String = dir
+        .canonicalize()
+        .unwrap_or_else(|_| dir.to_path_buf())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let name = name.trim_matches('-');
+    if name.is_empty() {
+        "my-test".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Write the files of `template` for a project called `name`, i.e. `config_path`
+/// and the example scripts next to it.
+///
+/// Unless `force`, fail without writing anything if any of the files exists.
+///
+/// Returns the paths of the files written.
+pub fn init(
+    config_path: &Path,
+    template: Template,
+    name: &str,
+    force: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    // The starter configurations are commented YAML.
+    if crate::ConfigFormat::of(config_path) != crate::ConfigFormat::Yaml {
+        return Err(anyhow!(
+            "Cannot write {:?}: starter configurations are only available in YAML",
+            config_path
+        ));
+    }
+    // Python packages and appservice namespaces don't like dashes.
+    let module = name.replace('-', "_");
+    let scripts_dir = config_path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(SCRIPTS_DIR);
+    let files: Vec<(PathBuf, String)> = template
+        .files()
+        .into_iter()
+        .map(|(path, content)| {
+            let path = if path.is_empty() {
+                config_path.to_path_buf()
+            } else {
+                scripts_dir.join(path)
+            };
+            let content = content
+                .replace("{{name}}", name)
+                .replace("{{module}}", &module);
+            (path, content)
+        })
+        .collect();
+    if !force {
+        let existing: Vec<&PathBuf> = files
+            .iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.exists())
+            .collect();
+        if !existing.is_empty() {
+            return Err(anyhow!(
+                "Not overwriting {:?}, use `--force` to replace them",
+                existing
+            ));
+        }
+    }
+    std::fs::create_dir_all(&scripts_dir)
+        .with_context(|| format!("Could not create directory {:?}", scripts_dir))?;
+    for (path, content) in &files {
+        std::fs::write(path, content).with_context(|| format!("Could not write {:?}", path))?;
+        #[cfg(unix)]
+        if path.extension().map_or(false, |ext| ext == "sh") {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+                .with_context(|| format!("Could not make {:?} executable", path))?;
+        }
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
@@ -0,0 +1,219 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running several instances of the same test side by side, with `--instance`.
+//!
+//! The name of the test is suffixed with the id of the instance, which gives
+//! each instance its own image tag, network, containers and test root. Host
+//! ports are replaced with free ports, recorded in `<name>.ports.json` next to
+//! the test root, so that later invocations for the same instance, e.g.
+//! `mx-tester --instance a run` after `mx-tester --instance a up`, reuse them.
+//! The ports file is only written while holding the lock of the instance, so
+//! that concurrent invocations for the same instance agree on the ports.
+
+use std::{collections::BTreeMap, convert::TryFrom, path::PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+
+use crate::{lock::TestLock, run_id, Config, HomeserverConfig};
+
+/// The instance id requesting a new id for each run of mx-tester.
+pub const AUTO: &str = "auto";
+
+/// The id of an instance, as specified with `--instance`.
+///
+/// With `auto`, the first characters of `run_id()`, so the instance only
+/// lives as long as this run of mx-tester, e.g. `build up run down`.
+pub fn instance_id(instance: &str) -> Result<String, Error> {
+    let id = if instance == AUTO {
+        run_id().chars().take(8).collect()
+    } else {
+        instance.to_string()
+    };
+    // The id ends up in the names of containers, networks and image tags.
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Invalid instance {:?}, expected lowercase letters, digits, `-` and `_`",
+            instance
+        ));
+    }
+    Ok(id)
+}
+
+/// The file recording the host ports of an instance.
+pub fn ports_path(config: &Config) -> PathBuf {
+    let test_root = config.test_root();
+    let mut file_name = test_root
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    file_name.push(".ports.json");
+    test_root.with_file_name(file_name)
+}
+
+/// A port that is currently free on the host, over TCP and, if `udp` is
+/// `true`, over UDP as well, e.g. for TURN.
+///
+/// Another process may grab it before we use it, but that's unlikely.
+pub fn free_port(udp: bool) -> Result<u16, Error> {
+    /// How many TCP ports to try before giving up on finding one that is also free over UDP.
+    const MAX_ATTEMPTS: usize = 100;
+    for _ in 0..MAX_ATTEMPTS {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .context("Could not find a free port on the host")?;
+        let port = listener.local_addr()?.port();
+        if !udp || std::net::UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            return Ok(port);
+        }
+    }
+    Err(anyhow!(
+        "Could not find a port free over both TCP and UDP on the host"
+    ))
+}
+
+/// Read the ports recorded for an instance, if any.
+fn read_ports(path: &std::path::Path) -> Result<BTreeMap<String, u16>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            serde_json::from_str(&content).with_context(|| format!("Invalid ports file {:?}", path))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err).with_context(|| format!("Could not read {:?}", path)),
+    }
+}
+
+/// Replace the host port of a homeserver, along with its server name and
+/// public base url if they refer to it.
+fn move_homeserver(homeserver: &mut HomeserverConfig, port: u16) {
+    let before = format!("localhost:{}", homeserver.host_port);
+    let after = format!("localhost:{}", port);
+    if homeserver.server_name == before {
+        homeserver.server_name = after.clone();
+    }
+    homeserver.public_baseurl = homeserver.public_baseurl.replace(&before, &after);
+    homeserver.host_port = port.into();
+}
+
+/// Replace each host port of `config` with `replace(key, udp, port)`, where `key`
+/// identifies the port in the ports file and `udp` is `true` if the port is also
+/// published over UDP.
+fn replace_ports<F>(config: &mut Config, mut replace: F) -> Result<(), Error>
+where
+    F: FnMut(String, bool, u16) -> Result<u16, Error>,
+{
+    let mut replace_u64 = |key: String, port: u64| -> Result<u64, Error> {
+        let port = u16::try_from(port).with_context(|| format!("Invalid port {}", port))?;
+        Ok(replace(key, false, port)?.into())
+    };
+    let port = replace_u64("homeserver".to_string(), config.homeserver.host_port)?;
+    move_homeserver(&mut config.homeserver, port);
+    for peer in &mut config.homeservers {
+        let port = replace_u64(
+            format!("homeservers.{}", peer.name),
+            peer.homeserver.host_port,
+        )?;
+        move_homeserver(&mut peer.homeserver, port);
+    }
+    for (index, mapping) in config.docker.port_mapping.iter_mut().enumerate() {
+        mapping.host = replace_u64(format!("docker.port_mapping.{}", index), mapping.host)?;
+    }
+    for service in &mut config.services {
+        for (index, mapping) in service.ports.iter_mut().enumerate() {
+            mapping.host =
+                replace_u64(format!("services.{}.{}", service.name, index), mapping.host)?;
+        }
+    }
+    if let Some(ref mut debug) = config.debug.python {
+        debug.port = replace("debug.python".to_string(), false, debug.port)?;
+    }
+    if let Some(ref mut postgres) = config.postgres {
+        if let Some(port) = postgres.host_port {
+            postgres.host_port = Some(replace("postgres".to_string(), false, port)?);
+        }
+    }
+    if let Some(ref mut identity_server) = config.identity_server {
+        if let Some(port) = identity_server.host_port {
+            identity_server.host_port = Some(replace("identity_server".to_string(), false, port)?);
+        }
+    }
+    if let Some(ref mut push) = config.push {
+        if let Some(port) = push.host_port {
+            push.host_port = Some(replace("push".to_string(), false, port)?);
+        }
+    }
+    if let Some(ref mut turn) = config.turn {
+        if let Some(port) = turn.host_port {
+            // TURN is published over both TCP and UDP.
+            turn.host_port = Some(replace("turn".to_string(), true, port)?);
+        }
+    }
+    if let Some(ref mut email) = config.email {
+        email.host_port = replace("email".to_string(), false, email.host_port)?;
+    }
+    if let Some(ref mut jaeger) = config.jaeger {
+        jaeger.host_port = replace("jaeger".to_string(), false, jaeger.host_port)?;
+    }
+    if let Some(ref mut sso) = config.sso {
+        sso.host_port = replace("sso".to_string(), false, sso.host_port)?;
+    }
+    if let Some(ref mut mas) = config.mas {
+        mas.host_port = replace("mas".to_string(), false, mas.host_port)?;
+    }
+    if let Some(ref mut recording) = config.recording {
+        recording.port = replace("recording".to_string(), false, recording.port)?;
+    }
+    Ok(())
+}
+
+/// Turn `config` into the configuration of instance `instance` of the test.
+///
+/// Host ports are those recorded by a previous invocation for the same
+/// instance, if any, or free ports, which `save_ports` records.
+pub fn namespace(config: &mut Config, instance: &str) -> Result<(), Error> {
+    let id = instance_id(instance)?;
+    config.name = format!("{}-{}", config.name, id);
+
+    let recorded = read_ports(&ports_path(config))?;
+    replace_ports(config, |key, udp, _| match recorded.get(&key) {
+        Some(port) => Ok(*port),
+        None => free_port(udp),
+    })
+}
+
+/// Record the host ports of an instance, as set up by `namespace`, for later
+/// invocations for the same instance.
+///
+/// This requires the lock of the instance. If another invocation for the same
+/// instance has recorded ports since `namespace`, e.g. while we were waiting
+/// for the lock, we use them instead of ours.
+pub fn save_ports(config: &mut Config, _lock: &TestLock) -> Result<(), Error> {
+    let path = ports_path(config);
+    let recorded = read_ports(&path)?;
+    let mut ports = BTreeMap::new();
+    replace_ports(config, |key, _, port| {
+        let port = recorded.get(&key).copied().unwrap_or(port);
+        ports.insert(key, port);
+        Ok(port)
+    })?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {:?}", parent))?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&ports)?)
+        .with_context(|| format!("Could not write {:?}", path))
+}
@@ -0,0 +1,188 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing the requests handled by Synapse (Jaeger), as specified in `jaeger`.
+//!
+//! Jaeger runs in its own container on the same Docker network as Synapse,
+//! whose opentracing support reports spans to the Jaeger agent. The UI of
+//! Jaeger is published on the host, so that the path of a request through
+//! Synapse and modules may be inspected while the homeserver is up.
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+
+/// The UDP port on which the Jaeger agent receives spans (compact thrift), within its container.
+const AGENT_PORT: u16 = 6831;
+
+/// Configuring the tracing of Synapse.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct JaegerConfig {
+    /// The Docker image of Jaeger, with the agent, the collector and the UI.
+    ///
+    /// Defaults to `jaegertracing/all-in-one:latest`.
+    #[serde(default = "JaegerConfig::default_image")]
+    #[builder(default = JaegerConfig::default_image())]
+    pub image: String,
+
+    /// The port of the host on which the UI of Jaeger is published.
+    ///
+    /// Defaults to 16686.
+    #[serde(default = "JaegerConfig::default_host_port")]
+    #[builder(default = JaegerConfig::default_host_port())]
+    pub host_port: u16,
+
+    /// The proportion of requests to trace, between 0 and 1.
+    ///
+    /// Defaults to 1, i.e. every request.
+    #[serde(default = "JaegerConfig::default_sample_rate")]
+    #[builder(default = JaegerConfig::default_sample_rate())]
+    pub sample_rate: f64,
+
+    /// Additional hostnames of Jaeger on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl Default for JaegerConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl JaegerConfig {
+    fn default_image() -> String {
+        "jaegertracing/all-in-one:latest".to_string()
+    }
+    fn default_host_port() -> u16 {
+        16686
+    }
+    fn default_sample_rate() -> f64 {
+        1.
+    }
+
+    /// The URL of the UI of Jaeger, on the host.
+    pub fn ui_url(&self) -> String {
+        format!("http://localhost:{}", self.host_port)
+    }
+}
+
+/// The name of the container running Jaeger.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-jaeger-{}", config.name)
+}
+
+/// Check that `jaeger` is consistent, adding a description of each
+/// problem to `problems`.
+pub fn check(jaeger: &JaegerConfig, problems: &mut Vec<String>) {
+    if !(0. ..=1.).contains(&jaeger.sample_rate) {
+        problems.push(format!(
+            "`jaeger.sample_rate` must be between 0 and 1, got {}",
+            jaeger.sample_rate
+        ));
+    }
+}
+
+/// Patch the `opentracing` section of homeserver.yaml to report to Jaeger.
+///
+/// Fields of `opentracing` specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    jaeger: &JaegerConfig,
+    content: &mut serde_yaml::Mapping,
+) -> Result<(), Error> {
+    let section = content
+        .entry("opentracing".into())
+        .or_insert_with(|| serde_yaml::Value::Mapping(serde_yaml::Mapping::new()));
+    if section.is_null() {
+        *section = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let section = section
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("In homeserver.yaml, expected a mapping for key `opentracing`"))?;
+    for (key, value) in [
+        ("enabled", yaml!(true)),
+        // Trace requests from and to any homeserver, e.g. peers of `homeservers`.
+        ("homeserver_whitelist", yaml!([".*"])),
+        (
+            "jaeger_config",
+            yaml!({
+                "sampler" => yaml!({
+                    "type" => "probabilistic",
+                    "param" => jaeger.sample_rate,
+                }),
+                "logging" => false,
+                "local_agent" => yaml!({
+                    "reporting_host" => container_name(config),
+                    "reporting_port" => AGENT_PORT,
+                }),
+            }),
+        ),
+    ] {
+        if !section.contains_key(key) {
+            section.insert(key.into(), value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use bollard::Docker;
+
+    use crate::services::{stop_container, Container, ContainerPort};
+
+    /// The port of the UI of Jaeger, within its container.
+    const UI_PORT: u16 = 16686;
+
+    /// Start Jaeger, if `jaeger` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let jaeger = match config.jaeger {
+            None => return Ok(()),
+            Some(ref jaeger) => jaeger,
+        };
+        Container {
+            label: "Jaeger".to_string(),
+            name: container_name(config),
+            image: jaeger.image.clone(),
+            ports: vec![
+                ContainerPort::udp(AGENT_PORT, None),
+                ContainerPort::tcp(UI_PORT, Some(u64::from(jaeger.host_port))),
+            ],
+            aliases: jaeger.aliases.clone(),
+            log_name: "jaeger.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await?;
+        println!("** Jaeger UI at {}", jaeger.ui_url());
+        Ok(())
+    }
+
+    /// Stop and remove Jaeger, if `jaeger` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.jaeger.is_none() {
+            return Ok(());
+        }
+        stop_container(docker, config, &container_name(config), "Jaeger").await
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detecting resources left behind by a test.
+//!
+//! Containers, networks, images and volumes created by mx-tester carry label
+//! `LABEL_TEST_NAME`, which lets us find leftovers once a test is over.
+//!
+//! `Leaks::detect_all` finds the leftovers of all the tests of a project at
+//! once, e.g. after aborted runs, including those of versions of mx-tester
+//! that didn't label their resources, which we recognize by name.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{
+    container::{ListContainersOptions, RemoveContainerOptions},
+    image::{ListImagesOptions, RemoveImageOptions},
+    network::ListNetworksOptions,
+    volume::ListVolumesOptions,
+    Docker,
+};
+use log::warn;
+
+use crate::{
+    lock::{is_locked, TestLock},
+    Config, LABEL_ROOT, LABEL_TEST_NAME,
+};
+
+/// The prefix of the names of the containers and images of mx-tester.
+const NAME_PREFIX: &str = "mx-tester-";
+
+/// The prefix of the names of the networks of mx-tester.
+const NETWORK_PREFIX: &str = "net-mx-tester-";
+
+/// Resources left behind by a test.
+#[derive(Debug, Default)]
+pub struct Leaks {
+    /// Containers, running or not, by name.
+    pub containers: Vec<String>,
+
+    /// Networks, by name.
+    pub networks: Vec<String>,
+
+    /// Untagged images, by id, typically left behind by previous builds.
+    ///
+    /// The image built for the test is expected to remain, so it is not a leak.
+    pub images: Vec<String>,
+
+    /// Volumes, by name.
+    pub volumes: Vec<String>,
+}
+
+impl Leaks {
+    /// Find the resources left behind by the test.
+    ///
+    /// This is meaningful only once the test has been brought `down`.
+    pub async fn detect(docker: &Docker, config: &Config) -> Result<Self, Error> {
+        let label = format!("{}={}", LABEL_TEST_NAME, config.name);
+        let filters: HashMap<&str, Vec<&str>> =
+            std::iter::once(("label", vec![label.as_str()])).collect();
+
+        let containers = docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters: filters.clone(),
+                ..ListContainersOptions::default()
+            }))
+            .await
+            .context("Could not list containers")?
+            .into_iter()
+            .filter_map(|container| container.names?.into_iter().next())
+            .map(|name| name.trim_start_matches('/').to_string())
+            .collect();
+
+        let networks = docker
+            .list_networks(Some(ListNetworksOptions {
+                filters: filters.clone(),
+            }))
+            .await
+            .context("Could not list networks")?
+            .into_iter()
+            .filter_map(|network| network.name)
+            .collect();
+
+        let mut image_filters = filters.clone();
+        image_filters.insert("dangling", vec!["true"]);
+        let images = docker
+            .list_images(Some(ListImagesOptions {
+                all: false,
+                filters: image_filters,
+                ..ListImagesOptions::default()
+            }))
+            .await
+            .context("Could not list images")?
+            .into_iter()
+            .map(|image| image.id)
+            .collect();
+
+        let volumes = docker
+            .list_volumes(Some(ListVolumesOptions { filters }))
+            .await
+            .context("Could not list volumes")?
+            .volumes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|volume| volume.name)
+            .collect();
+
+        Ok(Leaks {
+            containers,
+            networks,
+            images,
+            volumes,
+        })
+    }
+
+    /// Find the resources left behind by all tests whose test root is in
+    /// `root`, i.e. `config.directories.root`.
+    ///
+    /// Resources labelled with another `LABEL_ROOT` belong to other projects
+    /// and are skipped. Resources of versions of mx-tester that didn't record
+    /// the root are only considered if their test has a directory in `root`.
+    /// Tests currently locked by another mx-tester process, as per `TestLock`,
+    /// are skipped. Unlike `detect`, this includes the images built for tests.
+    pub async fn detect_all(docker: &Docker, root: &Path) -> Result<Self, Error> {
+        let filters: HashMap<&str, Vec<&str>> =
+            std::iter::once(("label", vec![LABEL_TEST_NAME])).collect();
+        // The tests that have run in `root`.
+        let local_tests: Vec<String> = match std::fs::read_dir(root) {
+            Ok(entries) => entries
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| !name.starts_with('.'))
+                .collect(),
+            Err(_) => vec![],
+        };
+        let is_leak = |labels: Option<&HashMap<String, String>>, name: &str| -> bool {
+            match labels.and_then(|labels| Some((labels.get(LABEL_TEST_NAME)?, labels))) {
+                Some((test, labels)) => {
+                    let in_root = match labels.get(LABEL_ROOT) {
+                        Some(label) => Path::new(label) == root,
+                        None => local_tests.contains(test),
+                    };
+                    in_root && !is_test_in_use(root, test)
+                }
+                // Not labelled at all, recognize the test by name.
+                None => local_tests.iter().any(|test| {
+                    name.contains(&format!("-{}", test)) && !is_test_in_use(root, test)
+                }),
+            }
+        };
+        let mut leaks = Leaks::default();
+
+        let mut containers = BTreeSet::new();
+        for filters in [
+            filters.clone(),
+            std::iter::once(("name", vec![NAME_PREFIX])).collect(),
+        ] {
+            for container in docker
+                .list_containers(Some(ListContainersOptions {
+                    all: true,
+                    filters,
+                    ..ListContainersOptions::default()
+                }))
+                .await
+                .context("Could not list containers")?
+            {
+                let labelled = container
+                    .labels
+                    .as_ref()
+                    .is_some_and(|labels| labels.contains_key(LABEL_TEST_NAME));
+                if let Some(name) = container.names.and_then(|names| names.into_iter().next()) {
+                    let name = name.trim_start_matches('/');
+                    // Docker matches substrings of names.
+                    if (labelled || name.starts_with(NAME_PREFIX))
+                        && is_leak(container.labels.as_ref(), name)
+                    {
+                        containers.insert(name.to_string());
+                    }
+                }
+            }
+        }
+        leaks.containers = containers.into_iter().collect();
+
+        leaks.networks = docker
+            .list_networks::<&str>(None)
+            .await
+            .context("Could not list networks")?
+            .into_iter()
+            .filter_map(|network| {
+                let name = network.name?;
+                let labelled = network
+                    .labels
+                    .as_ref()
+                    .is_some_and(|labels| labels.contains_key(LABEL_TEST_NAME));
+                let leaked = (labelled || name.starts_with(NETWORK_PREFIX))
+                    && is_leak(network.labels.as_ref(), &name);
+                leaked.then_some(name)
+            })
+            .collect();
+
+        leaks.images = docker
+            .list_images::<&str>(None)
+            .await
+            .context("Could not list images")?
+            .into_iter()
+            .filter(|image| {
+                // Images built for tests may have lost their tag.
+                if image.labels.contains_key(LABEL_TEST_NAME) {
+                    is_leak(Some(&image.labels), "")
+                } else {
+                    image
+                        .repo_tags
+                        .iter()
+                        .any(|tag| tag.starts_with(NAME_PREFIX) && is_leak(None, tag))
+                }
+            })
+            .map(|image| image.id)
+            .collect();
+
+        leaks.volumes = docker
+            .list_volumes(Some(ListVolumesOptions { filters }))
+            .await
+            .context("Could not list volumes")?
+            .volumes
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|volume| is_leak(Some(&volume.labels), &volume.name))
+            .map(|volume| volume.name)
+            .collect();
+        Ok(leaks)
+    }
+
+    /// Whether nothing was left behind.
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+            && self.networks.is_empty()
+            && self.images.is_empty()
+            && self.volumes.is_empty()
+    }
+
+    /// Print a report of leftovers, if any.
+    ///
+    /// If `strict`, leftovers are an error.
+    pub fn report(&self, strict: bool) -> Result<(), Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        warn!("Resources left behind by the test: {:?}", self);
+        let mut summary = String::from("** resources left behind by the test:");
+        for (kind, items) in [
+            ("container", &self.containers),
+            ("network", &self.networks),
+            ("image", &self.images),
+            ("volume", &self.volumes),
+        ] {
+            for item in items {
+                summary.push_str(&format!("\n*** {} {}", kind, item));
+            }
+        }
+        println!("{}", summary);
+        if strict {
+            return Err(anyhow!(
+                "Resources left behind by the test (see above), use `mx-tester clean` to remove them"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove all leftovers.
+    pub async fn remove(&self, docker: &Docker) -> Result<(), Error> {
+        for container in &self.containers {
+            docker
+                .remove_container(
+                    container,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        v: true,
+                        ..RemoveContainerOptions::default()
+                    }),
+                )
+                .await
+                .with_context(|| format!("Could not remove container {}", container))?;
+        }
+        for network in &self.networks {
+            docker
+                .remove_network(network)
+                .await
+                .with_context(|| format!("Could not remove network {}", network))?;
+        }
+        for image in &self.images {
+            docker
+                .remove_image(
+                    image,
+                    Some(RemoveImageOptions {
+                        force: true,
+                        ..RemoveImageOptions::default()
+                    }),
+                    None,
+                )
+                .await
+                .with_context(|| format!("Could not remove image {}", image))?;
+        }
+        for volume in &self.volumes {
+            docker
+                .remove_volume(volume, None)
+                .await
+                .with_context(|| format!("Could not remove volume {}", volume))?;
+        }
+        Ok(())
+    }
+}
+
+/// A configuration for test `name` in `root`, to find its files.
+fn test_config(root: &Path, name: &str) -> Config {
+    let mut config = Config::builder().name(name.to_string()).build();
+    config.directories.root = root.to_path_buf();
+    config
+}
+
+/// Whether another mx-tester process is currently running test `name`.
+fn is_test_in_use(root: &Path, name: &str) -> bool {
+    is_locked(&TestLock::path(&test_config(root, name))).unwrap_or_else(|err| {
+        warn!("Could not check whether test {} is in use: {:#}", name, err);
+        true
+    })
+}
@@ -12,51 +12,120 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[macro_use]
+mod util;
+pub mod abuse;
+#[cfg(feature = "docker")]
+pub mod admin;
+pub mod annotate;
+pub mod appservice;
+pub mod artifacts;
+pub mod assertions;
+pub mod bench;
+#[cfg(feature = "docker")]
+pub mod bots;
+#[cfg(feature = "docker")]
 pub mod cleanup;
+pub mod commands;
+pub mod coverage;
+#[cfg(feature = "docker")]
+pub mod docker_config;
+#[cfg(feature = "docker")]
+pub mod doctor;
+pub mod email;
+#[cfg(feature = "docker")]
+pub mod environment;
 pub mod exec;
+pub mod federation;
+pub mod fixtures;
+pub mod fuzz;
+#[cfg(feature = "matrix-client")]
+pub mod helpers;
+pub mod identity;
+pub mod init;
+pub mod instance;
+pub mod jaeger;
+#[cfg(feature = "docker")]
+pub mod leaks;
+#[cfg(feature = "docker")]
+mod lifecycle;
+pub mod lock;
+pub mod manifest;
+pub mod mas;
+#[cfg(feature = "workers")]
+pub mod nginx;
+pub mod output;
+pub mod patch;
+pub mod postgres;
+pub mod profile;
+pub mod push;
+pub mod recording;
 pub mod registration;
-mod util;
+#[cfg(feature = "docker")]
+pub mod runtime;
+pub mod services;
+#[cfg(feature = "docker")]
+pub mod shell;
+#[cfg(feature = "docker")]
+pub mod snapshot;
+pub mod sso;
+#[cfg(feature = "matrix-client")]
+pub mod synapse_admin;
+pub mod telemetry;
+#[cfg(feature = "docker")]
+pub mod tester;
+pub mod turn;
+pub mod validate;
+#[cfg(feature = "docker")]
+pub mod watch;
+#[cfg(feature = "workers")]
+pub mod workers;
+
+#[cfg(feature = "docker")]
+pub use lifecycle::{build, clean, clean_all, connect, down, run, up};
+#[cfg(feature = "docker")]
+pub use snapshot::{restore, snapshot};
+#[cfg(feature = "docker")]
+pub use tester::{SharedTester, Tester};
 
 use std::{
-    borrow::Cow,
     collections::HashMap,
+    convert::TryFrom,
     ffi::{OsStr, OsString},
-    io::Write,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 use anyhow::{anyhow, Context, Error};
-use bollard::{
-    auth::DockerCredentials,
-    container::{
-        Config as BollardContainerConfig, CreateContainerOptions, ListContainersOptions,
-        LogsOptions, StartContainerOptions, WaitContainerOptions,
-    },
-    exec::{CreateExecOptions, StartExecOptions},
-    models::{
-        EndpointSettings, HostConfig, HostConfigLogConfig, PortBinding, RestartPolicy,
-        RestartPolicyNameEnum,
-    },
-    network::{ConnectNetworkOptions, CreateNetworkOptions, ListNetworksOptions},
-    Docker,
-};
-use cleanup::{Cleanup, Disarm};
-use futures_util::stream::StreamExt;
-use itertools::Itertools;
 use lazy_static::lazy_static;
-use log::{debug, error, warn};
+use log::debug;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio_util::codec::{BytesCodec, FramedRead};
 use typed_builder::TypedBuilder;
 
-use registration::{handle_user_registration, User};
-
-use crate::{
-    exec::{CommandExt, Executor},
-    util::YamlExt,
-};
+use abuse::Scenario;
+use appservice::AppService;
+use artifacts::ArtifactsConfig;
+use assertions::AssertionsConfig;
+use bench::BenchConfig;
+use email::EmailConfig;
+use federation::FederatedHomeserver;
+use fuzz::FuzzConfig;
+use identity::IdentityServerConfig;
+use jaeger::JaegerConfig;
+use manifest::Manifest;
+use mas::MasConfig;
+use postgres::PostgresConfig;
+use profile::Profiler;
+use push::PushConfig;
+use recording::RecordingConfig;
+use registration::User;
+use services::Service;
+use sso::SsoConfig;
+use telemetry::TelemetryConfig;
+use turn::TurnConfig;
+
+use crate::exec::{CommandExt, Executor};
+use crate::util::YamlExt;
 
 lazy_static! {
     /// Environment variable: the directory where a given module should be copied.
@@ -79,11 +148,76 @@ lazy_static! {
     /// Passed to `build`, `up`, `run`, `down` scripts.
     static ref MX_TEST_CWD: OsString = OsString::from_str("MX_TEST_CWD").unwrap();
 
+    /// Environment variable: the id of this run of mx-tester, see `run_id()`.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_RUN_ID: OsString = OsString::from_str("MX_TEST_RUN_ID").unwrap();
+
+    /// The id of this run of mx-tester, see `run_id()`.
+    static ref RUN_ID: String = std::env::var(MX_TEST_RUN_ID.as_os_str())
+        .ok()
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
     /// Environment variable: defined if workers are enabled.
     ///
     /// Passed to `build`, `up`, `run`, `down` scripts.
     static ref MX_TEST_WORKERS_ENABLED: OsString = OsString::from_str("MX_TEST_WORKERS_ENABLED").unwrap();
 
+    /// Environment variable: the directory containing the registration files of
+    /// application services, defined if `appservices` is not empty.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_APPSERVICES_DIR: OsString = OsString::from_str("MX_TEST_APPSERVICES_DIR").unwrap();
+
+    /// Environment variable: the address of the identity server on the network,
+    /// e.g. `id_server` in 3PID invites, defined if `identity_server` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_IDENTITY_SERVER: OsString = OsString::from_str("MX_TEST_IDENTITY_SERVER").unwrap();
+
+    /// Environment variable: the URL of the proxy recording client traffic,
+    /// defined if `recording` is specified. The proxy only runs during `run`.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_RECORDING_PROXY: OsString = OsString::from_str("MX_TEST_RECORDING_PROXY").unwrap();
+
+    /// Environment variable: the URL of the Push Gateway API of Sygnal, as seen
+    /// from the network, defined if `push` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_PUSH_GATEWAY_URL: OsString = OsString::from_str("MX_TEST_PUSH_GATEWAY_URL").unwrap();
+
+    /// Environment variable: the URL of the Push Gateway API of the stub push
+    /// receiver, as seen from the network, defined if `push` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_PUSH_RECEIVER_URL: OsString = OsString::from_str("MX_TEST_PUSH_RECEIVER_URL").unwrap();
+
+    /// Environment variable: the URL of the HTTP API of MailHog, on the host,
+    /// e.g. `$MX_TEST_EMAIL_API_URL/api/v2/messages`, defined if `email` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_EMAIL_API_URL: OsString = OsString::from_str("MX_TEST_EMAIL_API_URL").unwrap();
+
+    /// Environment variable: the URL of the UI of Jaeger, on the host,
+    /// defined if `jaeger` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_JAEGER_URL: OsString = OsString::from_str("MX_TEST_JAEGER_URL").unwrap();
+
+    /// Environment variable: the issuer of the identity provider, as seen from
+    /// the host, defined if `sso` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_SSO_ISSUER: OsString = OsString::from_str("MX_TEST_SSO_ISSUER").unwrap();
+
+    /// Environment variable: the URL of the database of Synapse, as seen from
+    /// the host, defined if `postgres.host_port` or `postgres.host` is specified.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_POSTGRES_URL: OsString = OsString::from_str("MX_TEST_POSTGRES_URL").unwrap();
+
     /// Environment variable: the name of the network.
     ///
     /// Passed to `build`, `up`, `run`, `down` scripts.
@@ -98,12 +232,20 @@ lazy_static! {
     ///
     /// Passed to `build`, `up`, `run`, `down` scripts.
     static ref MX_TEST_UP_RUN_DOWN_CONTAINER_NAME: OsString = OsString::from_str("MX_TEST_UP_RUN_DOWN_CONTAINER_NAME").unwrap();
-}
 
-/// The amount of memory to allocate
-const MEMORY_ALLOCATION_BYTES: i64 = 4 * 1024 * 1024 * 1024;
+    /// Environment variable: the URL of the homeserver, as seen from the host,
+    /// i.e. `homeserver.public_baseurl`.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_HOMESERVER_URL: OsString = OsString::from_str("MX_TEST_HOMESERVER_URL").unwrap();
+
+    /// Environment variable: the server name of the homeserver, i.e. `homeserver.server_name`.
+    ///
+    /// Passed to `build`, `up`, `run`, `down` scripts.
+    static ref MX_TEST_SERVER_NAME: OsString = OsString::from_str("MX_TEST_SERVER_NAME").unwrap();
+}
 
-/// The maximal number of times we can restart Synapse in case it stops accidentally.
+/// The default maximal number of times we can restart Synapse in case it stops accidentally.
 ///
 /// Accidental stops are typically due:
 /// 1. to Synapse not being able to open its port at startup (this happens, for reasons unknown);
@@ -111,20 +253,21 @@ const MEMORY_ALLOCATION_BYTES: i64 = 4 * 1024 * 1024 * 1024;
 /// 3. to a synax error or startup error in a module.
 const MAX_SYNAPSE_RESTART_COUNT: i64 = 20;
 
+/// The log levels of Python, for `log_level`.
+const LOG_LEVELS: [&str; 5] = ["DEBUG", "INFO", "WARNING", "ERROR", "CRITICAL"];
+
 /// The port used by the homeserver inside Docker.
 ///
 /// In single process mode, that's the port used by Synapse.
 /// In worker mode, that's the port used by nginx as a load-balancer.
-const HARDCODED_GUEST_PORT: u64 = 8008;
+pub(crate) const HARDCODED_GUEST_PORT: u64 = 8008;
 
 /// In worker mode, the port used by the homeserver for the main process
 /// inside Docker.
-const HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT: u64 = 8080;
-
-const TIMEOUT_USER_REGISTRATION_SIMPLE: std::time::Duration = std::time::Duration::new(120, 0);
+pub(crate) const HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT: u64 = 8080;
 
 /// A port in the container made accessible on the host machine.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PortMapping {
     /// The port, as visible on the host machine.
     pub host: u64,
@@ -134,7 +277,7 @@ pub struct PortMapping {
 }
 
 /// Docker-specific configuration to use in the test.
-#[derive(Debug, Deserialize, TypedBuilder)]
+#[derive(Debug, Deserialize, Serialize, TypedBuilder)]
 pub struct DockerConfig {
     /// The hostname to give the synapse container on the docker network, if the docker network has been provided.
     /// Defaults to `synapse` but will not be used unless a network is provided in network.
@@ -149,8 +292,150 @@ pub struct DockerConfig {
     #[serde(default)]
     #[builder(default = vec![])]
     pub port_mapping: Vec<PortMapping>,
+
+    /// What to do if Synapse stops, e.g. `on-failure:5` or `never`.
+    ///
+    /// Defaults to `on-failure:20`.
+    #[serde(default)]
+    #[builder(default)]
+    pub restart: RestartPolicyConfig,
+
+    /// How long to wait for Synapse to accept requests during `up`, in seconds.
+    ///
+    /// Defaults to 180.
+    #[serde(default = "DockerConfig::default_readiness_timeout_sec")]
+    #[builder(default = DockerConfig::default_readiness_timeout_sec())]
+    pub readiness_timeout_sec: u64,
+
+    /// Whether to connect to the Docker daemon with SSL.
+    ///
+    /// May be overridden from the command-line.
+    #[serde(default)]
+    #[builder(default)]
+    pub ssl: DockerSsl,
+
+    /// The timeout for requests to the Docker daemon, in seconds.
+    ///
+    /// Defaults to 600, as some requests (e.g. building an image) take a while.
+    /// May be overridden from the command-line.
+    #[serde(default = "DockerConfig::default_timeout_sec")]
+    #[builder(default = DockerConfig::default_timeout_sec())]
+    pub timeout_sec: u64,
+
+    /// How many times to retry connecting to the Docker daemon, e.g. if it
+    /// is still starting up in CI.
+    ///
+    /// Defaults to 0, i.e. fail immediately.
+    /// May be overridden from the command-line.
+    #[serde(default)]
+    #[builder(default)]
+    pub connect_retries: u32,
+
+    /// How long to wait before the first retry, in milliseconds. The delay
+    /// doubles with each retry.
+    ///
+    /// Defaults to 1000.
+    /// May be overridden from the command-line.
+    #[serde(default = "DockerConfig::default_connect_backoff_ms")]
+    #[builder(default = DockerConfig::default_connect_backoff_ms())]
+    pub connect_backoff_ms: u64,
+
+    /// The container runtime, either `docker` or `podman`.
+    ///
+    /// Defaults to `docker`.
+    /// May be overridden from the command-line.
+    #[serde(default)]
+    #[builder(default)]
+    pub runtime: Runtime,
+
+    /// Additional hostnames of Synapse on the Docker network, e.g.
+    /// `matrix.example.test`, to test server name delegation or certificates.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+
+    /// The host on which the ports of the containers are published, as seen
+    /// from mx-tester, e.g. when mx-tester itself runs in a container.
+    ///
+    /// Defaults to the host of `DOCKER_HOST` if it is a TCP address,
+    /// `localhost` otherwise.
+    #[serde(default)]
+    #[builder(default)]
+    pub published_host: Option<String>,
+}
+
+/// Whether to connect to the Docker daemon with SSL.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum DockerSsl {
+    /// If a server is configured, use SSL if `DOCKER_CERT_PATH` is set,
+    /// HTTP otherwise. This may be broken in your CI.
+    #[default]
+    #[serde(rename = "detect")]
+    Detect,
+
+    /// Always use SSL, fail if no server is configured.
+    #[serde(rename = "always")]
+    Always,
+
+    /// Never use SSL, ignore any SSL configuration.
+    #[serde(rename = "never")]
+    Never,
+}
+
+/// The container runtime.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Runtime {
+    /// The Docker daemon, as configured by `DOCKER_HOST` & co.
+    #[default]
+    #[serde(rename = "docker")]
+    Docker,
+
+    /// The Podman API service, at `CONTAINER_HOST` or the default socket,
+    /// e.g. `$XDG_RUNTIME_DIR/podman/podman.sock` for rootless Podman.
+    #[serde(rename = "podman")]
+    Podman,
 }
 
+/// What to do if Synapse stops.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum RestartPolicyConfig {
+    /// Never restart Synapse, fail immediately.
+    Never,
+
+    /// Restart Synapse at most N times if it stops with an error.
+    OnFailure(i64),
+}
+impl Default for RestartPolicyConfig {
+    fn default() -> Self {
+        RestartPolicyConfig::OnFailure(MAX_SYNAPSE_RESTART_COUNT)
+    }
+}
+impl From<RestartPolicyConfig> for String {
+    fn from(policy: RestartPolicyConfig) -> String {
+        match policy {
+            RestartPolicyConfig::Never => "never".to_string(),
+            RestartPolicyConfig::OnFailure(count) => format!("on-failure:{}", count),
+        }
+    }
+}
+impl TryFrom<String> for RestartPolicyConfig {
+    type Error = Error;
+    fn try_from(source: String) -> Result<Self, Error> {
+        match source.split_once(':') {
+            None if source == "never" => Ok(RestartPolicyConfig::Never),
+            None if source == "on-failure" => Ok(RestartPolicyConfig::default()),
+            Some(("on-failure", count)) => count
+                .parse()
+                .map(RestartPolicyConfig::OnFailure)
+                .with_context(|| format!("Invalid restart count in `{}`", source)),
+            _ => Err(anyhow!(
+                "Invalid restart policy `{}`, expected `never` or `on-failure:N`",
+                source
+            )),
+        }
+    }
+}
 impl Default for DockerConfig {
     fn default() -> DockerConfig {
         Self::builder().build()
@@ -161,13 +446,135 @@ impl DockerConfig {
     fn default_hostname() -> String {
         "synapse".to_string()
     }
+    fn default_readiness_timeout_sec() -> u64 {
+        180
+    }
+    fn default_timeout_sec() -> u64 {
+        600
+    }
+    fn default_connect_backoff_ms() -> u64 {
+        1000
+    }
+
+    /// The host on which the ports of the containers are published, as seen
+    /// from mx-tester.
+    pub fn published_host(&self) -> String {
+        if let Some(ref host) = self.published_host {
+            return host.clone();
+        }
+        std::env::var("DOCKER_HOST")
+            .ok()
+            .and_then(|docker_host| {
+                let address = docker_host.strip_prefix("tcp://")?;
+                let host = match address.rsplit_once(':') {
+                    Some((host, _port)) => host,
+                    None => address,
+                };
+                (!host.is_empty()).then(|| host.to_string())
+            })
+            .unwrap_or_else(|| "localhost".to_string())
+    }
+}
+
+/// Credentials to connect to a Docker registry, as per `docker login`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Credentials {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serveraddress: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identitytoken: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registrytoken: Option<String>,
+}
+
+/// The host of Docker Hub, the registry of images that don't specify one.
+pub(crate) const DOCKER_HUB: &str = "docker.io";
+
+impl Credentials {
+    /// Whether these credentials actually authenticate anyone.
+    pub fn is_empty(&self) -> bool {
+        self.username.is_none()
+            && self.auth.is_none()
+            && self.identitytoken.is_none()
+            && self.registrytoken.is_none()
+    }
+
+    /// The host of the registry of these credentials, e.g. `ghcr.io`, or
+    /// `docker.io` if `serveraddress` is unspecified.
+    pub fn registry(&self) -> &str {
+        let address = self.serveraddress.as_deref().unwrap_or_default();
+        let address = address
+            .trim_start_matches("https://")
+            .trim_start_matches("http://");
+        match address.split('/').next().unwrap_or_default() {
+            "" | "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+            host => host,
+        }
+    }
+}
+
+/// The host of the registry of an image, e.g. `ghcr.io` for
+/// `ghcr.io/matrix-org/mjolnir:latest` or `docker.io` for `matrixdotorg/synapse`.
+pub fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => host,
+        _ => DOCKER_HUB,
+    }
+}
+
+/// The homeserver implementation to test against.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum HomeserverKind {
+    /// Synapse, as specified in `synapse`.
+    #[default]
+    #[serde(rename = "synapse")]
+    Synapse,
+
+    /// Dendrite, as specified in `homeserver.tag`.
+    ///
+    /// Dendrite does not support modules or workers.
+    #[serde(rename = "dendrite")]
+    Dendrite,
+}
+
+impl HomeserverKind {
+    /// A human-readable name, for messages and image names.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HomeserverKind::Synapse => "synapse",
+            HomeserverKind::Dendrite => "dendrite",
+        }
+    }
 }
 
+/// The version of Dendrite to use by default.
+const DEFAULT_DENDRITE_VERSION: &str = "matrixdotorg/dendrite-monolith:latest";
+
 /// Configuration for the homeserver.
 ///
-/// This will be applied to homeserver.yaml.
+/// This will be applied to homeserver.yaml (or dendrite.yaml).
 #[derive(Debug, Deserialize, Serialize, TypedBuilder)]
 pub struct HomeserverConfig {
+    /// The homeserver implementation, `synapse` or `dendrite`.
+    #[serde(default)]
+    #[builder(default)]
+    pub kind: HomeserverKind,
+
+    /// For Dendrite, the Docker image, e.g. `matrixdotorg/dendrite-monolith:latest`.
+    ///
+    /// The image of Synapse is specified in `synapse`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default)]
+    pub tag: Option<String>,
+
     /// The port exposed on the host
     #[serde(default = "HomeserverConfig::host_port_default")]
     #[builder(default = HomeserverConfig::host_port_default())]
@@ -188,7 +595,15 @@ pub struct HomeserverConfig {
     /// The registration shared secret, if provided.
     pub registration_shared_secret: String,
 
-    #[serde(flatten)]
+    /// If `true`, turn off presence, URL previews, user directory updates,
+    /// room statistics and requests to the key server of matrix.org, for a
+    /// faster `up` and quieter logs. Any of these may still be turned back on
+    /// with the corresponding key in `homeserver`. Synapse only.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub minimal: bool,
+
+    #[serde(flatten, serialize_with = "util::serialize_sorted")]
     #[builder(default)]
     /// Any extra fields in the homeserver config
     pub extra_fields: HashMap<String, serde_yaml::Value>,
@@ -221,12 +636,77 @@ impl HomeserverConfig {
     }
 }
 
+/// Configuring debugging.
+#[derive(Debug, Default, TypedBuilder, Deserialize, Serialize)]
+pub struct DebugConfig {
+    /// If specified, install debugpy in the image and let it listen in Synapse,
+    /// so that a debugger (e.g. VS Code) may attach between `up` and `down`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub python: Option<PythonDebugConfig>,
+}
+
+/// Configuring debugpy.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct PythonDebugConfig {
+    /// The port on which debugpy listens, both in the container and on the host.
+    ///
+    /// Defaults to 5678, as debugpy.
+    #[serde(default = "PythonDebugConfig::default_port")]
+    #[builder(default = PythonDebugConfig::default_port())]
+    pub port: u16,
+}
+impl PythonDebugConfig {
+    fn default_port() -> u16 {
+        5678
+    }
+}
+
 /// Configuring workers
-#[derive(Debug, TypedBuilder, Deserialize)]
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
 pub struct WorkersConfig {
     #[serde(default)]
     #[builder(default = false)]
     pub enabled: bool,
+
+    /// The workers to launch, by type, one entry per instance.
+    ///
+    /// Defaults to the workers of Complement, which include two instances
+    /// of `event_persister`, in order to launch two event persisters.
+    #[serde(default = "WorkersConfig::default_types")]
+    #[builder(default = WorkersConfig::default_types())]
+    pub types: Vec<String>,
+
+    /// If specified, a directory containing replacements for some of the
+    /// resources of workers mode embedded in mx-tester, e.g. `nginx.conf.j2`,
+    /// relative to the current directory.
+    ///
+    /// Which resources were replaced is recorded in the manifest.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub templates_dir: Option<PathBuf>,
+}
+impl WorkersConfig {
+    fn default_types() -> Vec<String> {
+        vec![
+            "event_persister",
+            "event_persister",
+            "background_worker",
+            "frontend_proxy",
+            "event_creator",
+            "user_dir",
+            "media_repository",
+            "federation_inbound",
+            "federation_reader",
+            "federation_sender",
+            "synchrotron",
+            "appservice",
+            "pusher",
+        ]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+    }
 }
 impl Default for WorkersConfig {
     fn default() -> Self {
@@ -234,8 +714,88 @@ impl Default for WorkersConfig {
     }
 }
 
+/// Configuring how images are built during `build`.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct BuildConfig {
+    /// Reusing the layers of previous builds.
+    #[serde(default)]
+    #[builder(default)]
+    pub cache: BuildCacheConfig,
+}
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Reusing the layers of previous builds, e.g. the `apt-get install` of
+/// workers mode or the `install` scripts of modules, rather than rebuilding
+/// every image from scratch.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct BuildCacheConfig {
+    /// If `true`, reuse the layers cached by the Docker daemon. Layers that
+    /// come after a change, e.g. to the source of a module, are rebuilt.
+    ///
+    /// Defaults to `false`, i.e. rebuild everything.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub enabled: bool,
+
+    /// Images whose layers may be reused, e.g. the image of a previous CI run
+    /// pushed to a registry. Pulled before building, if they're not already
+    /// available.
+    ///
+    /// Requires `enabled`.
+    #[serde(default)]
+    #[builder(default)]
+    pub from: Vec<String>,
+}
+impl Default for BuildCacheConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// A bot under test, running in its own container alongside Synapse.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bot {
+    /// A name for the bot, unique within this test.
+    ///
+    /// Used to name its container, image and logs.
+    pub name: String,
+
+    /// The Docker image to run, e.g. `matrixdotorg/mjolnir:latest`.
+    ///
+    /// Exactly one of `image` and `build` must be specified.
+    #[serde(default)]
+    pub image: Option<String>,
+
+    /// A directory containing a Dockerfile, built during `build`.
+    ///
+    /// Exactly one of `image` and `build` must be specified.
+    #[serde(default)]
+    pub build: Option<PathBuf>,
+
+    /// If specified, override the command of the image.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+
+    /// Additional environment variables for the bot.
+    #[serde(default, serialize_with = "util::serialize_sorted")]
+    pub env: HashMap<String, String>,
+
+    /// If specified, the localname of a user declared in `users`. Its user id
+    /// and access token are passed to the bot.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Additional hostnames of the bot on the Docker network.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
 /// The contents of a mx-tester.yaml
-#[derive(Debug, TypedBuilder, Deserialize)]
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
 pub struct Config {
     /// A name for this test.
     ///
@@ -272,12 +832,18 @@ pub struct Config {
     /// Configuration for the docker network.
     pub docker: DockerConfig,
 
+    #[serde(default)]
+    // A setter would clash with `ConfigBuilder::build`.
+    #[builder(default, setter(skip))]
+    /// Configuration for building images.
+    pub build: BuildConfig,
+
     #[serde(default)]
     #[builder(default)]
     /// Any users to register and make available
     pub users: Vec<User>,
 
-    #[serde(default)]
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
     #[builder(default)]
     /// The version of Synapse to use
     pub synapse: SynapseVersion,
@@ -287,7 +853,15 @@ pub struct Config {
     /// Information for logging to a registry.
     ///
     /// May be overridden from the command-line.
-    pub credentials: DockerCredentials,
+    pub credentials: Credentials,
+
+    #[serde(default, serialize_with = "util::serialize_sorted")]
+    #[builder(default)]
+    /// Named sets of credentials, e.g. one per registry.
+    ///
+    /// All of them are used when building images and pulling images from
+    /// their registry. `--registry-profile` replaces `credentials` with one of them.
+    pub registry_profiles: HashMap<String, Credentials>,
 
     #[serde(default)]
     #[builder(default)]
@@ -303,294 +877,951 @@ pub struct Config {
     /// May be overridden from the command-line.
     pub workers: WorkersConfig,
 
+    #[serde(default)]
+    #[builder(default)]
+    /// Debugging Synapse and modules.
+    pub debug: DebugConfig,
+
     #[serde(default = "util::true_")]
     #[builder(default = true)]
     /// Specify whether workers should be used.
     ///
     /// May be overridden from the command-line.
     pub autoclean_on_error: bool,
-}
 
-impl Config {
-    /// Create a map containing the environment variables that are common
-    /// to all scripts.
+    #[serde(default, serialize_with = "util::serialize_sorted")]
+    #[builder(default)]
+    /// Replacements for the `config` block of modules, indexed by module name.
     ///
-    /// Callers may add additional variables that are specific to a given
-    /// script step.
-    pub fn shared_env_variables(&self) -> Result<HashMap<&'static OsStr, OsString>, Error> {
-        let synapse_root = self.synapse_root();
-        let script_tmpdir = synapse_root.join("scripts");
-        std::fs::create_dir_all(&script_tmpdir)
-            .with_context(|| format!("Could not create directory {:#?}", script_tmpdir,))?;
-        let curdir = std::env::current_dir()?;
-        let env: HashMap<&'static OsStr, OsString> = std::iter::IntoIterator::into_iter([
-            (
-                MX_TEST_SYNAPSE_DIR.as_os_str(),
-                synapse_root.as_os_str().into(),
-            ),
-            (
-                MX_TEST_SCRIPT_TMPDIR.as_os_str(),
-                script_tmpdir.as_os_str().into(),
-            ),
-            (MX_TEST_CWD.as_os_str(), curdir.as_os_str().into()),
-            (MX_TEST_NETWORK_NAME.as_os_str(), self.network().into()),
-            (
-                MX_TEST_SETUP_CONTAINER_NAME.as_os_str(),
-                self.setup_container_name().into(),
-            ),
-            (
-                MX_TEST_UP_RUN_DOWN_CONTAINER_NAME.as_os_str(),
-                self.run_container_name().into(),
-            ),
-        ])
-        .chain(
-            if self.workers.enabled {
-                Some((MX_TEST_WORKERS_ENABLED.as_os_str(), "true".into()))
-            } else {
-                None
-            }
-            .into_iter(),
-        )
-        .collect();
-        Ok(env)
-    }
-
-    /// Patch the homeserver.yaml at the given path (usually one that has been generated by synapse)
-    /// with the properties in this struct (which will usually have been provided from mx-tester.yaml)
+    /// These are applied when patching homeserver.yaml during `up`, so a single
+    /// `build` may be tested against many module configurations.
     ///
-    /// In multiple workers mode, also patch the worker files.
-    pub fn patch_homeserver_config(&self) -> Result<(), Error> {
-        use serde_yaml::Mapping;
-        let target_path = self.synapse_root().join("data").join("homeserver.yaml");
-        debug!("Attempting to open {:#?}", target_path);
-        let config_file = std::fs::File::open(&target_path)
-            .context("Could not open the homeserver.yaml generated by synapse")?;
-        let mut config: Mapping = serde_yaml::from_reader(config_file)
-            .context("The homeserver.yaml generated by synapse is invalid")?;
-        self.patch_homeserver_config_content(&mut config)?;
-        serde_yaml::to_writer(std::fs::File::create(&target_path)?, &config)
-            .context("Could not write combined homeserver config")?;
-        Ok(())
-    }
-    pub fn patch_homeserver_config_content(
-        &self,
-        config: &mut serde_yaml::Mapping,
-    ) -> Result<(), Error> {
-        use serde_yaml::Value as YAML;
-        const LISTENERS: &str = "listeners";
-        const MODULES: &str = "modules";
-        let combined_config = config;
-
-        for (key, value) in [
-            ("public_baseurl", &self.homeserver.public_baseurl),
-            ("server_name", &self.homeserver.server_name),
-            (
-                "registration_shared_secret",
-                &self.homeserver.registration_shared_secret,
-            ),
-        ] {
-            combined_config.insert(key.into(), value.to_string().into());
-        }
-        combined_config.insert(
-            "enable_registration_without_verification".into(),
-            true.into(),
-        );
+    /// May be overridden from the command-line.
+    pub module_overrides: HashMap<String, serde_yaml::Value>,
 
-        // Copy extra fields.
-        // Note: This may include `modules` or `listeners`.
-        for (key, value) in &self.homeserver.extra_fields {
-            combined_config.insert(YAML::from(key.clone()), value.clone());
-        }
+    #[serde(default)]
+    #[builder(default = false)]
+    /// If `true`, once users and rooms have been created during `up`,
+    /// rebuild the user directory and wait until this is complete.
+    pub rebuild_user_directory: bool,
 
-        // Setup large default rate limits.
-        let large_rate_limit: serde_yaml::Value = yaml!({
-            "per_second" => 1_000_000_000,
-            "burst_count" => 1_000_000_000,
-        });
-        for (key, rate_limit) in &[
-            ("rc_message", large_rate_limit.clone()),
-            ("rc_registration", large_rate_limit.clone()),
-            ("rc_admin_redaction", large_rate_limit.clone()),
+    #[serde(default)]
+    #[builder(default = false)]
+    /// If `true`, once users and rooms have been created during `up`, wait
+    /// until Synapse has completed its background updates, which may
+    /// otherwise make some admin APIs flaky on a fresh database.
+    pub wait_for_background_updates: bool,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// What to do with the logs and manifest of the test, e.g. upload them
+    /// to a bucket at the end of `down`.
+    pub artifacts: ArtifactsConfig,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Checks run at the end of `run`, e.g. that Synapse logged no errors.
+    pub assertions: AssertionsConfig,
+
+    #[serde(default)]
+    #[builder(default = false)]
+    /// If `true`, resources left behind at the end of `down` are an error
+    /// rather than a warning.
+    ///
+    /// May be overridden from the command-line.
+    pub strict_leaks: bool,
+
+    #[serde(default)]
+    #[builder(default = false)]
+    /// If `true`, do not register users or create rooms during `up`, e.g. because
+    /// they already exist in the homeserver.
+    ///
+    /// May be overridden from the command-line.
+    pub skip_registration: bool,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Bots under test, started once Synapse is up and users are registered,
+    /// stopped during `down`.
+    pub bots: Vec<Bot>,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Application services under test, registered with the homeserver during `up`.
+    pub appservices: Vec<AppService>,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Homeservers federating with the homeserver under test, started during `up`
+    /// on the same Docker network, each with its own users.
+    pub homeservers: Vec<FederatedHomeserver>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, a TURN server started during `up` and handed out by Synapse.
+    pub turn: Option<TurnConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, a profiler run against Synapse for the duration of `run`,
+    /// e.g. `py-spy`.
+    pub profile: Option<Profiler>,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Configuring `mx-tester bench`.
+    pub bench: BenchConfig,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Abusive traffic performed by throwaway users with `mx-tester abuse`,
+    /// e.g. to test anti-abuse modules.
+    pub abuse: Vec<Scenario>,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Configuring `mx-tester fuzz-config`.
+    pub fuzz_config: FuzzConfig,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Exporting OpenTelemetry traces of the test run.
+    pub telemetry: TelemetryConfig,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, matrix-authentication-service, started during `up`,
+    /// to which Synapse delegates authentication (MSC3861).
+    pub mas: Option<MasConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, an identity server (Sydent) started during `up` and
+    /// trusted by Synapse, e.g. to test 3PID invites.
+    pub identity_server: Option<IdentityServerConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, record the client traffic that goes through a proxy during
+    /// `run`, for later use with `mx-tester replay`.
+    pub recording: Option<RecordingConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, a push gateway (Sygnal) and a stub push receiver started
+    /// during `up`, e.g. to check that Synapse dispatches push notifications.
+    pub push: Option<PushConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, an SMTP server (MailHog) started during `up`, which
+    /// captures the emails sent by Synapse.
+    pub email: Option<EmailConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, a Jaeger instance started during `up`, to which Synapse
+    /// reports the spans of the requests it handles.
+    pub jaeger: Option<JaegerConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, an identity provider (Keycloak) started during `up`,
+    /// through which users may log in to Synapse with single sign-on.
+    pub sso: Option<SsoConfig>,
+
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    /// If specified, a PostgreSQL server started during `up`, in its own
+    /// container, or an already-running PostgreSQL server, as the database
+    /// of Synapse.
+    pub postgres: Option<PostgresConfig>,
+
+    #[serde(default)]
+    #[builder(default)]
+    /// Helper containers started during `up`, before the homeserver, e.g.
+    /// databases, bridges or mocks of third-party APIs.
+    pub services: Vec<Service>,
+
+    #[serde(skip)]
+    #[builder(default)]
+    /// If this configuration is that of a homeserver declared in `homeservers`,
+    /// as per `Config::federated`, its name.
+    pub federated_name: Option<String>,
+
+    #[serde(skip)]
+    #[builder(default)]
+    /// If placeholders in `users` have been expanded, as per
+    /// `fixtures::expand`, the seed used.
+    pub fixtures_seed: Option<u64>,
+}
+
+impl Config {
+    /// Create a map containing the environment variables that are common
+    /// to all scripts.
+    ///
+    /// Callers may add additional variables that are specific to a given
+    /// script step.
+    pub fn shared_env_variables(&self) -> Result<HashMap<&'static OsStr, OsString>, Error> {
+        let synapse_root = self.synapse_root();
+        let script_tmpdir = self.script_tmpdir();
+        std::fs::create_dir_all(&script_tmpdir)
+            .with_context(|| format!("Could not create directory {:#?}", script_tmpdir,))?;
+        let curdir = std::env::current_dir()?;
+        let env: HashMap<&'static OsStr, OsString> = std::iter::IntoIterator::into_iter([
             (
-                "rc_login",
-                yaml!({
-                    "address" => large_rate_limit.clone(),
-                    "account" => large_rate_limit.clone(),
-                    "failed_attempts" => large_rate_limit.clone(),
-                }),
+                MX_TEST_SYNAPSE_DIR.as_os_str(),
+                synapse_root.as_os_str().into(),
             ),
             (
-                "rc_invites",
-                yaml!({
-                    "per_room" => large_rate_limit.clone(),
-                    "per_user" => large_rate_limit.clone(),
-                    "per_sender" => large_rate_limit,
-                }),
+                MX_TEST_SCRIPT_TMPDIR.as_os_str(),
+                script_tmpdir.as_os_str().into(),
             ),
-        ] {
-            if !combined_config.contains_key(key) {
-                // Setup a large default rate limit.
-                combined_config.insert(key.to_string().into(), rate_limit.clone());
-            } else if combined_config[key].is_default() {
-                // ...or the Synapse default rate limit.
-                combined_config.remove(key);
-            } else {
-                // Otherwise, assume that the author of mx-tester.yaml knows what they're doing.
+            (MX_TEST_CWD.as_os_str(), curdir.as_os_str().into()),
+            (MX_TEST_RUN_ID.as_os_str(), run_id().into()),
+            (MX_TEST_NETWORK_NAME.as_os_str(), self.network().into()),
+            (
+                MX_TEST_SETUP_CONTAINER_NAME.as_os_str(),
+                self.setup_container_name().into(),
+            ),
+            (
+                MX_TEST_UP_RUN_DOWN_CONTAINER_NAME.as_os_str(),
+                self.run_container_name().into(),
+            ),
+            (
+                MX_TEST_HOMESERVER_URL.as_os_str(),
+                self.homeserver.public_baseurl.clone().into(),
+            ),
+            (
+                MX_TEST_SERVER_NAME.as_os_str(),
+                self.homeserver.server_name.clone().into(),
+            ),
+        ])
+        .chain(if self.workers.enabled {
+            Some((MX_TEST_WORKERS_ENABLED.as_os_str(), "true".into()))
+        } else {
+            None
+        })
+        .chain(if self.appservices.is_empty() {
+            None
+        } else {
+            Some((
+                MX_TEST_APPSERVICES_DIR.as_os_str(),
+                self.appservices_dir().into_os_string(),
+            ))
+        })
+        .chain(self.identity_server.as_ref().map(|_| {
+            (
+                MX_TEST_IDENTITY_SERVER.as_os_str(),
+                identity::server_address(self).into(),
+            )
+        }))
+        .chain(self.push.as_ref().map(|_| {
+            (
+                MX_TEST_PUSH_GATEWAY_URL.as_os_str(),
+                push::gateway_url(self).into(),
+            )
+        }))
+        .chain(self.push.as_ref().map(|_| {
+            (
+                MX_TEST_PUSH_RECEIVER_URL.as_os_str(),
+                push::receiver_url(self).into(),
+            )
+        }))
+        .chain(
+            self.email
+                .as_ref()
+                .map(|email| (MX_TEST_EMAIL_API_URL.as_os_str(), email.api_url().into())),
+        )
+        .chain(
+            self.jaeger
+                .as_ref()
+                .map(|jaeger| (MX_TEST_JAEGER_URL.as_os_str(), jaeger.ui_url().into())),
+        )
+        .chain(
+            self.sso
+                .as_ref()
+                .map(|sso| (MX_TEST_SSO_ISSUER.as_os_str(), sso.issuer().into())),
+        )
+        .chain(
+            self.postgres
+                .as_ref()
+                .and_then(PostgresConfig::host_url)
+                .map(|url| (MX_TEST_POSTGRES_URL.as_os_str(), url.into())),
+        )
+        .chain(self.recording.as_ref().map(|recording| {
+            (
+                MX_TEST_RECORDING_PROXY.as_os_str(),
+                recording::proxy_url(recording).into(),
+            )
+        }))
+        .collect();
+        Ok(env)
+    }
+
+    /// Patch the homeserver.yaml at the given path (usually one that has been generated by synapse)
+    /// with the properties in this struct (which will usually have been provided from mx-tester.yaml)
+    ///
+    /// In multiple workers mode, also patch the worker files.
+    pub fn patch_homeserver_config(&self) -> Result<(), Error> {
+        use serde_yaml::Mapping;
+        if let HomeserverKind::Dendrite = self.homeserver.kind {
+            let target_path = self.homeserver_config_path();
+            debug!("Attempting to open {:#?}", target_path);
+            let config_file = std::fs::File::open(&target_path)
+                .context("Could not open the dendrite.yaml generated by dendrite")?;
+            let config: Mapping = serde_yaml::from_reader(config_file)
+                .context("The dendrite.yaml generated by dendrite is invalid")?;
+            let mut config = patch::patch_dendrite_config(config, &self.homeserver)?;
+            if !self.appservices.is_empty() {
+                config
+                    .entry("app_service_api".into())
+                    .or_insert_with(|| serde_yaml::Value::Mapping(Mapping::new()))
+                    .as_mapping_mut()
+                    .ok_or_else(|| {
+                        anyhow!("In dendrite.yaml, expected a mapping for key `app_service_api`")
+                    })?
+                    .entry("config_files".into())
+                    .or_insert(serde_yaml::Value::Null)
+                    .to_seq_mut()
+                    .ok_or_else(|| {
+                        anyhow!("In dendrite.yaml, expected a sequence for key `app_service_api.config_files`")
+                    })?
+                    .extend(self.appservice_guest_paths().into_iter().map(Into::into));
             }
+            // Don't override mx-tester.yml.
+            let has_turn = self
+                .homeserver
+                .extra_fields
+                .get("client_api")
+                .and_then(|client_api| client_api.get("turn"))
+                .is_some();
+            if let (Some(ref turn), false) = (&self.turn, has_turn) {
+                config
+                    .entry("client_api".into())
+                    .or_insert_with(|| serde_yaml::Value::Mapping(Mapping::new()))
+                    .as_mapping_mut()
+                    .ok_or_else(|| {
+                        anyhow!("In dendrite.yaml, expected a mapping for key `client_api`")
+                    })?
+                    .insert(
+                        "turn".into(),
+                        yaml!({
+                            "turn_uris" => serde_yaml::to_value(turn::uris(self))?,
+                            "turn_shared_secret" => turn.shared_secret.clone()
+                        }),
+                    );
+            }
+            serde_yaml::to_writer(std::fs::File::create(&target_path)?, &config)
+                .context("Could not write combined dendrite config")?;
+            Manifest::record_homeserver_config(self, &config)?;
+            return Ok(());
         }
-
-        // Make sure that we listen on the appropriate port.
-        // For some reason, `start.py generate` tends to put port 4153 instead of HARDCODED_GUEST_PORT.
-        let listeners = combined_config
-            .entry(LISTENERS.into())
-            .or_insert_with(|| yaml!([]));
-        *listeners = yaml!([yaml!({
-            "port" => if self.workers.enabled { HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT } else { HARDCODED_GUEST_PORT },
-            "tls" => false,
-            "type" => "http",
-            "bind_addresses" => yaml!(["::"]),
-            "x_forwarded" => false,
-            "resources" => yaml!([
-                yaml!({
-                    "names" => yaml!(["client"]),
-                    "compress" => true
-                }),
-                yaml!({
-                    "names" => yaml!(["federation"]),
-                    "compress" => false
-                })
-            ]),
-        })]);
+        let target_path = self.homeserver_config_path();
+        debug!("Attempting to open {:#?}", target_path);
+        let config_file = std::fs::File::open(&target_path)
+            .context("Could not open the homeserver.yaml generated by synapse")?;
+        let mut config: Mapping = serde_yaml::from_reader(config_file)
+            .context("The homeserver.yaml generated by synapse is invalid")?;
+        self.patch_homeserver_config_content(&mut config)?;
+        serde_yaml::to_writer(std::fs::File::create(&target_path)?, &config)
+            .context("Could not write combined homeserver config")?;
         if self.workers.enabled {
-            // Setup the replication port.
-            listeners
-                .as_sequence_mut()
-                .unwrap() // We just set it up as a sequence
-                .push(yaml!({
-                    "port" => 9093,
-                        "bind_address" => "127.0.0.1",
-                        "type" => "http",
-                        "resources" => yaml!([
-                            yaml!({
-                                "names" => yaml!(["replication"])
-                            })
-                        ])
-                }));
-        }
-
-        // Copy modules config.
-        let modules_root = combined_config
-            .entry(MODULES.into())
-            .or_insert_with(|| yaml!([]))
-            .to_seq_mut()
-            .ok_or_else(|| anyhow!("In homeserver.yaml, expected a sequence for key `modules`"))?;
-        for module in &self.modules {
-            modules_root.push(module.config.clone());
+            // The shared worker config (generated by workers_start.py) is loaded after
+            // homeserver.yaml by all processes, so it receives the same patched config,
+            // including modules.
+            //
+            // Note: In future versions, we might decide to only patch specific workers.
+            let conf_path = self.synapse_workers_dir().join("shared.yaml");
+            if !conf_path.exists() {
+                return Err(anyhow!(
+                    "Could not open workers shared config: {:?}",
+                    conf_path
+                ));
+            }
+            serde_yaml::to_writer(std::fs::File::create(&conf_path)?, &config)
+                .context("Could not write workers shared config")?;
         }
+        self.patch_log_configs(&config)?;
+        Manifest::record_homeserver_config(self, &config)?;
+        Ok(())
+    }
 
+    /// Patch the logging configurations generated by Synapse, i.e. that of
+    /// the main process, as per `log_config` in `homeserver`, the content of
+    /// homeserver.yaml, and, in workers mode, those of workers, with the
+    /// `log_level` of modules.
+    pub fn patch_log_configs(&self, homeserver: &serde_yaml::Mapping) -> Result<(), Error> {
+        let loggers = self
+            .modules
+            .iter()
+            .filter_map(|module| module.logger().transpose())
+            .collect::<Result<Vec<_>, Error>>()?;
+        if loggers.is_empty() {
+            return Ok(());
+        }
+        let mut paths = vec![];
+        if let Some(guest_path) = homeserver.get("log_config").and_then(|path| path.as_str()) {
+            let host_path = Path::new(guest_path)
+                .strip_prefix("/data")
+                .map(|path| self.synapse_data_dir().join(path))
+                .map_err(|_| {
+                    anyhow!(
+                        "In homeserver.yaml, expected `log_config` in /data, got {}",
+                        guest_path
+                    )
+                })?;
+            paths.push(host_path);
+        }
         if self.workers.enabled {
-            for (key, value) in std::iter::IntoIterator::into_iter([
-                // No worker support without redis.
-                (
-                    "redis",
-                    yaml!({
-                        "enabled" => true,
-                    }),
-                ),
-                // No worker support without postgresql
-                (
-                    "database",
-                    yaml!({
-                        "name" => "psycopg2",
-                        "txn_limit" => 10_000,
-                        "args" => yaml!({
-                            "user" => "synapse",
-                            "password" => "password",
-                            "host" => "localhost",
-                            "port" => 5432,
-                            "cp_min" => 5,
-                            "cp_max" => 10
-                        })
-                    }),
-                ),
-                // Deactivate a few features in the main process
-                // and let a worker take over them.
-                ("notify_appservices", yaml!(false)),
-                ("send_federation", yaml!(false)),
-                ("update_user_directory", yaml!(false)),
-                ("start_pushers", yaml!(false)),
-                ("url_preview_enabled", yaml!(false)),
-                (
-                    "url_preview_ip_range_blacklist",
-                    yaml!(["255.255.255.255/32",]),
-                ),
-                // Also, let's get rid of that warning, it pollutes logs.
-                ("suppress_key_server_warning", yaml!(true)),
-            ]) {
-                combined_config.insert(yaml!(key), value);
+            let dir = self.synapse_workers_dir();
+            for entry in
+                std::fs::read_dir(&dir).with_context(|| format!("Could not read {:?}", dir))?
+            {
+                let path = entry?.path();
+                if path.to_string_lossy().ends_with(".log.config") {
+                    paths.push(path);
+                }
             }
+        }
+        for path in paths {
+            debug!("Patching log config {:?}", path);
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read log config {:?}", path))?;
+            let log_config: serde_yaml::Mapping = serde_yaml::from_str(&content)
+                .with_context(|| format!("Invalid log config {:?}", path))?;
+            let log_config = patch::patch_log_config(log_config, &loggers)?;
+            serde_yaml::to_writer(std::fs::File::create(&path)?, &log_config)
+                .with_context(|| format!("Could not write log config {:?}", path))?;
+        }
+        Ok(())
+    }
 
-            // Patch shared worker config (generated by workers_start.py) to inject modules into all workers.
-            //
-            // Note: In future versions, we might decide to only patch specific workers.
-            let conf_path = self.synapse_workers_dir().join("shared.yaml");
-            let conf_file = std::fs::File::open(&conf_path).with_context(|| {
-                format!("Could not open workers shared config: {:?}", conf_path)
-            })?;
-            let mut config: serde_yaml::Mapping = serde_yaml::from_reader(&conf_file)
-                .with_context(|| {
-                    format!("Could not parse workers shared config: {:?}", conf_path)
-                })?;
+    /// Check that the configuration is consistent, e.g. that room members and
+    /// module overrides refer to declared users and modules.
+    ///
+    /// All problems are reported at once.
+    pub fn validate(&self) -> Result<(), Error> {
+        report_problems(self.problems())
+    }
 
-            let modules_root = config
-                .entry(MODULES.into())
-                .or_insert_with(|| yaml!([]))
-                .to_seq_mut()
-                .ok_or_else(|| anyhow!("In shared.yaml, expected a sequence for key `modules`"))?;
-            for module in &self.modules {
-                modules_root.push(module.config.clone());
+    /// The inconsistencies of the configuration, as per `validate`.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for name in self.module_overrides.keys() {
+            if !self.modules.iter().any(|module| &module.name == name) {
+                problems.push(format!(
+                    "Cannot override configuration of module {}: no such module",
+                    name
+                ));
+            }
+        }
+        registration::check_users(&self.users, &mut problems);
+        for module in &self.modules {
+            match module.source {
+                ModuleSource::Build if module.build.lines.is_empty() => problems.push(format!(
+                    "Module {} needs a `build` script or a `source`",
+                    module.name
+                )),
+                ModuleSource::Build => {}
+                ModuleSource::Wheel {
+                    ref path,
+                    ref sha256,
+                } => {
+                    if path.extension().map_or(true, |ext| ext != "whl") {
+                        problems.push(format!(
+                            "Module {}: `source.wheel.path` {:?} is not a wheel, i.e. a `.whl` file",
+                            module.name, path
+                        ));
+                    }
+                    if let InstallMode::Editable = module.install_mode {
+                        problems.push(format!(
+                            "Module {}: a wheel cannot be installed in editable mode",
+                            module.name
+                        ));
+                    }
+                    if let Some(ref sha256) = sha256 {
+                        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                            problems.push(format!(
+                                "Module {}: `source.wheel.sha256` should be 64 hexadecimal digits",
+                                module.name
+                            ));
+                        }
+                    }
+                }
+            }
+            if let (InstallMode::Editable, None) = (module.install_mode, &module.path) {
+                problems.push(format!(
+                    "Module {} is installed in editable mode, it needs a `path`",
+                    module.name
+                ));
+            }
+            if let Err(err) = module.logger() {
+                problems.push(err.to_string());
+            }
+        }
+        if let Some(ref postgres) = self.postgres {
+            postgres::check(postgres, &mut problems);
+        }
+        if let Some(ref jaeger) = self.jaeger {
+            jaeger::check(jaeger, &mut problems);
+        }
+        artifacts::check(&self.artifacts, &mut problems);
+        assertions::check(&self.assertions, &mut problems);
+        abuse::check(&self.abuse, &self.users, &mut problems);
+        fuzz::check(&self.fuzz_config, &mut problems);
+        if !self.build.cache.from.is_empty() && !self.build.cache.enabled {
+            problems.push("`build.cache.from` requires `build.cache.enabled`".to_string());
+        }
+        match self.homeserver.kind {
+            HomeserverKind::Synapse => {
+                if self.homeserver.tag.is_some() {
+                    problems.push(
+                        "`homeserver.tag` is only meaningful for Dendrite, the image of Synapse is specified in `synapse`".to_string(),
+                    );
+                }
+            }
+            HomeserverKind::Dendrite => {
+                if !self.modules.is_empty() {
+                    problems.push("Dendrite does not support `modules`".to_string());
+                }
+                if self.workers.enabled {
+                    problems.push("Dendrite does not support `workers`".to_string());
+                }
+                if self.rebuild_user_directory {
+                    problems.push("Dendrite does not support `rebuild_user_directory`".to_string());
+                }
+                if self.wait_for_background_updates {
+                    problems.push(
+                        "Dendrite does not support `wait_for_background_updates`".to_string(),
+                    );
+                }
+                if self.homeserver.minimal {
+                    problems.push("Dendrite does not support `homeserver.minimal`".to_string());
+                }
+                if self.artifacts.coverage.is_some() {
+                    problems.push("Dendrite does not support `artifacts.coverage`".to_string());
+                }
+                if self.debug.python.is_some() {
+                    problems.push("Dendrite does not support `debug.python`".to_string());
+                }
+                if self.profile.is_some() {
+                    problems.push("Dendrite does not support `profile`".to_string());
+                }
+                if self.mas.is_some() {
+                    problems.push("Dendrite does not support `mas`".to_string());
+                }
+                if self.identity_server.is_some() {
+                    problems.push("Dendrite does not support `identity_server`".to_string());
+                }
+                if self.email.is_some() {
+                    problems.push("Dendrite does not support `email`".to_string());
+                }
+                if self.jaeger.is_some() {
+                    problems.push("Dendrite does not support `jaeger`".to_string());
+                }
+                if self.sso.is_some() {
+                    problems.push("Dendrite does not support `sso`".to_string());
+                }
+                if self.postgres.is_some() {
+                    problems.push("Dendrite does not support `postgres`".to_string());
+                }
+                if !self.bench.endpoints.is_empty() {
+                    problems.push(
+                        "Dendrite does not support `bench`, as it has no access log".to_string(),
+                    );
+                }
+            }
+        }
+        if self.bench.iterations == 0 {
+            problems.push("`bench.iterations` must be at least 1".to_string());
+        }
+        if self.docker.readiness_timeout_sec == 0 {
+            problems.push("`docker.readiness_timeout_sec` must be at least 1".to_string());
+        }
+        if self.docker.timeout_sec == 0 {
+            problems.push("`docker.timeout_sec` must be at least 1".to_string());
+        }
+        let public_baseurl = &self.homeserver.public_baseurl;
+        if !public_baseurl.starts_with("http://") && !public_baseurl.starts_with("https://") {
+            problems.push(format!(
+                "Invalid `homeserver.public_baseurl` {}, expected `http://...` or `https://...`",
+                public_baseurl
+            ));
+        }
+        let mut host_ports = std::collections::HashSet::new();
+        for port in std::iter::once(self.homeserver.host_port)
+            .chain(self.docker.port_mapping.iter().map(|mapping| mapping.host))
+        {
+            if u16::try_from(port).is_err() {
+                problems.push(format!("Invalid host port {}", port));
+            } else if !host_ports.insert(port) {
+                problems.push(format!(
+                    "Host port {} is mapped several times, check `homeserver.host_port` and `docker.port_mapping`",
+                    port
+                ));
+            }
+        }
+        for endpoint in &self.bench.endpoints {
+            if !matches!(endpoint.split_once(' '), Some((_, path)) if path.starts_with('/')) {
+                problems.push(format!(
+                    "In `bench.endpoints`, expected a method and a path, e.g. `GET /_matrix/client/v3/sync`, got `{}`",
+                    endpoint
+                ));
+            }
+        }
+        let mut bots = std::collections::HashSet::new();
+        for bot in &self.bots {
+            if !bots.insert(bot.name.as_str()) {
+                problems.push(format!("Bot {} is declared more than once", bot.name));
+            }
+            if bot.image.is_some() == bot.build.is_some() {
+                problems.push(format!(
+                    "Bot {}: exactly one of `image` and `build` must be specified",
+                    bot.name
+                ));
+            }
+            if let Some(ref user) = bot.user {
+                if !self.users.iter().any(|u| &u.localname == user) {
+                    problems.push(format!(
+                        "Bot {}: user {} is not declared in `users`",
+                        bot.name, user
+                    ));
+                }
+            }
+        }
+        let mut services = std::collections::HashSet::new();
+        for service in &self.services {
+            if !services.insert(service.name.as_str()) {
+                problems.push(format!(
+                    "Service {} is declared more than once",
+                    service.name
+                ));
+            }
+        }
+        if let Err(err) = services::start_order(&self.services) {
+            problems.push(err.to_string());
+        }
+        // Hostnames must be unique on the network.
+        let mut aliases = std::collections::HashMap::new();
+        for (owner, alias) in self
+            .docker
+            .aliases
+            .iter()
+            .map(|alias| ("Synapse".to_string(), alias))
+            .chain(self.bots.iter().flat_map(|bot| {
+                bot.aliases
+                    .iter()
+                    .map(move |alias| (format!("bot {}", bot.name), alias))
+            }))
+            .chain(self.services.iter().flat_map(|service| {
+                service
+                    .aliases
+                    .iter()
+                    .map(move |alias| (format!("service {}", service.name), alias))
+            }))
+            .chain(
+                std::iter::IntoIterator::into_iter([
+                    ("turn", self.turn.as_ref().map(|turn| &turn.aliases)),
+                    ("mas", self.mas.as_ref().map(|mas| &mas.aliases)),
+                    (
+                        "identity_server",
+                        self.identity_server
+                            .as_ref()
+                            .map(|identity_server| &identity_server.aliases),
+                    ),
+                    ("push", self.push.as_ref().map(|push| &push.aliases)),
+                    ("email", self.email.as_ref().map(|email| &email.aliases)),
+                    ("jaeger", self.jaeger.as_ref().map(|jaeger| &jaeger.aliases)),
+                    ("sso", self.sso.as_ref().map(|sso| &sso.aliases)),
+                    (
+                        "postgres",
+                        self.postgres.as_ref().map(|postgres| &postgres.aliases),
+                    ),
+                ])
+                .flat_map(|(owner, aliases)| {
+                    aliases
+                        .into_iter()
+                        .flatten()
+                        .map(move |alias| (owner.to_string(), alias))
+                }),
+            )
+        {
+            if alias.is_empty()
+                || !alias
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            {
+                problems.push(format!("{}: invalid alias `{}`", owner, alias));
+            }
+            if let Some(previous) = aliases.insert(alias.as_str(), owner.clone()) {
+                problems.push(format!(
+                    "Alias `{}` is declared by both {} and {}",
+                    alias, previous, owner
+                ));
+            }
+        }
+        let mut appservices = std::collections::HashSet::new();
+        for appservice in &self.appservices {
+            if !appservices.insert(appservice.id.as_str()) {
+                problems.push(format!(
+                    "Application service {} is declared more than once",
+                    appservice.id
+                ));
+            }
+            match appservice.bot {
+                None if appservice.url.is_none() => problems.push(format!(
+                    "Application service {}: either `url` or `bot` must be specified",
+                    appservice.id
+                )),
+                Some(ref bot) if !self.bots.iter().any(|b| &b.name == bot) => {
+                    problems.push(format!(
+                        "Application service {}: bot {} is not declared in `bots`",
+                        appservice.id, bot
+                    ))
+                }
+                _ => {}
+            }
+        }
+        for appservice in &self.appservices {
+            if let Some(ref bridge) = appservice.bridge {
+                if bridge.prefix.is_empty() {
+                    problems.push(format!(
+                        "Application service {}: `bridge.prefix` may not be empty",
+                        appservice.id
+                    ));
+                }
+            }
+        }
+        for bot in &self.bots {
+            if self
+                .appservices
+                .iter()
+                .filter(|appservice| appservice.bot.as_ref() == Some(&bot.name))
+                .count()
+                > 1
+            {
+                problems.push(format!(
+                    "Bot {}: runs more than one application service",
+                    bot.name
+                ));
+            }
+        }
+        if self.debug.python.is_some() && self.workers.enabled {
+            problems.push(
+                "`debug.python` does not support `workers`, as each worker would need its own port"
+                    .to_string(),
+            );
+        }
+        if self.workers.templates_dir.is_some() && !self.workers.enabled {
+            problems.push("`workers.templates_dir` requires `workers.enabled`".to_string());
+        }
+        #[cfg(feature = "workers")]
+        for worker_type in &self.workers.types {
+            if !workers::WORKER_TYPES.contains(&worker_type.as_str()) {
+                problems.push(format!(
+                    "Unknown worker type `{}` in `workers.types`, expected one of {}",
+                    worker_type,
+                    workers::WORKER_TYPES.join(", ")
+                ));
+            }
+        }
+        if self.sso.is_some() && self.mas.is_some() {
+            problems.push(
+                "`sso` and `mas` are mutually exclusive, as Synapse delegates authentication to MAS"
+                    .to_string(),
+            );
+        }
+        if let Some(ref sso) = self.sso {
+            let mut usernames = std::collections::HashSet::new();
+            for user in &sso.users {
+                if !usernames.insert(user.username.as_str()) {
+                    problems.push(format!(
+                        "SSO user {} is declared more than once",
+                        user.username
+                    ));
+                }
+            }
+        }
+        if !self.homeservers.is_empty() {
+            if self.workers.enabled {
+                problems
+                    .push("Federation with `homeservers` does not support `workers`".to_string());
+            }
+            let mut names = std::collections::HashSet::new();
+            let mut server_names = std::collections::HashSet::new();
+            let mut host_ports = std::collections::HashSet::new();
+            let mut public_baseurls = std::collections::HashSet::new();
+            for (name, homeserver) in std::iter::once(("homeserver", &self.homeserver)).chain(
+                self.homeservers
+                    .iter()
+                    .map(|peer| (peer.name.as_str(), &peer.homeserver)),
+            ) {
+                if name != "homeserver" && !names.insert(name) {
+                    problems.push(format!(
+                        "Homeserver {} is declared more than once in `homeservers`",
+                        name
+                    ));
+                }
+                if let HomeserverKind::Dendrite = homeserver.kind {
+                    problems.push(format!(
+                        "Homeserver {}: federation with `homeservers` is only supported with Synapse",
+                        name
+                    ));
+                }
+                if !server_names.insert(homeserver.server_name.as_str()) {
+                    problems.push(format!(
+                        "Homeserver {}: server name {} is used by several homeservers",
+                        name, homeserver.server_name
+                    ));
+                }
+                if federation::network_alias(&homeserver.server_name) == "localhost" {
+                    problems.push(format!(
+                        "Homeserver {}: with `homeservers`, the server name must be reachable from other homeservers, e.g. `{}:8448`, not {}",
+                        name, name, homeserver.server_name
+                    ));
+                }
+                if !host_ports.insert(homeserver.host_port) {
+                    problems.push(format!(
+                        "Homeserver {}: host port {} is used by several homeservers",
+                        name, homeserver.host_port
+                    ));
+                }
+                if !public_baseurls.insert(homeserver.public_baseurl.as_str()) {
+                    problems.push(format!(
+                        "Homeserver {}: public base url {} is used by several homeservers",
+                        name, homeserver.public_baseurl
+                    ));
+                }
+            }
+            for peer in &self.homeservers {
+                registration::check_users(&peer.users, &mut problems);
             }
+        }
+        problems
+    }
 
-            for (key, value) in std::iter::IntoIterator::into_iter([
-                // Disable url_preview_enabled.
-                ("url_preview_enabled", yaml!(false)),
+    /// Patch the contents of a homeserver.yaml with the configuration of this test.
+    ///
+    /// This has no side effect, see `patch::patch_homeserver_config`.
+    pub fn patch_homeserver_config_content(
+        &self,
+        config: &mut serde_yaml::Mapping,
+    ) -> Result<(), Error> {
+        for name in self.module_overrides.keys() {
+            if !self.modules.iter().any(|module| &module.name == name) {
+                return Err(anyhow!(
+                    "Cannot override configuration of module {}: no such module",
+                    name
+                ));
+            }
+        }
+        let modules = self
+            .modules
+            .iter()
+            .map(|module| self.module_homeserver_config(module))
+            .collect::<Result<Vec<_>, Error>>()?;
+        *config = patch::patch_homeserver_config(
+            std::mem::take(config),
+            &self.homeserver,
+            self.workers.enabled,
+            &modules,
+        )?;
+        if !self.appservices.is_empty() {
+            config
+                .entry("app_service_config_files".into())
+                .or_insert(serde_yaml::Value::Null)
+                .to_seq_mut()
+                .ok_or_else(|| {
+                    anyhow!("In homeserver.yaml, expected a sequence for key `app_service_config_files`")
+                })?
+                .extend(self.appservice_guest_paths().into_iter().map(Into::into));
+        }
+        if !self.homeservers.is_empty() {
+            federation::patch_homeserver_config(config, &self.homeserver.extra_fields)?;
+        }
+        if let Some(ref turn) = self.turn {
+            turn::patch_homeserver_config(self, turn, config, &self.homeserver.extra_fields)?;
+        }
+        if self.identity_server.is_some() {
+            identity::patch_homeserver_config(self, config, &self.homeserver.extra_fields)?;
+        }
+        if self.push.is_some() {
+            push::patch_homeserver_config(config, &self.homeserver.extra_fields)?;
+        }
+        if let Some(ref email) = self.email {
+            email::patch_homeserver_config(self, email, config)?;
+        }
+        if let Some(ref jaeger) = self.jaeger {
+            jaeger::patch_homeserver_config(self, jaeger, config)?;
+        }
+        if let Some(ref sso) = self.sso {
+            sso::patch_homeserver_config(self, sso, config, &self.homeserver.extra_fields)?;
+        }
+        if let Some(ref postgres) = self.postgres {
+            postgres::patch_homeserver_config(
+                self,
+                postgres,
+                config,
+                &self.homeserver.extra_fields,
+            )?;
+        }
+        if self
+            .appservices
+            .iter()
+            .any(|appservice| appservice.double_puppet_id().is_some())
+        {
+            // Let bridges login as their users, either with an existing session
+            // or with appservice login (MSC2778). Don't override mx-tester.yml.
+            for (key, value) in [
                 (
-                    "url_preview_ip_range_blacklist",
-                    yaml!(["255.255.255.255/32"]),
+                    "login_via_existing_session",
+                    yaml!({ "enabled" => true, "require_ui_auth" => false }),
                 ),
-                // No worker without postgres.
                 (
-                    "database",
-                    yaml!({
-                        "name" => "psycopg2",
-                        "txn_limit" => 10_000,
-                        "args" => yaml!({
-                            "user" => "synapse",
-                            "password" => "password",
-                            "host" => "localhost",
-                            "port" => 5432,
-                            "cp_min" => 5,
-                            "cp_max" => 10
-                        })
-                    }),
+                    "experimental_features",
+                    yaml!({ "msc2778_enabled" => true }),
                 ),
-            ]) {
-                config.insert(yaml!(key), value);
+            ] {
+                if !self.homeserver.extra_fields.contains_key(key) {
+                    config.insert(key.into(), value);
+                }
             }
-
-            // Deactivate url preview
-            serde_yaml::to_writer(std::fs::File::create(&conf_path)?, &combined_config)
-                .context("Could not write workers shared config")?;
         }
-
+        // After appservices, to keep any other `experimental_features`.
+        if let Some(ref mas) = self.mas {
+            mas::patch_homeserver_config(self, mas, config, &self.homeserver.extra_fields)?;
+        }
         Ok(())
     }
 
+    /// The entry for a module in homeserver.yaml, taking `module_overrides` into account.
+    fn module_homeserver_config(&self, module: &ModuleConfig) -> Result<serde_yaml::Value, Error> {
+        let mut result = module.config.clone();
+        if let Some(config) = self.module_overrides.get(&module.name) {
+            result
+                .as_mapping_mut()
+                .ok_or_else(|| {
+                    anyhow!("In module {}, expected a mapping for `config`", module.name)
+                })?
+                .insert("config".into(), config.clone());
+        }
+        Ok(result)
+    }
+
     /// The directory in which we're putting all data for this test.
     ///
     /// Cleaned up upon test start.
     pub fn test_root(&self) -> PathBuf {
-        self.directories.root.join(&self.name)
+        match self.federated_name {
+            None => self.directories.root.join(&self.name),
+            Some(ref federated_name) => self
+                .directories
+                .root
+                .join(&self.name)
+                .join("federation")
+                .join(federated_name),
+        }
     }
 
     /// The directory in which we're putting everything related to synapse data for this test.
@@ -598,11 +1829,45 @@ impl Config {
         self.test_root().join("synapse")
     }
 
+    /// The directory in which we store the snapshots of the data of Synapse,
+    /// see `snapshot`.
+    ///
+    /// Unlike `test_root`, not cleaned up upon test start.
+    pub fn snapshots_dir(&self) -> PathBuf {
+        let dir = self.directories.root.join(".snapshots").join(&self.name);
+        match self.federated_name {
+            None => dir,
+            Some(ref federated_name) => dir.join("federation").join(federated_name),
+        }
+    }
+
     /// The directory in which Synapse may write data.
     pub fn synapse_data_dir(&self) -> PathBuf {
         self.synapse_root().join("data")
     }
 
+    /// The configuration file of the homeserver, i.e. homeserver.yaml for Synapse
+    /// or dendrite.yaml for Dendrite.
+    pub fn homeserver_config_path(&self) -> PathBuf {
+        self.synapse_data_dir().join(match self.homeserver.kind {
+            HomeserverKind::Synapse => "homeserver.yaml",
+            HomeserverKind::Dendrite => "dendrite.yaml",
+        })
+    }
+
+    /// The Docker image of the homeserver we build upon, e.g. `matrixdotorg/synapse:latest`.
+    pub fn homeserver_image(&self) -> String {
+        match (self.homeserver.kind, &self.synapse) {
+            (HomeserverKind::Synapse, SynapseVersion::Docker { ref tag }) => tag.clone(),
+            (HomeserverKind::Synapse, SynapseVersion::Local { ref base, .. }) => base.clone(),
+            (HomeserverKind::Dendrite, _) => self
+                .homeserver
+                .tag
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DENDRITE_VERSION.to_string()),
+        }
+    }
+
     /// The directory in which we're putting the configuration of workers for this test.
     pub fn synapse_workers_dir(&self) -> PathBuf {
         self.synapse_root().join("workers")
@@ -625,16 +1890,23 @@ impl Config {
 
     /// A tag for the Docker image we're creating/using.
     pub fn tag(&self) -> String {
-        match self.synapse {
-            SynapseVersion::Docker { ref tag } => {
-                format!(
-                    "mx-tester-synapse-{}-{}{workers}",
-                    tag,
-                    self.name,
-                    workers = if self.workers.enabled { "-workers" } else { "" }
-                )
-            }
-        }
+        let tag = match (self.homeserver.kind, &self.synapse) {
+            (HomeserverKind::Synapse, SynapseVersion::Local { .. }) => "local".to_string(),
+            _ => self.homeserver_image(),
+        };
+        // Our tag cannot contain a digest, so turn `image@sha256:...`
+        // into `image:sha256-...`.
+        let tag = match tag.split_once('@') {
+            Some((image, digest)) => format!("{}:{}", image, digest.replace(':', "-")),
+            None => tag,
+        };
+        format!(
+            "mx-tester-{}-{}-{}{workers}",
+            self.homeserver.kind.name(),
+            tag,
+            self.name,
+            workers = if self.workers.enabled { "-workers" } else { "" }
+        )
     }
 
     /// A name for the network we're creating/using.
@@ -645,30 +1917,204 @@ impl Config {
     /// The name for the container we're using to setup Synapse.
     pub fn setup_container_name(&self) -> String {
         format!(
-            "mx-tester-synapse-setup-{}{}",
+            "mx-tester-synapse-setup-{}{}{}",
+            self.name,
+            if self.workers.enabled { "-workers" } else { "" },
+            self.federated_suffix()
+        )
+    }
+
+    /// The name for the container we're using to actually run Synapse.
+    pub fn run_container_name(&self) -> String {
+        format!(
+            "mx-tester-synapse-run-{}{}{}",
             self.name,
-            if self.workers.enabled { "-workers" } else { "" }
+            if self.workers.enabled { "-workers" } else { "" },
+            self.federated_suffix()
         )
     }
 
-    /// The name for the container we're using to actually run Synapse.
-    pub fn run_container_name(&self) -> String {
-        format!(
-            "mx-tester-synapse-run-{}{}",
-            self.name,
-            if self.workers.enabled { "-workers" } else { "" }
-        )
+    fn federated_suffix(&self) -> String {
+        match self.federated_name {
+            None => String::new(),
+            Some(ref federated_name) => format!("-federation-{}", federated_name),
+        }
+    }
+
+    /// The configuration of a homeserver declared in `homeservers`.
+    ///
+    /// It shares the image, network, modules and options of this configuration,
+    /// but has its own homeserver, users, containers and directories, and no
+    /// scripts, bots, application services, artifacts or debugger.
+    pub fn federated(&self, peer: &FederatedHomeserver) -> Result<Config, Error> {
+        let mut value = serde_yaml::to_value(self).context("Could not serialize config")?;
+        let mapping = value
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("Expected config to serialize as a mapping"))?;
+        mapping.insert("homeserver".into(), serde_yaml::to_value(&peer.homeserver)?);
+        mapping.insert("users".into(), serde_yaml::to_value(&peer.users)?);
+        for key in [
+            "up",
+            "run",
+            "down",
+            "bots",
+            "appservices",
+            "artifacts",
+            "debug",
+            "profile",
+            "bench",
+            "abuse",
+            "fuzz_config",
+            "telemetry",
+            "jaeger",
+            "mas",
+            "recording",
+            "services",
+            "postgres",
+        ] {
+            mapping.remove(key);
+        }
+        // Ports in `docker.port_mapping` are published by the main homeserver.
+        if let Some(docker) = mapping
+            .get_mut("docker")
+            .and_then(serde_yaml::Value::as_mapping_mut)
+        {
+            docker.remove("port_mapping");
+            docker.remove("aliases");
+        }
+        let mut config: Config = serde_yaml::from_value(value)
+            .with_context(|| format!("Invalid configuration for homeserver {}", peer.name))?;
+        config.federated_name = Some(peer.name.clone());
+        config.fixtures_seed = self.fixtures_seed;
+        Ok(config)
+    }
+
+    /// Replace `credentials` with the profile `name` of `registry_profiles`.
+    pub fn select_registry_profile(&mut self, name: &str) -> Result<(), Error> {
+        let profile = self.registry_profiles.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.registry_profiles.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            anyhow!(
+                "Unknown registry profile `{}`, expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+        self.credentials = profile.clone();
+        Ok(())
+    }
+
+    /// All the non-empty credentials, `credentials` first, then the profiles
+    /// of `registry_profiles`, by name.
+    pub fn all_credentials(&self) -> Vec<&Credentials> {
+        let mut profiles: Vec<(&String, &Credentials)> = self.registry_profiles.iter().collect();
+        profiles.sort_by_key(|(name, _)| *name);
+        std::iter::once(&self.credentials)
+            .chain(profiles.into_iter().map(|(_, credentials)| credentials))
+            .filter(|credentials| !credentials.is_empty())
+            .collect()
+    }
+
+    /// The credentials to pull an image, i.e. the first of `all_credentials`
+    /// for the registry of the image, if any.
+    pub fn credentials_for_image(&self, image: &str) -> Option<&Credentials> {
+        let registry = image_registry(image);
+        self.all_credentials()
+            .into_iter()
+            .find(|credentials| credentials.registry() == registry)
+    }
+
+    /// The directory in which registration files are written.
+    ///
+    /// Made available to scripts as `MX_TEST_APPSERVICES_DIR`.
+    pub fn appservices_dir(&self) -> PathBuf {
+        self.synapse_data_dir().join("appservices")
+    }
+
+    /// The path of the registration file of an application service.
+    pub fn appservice_registration_path(&self, appservice: &AppService) -> PathBuf {
+        self.appservices_dir()
+            .join(format!("{}.yaml", appservice.id))
+    }
+
+    /// The path of the registration file used for double-puppeting by a bridge,
+    /// if it uses the bridge preset with `double_puppet`.
+    pub fn double_puppet_registration_path(&self, appservice: &AppService) -> Option<PathBuf> {
+        appservice
+            .double_puppet_id()
+            .map(|id| self.appservices_dir().join(format!("{}.yaml", id)))
+    }
+
+    /// The paths of all the registration files, as seen by the homeserver.
+    pub(crate) fn appservice_guest_paths(&self) -> Vec<String> {
+        self.appservices
+            .iter()
+            .flat_map(|appservice| {
+                std::iter::once(appservice.id.clone()).chain(appservice.double_puppet_id())
+            })
+            .map(|id| format!("{}/{}.yaml", appservice::GUEST_APPSERVICES_DIR, id))
+            .collect()
+    }
+
+    /// The URL at which the homeserver reaches an application service.
+    pub fn appservice_url(&self, appservice: &AppService) -> Option<String> {
+        if let Some(ref url) = appservice.url {
+            return Some(url.clone());
+        }
+        let bot = self
+            .bots
+            .iter()
+            .find(|bot| Some(&bot.name) == appservice.bot.as_ref())?;
+        Some(format!(
+            "http://{}:{}",
+            self.bot_container_name(bot),
+            appservice.port
+        ))
+    }
+
+    /// The name for the container running a bot.
+    pub fn bot_container_name(&self, bot: &Bot) -> String {
+        format!("mx-tester-bot-{}-{}", self.name, bot.name)
+    }
+
+    /// The image to run for a bot, either the image specified in mx-tester.yml
+    /// or the image built during `build`.
+    pub fn bot_image(&self, bot: &Bot) -> String {
+        match bot.image {
+            Some(ref image) => image.clone(),
+            None => format!("mx-tester-bot-{}-{}", self.name, bot.name),
+        }
+    }
+
+    /// The temporary directory made available to scripts as `MX_TEST_SCRIPT_TMPDIR`.
+    pub fn script_tmpdir(&self) -> PathBuf {
+        self.synapse_root().join("scripts")
+    }
+
+    /// The labels attached to the Docker containers, networks and images of this test,
+    /// so that leftovers may be detected.
+    pub fn docker_labels(&self) -> HashMap<String, String> {
+        std::iter::IntoIterator::into_iter([
+            (LABEL_TEST_NAME.to_string(), self.name.clone()),
+            (LABEL_RUN_ID.to_string(), run_id().to_string()),
+            (
+                LABEL_ROOT.to_string(),
+                self.directories.root.to_string_lossy().into_owned(),
+            ),
+        ])
+        .collect()
     }
 }
 
 /// Configurable directories for this test.
-#[derive(Debug, TypedBuilder, Deserialize)]
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
 pub struct Directories {
     /// The root of the test.
     ///
     /// All temporary files and logs are created as subdirectories of this directory.
     ///
-    /// If unspecified, `mx-tester` in the platform's temporary directory.
+    /// If unspecified, `mx-tester` in the platform's temporary directory. The
+    /// `mx-tester` binary uses `Directories::for_config_file` instead.
     #[builder(default=std::env::temp_dir().join("mx-tester"))]
     pub root: PathBuf,
 }
@@ -677,8 +2123,223 @@ impl Default for Directories {
         Directories::builder().build()
     }
 }
+impl Directories {
+    /// The default root of the test described by a configuration file, i.e.
+    /// `mx-tester/<project>-<hash>` in the cache directory of the user, where
+    /// `<project>` is the name of the directory containing the file and
+    /// `<hash>` identifies its path. Unlike a shared temporary directory, this
+    /// doesn't collide between projects and survives reboots.
+    ///
+    /// The cache directory is `$XDG_CACHE_HOME`, by default `~/.cache`, or the
+    /// platform's temporary directory if there is no home directory.
+    #[cfg(feature = "docker")]
+    pub fn for_config_file(path: &Path) -> PathBuf {
+        use data_encoding::HEXLOWER;
+        use sha1::{Digest, Sha1};
+
+        let path = path.canonicalize().unwrap_or_else(|_| {
+            std::env::current_dir()
+                .map(|dir| dir.join(path))
+                .unwrap_or_else(|_| path.to_path_buf())
+        });
+        let project = path
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "mx-tester".to_string());
+        let hash = HEXLOWER.encode(&Sha1::digest(path.to_string_lossy().as_bytes()));
+        let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        cache_dir
+            .join("mx-tester")
+            .join(format!("{}-{}", project, &hash[..12]))
+    }
+}
+
+/// Turn problems found in the configuration into an error listing them all.
+pub(crate) fn report_problems(problems: Vec<String>) -> Result<(), Error> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Invalid configuration:\n{}",
+        problems
+            .iter()
+            .map(|problem| format!("- {}", problem))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+/// The format of a configuration file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format of a configuration file, from its extension, i.e. `.toml`,
+    /// `.json` or, for anything else, YAML.
+    pub fn of(path: &Path) -> Self {
+        match path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    /// Parse a configuration file in this format.
+    ///
+    /// Whatever the format, the result is YAML, as the rest of mx-tester,
+    /// e.g. patching homeserver.yaml, works with YAML.
+    pub fn parse(self, content: &str) -> Result<serde_yaml::Value, Error> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+        })
+    }
+}
+
+/// Read a configuration file, in the format given by its extension, as YAML.
+///
+/// If the file specifies `extends: path/to/base.yml`, relative to the file,
+/// the base configuration is read, recursively, and the file is merged into
+/// it, see `merge_config`. The paths of the base, see `CONFIG_PATHS`, are
+/// relative to the base.
+pub fn read_config_file(path: &Path) -> Result<serde_yaml::Value, Error> {
+    read_config_file_with_bases(path, &mut vec![])
+}
+
+/// Read a configuration file, `extended` being the files that extend it, to detect cycles.
+fn read_config_file_with_bases(
+    path: &Path,
+    extended: &mut Vec<PathBuf>,
+) -> Result<serde_yaml::Value, Error> {
+    let is_base = !extended.is_empty();
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Could not open config file {:?}", path))?;
+    if extended.contains(&canonical) {
+        return Err(anyhow!(
+            "Config file {:?} extends itself, through {:?}",
+            path,
+            extended
+        ));
+    }
+    extended.push(canonical);
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not open config file {:?}", path))?;
+    let mut value = ConfigFormat::of(path)
+        .parse(&content)
+        .with_context(|| format!("Invalid config file {:?}", path))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if is_base {
+        rebase_config_paths(&mut value, dir);
+    }
+    let base = match value.as_mapping_mut().and_then(|m| m.remove("extends")) {
+        None | Some(serde_yaml::Value::Null) => return Ok(value),
+        Some(serde_yaml::Value::String(base)) => base,
+        Some(other) => {
+            return Err(anyhow!(
+                "In config file {:?}, `extends` should be a path, got {:?}",
+                path,
+                other
+            ))
+        }
+    };
+    let base_path = dir.join(base);
+    let mut merged = read_config_file_with_bases(&base_path, extended)
+        .with_context(|| format!("Could not read the base of config file {:?}", path))?;
+    merge_config(&mut merged, value);
+    Ok(merged)
+}
+
+/// The fields of a configuration that are paths on the host, as keys, `*`
+/// standing for each item of a sequence.
+///
+/// Relative paths are relative to the current directory, except in a base
+/// configuration, where they are relative to the base.
+const CONFIG_PATHS: [&[&str]; 8] = [
+    &["modules", "*", "path"],
+    &["modules", "*", "source", "wheel", "path"],
+    &["bots", "*", "build"],
+    &["services", "*", "volumes", "*", "host"],
+    &["workers", "templates_dir"],
+    &["bench", "baseline"],
+    &["directories", "root"],
+    &["synapse", "local", "path"],
+];
+
+/// Prefix the relative paths of configuration `value` with `dir`.
+fn rebase_config_paths(value: &mut serde_yaml::Value, dir: &Path) {
+    use serde_yaml::Value;
+    fn rebase(value: &mut Value, keys: &[&str], dir: &Path) {
+        match keys.split_first() {
+            None => {
+                if let Value::String(path) = value {
+                    if Path::new(path.as_str()).is_relative() {
+                        *path = dir.join(path.as_str()).to_string_lossy().into_owned();
+                    }
+                }
+            }
+            Some((&"*", keys)) => {
+                if let Value::Sequence(items) = value {
+                    for item in items {
+                        rebase(item, keys, dir);
+                    }
+                }
+            }
+            Some((key, keys)) => {
+                if let Some(child) = value.get_mut(*key) {
+                    rebase(child, keys, dir);
+                }
+            }
+        }
+    }
+    for keys in CONFIG_PATHS {
+        rebase(value, keys, dir);
+    }
+}
+
+/// Merge configuration `overrides` into configuration `base`.
+///
+/// Mappings are merged key by key, recursively, so e.g. overriding
+/// `homeserver.server_name` keeps the other fields of `homeserver`. Anything
+/// else, including sequences such as `modules` or `users`, is replaced. A key
+/// set to `null` is removed from `base`, i.e. it is reset to its default.
+pub fn merge_config(base: &mut serde_yaml::Value, overrides: serde_yaml::Value) {
+    use serde_yaml::Value;
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                if value.is_null() {
+                    base.remove(&key);
+                    continue;
+                }
+                match base.get_mut(&key) {
+                    Some(existing) => merge_config(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
 
 /// The result of the test, as seen by `down()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Status {
     /// The test was a success.
     Success,
@@ -690,15 +2351,69 @@ pub enum Status {
     Manual,
 }
 
+/// The Docker label identifying the test to which a container, network or image belongs.
+pub const LABEL_TEST_NAME: &str = "org.matrix.mx-tester.name";
+
+/// The Docker label identifying the run of mx-tester that created a container,
+/// network or image, see `run_id()`.
+pub const LABEL_RUN_ID: &str = "org.matrix.mx-tester.run-id";
+
+/// The Docker label identifying the directory `directories.root` of the test to
+/// which a container, network or image belongs, i.e. where its lock is.
+pub const LABEL_ROOT: &str = "org.matrix.mx-tester.root";
+
+/// A unique id for this run of mx-tester, i.e. a UUID generated at startup.
+///
+/// It is attached to Docker resources, written at the top of log files and
+/// recorded in the manifest, so that the logs and resources of several runs,
+/// e.g. in CI, may be told apart. If environment variable `MX_TEST_RUN_ID`
+/// is set, e.g. because a script calls `mx-tester admin`, we use its value
+/// instead, so that nested invocations share the id of the outer run.
+pub fn run_id() -> &'static str {
+    &RUN_ID
+}
+
+/// The first line written to a log file, identifying the run of mx-tester
+/// and what is being logged.
+pub fn log_header(subject: &str) -> String {
+    format!(
+        "--- mx-tester {} run {}: {} ---\n",
+        env!("CARGO_PKG_VERSION"),
+        run_id(),
+        subject
+    )
+}
+
 /// The version of Synapse to use by default.
 const DEFAULT_SYNAPSE_VERSION: &str = "matrixdotorg/synapse:latest";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub enum SynapseVersion {
     #[serde(rename = "docker")]
-    Docker { tag: String },
-    // FIXME: Allow using a version of Synapse that lives in a local directory
-    // (this will be sufficient to also implement pulling from github develop)
+    Docker {
+        /// The Docker image, e.g. `matrixdotorg/synapse:latest` or, to always
+        /// test against the same image, `matrixdotorg/synapse@sha256:...`.
+        tag: String,
+    },
+
+    /// A local checkout of Synapse, e.g. to test modules against changes in
+    /// progress. It is installed in editable mode during `build`, on top of `base`.
+    #[serde(rename = "local")]
+    Local {
+        /// The root of the checkout, i.e. the directory containing `pyproject.toml`.
+        path: PathBuf,
+
+        /// The Docker image providing the dependencies of Synapse.
+        ///
+        /// Defaults to `matrixdotorg/synapse:latest`.
+        #[serde(default = "SynapseVersion::default_base")]
+        base: String,
+    },
+}
+impl SynapseVersion {
+    fn default_base() -> String {
+        DEFAULT_SYNAPSE_VERSION.to_string()
+    }
 }
 impl Default for SynapseVersion {
     fn default() -> Self {
@@ -708,7 +2423,7 @@ impl Default for SynapseVersion {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(transparent)]
 pub struct Script {
     /// The lines of the script.
@@ -753,7 +2468,8 @@ impl Script {
 }
 
 /// A script for `build`.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(not(feature = "docker"), allow(dead_code))]
 pub struct ModuleConfig {
     /// The name of the module.
     ///
@@ -764,8 +2480,15 @@ pub struct ModuleConfig {
     /// specified by environment variable `MX_TEST_MODULE_DIR`.
     ///
     /// This script will be executed in the **host**.
+    ///
+    /// Required, unless `source` is `wheel`, in which case it is ignored.
+    #[serde(default)]
     build: Script,
 
+    /// Where the module comes from.
+    #[serde(default)]
+    source: ModuleSource,
+
     /// A script to install dependencies.
     ///
     /// This script will be executed in the **guest**.
@@ -773,15 +2496,26 @@ pub struct ModuleConfig {
     install: Option<Script>,
 
     /// Additional environment information to use in the **guest**.
-    #[serde(default)]
+    #[serde(default, serialize_with = "util::serialize_sorted")]
     env: HashMap<String, String>,
 
     /// Additional resources to copy from the **host** into the **guest**.
     /// Key: Guest path, relative to the module's directory.
     /// Value: Guest path, relative to the project directory.
-    #[serde(default)]
+    #[serde(default, serialize_with = "util::serialize_sorted")]
     copy: HashMap<String, String>,
 
+    /// How to install the module in the **guest**.
+    #[serde(default)]
+    install_mode: InstallMode,
+
+    /// The directory containing the source code of the module on the **host**,
+    /// relative to the project directory.
+    ///
+    /// Required if `install_mode` is `editable`.
+    #[serde(default)]
+    path: Option<PathBuf>,
+
     /// A Yaml config to copy into homeserver.yaml.
     /// See https://matrix-org.github.io/synapse/latest/modules/index.html
     ///
@@ -792,10 +2526,118 @@ pub struct ModuleConfig {
     ///   key: value
     /// ```
     config: serde_yaml::Value,
+
+    /// If specified, the log level of the module in Synapse, e.g. `DEBUG`,
+    /// in the main process and, in workers mode, in every worker.
+    ///
+    /// This applies to the loggers of the top-level package of the module,
+    /// e.g. `my_module` for `module: my_module.Module`.
+    #[serde(default)]
+    log_level: Option<String>,
+}
+
+impl ModuleConfig {
+    /// The logger of the module and its level, if `log_level` is specified.
+    fn logger(&self) -> Result<Option<(String, String)>, Error> {
+        let level = match self.log_level {
+            None => return Ok(None),
+            Some(ref level) => level.to_ascii_uppercase(),
+        };
+        if !LOG_LEVELS.contains(&level.as_str()) {
+            return Err(anyhow!(
+                "Module {}: invalid `log_level` {}, expected any of {}",
+                self.name,
+                level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        let package = self
+            .config
+            .get("module")
+            .and_then(|module| module.as_str())
+            .and_then(|module| module.split('.').next())
+            .filter(|package| !package.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Module {}: `log_level` requires `config.module`, to determine the logger of the module",
+                    self.name
+                )
+            })?;
+        Ok(Some((package.to_string(), level)))
+    }
+
+    /// What to `pip install` in the **guest**.
+    #[cfg(feature = "docker")]
+    fn guest_install_path(&self) -> String {
+        match self.source {
+            ModuleSource::Build => format!("/mx-tester/{}", self.name),
+            ModuleSource::Wheel { ref path, .. } => format!(
+                "/mx-tester/{}/{}",
+                self.name,
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+        }
+    }
+
+    /// The absolute path to the source code of the module on the host.
+    #[cfg(feature = "docker")]
+    fn host_path(&self) -> Result<PathBuf, Error> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Module {} is installed in editable mode, it needs a `path`",
+                self.name
+            )
+        })?;
+        path.canonicalize().with_context(|| {
+            format!(
+                "Could not find source code of module {} at {:?}",
+                self.name, path
+            )
+        })
+    }
+}
+
+/// Where a module comes from.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub enum ModuleSource {
+    /// Built by the `build` script of the module.
+    #[serde(rename = "build")]
+    #[default]
+    Build,
+
+    /// A wheel built beforehand, e.g. by another CI job, copied into the image
+    /// and installed with `pip install`, without executing `build`.
+    #[serde(rename = "wheel")]
+    Wheel {
+        /// The wheel on the **host**, relative to the project directory,
+        /// e.g. `dist/my_module-1.0-py3-none-any.whl`.
+        path: PathBuf,
+
+        /// If specified, the expected SHA-256 of the wheel, in hexadecimal.
+        /// The build fails if the wheel doesn't match, e.g. because it was
+        /// tampered with or it is stale.
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+}
+
+/// How to install a module in the guest.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum InstallMode {
+    /// Copy the module into the image and install it with `pip install`.
+    #[serde(rename = "regular")]
+    #[default]
+    Regular,
+
+    /// Install the module with `pip install -e` and mount the module's `path`
+    /// from the host into the container, so that changes to the source code
+    /// are taken into account by simply restarting Synapse.
+    #[serde(rename = "editable")]
+    Editable,
 }
 
 /// A script for `up`.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum UpScript {
     /// If `up` and/or `down` are specified, take them into account.
@@ -811,7 +2653,8 @@ impl Default for UpScript {
 }
 
 /// A script for `up`.
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[cfg_attr(not(feature = "docker"), allow(dead_code))]
 pub struct FullUpScript {
     /// Code to run before bringing up the image.
     before: Option<Script>,
@@ -821,7 +2664,8 @@ pub struct FullUpScript {
 }
 
 /// A script for `down`.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(not(feature = "docker"), allow(dead_code))]
 pub struct DownScript {
     /// Code to run in case the test is a success.
     success: Option<Script>,
@@ -834,1058 +2678,3 @@ pub struct DownScript {
     /// Executed after `success` or `failure`.
     finally: Option<Script>,
 }
-
-/// Start a Synapse container.
-///
-/// - `cmd`: a shell command to execute;
-/// - `detach`: if `true`, the Synapse container will continue executing past the end of this function and process;
-async fn start_synapse_container(
-    docker: &Docker,
-    config: &Config,
-    container_name: &str,
-    cmd: Vec<String>,
-    detach: bool,
-) -> Result<(), Error> {
-    let data_dir = config.synapse_data_dir();
-    let data_dir = data_dir.as_path();
-
-    let mut env = vec![
-        format!("SYNAPSE_SERVER_NAME={}", config.homeserver.server_name),
-        "SYNAPSE_REPORT_STATS=no".into(),
-        "SYNAPSE_CONFIG_DIR=/data".into(),
-        format!(
-            "SYNAPSE_HTTP_PORT={}",
-            if config.workers.enabled {
-                HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT
-            } else {
-                HARDCODED_GUEST_PORT
-            }
-        ),
-    ];
-    if config.workers.enabled {
-        // The list of workers to launch, as copied from Complement.
-        // It has two instances of `event_persister` by design, in order
-        // to launch two event persisters.
-        env.push("SYNAPSE_WORKER_TYPES=event_persister, event_persister, background_worker, frontend_proxy, event_creator, user_dir, media_repository, federation_inbound, federation_reader, federation_sender, synchrotron, appservice, pusher".to_string());
-        env.push("SYNAPSE_WORKERS_WRITE_LOGS_TO_DISK=1".to_string());
-    }
-    let env = env;
-    debug!("We need to create container for {}", container_name);
-
-    // Generate configuration to open and map ports.
-    let mut host_port_bindings = HashMap::new();
-    let mut exposed_ports = HashMap::new();
-    for mapping in config.docker.port_mapping.iter().chain(
-        [PortMapping {
-            host: config.homeserver.host_port,
-            guest: HARDCODED_GUEST_PORT,
-        }]
-        .iter(),
-    ) {
-        let key = format!("{}/tcp", mapping.guest);
-        host_port_bindings.insert(
-            key.clone(),
-            Some(vec![PortBinding {
-                host_port: Some(format!("{}", mapping.host)),
-                ..PortBinding::default()
-            }]),
-        );
-        exposed_ports.insert(key.clone(), HashMap::new());
-    }
-    debug!("port_bindings: {:#?}", host_port_bindings);
-
-    debug!("Creating container {}", container_name);
-    let response = docker
-        .create_container(
-            Some(CreateContainerOptions {
-                name: container_name,
-            }),
-            BollardContainerConfig {
-                env: Some(env.clone()),
-                exposed_ports: Some(exposed_ports),
-                hostname: Some(config.docker.hostname.clone()),
-                host_config: Some(HostConfig {
-                    log_config: Some(HostConfigLogConfig {
-                        typ: Some("json-file".to_string()),
-                        config: None,
-                    }),
-                    // Synapse has a tendency to not start correctly
-                    // or to stop shortly after startup. The following
-                    // restart policy seems to help a lot.
-                    restart_policy: Some(RestartPolicy {
-                        name: Some(RestartPolicyNameEnum::ON_FAILURE),
-                        maximum_retry_count: Some(MAX_SYNAPSE_RESTART_COUNT),
-                    }),
-                    // Extremely large memory allowance.
-                    memory_reservation: Some(MEMORY_ALLOCATION_BYTES),
-                    memory_swap: Some(-1),
-                    // Mount guest directories as host directories.
-                    binds: Some(vec![
-                        // Synapse logs, etc.
-                        format!("{}:/data:rw", data_dir.as_os_str().to_string_lossy()),
-                        // Everything below this point is for workers.
-                        format!(
-                            "{}:/conf/workers:rw",
-                            config.synapse_workers_dir().to_string_lossy()
-                        ),
-                        format!(
-                            "{}:/etc/nginx/conf.d:rw",
-                            config.etc_dir().join("nginx").to_string_lossy()
-                        ),
-                        format!(
-                            "{}:/etc/supervisor/conf.d:rw",
-                            config.etc_dir().join("supervisor").to_string_lossy()
-                        ),
-                        format!(
-                            "{}:/var/log/nginx:rw",
-                            config.logs_dir().join("nginx").to_string_lossy()
-                        ),
-                        format!(
-                            "{}:/var/log/workers:rw",
-                            config.logs_dir().join("workers").to_string_lossy()
-                        ),
-                    ]),
-                    // Expose guest port `guest_mapping` as `host_mapping`.
-                    port_bindings: Some(host_port_bindings),
-                    // Enable access to host as `host.docker.internal` from the guest.
-                    // On macOS and Windows, this is expected to be transparent but
-                    // on Linux, an option needs to be added.
-                    #[cfg(target_os = "linux")]
-                    extra_hosts: Some(vec!["host.docker.internal:host-gateway".to_string()]),
-                    ..HostConfig::default()
-                }),
-                image: Some(config.tag()),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                attach_stdin: Some(false),
-                cmd: Some(cmd.clone()),
-                // Specify that a few directories may be mounted.
-                // The empty hashmap... is an oddity of the Docker Engine API.
-                volumes: Some(
-                    vec![
-                        ("/data".to_string(), HashMap::new()),
-                        ("/conf/workers".to_string(), HashMap::new()),
-                        ("/etc/nginx/conf.d".to_string(), HashMap::new()),
-                        ("/etc/supervisor/conf.d".to_string(), HashMap::new()),
-                        ("/var/log/workers".to_string(), HashMap::new()),
-                    ]
-                    .into_iter()
-                    .collect(),
-                ),
-                tty: Some(false),
-                #[cfg(unix)]
-                user: Some(format!("{}", nix::unistd::getuid())),
-                ..BollardContainerConfig::default()
-            },
-        )
-        .await
-        .context("Failed to build container")?;
-
-    // For debugging purposes, try and find out when/why the container stops.
-    let mut wait = docker.wait_container(
-        container_name,
-        Some(WaitContainerOptions {
-            condition: "not-running",
-        }),
-    );
-    {
-        let container_name = container_name.to_string();
-        tokio::task::spawn(async move {
-            debug!(target: "mx-tester-wait", "{} Container started", container_name);
-            while let Some(next) = wait.next().await {
-                let response = next.context("Error while waiting for container to stop")?;
-                debug!(target: "mx-tester-wait", "{} {:#?}", container_name, response);
-            }
-            debug!(target: "mx-tester-wait", "{} Container is now down", container_name);
-            Ok::<(), Error>(())
-        });
-    }
-
-    for warning in response.warnings {
-        warn!(target: "creating-container", "{}", warning);
-    }
-
-    // ... add the container to the network.
-    docker
-        .connect_network(
-            config.network().as_ref(),
-            ConnectNetworkOptions {
-                container: container_name,
-                endpoint_config: EndpointSettings::default(),
-            },
-        )
-        .await
-        .context("Failed to connect container")?;
-
-    let is_container_running = docker.is_container_running(container_name).await?;
-    if !is_container_running {
-        docker
-            .start_container(container_name, None::<StartContainerOptions<String>>)
-            .await
-            .context("Failed to start container")?;
-        let mut logs = docker.logs(
-            container_name,
-            Some(LogsOptions {
-                follow: true,
-                stdout: true,
-                stderr: true,
-                tail: "10",
-                ..LogsOptions::default()
-            }),
-        );
-
-        // Write logs to the synapse data directory.
-        let log_file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(config.logs_dir().join("docker").join(format!(
-                "{}.log",
-                if detach { "up-run-down" } else { "build" }
-            )))
-            .await?;
-        let mut buffer = BufWriter::new(log_file);
-        tokio::task::spawn(async move {
-            debug!(target: "mx-tester-log", "Starting log watcher");
-            while let Some(next) = logs.next().await {
-                match next {
-                    Ok(content) => {
-                        debug!(target: "mx-tester-log", "{}", content);
-                        buffer.write_all(format!("{}", content).as_bytes()).await?;
-                        buffer.flush().await?;
-                    }
-                    Err(err) => {
-                        error!(target: "mx-tester-log", "{}", err);
-                        buffer
-                            .write_all(format!("ERROR: {}", err).as_bytes())
-                            .await?;
-                        buffer.flush().await?;
-                        return Err(err).context("Error in log");
-                    }
-                }
-            }
-            debug!(target: "mx-tester-log", "Stopped log watcher");
-            Ok(())
-        });
-    }
-
-    let cleanup = if config.autoclean_on_error {
-        Some(Cleanup::new(config))
-    } else {
-        None
-    };
-    let exec = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions::<Cow<'_, str>> {
-                cmd: Some(cmd.into_iter().map(|s| s.into()).collect()),
-                env: Some(env.into_iter().map(|s| s.into()).collect()),
-                #[cfg(unix)]
-                user: Some(format!("{}", nix::unistd::getuid()).into()),
-                ..CreateExecOptions::default()
-            },
-        )
-        .await
-        .context("Error while preparing to Synapse container")?;
-    let execution = docker
-        .start_exec(
-            &exec.id,
-            Some(StartExecOptions {
-                detach,
-                ..StartExecOptions::default()
-            }),
-        )
-        .await
-        .context("Error starting Synapse container")?;
-
-    if !detach {
-        let log_file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(config.logs_dir().join("docker").join(format!(
-                "{}.out",
-                if detach { "up-run-down" } else { "build" }
-            )))
-            .await?;
-        let mut buffer = BufWriter::new(log_file);
-        tokio::task::spawn(async move {
-            debug!(target: "synapse", "Launching Synapse container");
-            match execution {
-                bollard::exec::StartExecResults::Attached {
-                    mut output,
-                    input: _,
-                } => {
-                    while let Some(data) = output.next().await {
-                        let output = data.context("Error during run")?;
-                        debug!(target: "synapse", "{}", output);
-                        buffer.write_all(format!("{}", output).as_bytes()).await?;
-                        buffer.flush().await?;
-                    }
-                }
-                bollard::exec::StartExecResults::Detached => panic!(),
-            }
-            debug!(target: "synapse", "Synapse container finished");
-            Ok::<(), Error>(())
-        })
-        .await??;
-    }
-    cleanup.disarm();
-    Ok(())
-}
-
-/// Rebuild the Synapse image with modules.
-pub async fn build(docker: &Docker, config: &Config) -> Result<(), Error> {
-    // This will break (on purpose) once we extend `SynapseVersion`.
-    let SynapseVersion::Docker {
-        tag: ref docker_tag,
-    } = config.synapse;
-    let setup_container_name = config.setup_container_name();
-    let run_container_name = config.run_container_name();
-
-    println!("\n* build step: starting");
-
-    // Remove any trace of a previous build. Ignore failures.
-    let _ = docker.stop_container(&run_container_name, None).await;
-    let _ = docker.remove_container(&run_container_name, None).await;
-    let _ = docker.stop_container(&setup_container_name, None).await;
-    let _ = docker.remove_container(&setup_container_name, None).await;
-    let _ = docker.remove_image(config.tag().as_ref(), None, None).await;
-
-    let synapse_root = config.synapse_root();
-    let _ = std::fs::remove_dir_all(config.test_root());
-    let modules_log_dir = config.scripts_logs_dir().join("modules");
-    for dir in &[
-        &config.synapse_data_dir(),
-        &config.synapse_workers_dir(),
-        &config.etc_dir().join("nginx"),
-        &config.etc_dir().join("supervisor"),
-        &config.logs_dir().join("docker"),
-        &config.logs_dir().join("nginx"),
-        &config.logs_dir().join("workers"),
-        &modules_log_dir,
-    ] {
-        std::fs::create_dir_all(dir)
-            .with_context(|| format!("Could not create directory {:#?}", dir,))?;
-    }
-
-    // Build modules
-    println!("** building modules");
-    let mut env = config.shared_env_variables()?;
-
-    for module in &config.modules {
-        let path = synapse_root.join(&module.name);
-        env.insert(&*MX_TEST_MODULE_DIR, path.as_os_str().into());
-        debug!(
-            "Calling build script for module {} with MX_TEST_DIR={:#?}",
-            &module.name, path
-        );
-        let log_dir = modules_log_dir.join(&module.name);
-        std::fs::create_dir_all(&log_dir)
-            .with_context(|| format!("Could not create directory {:#?}", log_dir,))?;
-        module
-            .build
-            .run("build", &log_dir, &env)
-            .await
-            .context("Error running build script")?;
-        debug!("Completed one module.");
-    }
-    println!("** building modules success");
-
-    // Prepare resource files.
-    if config.workers.enabled {
-        let conf_dir = synapse_root.join("conf");
-        std::fs::create_dir_all(&conf_dir)
-            .context("Could not create directory for worker configuration file")?;
-        let data = [
-            // These files are used by `workers_start.py` to generate worker configuration.
-            // They have been copied manually from Synapse's git repo.
-            // Hopefully, in the future, Synapse+worker images will be available on DockerHub.
-            (
-                conf_dir.join("worker.yaml.j2"),
-                include_str!("../res/workers/worker.yaml.j2"),
-            ),
-            (
-                conf_dir.join("shared.yaml.j2"),
-                include_str!("../res/workers/shared.yaml.j2"),
-            ),
-            (
-                conf_dir.join("supervisord.conf.j2"),
-                include_str!("../res/workers/supervisord.conf.j2"),
-            ),
-            (
-                conf_dir.join("nginx.conf.j2"),
-                include_str!("../res/workers/nginx.conf.j2"),
-            ),
-            (
-                conf_dir.join("log.config"),
-                include_str!("../res/workers/log.config"),
-            ),
-            // workers_start.py is adapted from Synapse's git repo.
-            (
-                synapse_root.join("workers_start.py"),
-                include_str!("../res/workers/workers_start.py"),
-            ),
-            // setup postgres user and database
-            (
-                conf_dir.join("postgres.sql"),
-                include_str!("../res/workers/postgres.sql"),
-            ),
-        ];
-        for (path, content) in &data {
-            std::fs::write(path, content).with_context(|| {
-                format!("Could not inject worker configuration file {:?}", path)
-            })?;
-        }
-    }
-
-    // Prepare Dockerfile including modules.
-    let dockerfile_content = format!("
-# A custom Dockerfile to rebuild synapse from the official release + plugins
-
-FROM {docker_tag}
-
-VOLUME [\"/data\", \"/conf/workers\", \"/etc/nginx/conf.d\", \"/etc/supervisor/conf.d\", \"/var/log/workers\"]
-
-# We're not running as root, to avoid messing up with the host
-# filesystem, so we need a proper user. We give it the current
-# use's uid to make sure that files written by this Docker image
-# can be read and removed by the host's user.
-# Note that we need tty to workaround the following Docker issue:
-# https://github.com/moby/moby/issues/31243#issuecomment-406825071
-RUN useradd mx-tester {maybe_uid} --groups sudo,tty
-
-# Add a password, to be able to run sudo. We'll use it to
-# chmod files.
-RUN echo \"mx-tester:password\" | chpasswd
-
-# Show the Synapse version, to aid with debugging.
-RUN pip show matrix-synapse
-
-{maybe_setup_workers}
-
-# Copy and install custom modules.
-RUN mkdir /mx-tester
-{setup}
-{env}
-{copy_modules}
-{copy_resources}
-{install}
-
-ENTRYPOINT []
-
-EXPOSE {synapse_http_port}/tcp 8009/tcp 8448/tcp
-",
-    docker_tag = docker_tag,
-    // Module setup steps, as per `config.modules[_].install`.
-    setup = config.modules.iter()
-        .filter_map(|module| module.install.as_ref().map(|script| format!("## Setup {}\n{}\n", module.name, script.lines.iter().map(|line| format!("RUN {}", line)).format("\n"))))
-        .format("\n"),
-    // Module env changes, as per `config.modules[_].env`.
-    env = config.modules.iter()
-        .map(|module| module.env.iter()
-            .map(|(key, value)| format!("ENV {}={}\n", key, value))
-            .format("")
-        ).format(""),
-    copy_modules = config.modules.iter()
-        // FIXME: We probably want to test what happens with weird characters. Perhaps we'll need to somehow escape module.
-        .map(|module| format!("COPY {module} /mx-tester/{module}", module=module.name))
-        .format("\n"),
-    // Modules additional resources, as per `config.modules[_].copy`.
-    copy_resources = config.modules.iter()
-        .map(|module| module.copy.iter()
-            .map(move |(dest, source)| format!("COPY {source} /mx-tester/{module}/{dest}\n",
-                dest = dest,
-                source = source,
-                module = module.name,
-            ))
-            .format("")
-        ).format(""),
-    // Modules copy and `pip` install.
-    install = config.modules.iter()
-        // FIXME: We probably want to test what happens with weird characters. Perhaps we'll need to somehow escape module.
-        .map(|module| format!("RUN /usr/local/bin/python -m pip install /mx-tester/{module}", module=module.name))
-        .format("\n"),
-    // Configure user id.
-    maybe_uid = {
-        let my_uid = nix::unistd::getuid();
-        if my_uid != nix::unistd::ROOT {
-           // We're running mx-tester as a regular user.
-            // Docker/Synapse should be executed as the same user, otherwise we won't be
-            // able to look at or remove all the files created by them.
-            Cow::from(format!("--uid {}", my_uid))
-        } else {
-            // We're running mx-tester as root.
-            // This can happen in CI, for instance.
-            // In this case, let `useradd` pick a uid for our user. We can't use
-            // the root uid as it's already taken by root in the guest environment
-            // and any uid will do since we're root in the host environment anyway,
-            // so we can look at and remove any file created by other users.
-            Cow::from("")
-        }
-    },
-    synapse_http_port = HARDCODED_GUEST_PORT,
-    maybe_setup_workers =
-    if config.workers.enabled {
-"
-# Install dependencies
-RUN apt-get update && apt-get install -y postgresql postgresql-client-13 supervisor redis nginx sudo lsof
-
-# For workers, we're not using start.py but workers_start.py
-# (which does call start.py, but that's a long story).
-COPY workers_start.py /workers_start.py
-COPY conf/* /conf/
-
-# We're not going to be running workers_start.py as root, so
-# let's make sure that it *can* run, write to /etc/nginx & co.
-RUN chmod ugo+rx /workers_start.py && chown mx-tester /workers_start.py
-"
-    } else {
-        ""
-    }
-    );
-    debug!("dockerfile {}", dockerfile_content);
-
-    let dockerfile_path = synapse_root.join("Dockerfile");
-    std::fs::write(&dockerfile_path, dockerfile_content)
-        .with_context(|| format!("Could not write file {:#?}", dockerfile_path,))?;
-
-    debug!("Building tar file");
-    let docker_dir_path = config.test_root().join("tar");
-    std::fs::create_dir_all(&docker_dir_path)
-        .with_context(|| format!("Could not create directory {:#?}", docker_dir_path,))?;
-    let body = {
-        // Build the tar file.
-        let tar_path = docker_dir_path.join("docker.tar");
-        {
-            let tar_file = std::fs::File::create(&tar_path)?;
-            let mut tar_builder = tar::Builder::new(std::io::BufWriter::new(tar_file));
-            debug!("tar: adding directory {:#?}", synapse_root);
-            tar_builder
-                .append_dir_all("", &synapse_root)
-                .with_context(|| format!("Error while creating tar for {:#?}", &synapse_root))?;
-            tar_builder
-                .finish()
-                .with_context(|| format!("Error finalizing tar for {:#?}", &synapse_root))?
-        }
-
-        let tar_file = tokio::fs::File::open(&tar_path).await?;
-        let stream = FramedRead::new(tar_file, BytesCodec::new());
-        hyper::Body::wrap_stream(stream)
-    };
-    let logs_path = config.logs_dir().join("docker").join("build.log");
-    println!(
-        "** building Docker image. Logs will be stored at {:?}",
-        logs_path
-    );
-    debug!("Building image with tag {}", config.tag());
-    {
-        let mut log =
-            std::fs::File::create(logs_path).context("Could not create docker build logs")?;
-        let mut stream = docker.build_image(
-            bollard::image::BuildImageOptions {
-                pull: true,
-                nocache: true,
-                t: config.tag(),
-                q: false,
-                rm: true,
-                ..Default::default()
-            },
-            config.credentials.serveraddress.as_ref().map(|server| {
-                let mut credentials = HashMap::new();
-                credentials.insert(server.clone(), config.credentials.clone());
-                credentials
-            }),
-            Some(body),
-        );
-        while let Some(result) = stream.next().await {
-            let info = result.context("Daemon `docker build` indicated an error")?;
-            if let Some(ref error) = info.error {
-                return Err(anyhow!("Error while building an image: {}", error,));
-            }
-            if let Some(ref progress) = info.progress {
-                debug!("Build image progress {:#?}", info);
-                log.write_all(progress.as_bytes())
-                    .context("Could not write docker build logs")?;
-            }
-        }
-    }
-    debug!("Image built");
-    println!("** building Docker image success");
-
-    println!("* build step: success");
-    Ok(())
-}
-
-/// Bring things up. Returns any environment variables to pass to the run script.
-pub async fn up(docker: &Docker, config: &Config) -> Result<(), Error> {
-    // This will break (on purpose) once we extend `SynapseVersion`.
-    let SynapseVersion::Docker { .. } = config.synapse;
-    let cleanup = if config.autoclean_on_error {
-        Some(Cleanup::new(config))
-    } else {
-        None
-    };
-
-    println!("\n* up step: starting");
-    // Create the network if necessary.
-    // We'll add the container once it's available.
-    let network_name = config.network();
-    debug!("We'll need network {}", network_name);
-    if !docker.is_network_up(&network_name).await? {
-        debug!("Creating network {}", network_name);
-        docker
-            .create_network(CreateNetworkOptions {
-                name: Cow::from(network_name.as_str()),
-                check_duplicate: true,
-                attachable: true,
-                ..CreateNetworkOptions::default()
-            })
-            .await?;
-        assert!(
-            docker.is_network_up(&network_name).await?,
-            "The network should now be up"
-        );
-        debug!("Network is now up");
-    } else {
-        // This can happen for instance if a script needs to
-        // spawn another image on the same network and creates
-        // that network manually.
-        debug!("Network {} already exists", network_name);
-    }
-
-    // Only execute the `up` script once the network is up,
-    // in case we want to e.g. bring up images that need
-    // that same network.
-    let script_log_dir = config.scripts_logs_dir();
-    match config.up {
-        Some(UpScript::FullUpScript(FullUpScript {
-            before: Some(ref script),
-            ..
-        }))
-        | Some(UpScript::SimpleScript(ref script)) => {
-            let env = config.shared_env_variables()?;
-            script
-                .run("up", &script_log_dir, &env)
-                .await
-                .context("Error running `up` script (before)")?;
-        }
-        _ => {}
-    }
-
-    let setup_container_name = config.setup_container_name();
-    let run_container_name = config.run_container_name();
-
-    // Create the synapse data directory.
-    // We'll use it as volume.
-    let synapse_data_directory = config.synapse_data_dir();
-    std::fs::create_dir_all(&synapse_data_directory)
-        .with_context(|| format!("Cannot create directory {:#?}", synapse_data_directory))?;
-
-    // Cleanup leftovers.
-    let homeserver_path = synapse_data_directory.join("homeserver.yaml");
-    let _ = std::fs::remove_file(&homeserver_path);
-
-    // Start a container to generate homeserver.yaml.
-    start_synapse_container(
-        docker,
-        config,
-        &setup_container_name,
-        if config.workers.enabled {
-            vec!["/workers_start.py".to_string(), "generate".to_string()]
-        } else {
-            vec!["/start.py".to_string(), "generate".to_string()]
-        },
-        false,
-    )
-    .await
-    .context("Couldn't generate homeserver.yaml")?;
-
-    // HACK: I haven't found a way to reuse the container with a different cmd
-    // (the API looks like it supports overriding cmds when creating an
-    // Exec but doesn't seem to actually implement this feature), so
-    // we stop and remove the container, we'll create a new one when
-    // we're ready to start Synapse.
-    debug!("done generating");
-    let _ = docker.stop_container(&setup_container_name, None).await;
-    let _ = docker.remove_container(&setup_container_name, None).await;
-    docker.wait_container_removed(&setup_container_name).await?;
-
-    debug!("Updating homeserver.yaml");
-    // Apply config from mx-tester.yml to the homeserver.yaml that was just created
-    config
-        .patch_homeserver_config()
-        .context("Error updating homeserver config")?;
-
-    // Docker has a tendency to return before containers are fully torn down.
-    // Let's make extra-sure by waiting until the container is not running
-    // anymore *and* the ports are free.
-    while docker.is_container_running(&setup_container_name).await? {
-        debug!(
-            "Waiting until docker container {} is down before relaunching it",
-            setup_container_name
-        );
-        tokio::time::sleep(std::time::Duration::new(5, 0)).await;
-    }
-
-    println!(
-        "** starting Synapse. Logs will be stored at {:?}",
-        config.logs_dir().join("docker").join("up-run-down.log")
-    );
-    start_synapse_container(
-        docker,
-        config,
-        &run_container_name,
-        if config.workers.enabled {
-            vec!["/workers_start.py".to_string(), "start".to_string()]
-        } else {
-            vec!["/start.py".to_string()]
-        },
-        true,
-    )
-    .await
-    .context("Failed to start Synapse")?;
-
-    debug!("Synapse should now be launched and ready");
-
-    // We should now be able to register users.
-    //
-    // As of this writing, we're not sure whether the `synapse_is_responsive` manipulation
-    // above works. If it doesn't, we can still have a case in which Synapse won't start,
-    // causing `handle_user_registration` to loop endlessly. The `timeout` should make
-    // sure that we fail properly and with an understandable error message.
-    let registration = async {
-        handle_user_registration(config)
-            .await
-            .context("Failed to setup users")
-    };
-
-    if config.workers.enabled {
-        // With workers, registration is so long that we don't want to timeou.
-        registration.await?;
-    } else {
-        match tokio::time::timeout(TIMEOUT_USER_REGISTRATION_SIMPLE, registration).await {
-            Err(_) => {
-                // Timeout.
-                panic!(
-                    "User registration is taking too long. {is_running}",
-                    is_running = if docker.is_container_running(&run_container_name).await? {
-                        "Container is running, so this is usually an error in Synapse or modules."
-                    } else {
-                        "For some reason, the Docker image has stopped."
-                    },
-                );
-            }
-            Ok(result) => result,
-        }?
-    };
-    if let Some(UpScript::FullUpScript(FullUpScript {
-        after: Some(ref script),
-        ..
-    })) = config.up
-    {
-        let env = config.shared_env_variables()?;
-        script
-            .run("up", &script_log_dir, &env)
-            .await
-            .context("Error running `up` script (after)")?;
-    }
-
-    cleanup.disarm();
-
-    println!("* up step: success");
-    Ok(())
-}
-
-/// Bring things down.
-pub async fn down(docker: &Docker, config: &Config, status: Status) -> Result<(), Error> {
-    // This will break (on purpose) once we extend `SynapseVersion`.
-    let SynapseVersion::Docker { .. } = config.synapse;
-    let run_container_name = config.run_container_name();
-
-    println!("\n* down step: starting");
-
-    // Store results, we'll report them after we've brought down everything
-    // that we can bring down.
-    let script_log_dir = config.scripts_logs_dir();
-    let script_result = if let Some(ref down_script) = config.down {
-        let env = config.shared_env_variables()?;
-        // First run on_failure/on_success.
-        // Store errors for later.
-        let result = match (status, down_script) {
-            (
-                Status::Failure,
-                DownScript {
-                    failure: Some(ref on_failure),
-                    ..
-                },
-            ) => on_failure
-                .run("on_failure", &script_log_dir, &env)
-                .await
-                .context("Error while running script `down/failure`"),
-            (
-                Status::Success,
-                DownScript {
-                    success: Some(ref on_success),
-                    ..
-                },
-            ) => on_success
-                .run("on_success", &script_log_dir, &env)
-                .await
-                .context("Error while running script `down/success`"),
-            _ => Ok(()),
-        };
-        // Then run on_always.
-        if let Some(ref on_always) = down_script.finally {
-            result.and(
-                on_always
-                    .run("on_always", &script_log_dir, &env)
-                    .await
-                    .context("Error while running script `down/finally`"),
-            )
-        } else {
-            result
-        }
-    } else {
-        Ok(())
-    };
-
-    debug!(target: "mx-tester-down", "Taking down synapse.");
-    let stop_container_result = match docker.stop_container(&run_container_name, None).await {
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if (200..300).contains(&status_code) => {
-            debug!(target: "mx-tester-down", "Synapse container stopped: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 304 => {
-            debug!(target: "mx-tester-down", "Synapse container was already down: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 404 => {
-            debug!(target: "mx-tester-down", "Synapse container not found for stopping: {}", message);
-            Ok(())
-        }
-        Err(err) => Err(err).context("Error stopping container"),
-        Ok(_) => {
-            debug!(target: "mx-tester-down", "Synapse container stopped");
-            Ok(())
-        }
-    };
-
-    let remove_container_result = match docker.remove_container(&run_container_name, None).await {
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if (200..300).contains(&status_code) => {
-            debug!(target: "mx-tester-down", "Synapse container removed: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 304 => {
-            debug!(target: "mx-tester-down", "Synapse container was already removed: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 404 => {
-            debug!(target: "mx-tester-down", "Synapse container not found for removing: {}", message);
-            Ok(())
-        }
-        Err(err) => Err(err).context("Error removing container"),
-        Ok(_) => {
-            debug!(target: "mx-tester-down", "Synapse container removed");
-            Ok(())
-        }
-    };
-
-    debug!(target: "mx-tester-down", "Taking down network.");
-    let remove_network_result = match docker.remove_network(config.network().as_ref()).await {
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if (200..300).contains(&status_code) => {
-            debug!(target: "mx-tester-down", "Network removed: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 304 => {
-            debug!(target: "mx-tester-down", "Network was already removed: {}", message);
-            Ok(())
-        }
-        Err(bollard::errors::Error::DockerResponseServerError {
-            message,
-            status_code,
-        }) if status_code == 404 => {
-            debug!(target: "mx-tester-down", "Network not found for removing: {}", message);
-            Ok(())
-        }
-        Err(err) => Err(err).context("Error removing network"),
-        Ok(_) => {
-            debug!(target: "mx-tester-down", "Network removed");
-            Ok(())
-        }
-    };
-
-    println!("* down step: complete");
-    // Finally, report any problem.
-    script_result
-        .and(stop_container_result)
-        .and(remove_container_result)
-        .and(remove_network_result)
-}
-
-/// Run the testing script.
-pub async fn run(_docker: &Docker, config: &Config) -> Result<(), Error> {
-    println!("\n* run step: starting");
-    if let Some(ref code) = config.run {
-        let env = config.shared_env_variables()?;
-        code.run("run", &config.scripts_logs_dir(), &env)
-            .await
-            .context("Error running `run` script")?;
-    }
-    println!("* run step: success");
-    Ok(())
-}
-
-/// Utility methods for `Docker`.
-#[async_trait::async_trait]
-trait DockerExt {
-    /// Check whether a network exists.
-    async fn is_network_up(&self, name: &str) -> Result<bool, Error>;
-
-    /// Check whether a container is currently running.
-    async fn is_container_running(&self, name: &str) -> Result<bool, Error>;
-
-    /// Check whether a container has been created (running or otherwise).
-    async fn is_container_created(&self, name: &str) -> Result<bool, Error>;
-
-    async fn wait_container_removed(&self, name: &str) -> Result<(), Error>;
-}
-
-#[async_trait::async_trait]
-impl DockerExt for Docker {
-    /// Check whether a network exists.
-    async fn is_network_up(&self, name: &str) -> Result<bool, Error> {
-        let networks = self
-            .list_networks(Some(ListNetworksOptions {
-                filters: vec![("name", vec![name])].into_iter().collect(),
-            }))
-            .await?;
-        // `filters` actually filter by substring, so we need to double-check the result.
-        debug!("is_network_up {:#?}", networks);
-        Ok(networks
-            .into_iter()
-            .filter_map(|network| network.name)
-            .any(|candidate_name| candidate_name.as_str() == name))
-    }
-
-    /// Check whether a container is currently running.
-    async fn is_container_running(&self, name: &str) -> Result<bool, Error> {
-        let containers = self
-            .list_containers(Some(ListContainersOptions {
-                // Check for running containers only
-                all: false,
-                filters: vec![("name", vec![name])].into_iter().collect(),
-                ..ListContainersOptions::default()
-            }))
-            .await?;
-        // `filters` actually filter by substring, so we need to double-check the result.
-        debug!("is_container_running {:#?}", containers);
-        let found = containers
-            .into_iter()
-            .flat_map(|container| container.names)
-            .flat_map(|names| names.into_iter())
-            .any(|container_name| container_name.as_str() == name);
-        Ok(found)
-    }
-
-    /// Check whether a container has been created (running or otherwise).
-    async fn is_container_created(&self, name: &str) -> Result<bool, Error> {
-        let containers: Vec<_> = self
-            .list_containers(Some(ListContainersOptions {
-                // Check for both running and non-running containers.
-                all: true,
-                // FIXME: This filter seems to filter by substring. That's... not reliable.
-                filters: vec![("name", vec![name])].into_iter().collect(),
-                ..ListContainersOptions::default()
-            }))
-            .await?;
-        // `filters` actually filter by substring, so we need to double-check the result.
-        debug!("is_container_created {:#?}", containers);
-        let found = containers
-            .into_iter()
-            .flat_map(|container| container.names)
-            .flat_map(|names| names.into_iter())
-            .any(|container_name| container_name.as_str() == name);
-        Ok(found)
-    }
-
-    async fn wait_container_removed(&self, name: &str) -> Result<(), Error> {
-        let mut stream = self.wait_container(
-            name,
-            Some(WaitContainerOptions {
-                condition: "removed",
-            }),
-        );
-        'waiting: while let Some(result) = stream.next().await {
-            match result {
-                Ok(bollard::models::ContainerWaitResponse {
-                    error: Some(error), ..
-                }) => {
-                    return Err(anyhow!(
-                        "Error while waiting for container {} to be removed: {:?}",
-                        name,
-                        error
-                    ));
-                }
-                Ok(_) => {
-                    // In progress.
-                }
-                Err(bollard::errors::Error::DockerResponseServerError { status_code, .. })
-                    if status_code == 304 =>
-                {
-                    // We started waiting too late, the container is already removed.
-                    break 'waiting;
-                }
-                Err(bollard::errors::Error::DockerResponseServerError { status_code, .. })
-                    if status_code == 404 =>
-                {
-                    // We started waiting too late, the container is already removed.
-                    break 'waiting;
-                }
-                Err(err) => {
-                    return Err(err)
-                        .context(format!("Waiting for container {} to be removed", name));
-                }
-            }
-        }
-        Ok(())
-    }
-}
-
-/// Utility trait: determine whether a yaml value is a stand-in for "please use the default"
-/// value provided by Synapse.
-trait IsDefault {
-    fn is_default(&self) -> bool;
-}
-impl IsDefault for serde_yaml::Value {
-    fn is_default(&self) -> bool {
-        if let Some(str) = self.as_str() {
-            if str == "synapse-default" {
-                return true;
-            }
-        }
-        false
-    }
-}
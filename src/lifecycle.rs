@@ -0,0 +1,2362 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Building and running Synapse in Docker: the `build`, `up`, `run`, `down`
+//! and `clean` steps.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    io::Write,
+};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{
+    auth::DockerCredentials,
+    container::{
+        Config as BollardContainerConfig, CreateContainerOptions, ListContainersOptions,
+        LogsOptions, NetworkingConfig, StartContainerOptions, WaitContainerOptions,
+    },
+    exec::{CreateExecOptions, StartExecOptions},
+    image::CreateImageOptions,
+    models::{
+        ContainerStateStatusEnum, EndpointSettings, HealthConfig, HealthStatusEnum, HostConfig,
+        HostConfigLogConfig, PortBinding, RestartPolicy, RestartPolicyNameEnum,
+    },
+    network::{ConnectNetworkOptions, CreateNetworkOptions, ListNetworksOptions},
+    Docker,
+};
+use data_encoding::HEXLOWER;
+use futures_util::stream::StreamExt;
+use itertools::Itertools;
+use log::{debug, error, warn};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::{
+    admin, appservice, artifacts, assertions, bots,
+    cleanup::{Cleanup, Disarm},
+    coverage,
+    docker_config::{registry_of, DockerConfigFile, DOCKER_HUB_SERVER_ADDRESS},
+    doctor, email,
+    environment::Environment,
+    federation, identity, image_registry, jaeger,
+    leaks::Leaks,
+    log_header,
+    manifest::{ImageInfo, Manifest, Package, TemplateInfo},
+    mas,
+    patch::DENDRITE_PRIVATE_KEY,
+    postgres, profile, push, recording,
+    registration::handle_user_registration,
+    services, sso, telemetry, turn,
+    util::{exec, with_heartbeat},
+    Config, Credentials, DownScript, FullUpScript, HomeserverKind, InstallMode, ModuleConfig,
+    ModuleSource, PortMapping, RestartPolicyConfig, Status, SynapseVersion, UpScript,
+    HARDCODED_GUEST_PORT, HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT, MX_TEST_MODULE_DIR,
+};
+
+/// The amount of memory to allocate
+const MEMORY_ALLOCATION_BYTES: i64 = 4 * 1024 * 1024 * 1024;
+
+/// The number of lines of Synapse logs to display if Synapse crashes or fails to start during `up`.
+const CRASH_LOG_LINES: &str = "20";
+
+/// How often Docker checks whether Synapse is healthy.
+const HEALTHCHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How many consecutive failed checks before Docker considers Synapse unhealthy.
+const HEALTHCHECK_RETRIES: i64 = 3;
+
+const TIMEOUT_USER_REGISTRATION_SIMPLE: std::time::Duration = std::time::Duration::new(120, 0);
+
+impl RestartPolicyConfig {
+    fn as_docker(&self) -> RestartPolicy {
+        match *self {
+            RestartPolicyConfig::Never => RestartPolicy {
+                name: Some(RestartPolicyNameEnum::NO),
+                maximum_retry_count: None,
+            },
+            RestartPolicyConfig::OnFailure(count) => RestartPolicy {
+                name: Some(RestartPolicyNameEnum::ON_FAILURE),
+                maximum_retry_count: Some(count),
+            },
+        }
+    }
+}
+
+impl Credentials {
+    fn as_docker(&self) -> DockerCredentials {
+        DockerCredentials {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            auth: self.auth.clone(),
+            email: self.email.clone(),
+            serveraddress: self.serveraddress.clone(),
+            identitytoken: self.identitytoken.clone(),
+            registrytoken: self.registrytoken.clone(),
+        }
+    }
+}
+
+/// The credentials for each registry, as expected by `docker build`, which
+/// may need to pull base images from several registries.
+///
+/// If several credentials are specified for a registry, the first one wins,
+/// then the credentials of the Docker CLI fill in the other registries.
+async fn registry_config(config: &Config) -> Option<HashMap<String, DockerCredentials>> {
+    let mut registries = HashMap::new();
+    let mut known = std::collections::HashSet::new();
+    for credentials in config.all_credentials() {
+        if !known.insert(credentials.registry().to_string()) {
+            continue;
+        }
+        let server = credentials
+            .serveraddress
+            .clone()
+            .unwrap_or_else(|| DOCKER_HUB_SERVER_ADDRESS.to_string());
+        registries.insert(server, credentials.as_docker());
+    }
+    match DockerConfigFile::load() {
+        Ok(docker_config) => {
+            for server in docker_config.servers() {
+                let registry = registry_of(server);
+                if known.contains(&registry) {
+                    continue;
+                }
+                if let Some(credentials) = docker_cli_credentials(&docker_config, &registry).await {
+                    known.insert(registry);
+                    registries.insert(server.to_string(), credentials);
+                }
+            }
+        }
+        Err(err) => warn!(
+            "Could not read the configuration of the Docker CLI: {:?}",
+            err
+        ),
+    }
+    if registries.is_empty() {
+        None
+    } else {
+        Some(registries)
+    }
+}
+
+/// The credentials of the Docker CLI for a registry, if any.
+///
+/// Failures, e.g. a credential helper that isn't installed, are only logged,
+/// as the images of the registry may well be public.
+async fn docker_cli_credentials(
+    docker_config: &DockerConfigFile,
+    registry: &str,
+) -> Option<DockerCredentials> {
+    match docker_config.credentials(registry).await {
+        Ok(credentials) => credentials.map(|credentials| credentials.as_docker()),
+        Err(err) => {
+            warn!(
+                "Could not get the credentials of the Docker CLI for {}: {:?}",
+                registry, err
+            );
+            None
+        }
+    }
+}
+
+/// Start a Synapse container.
+///
+/// - `cmd`: a shell command to execute;
+/// - `detach`: if `true`, the Synapse container will continue executing past the end of this function and process;
+async fn start_synapse_container(
+    docker: &Docker,
+    config: &Config,
+    container_name: &str,
+    cmd: Vec<String>,
+    detach: bool,
+) -> Result<(), Error> {
+    let data_dir = config.synapse_data_dir();
+    let data_dir = data_dir.as_path();
+    let runtime = config.docker.runtime.container_runtime();
+    let env = synapse_env(config, &config.workers.types);
+    debug!("We need to create container for {}", container_name);
+
+    // Let Docker find out whether Synapse is actually responding, rather than
+    // merely running. In workers mode, check both nginx and the main process.
+    //
+    // The Dendrite image has no curl and no `/health`.
+    let mut healthcheck = match config.homeserver.kind {
+        HomeserverKind::Synapse => {
+            format!("curl -fSs http://localhost:{}/health", HARDCODED_GUEST_PORT)
+        }
+        HomeserverKind::Dendrite => format!(
+            "wget -q -O /dev/null http://localhost:{}/_matrix/client/versions",
+            HARDCODED_GUEST_PORT
+        ),
+    };
+    if config.workers.enabled {
+        healthcheck.push_str(&format!(
+            " && curl -fSs http://localhost:{}/health",
+            HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT
+        ));
+    }
+    let healthcheck = HealthConfig {
+        test: Some(vec![
+            "CMD-SHELL".to_string(),
+            format!("{} || exit 1", healthcheck),
+        ]),
+        interval: Some(HEALTHCHECK_INTERVAL.as_nanos() as i64),
+        timeout: Some(HEALTHCHECK_INTERVAL.as_nanos() as i64),
+        retries: Some(HEALTHCHECK_RETRIES),
+        ..HealthConfig::default()
+    };
+
+    // Generate configuration to open and map ports.
+    let mut host_port_bindings = HashMap::new();
+    let mut exposed_ports = HashMap::new();
+    let debug_mapping = config.debug.python.as_ref().map(|debug| PortMapping {
+        host: u64::from(debug.port),
+        guest: u64::from(debug.port),
+    });
+    for mapping in config
+        .docker
+        .port_mapping
+        .iter()
+        .chain(
+            [PortMapping {
+                host: config.homeserver.host_port,
+                guest: HARDCODED_GUEST_PORT,
+            }]
+            .iter(),
+        )
+        .chain(debug_mapping.iter())
+    {
+        let key = format!("{}/tcp", mapping.guest);
+        host_port_bindings.insert(
+            key.clone(),
+            Some(vec![PortBinding {
+                host_port: Some(format!("{}", mapping.host)),
+                ..PortBinding::default()
+            }]),
+        );
+        exposed_ports.insert(key.clone(), HashMap::new());
+    }
+    debug!("port_bindings: {:#?}", host_port_bindings);
+
+    // If we're running in a container, the Docker daemon resolves bind-mounts
+    // on its own host, so translate our paths.
+    let environment = Environment::detect(docker)
+        .await
+        .context("Could not determine whether mx-tester is running in a container")?;
+    let bind = |path: &std::path::Path, guest: &str, mode: &str| {
+        environment
+            .host_path(path)
+            .map(|host_path| format!("{}:{}:{}", host_path.to_string_lossy(), guest, mode))
+    };
+    let mut binds = vec![
+        // Synapse logs, etc.
+        bind(data_dir, "/data", "rw")?,
+        // Everything below this point is for workers.
+        bind(&config.synapse_workers_dir(), "/conf/workers", "rw")?,
+        bind(&config.etc_dir().join("nginx"), "/etc/nginx/conf.d", "rw")?,
+        bind(
+            &config.etc_dir().join("supervisor"),
+            "/etc/supervisor/conf.d",
+            "rw",
+        )?,
+        bind(&config.logs_dir().join("nginx"), "/var/log/nginx", "rw")?,
+        bind(&config.logs_dir().join("workers"), "/var/log/workers", "rw")?,
+    ];
+    // Editable modules are installed from their source directory on the host.
+    for module in &config.modules {
+        if let InstallMode::Editable = module.install_mode {
+            let path = module.host_path()?;
+            binds.push(bind(&path, &format!("/mx-tester/{}", module.name), "ro")?);
+        }
+    }
+
+    debug!("Creating container {}", container_name);
+    let response = docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: container_name,
+            }),
+            BollardContainerConfig {
+                env: Some(env.clone()),
+                exposed_ports: Some(exposed_ports),
+                healthcheck: Some(healthcheck),
+                hostname: Some(config.docker.hostname.clone()),
+                host_config: Some(HostConfig {
+                    log_config: Some(HostConfigLogConfig {
+                        typ: Some("json-file".to_string()),
+                        config: None,
+                    }),
+                    // Synapse has a tendency to not start correctly
+                    // or to stop shortly after startup. By default,
+                    // restarting on failure seems to help a lot.
+                    restart_policy: Some(config.docker.restart.as_docker()),
+                    // Extremely large memory allowance.
+                    memory_reservation: Some(MEMORY_ALLOCATION_BYTES),
+                    memory_swap: Some(-1),
+                    // Mount guest directories as host directories.
+                    binds: Some(binds),
+                    // Expose guest port `guest_mapping` as `host_mapping`.
+                    port_bindings: Some(host_port_bindings),
+                    // Enable access to host as `host.docker.internal` from the guest.
+                    extra_hosts: runtime.extra_hosts(),
+                    userns_mode: runtime.userns_mode(),
+                    // Let the profiler, if any, attach to Synapse.
+                    cap_add: profile::capabilities(config),
+                    ..HostConfig::default()
+                }),
+                image: Some(config.tag()),
+                labels: Some(config.docker_labels()),
+                attach_stderr: Some(true),
+                attach_stdout: Some(true),
+                attach_stdin: Some(false),
+                cmd: Some(cmd.clone()),
+                // Specify that a few directories may be mounted.
+                // The empty hashmap... is an oddity of the Docker Engine API.
+                volumes: Some(
+                    vec![
+                        ("/data".to_string(), HashMap::new()),
+                        ("/conf/workers".to_string(), HashMap::new()),
+                        ("/etc/nginx/conf.d".to_string(), HashMap::new()),
+                        ("/etc/supervisor/conf.d".to_string(), HashMap::new()),
+                        ("/var/log/workers".to_string(), HashMap::new()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+                tty: Some(false),
+                // Dendrite writes its databases in its current directory.
+                working_dir: match config.homeserver.kind {
+                    HomeserverKind::Synapse => None,
+                    HomeserverKind::Dendrite => Some("/data".to_string()),
+                },
+                #[cfg(unix)]
+                user: Some(format!("{}", nix::unistd::getuid())),
+                ..BollardContainerConfig::default()
+            },
+        )
+        .await
+        .context("Failed to build container")?;
+
+    // For debugging purposes, try and find out when/why the container stops.
+    let mut wait = docker.wait_container(
+        container_name,
+        Some(WaitContainerOptions {
+            condition: "not-running",
+        }),
+    );
+    {
+        let container_name = container_name.to_string();
+        tokio::task::spawn(async move {
+            debug!(target: "mx-tester-wait", "{} Container started", container_name);
+            while let Some(next) = wait.next().await {
+                let response = next.context("Error while waiting for container to stop")?;
+                debug!(target: "mx-tester-wait", "{} {:#?}", container_name, response);
+            }
+            debug!(target: "mx-tester-wait", "{} Container is now down", container_name);
+            Ok::<(), Error>(())
+        });
+    }
+
+    for warning in response.warnings {
+        warn!(target: "creating-container", "{}", warning);
+    }
+
+    // ... add the container to the network.
+    // With federation, other homeservers reach this one through its server name.
+    let aliases: Vec<String> = config
+        .homeservers
+        .first()
+        .map(|_| federation::network_alias(&config.homeserver.server_name).to_string())
+        .into_iter()
+        .chain(config.docker.aliases.iter().cloned())
+        .collect();
+    docker
+        .connect_network(
+            config.network().as_ref(),
+            ConnectNetworkOptions {
+                container: container_name,
+                endpoint_config: EndpointSettings {
+                    aliases: if aliases.is_empty() {
+                        None
+                    } else {
+                        Some(aliases)
+                    },
+                    ..EndpointSettings::default()
+                },
+            },
+        )
+        .await
+        .context("Failed to connect container")?;
+
+    let is_container_running = docker.is_container_running(container_name).await?;
+    if !is_container_running {
+        docker
+            .start_container(container_name, None::<StartContainerOptions<String>>)
+            .await
+            .context("Failed to start container")?;
+        let mut logs = docker.logs(
+            container_name,
+            Some(LogsOptions {
+                follow: true,
+                stdout: true,
+                stderr: true,
+                tail: "10",
+                ..LogsOptions::default()
+            }),
+        );
+
+        // Write logs to the synapse data directory.
+        let log_file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.logs_dir().join("docker").join(format!(
+                "{}.log",
+                if detach { "up-run-down" } else { "build" }
+            )))
+            .await?;
+        let mut buffer = BufWriter::new(log_file);
+        buffer
+            .write_all(log_header(&format!("container {}", container_name)).as_bytes())
+            .await?;
+        buffer.flush().await?;
+        tokio::task::spawn(async move {
+            debug!(target: "mx-tester-log", "Starting log watcher");
+            while let Some(next) = logs.next().await {
+                match next {
+                    Ok(content) => {
+                        debug!(target: "mx-tester-log", "{}", content);
+                        buffer.write_all(format!("{}", content).as_bytes()).await?;
+                        buffer.flush().await?;
+                    }
+                    Err(err) => {
+                        error!(target: "mx-tester-log", "{}", err);
+                        buffer
+                            .write_all(format!("ERROR: {}", err).as_bytes())
+                            .await?;
+                        buffer.flush().await?;
+                        return Err(err).context("Error in log");
+                    }
+                }
+            }
+            debug!(target: "mx-tester-log", "Stopped log watcher");
+            Ok(())
+        });
+    }
+
+    let cleanup = if config.autoclean_on_error {
+        Some(Cleanup::new(config))
+    } else {
+        None
+    };
+    let exec = docker
+        .create_exec(
+            container_name,
+            CreateExecOptions::<Cow<'_, str>> {
+                cmd: Some(cmd.into_iter().map(|s| s.into()).collect()),
+                env: Some(env.into_iter().map(|s| s.into()).collect()),
+                #[cfg(unix)]
+                user: Some(format!("{}", nix::unistd::getuid()).into()),
+                ..CreateExecOptions::default()
+            },
+        )
+        .await
+        .context("Error while preparing to Synapse container")?;
+    let execution = docker
+        .start_exec(
+            &exec.id,
+            Some(StartExecOptions {
+                detach,
+                ..StartExecOptions::default()
+            }),
+        )
+        .await
+        .context("Error starting Synapse container")?;
+
+    if !detach {
+        let log_file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.logs_dir().join("docker").join(format!(
+                "{}.out",
+                if detach { "up-run-down" } else { "build" }
+            )))
+            .await?;
+        let mut buffer = BufWriter::new(log_file);
+        buffer
+            .write_all(log_header(&format!("exec in container {}", container_name)).as_bytes())
+            .await?;
+        buffer.flush().await?;
+        tokio::task::spawn(async move {
+            debug!(target: "synapse", "Launching Synapse container");
+            match execution {
+                bollard::exec::StartExecResults::Attached {
+                    mut output,
+                    input: _,
+                } => {
+                    while let Some(data) = output.next().await {
+                        let output = data.context("Error during run")?;
+                        debug!(target: "synapse", "{}", output);
+                        buffer.write_all(format!("{}", output).as_bytes()).await?;
+                        buffer.flush().await?;
+                    }
+                }
+                bollard::exec::StartExecResults::Detached => panic!(),
+            }
+            debug!(target: "synapse", "Synapse container finished");
+            Ok::<(), Error>(())
+        })
+        .await??;
+    }
+    cleanup.disarm();
+    Ok(())
+}
+
+/// Pull an image, unless it is already available, e.g. because it was built locally.
+pub(crate) async fn pull_image_if_missing(
+    docker: &Docker,
+    config: &Config,
+    image: &str,
+) -> Result<(), Error> {
+    if docker.inspect_image(image).await.is_ok() {
+        return Ok(());
+    }
+    println!("** pulling image {}", image);
+    telemetry::span(
+        "docker pull",
+        &[("image", image.to_string())],
+        pull_image(docker, config, image),
+    )
+    .await
+}
+
+/// Pull an image, with the credentials of its registry, if any.
+async fn pull_image(docker: &Docker, config: &Config, image: &str) -> Result<(), Error> {
+    let credentials = match config.credentials_for_image(image) {
+        Some(credentials) => Some(credentials.as_docker()),
+        None => match DockerConfigFile::load() {
+            Ok(docker_config) => {
+                docker_cli_credentials(&docker_config, image_registry(image)).await
+            }
+            Err(err) => {
+                warn!(
+                    "Could not read the configuration of the Docker CLI: {:?}",
+                    err
+                );
+                None
+            }
+        },
+    };
+    let mut stream = docker.create_image(
+        Some(CreateImageOptions {
+            from_image: image,
+            ..CreateImageOptions::default()
+        }),
+        None,
+        credentials,
+    );
+    while let Some(result) = stream.next().await {
+        result.with_context(|| format!("Could not pull image {}", image))?;
+    }
+    Ok(())
+}
+
+/// Give a container additional hostnames on the network, e.g. `aliases` of a bot.
+pub(crate) fn networking_config(
+    config: &Config,
+    aliases: &[String],
+) -> Option<NetworkingConfig<String>> {
+    if aliases.is_empty() {
+        return None;
+    }
+    let mut endpoints_config = HashMap::new();
+    endpoints_config.insert(
+        config.network(),
+        EndpointSettings {
+            aliases: Some(aliases.to_vec()),
+            ..EndpointSettings::default()
+        },
+    );
+    Some(NetworkingConfig { endpoints_config })
+}
+
+/// Write the logs of a container to a file, in the background, until the container stops.
+pub(crate) async fn write_container_logs(
+    docker: &Docker,
+    container_name: &str,
+    logs_path: &std::path::Path,
+) -> Result<(), Error> {
+    let mut logs = docker.logs(
+        container_name,
+        Some(LogsOptions::<String> {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            ..LogsOptions::default()
+        }),
+    );
+    let log_file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(logs_path)
+        .await
+        .with_context(|| format!("Could not open {:?}", logs_path))?;
+    let mut buffer = BufWriter::new(log_file);
+    buffer
+        .write_all(log_header(&format!("container {}", container_name)).as_bytes())
+        .await?;
+    buffer.flush().await?;
+    let container_name = container_name.to_string();
+    tokio::task::spawn(async move {
+        debug!(target: "mx-tester-log", "Starting log watcher for {}", container_name);
+        while let Some(next) = logs.next().await {
+            match next {
+                Ok(content) => {
+                    buffer.write_all(format!("{}", content).as_bytes()).await?;
+                    buffer.flush().await?;
+                }
+                Err(err) => {
+                    error!(target: "mx-tester-log", "{}", err);
+                    buffer
+                        .write_all(format!("ERROR: {}", err).as_bytes())
+                        .await?;
+                    buffer.flush().await?;
+                    return Err(err).context("Error in log");
+                }
+            }
+        }
+        debug!(target: "mx-tester-log", "Stopped log watcher for {}", container_name);
+        Ok(())
+    });
+    Ok(())
+}
+
+/// Create the directories bind-mounted into the Synapse container, so that
+/// they belong to the current user rather than to Docker.
+pub(crate) fn create_synapse_dirs(config: &Config) -> Result<(), Error> {
+    for dir in &[
+        &config.synapse_data_dir(),
+        &config.synapse_workers_dir(),
+        &config.etc_dir().join("nginx"),
+        &config.etc_dir().join("supervisor"),
+        &config.logs_dir().join("docker"),
+        &config.logs_dir().join("nginx"),
+        &config.logs_dir().join("workers"),
+    ] {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create directory {:#?}", dir,))?;
+    }
+    Ok(())
+}
+
+/// Write the files needed to build an image with workers.
+#[cfg(feature = "workers")]
+fn write_workers_resources(
+    config: &Config,
+    synapse_root: &std::path::Path,
+) -> Result<BTreeMap<String, TemplateInfo>, Error> {
+    let conf_dir = synapse_root.join("conf");
+    std::fs::create_dir_all(&conf_dir)
+        .context("Could not create directory for worker configuration file")?;
+    // Hopefully, in the future, Synapse+worker images will be available on DockerHub.
+    let mut infos = BTreeMap::new();
+    for template in crate::workers::templates(config)? {
+        let path = if template.name == "workers_start.py" {
+            synapse_root.join(template.name)
+        } else {
+            conf_dir.join(template.name)
+        };
+        if template.info.source == crate::manifest::TemplateSource::User {
+            println!(
+                "** using {} from `workers.templates_dir` (sha1 {})",
+                template.name, template.info.sha1
+            );
+        }
+        std::fs::write(&path, &template.content)
+            .with_context(|| format!("Could not inject worker configuration file {:?}", path))?;
+        infos.insert(template.name.to_string(), template.info);
+    }
+    Ok(infos)
+}
+
+/// Write the files needed to build an image with workers.
+#[cfg(not(feature = "workers"))]
+fn write_workers_resources(
+    _config: &Config,
+    _synapse_root: &std::path::Path,
+) -> Result<BTreeMap<String, TemplateInfo>, Error> {
+    Err(anyhow!(
+        "This configuration uses workers but mx-tester was built without feature `workers`"
+    ))
+}
+
+/// Rebuild the Synapse image with modules.
+pub async fn build(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let docker_tag = config.homeserver_image();
+    let setup_container_name = config.setup_container_name();
+    let run_container_name = config.run_container_name();
+
+    println!("\n* build step: starting");
+
+    for module in &config.modules {
+        if let InstallMode::Editable = module.install_mode {
+            module.host_path()?;
+        }
+    }
+
+    // Remove any trace of a previous build. Ignore failures.
+    let runtime = config.docker.runtime.container_runtime();
+    let _ = runtime.stop_container(docker, &run_container_name).await;
+    let _ = runtime.remove_container(docker, &run_container_name).await;
+    let _ = runtime.stop_container(docker, &setup_container_name).await;
+    let _ = runtime
+        .remove_container(docker, &setup_container_name)
+        .await;
+    let _ = runtime.remove_image(docker, config.tag().as_ref()).await;
+    let _ = bots::stop(docker, config).await;
+
+    let synapse_root = config.synapse_root();
+    let _ = std::fs::remove_dir_all(config.test_root());
+    create_synapse_dirs(config)?;
+    let modules_log_dir = config.scripts_logs_dir().join("modules");
+    std::fs::create_dir_all(&modules_log_dir)
+        .with_context(|| format!("Could not create directory {:#?}", modules_log_dir))?;
+
+    // Build modules
+    println!("** building modules");
+    let mut env = config.shared_env_variables()?;
+
+    for module in &config.modules {
+        prepare_module(config, module, &mut env).await?;
+        debug!("Completed one module.");
+    }
+    println!("** building modules success");
+
+    // Prepare resource files.
+    let templates = if config.workers.enabled {
+        write_workers_resources(config, &synapse_root)?
+    } else {
+        BTreeMap::new()
+    };
+
+    // Copy the local checkout of Synapse, if any, into the build context.
+    if let SynapseVersion::Local { ref path, .. } = config.synapse {
+        copy_synapse_source(path, &synapse_root.join("synapse-source"))?;
+    }
+    if let Some(ref coverage) = config.artifacts.coverage {
+        let path = synapse_root.join(coverage::COVERAGERC);
+        std::fs::write(&path, coverage::coveragerc(coverage))
+            .with_context(|| format!("Could not write {:?}", path))?;
+    }
+
+    let dockerfile_content = match config.homeserver.kind {
+        HomeserverKind::Synapse => synapse_dockerfile(config, &docker_tag),
+        HomeserverKind::Dendrite => dendrite_dockerfile(&docker_tag),
+    };
+    debug!("dockerfile {}", dockerfile_content);
+
+    let dockerfile_path = synapse_root.join("Dockerfile");
+    std::fs::write(&dockerfile_path, dockerfile_content)
+        .with_context(|| format!("Could not write file {:#?}", dockerfile_path,))?;
+
+    let logs_path = config.logs_dir().join("docker").join("build.log");
+    println!(
+        "** building Docker image. Logs will be stored at {:?}",
+        logs_path
+    );
+    debug!("Building image with tag {}", config.tag());
+    build_image(
+        docker,
+        config,
+        &synapse_root,
+        &config.tag(),
+        "docker.tar",
+        &logs_path,
+    )
+    .await?;
+    debug!("Image built");
+    println!("** building Docker image success");
+
+    bots::build(docker, config).await?;
+
+    let mut image_info = match config.homeserver.kind {
+        HomeserverKind::Synapse => inspect_image(docker, config)
+            .await
+            .context("Could not inspect the contents of the image")?,
+        // Dendrite is not a Python package, we have nothing to inspect.
+        HomeserverKind::Dendrite => ImageInfo {
+            tag: config.tag(),
+            ..ImageInfo::default()
+        },
+    };
+    image_info.base_image = docker_tag.clone();
+    image_info.templates = templates;
+    image_info.base_digest = base_digest(docker, &docker_tag).await;
+    if let Some(digest) = image_info.base_digest.as_ref() {
+        println!("** base image {} is {}", docker_tag, digest);
+    }
+    if let Some(drift) = Manifest::load(config)?
+        .image
+        .and_then(|previous| image_info.base_drift(&previous))
+    {
+        warn!("{}", drift);
+        println!("** warning: {}", drift);
+    }
+    if let HomeserverKind::Synapse = config.homeserver.kind {
+        println!(
+            "** image contains Synapse {synapse}{modules}",
+            synapse = image_info
+                .synapse_version
+                .as_deref()
+                .unwrap_or("(unknown version)"),
+            modules = image_info
+                .modules
+                .iter()
+                .map(|(name, package)| format!(", module {} {}", name, package.version))
+                .format("")
+        );
+    }
+    Manifest::update(config, move |manifest| manifest.image = Some(image_info))?;
+
+    println!("* build step: success");
+    Ok(())
+}
+
+/// A Dockerfile to rebuild Synapse from the official release + modules.
+fn synapse_dockerfile(config: &Config, docker_tag: &str) -> String {
+    format!("
+# A custom Dockerfile to rebuild synapse from the official release + plugins
+
+FROM {docker_tag}
+
+VOLUME [\"/data\", \"/conf/workers\", \"/etc/nginx/conf.d\", \"/etc/supervisor/conf.d\", \"/var/log/workers\"]
+
+# We're not running as root, to avoid messing up with the host
+# filesystem, so we need a proper user. We give it the current
+# use's uid to make sure that files written by this Docker image
+# can be read and removed by the host's user.
+# Note that we need tty to workaround the following Docker issue:
+# https://github.com/moby/moby/issues/31243#issuecomment-406825071
+RUN useradd mx-tester {maybe_uid} --groups sudo,tty
+
+# Add a password, to be able to run sudo. We'll use it to
+# chmod files.
+RUN echo \"mx-tester:password\" | chpasswd
+
+{maybe_local_synapse}
+
+# Show the Synapse version, to aid with debugging.
+RUN pip show matrix-synapse
+
+{maybe_setup_workers}
+
+# Copy and install custom modules.
+RUN mkdir /mx-tester
+{setup}
+{env}
+{copy_modules}
+{copy_resources}
+{install}
+{maybe_coverage}
+{maybe_debugpy}
+{maybe_profile}
+{maybe_federation}
+
+ENTRYPOINT []
+
+EXPOSE {synapse_http_port}/tcp 8009/tcp 8448/tcp
+",
+    docker_tag = docker_tag,
+    // Module setup steps, as per `config.modules[_].install`.
+    setup = config.modules.iter()
+        .filter_map(|module| module.install.as_ref().map(|script| format!("## Setup {}\n{}\n", module.name, script.lines.iter().map(|line| format!("RUN {}", line)).format("\n"))))
+        .format("\n"),
+    // Module env changes, as per `config.modules[_].env`.
+    env = config.modules.iter()
+        .map(|module| module.env.iter()
+            .map(|(key, value)| format!("ENV {}={}\n", key, value))
+            .format("")
+        ).format(""),
+    copy_modules = config.modules.iter()
+        // FIXME: We probably want to test what happens with weird characters. Perhaps we'll need to somehow escape module.
+        .map(|module| format!("COPY {module} /mx-tester/{module}", module=module.name))
+        .format("\n"),
+    // Modules additional resources, as per `config.modules[_].copy`.
+    copy_resources = config.modules.iter()
+        .map(|module| module.copy.iter()
+            .map(move |(dest, source)| format!("COPY {source} /mx-tester/{module}/{dest}\n",
+                dest = dest,
+                source = source,
+                module = module.name,
+            ))
+            .format("")
+        ).format(""),
+    // Modules copy and `pip` install.
+    install = config.modules.iter()
+        // FIXME: We probably want to test what happens with weird characters. Perhaps we'll need to somehow escape module.
+        .map(|module| format!("RUN /usr/local/bin/python -m pip install {editable}{path}",
+            path=module.guest_install_path(),
+            editable=match module.install_mode {
+                InstallMode::Regular => "",
+                InstallMode::Editable => "-e ",
+            }))
+        .format("\n"),
+    // Configure user id.
+    maybe_uid = {
+        let my_uid = nix::unistd::getuid();
+        if my_uid != nix::unistd::ROOT {
+           // We're running mx-tester as a regular user.
+            // Docker/Synapse should be executed as the same user, otherwise we won't be
+            // able to look at or remove all the files created by them.
+            Cow::from(format!("--uid {}", my_uid))
+        } else {
+            // We're running mx-tester as root.
+            // This can happen in CI, for instance.
+            // In this case, let `useradd` pick a uid for our user. We can't use
+            // the root uid as it's already taken by root in the guest environment
+            // and any uid will do since we're root in the host environment anyway,
+            // so we can look at and remove any file created by other users.
+            Cow::from("")
+        }
+    },
+    synapse_http_port = HARDCODED_GUEST_PORT,
+    maybe_debugpy = if config.debug.python.is_some() {
+"
+# Install debugpy, as per `debug.python`.
+RUN /usr/local/bin/python -m pip install debugpy
+"
+    } else {
+        ""
+    },
+    maybe_profile = match config.profile {
+        Some(profiler) => Cow::from(profile::dockerfile(profiler)),
+        None => Cow::from(""),
+    },
+    maybe_federation = if config.homeservers.is_empty() {
+        Cow::from("")
+    } else {
+        Cow::from(federation::dockerfile())
+    },
+    maybe_coverage = match config.artifacts.coverage {
+        Some(_) => Cow::from(coverage::dockerfile()),
+        None => Cow::from(""),
+    },
+    maybe_local_synapse = match config.synapse {
+        SynapseVersion::Docker { .. } => "",
+        SynapseVersion::Local { .. } => "
+# Install Synapse from a local checkout. Synapse has a Rust extension,
+# so we need a Rust toolchain.
+RUN apt-get update && apt-get install -y build-essential curl
+RUN curl https://sh.rustup.rs -sSf | sh -s -- -y --profile minimal
+ENV PATH=\"/root/.cargo/bin:${PATH}\"
+COPY synapse-source /mx-tester-synapse
+RUN /usr/local/bin/python -m pip install -e /mx-tester-synapse
+",
+    },
+    maybe_setup_workers =
+    if config.workers.enabled {
+"
+# Install dependencies
+RUN apt-get update && apt-get install -y postgresql postgresql-client-13 supervisor redis nginx sudo lsof
+
+# For workers, we're not using start.py but workers_start.py
+# (which does call start.py, but that's a long story).
+COPY workers_start.py /workers_start.py
+COPY conf/* /conf/
+
+# We're not going to be running workers_start.py as root, so
+# let's make sure that it *can* run, write to /etc/nginx & co.
+RUN chmod ugo+rx /workers_start.py && chown mx-tester /workers_start.py
+"
+    } else {
+        ""
+    }
+    )
+}
+
+/// Copy a local checkout of Synapse, without its git history or Rust build artifacts,
+/// which are large and not needed in the image.
+fn copy_synapse_source(source: &std::path::Path, dest: &std::path::Path) -> Result<(), Error> {
+    if !source.join("pyproject.toml").exists() {
+        return Err(anyhow!(
+            "{:?} does not look like a checkout of Synapse, expected a pyproject.toml",
+            source
+        ));
+    }
+    let source = source
+        .canonicalize()
+        .with_context(|| format!("Could not resolve {:?}", source))?;
+    println!("** copying Synapse from {:?}", source);
+    dircpy::CopyBuilder::new(&source, dest)
+        .overwrite(true)
+        .with_exclude_filter(&format!("{}/.git/", source.to_string_lossy()))
+        .with_exclude_filter(&format!("{}/target/", source.to_string_lossy()))
+        .run()
+        .with_context(|| format!("Could not copy {:?} to {:?}", source, dest))
+}
+
+/// A Dockerfile to run Dendrite from the official release.
+///
+/// Dendrite does not support modules, so we merely prepare the image
+/// to be run the same way as Synapse.
+fn dendrite_dockerfile(docker_tag: &str) -> String {
+    format!(
+        "
+FROM {docker_tag}
+
+VOLUME [\"/data\"]
+
+ENTRYPOINT []
+
+EXPOSE {http_port}/tcp 8448/tcp
+",
+        docker_tag = docker_tag,
+        http_port = HARDCODED_GUEST_PORT,
+    )
+}
+
+/// Put `module` in its directory of the build context, i.e. `MX_TEST_MODULE_DIR`,
+/// either by executing its `build` script or by copying its wheel.
+///
+/// `env` is the environment of the `build` script.
+pub(crate) async fn prepare_module(
+    config: &Config,
+    module: &ModuleConfig,
+    env: &mut HashMap<&'static std::ffi::OsStr, std::ffi::OsString>,
+) -> Result<(), Error> {
+    let path = config.synapse_root().join(&module.name);
+    match module.source {
+        ModuleSource::Build => {
+            env.insert(&*MX_TEST_MODULE_DIR, path.as_os_str().into());
+            debug!(
+                "Calling build script for module {} with MX_TEST_DIR={:#?}",
+                &module.name, path
+            );
+            let log_dir = config.scripts_logs_dir().join("modules").join(&module.name);
+            std::fs::create_dir_all(&log_dir)
+                .with_context(|| format!("Could not create directory {:#?}", log_dir,))?;
+            module
+                .build
+                .run("build", &log_dir, env)
+                .await
+                .context("Error running build script")
+        }
+        ModuleSource::Wheel {
+            path: ref wheel,
+            ref sha256,
+        } => {
+            println!("** using wheel {:?} for module {}", wheel, module.name);
+            let content = std::fs::read(wheel).with_context(|| {
+                format!("Could not read wheel {:?} of module {}", wheel, module.name)
+            })?;
+            if let Some(expected) = sha256 {
+                let actual = HEXLOWER.encode(&Sha256::digest(&content));
+                if !actual.eq_ignore_ascii_case(expected) {
+                    return Err(anyhow!(
+                        "Wheel {:?} of module {} has SHA-256 {}, expected {}",
+                        wheel,
+                        module.name,
+                        actual,
+                        expected
+                    ));
+                }
+            }
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Could not create directory {:#?}", path))?;
+            // pip needs the original file name, which contains the version and the platform.
+            let dest = path.join(wheel.file_name().unwrap_or_default());
+            std::fs::write(&dest, content).with_context(|| format!("Could not write {:?}", dest))
+        }
+    }
+}
+
+/// Build a Docker image from a directory containing a Dockerfile.
+///
+/// The image is labelled as belonging to this test.
+pub(crate) async fn build_image(
+    docker: &Docker,
+    config: &Config,
+    context_dir: &std::path::Path,
+    tag: &str,
+    tar_name: &str,
+    logs_path: &std::path::Path,
+) -> Result<(), Error> {
+    telemetry::span(
+        "docker build",
+        &[("image", tag.to_string())],
+        build_image_from_dir(docker, config, context_dir, tag, tar_name, logs_path),
+    )
+    .await
+}
+
+async fn build_image_from_dir(
+    docker: &Docker,
+    config: &Config,
+    context_dir: &std::path::Path,
+    tag: &str,
+    tar_name: &str,
+    logs_path: &std::path::Path,
+) -> Result<(), Error> {
+    debug!("Building tar file");
+    let docker_dir_path = config.test_root().join("tar");
+    std::fs::create_dir_all(&docker_dir_path)
+        .with_context(|| format!("Could not create directory {:#?}", docker_dir_path,))?;
+    let body = {
+        // Build the tar file.
+        let tar_path = docker_dir_path.join(tar_name);
+        {
+            let tar_file = std::fs::File::create(&tar_path)?;
+            let mut tar_builder = tar::Builder::new(std::io::BufWriter::new(tar_file));
+            debug!("tar: adding directory {:#?}", context_dir);
+            tar_builder
+                .append_dir_all("", context_dir)
+                .with_context(|| format!("Error while creating tar for {:#?}", context_dir))?;
+            tar_builder
+                .finish()
+                .with_context(|| format!("Error finalizing tar for {:#?}", context_dir))?
+        }
+
+        let tar_file = tokio::fs::File::open(&tar_path).await?;
+        let stream = FramedRead::new(tar_file, BytesCodec::new());
+        hyper::Body::wrap_stream(stream)
+    };
+    let mut log = std::fs::File::create(logs_path).context("Could not create docker build logs")?;
+    log.write_all(log_header(&format!("docker build {}", tag)).as_bytes())
+        .context("Could not write docker build logs")?;
+    let cache = &config.build.cache;
+    for image in &cache.from {
+        // A missing cache source only makes the build slower.
+        if let Err(err) = pull_image_if_missing(docker, config, image).await {
+            warn!("Could not pull cache source {}: {:?}", image, err);
+        }
+    }
+    let mut stream = docker.build_image(
+        bollard::image::BuildImageOptions {
+            pull: true,
+            nocache: !cache.enabled,
+            cachefrom: cache.from.clone(),
+            t: tag.to_string(),
+            labels: config.docker_labels(),
+            q: false,
+            rm: true,
+            ..Default::default()
+        },
+        registry_config(config).await,
+        Some(body),
+    );
+    let building = async {
+        while let Some(result) = stream.next().await {
+            let info = result.context("Daemon `docker build` indicated an error")?;
+            if let Some(ref error) = info.error {
+                return Err(anyhow!("Error while building an image: {}", error,));
+            }
+            if let Some(ref progress) = info.progress {
+                debug!("Build image progress {:#?}", info);
+                log.write_all(progress.as_bytes())
+                    .context("Could not write docker build logs")?;
+            }
+        }
+        Ok(())
+    };
+    with_heartbeat("Docker image build", || async { None }, building).await
+}
+
+/// A Python script listing the packages installed in the image, along with the url
+/// they were installed from, if any (see PEP 610).
+const LIST_PACKAGES_SCRIPT: &str = "
+import importlib.metadata, json
+packages = {}
+for dist in importlib.metadata.distributions():
+    name = dist.metadata['Name']
+    if name is None:
+        continue
+    direct_url = dist.read_text('direct_url.json')
+    packages[name] = {
+        'version': dist.version,
+        'url': json.loads(direct_url).get('url') if direct_url else None,
+    }
+print(json.dumps(packages))
+";
+
+/// Find out which versions of Synapse and modules are actually installed in the image.
+///
+/// This runs `LIST_PACKAGES_SCRIPT` in a transient container.
+async fn inspect_image(docker: &Docker, config: &Config) -> Result<ImageInfo, Error> {
+    let container_name = format!("{}-inspect", config.setup_container_name());
+    let _ = docker.remove_container(&container_name, None).await;
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: container_name.as_str(),
+            }),
+            BollardContainerConfig {
+                image: Some(config.tag()),
+                labels: Some(config.docker_labels()),
+                cmd: Some(vec![
+                    "/usr/local/bin/python".to_string(),
+                    "-c".to_string(),
+                    LIST_PACKAGES_SCRIPT.to_string(),
+                ]),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                tty: Some(false),
+                ..BollardContainerConfig::default()
+            },
+        )
+        .await
+        .context("Could not create container to inspect image")?;
+    let result = async {
+        docker
+            .start_container(&container_name, None::<StartContainerOptions<String>>)
+            .await?;
+        let mut wait = docker.wait_container(
+            &container_name,
+            Some(WaitContainerOptions {
+                condition: "not-running",
+            }),
+        );
+        while let Some(next) = wait.next().await {
+            next.context("Error while inspecting image")?;
+        }
+        let mut logs = docker.logs(
+            &container_name,
+            Some(LogsOptions::<String> {
+                stdout: true,
+                ..LogsOptions::default()
+            }),
+        );
+        let mut stdout = String::new();
+        while let Some(next) = logs.next().await {
+            if let bollard::container::LogOutput::StdOut { message } = next? {
+                stdout.push_str(&String::from_utf8_lossy(&message));
+            }
+        }
+        let packages: std::collections::BTreeMap<String, Package> =
+            serde_json::from_str(stdout.trim())
+                .with_context(|| format!("Could not parse list of packages {:?}", stdout))?;
+        Ok::<_, Error>(packages)
+    }
+    .await;
+    let _ = docker.remove_container(&container_name, None).await;
+    let packages = result?;
+
+    let synapse_version = packages
+        .get("matrix-synapse")
+        .map(|package| package.version.clone());
+    let modules = config
+        .modules
+        .iter()
+        .filter_map(|module| {
+            let url = format!("file:///mx-tester/{}", module.name);
+            packages
+                .values()
+                .find(|package| package.url.as_deref() == Some(url.as_str()))
+                .map(|package| (module.name.clone(), package.clone()))
+        })
+        .collect();
+    Ok(ImageInfo {
+        tag: config.tag(),
+        synapse_version,
+        modules,
+        packages,
+        ..ImageInfo::default()
+    })
+}
+
+/// The digest of a (pulled) image, e.g. `matrixdotorg/synapse@sha256:...`, if Docker knows it.
+async fn base_digest(docker: &Docker, image: &str) -> Option<String> {
+    let digests = match docker.inspect_image(image).await {
+        Ok(response) => response.repo_digests.unwrap_or_default(),
+        Err(err) => {
+            warn!("Could not inspect image {}: {}", image, err);
+            return None;
+        }
+    };
+    // Strip the tag or digest, but not the port of a registry, e.g. `localhost:5000/synapse:latest`.
+    let repository = match image.split_once('@') {
+        Some((repository, _)) => repository,
+        None => match image.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => repository,
+            _ => image,
+        },
+    };
+    let prefix = format!("{}@", repository);
+    digests
+        .iter()
+        .find(|digest| digest.starts_with(&prefix))
+        .or_else(|| digests.first())
+        .cloned()
+}
+
+/// Bring things up. Returns any environment variables to pass to the run script.
+pub async fn up(docker: &Docker, config: &Config) -> Result<(), Error> {
+    up_with_clients(docker, config).await.map(|_| ())
+}
+
+/// Bring things up, returning the logged-in client of each user declared
+/// in `users`, indexed by localname.
+pub(crate) async fn up_with_clients(
+    docker: &Docker,
+    config: &Config,
+) -> Result<HashMap<String, matrix_sdk::Client>, Error> {
+    let cleanup = if config.autoclean_on_error {
+        Some(Cleanup::new(config))
+    } else {
+        None
+    };
+
+    println!("\n* up step: starting");
+    config.validate()?;
+    // Fail early if we cannot bind our ports, rather than with an obscure
+    // Docker error or a registration timeout.
+    telemetry::span("check ports", &[], check_ports_available(docker, config)).await?;
+
+    // Create the network if necessary.
+    // We'll add the container once it's available.
+    let network_name = config.network();
+    debug!("We'll need network {}", network_name);
+    if !docker.is_network_up(&network_name).await? {
+        debug!("Creating network {}", network_name);
+        let labels = config.docker_labels();
+        docker
+            .create_network(CreateNetworkOptions {
+                name: Cow::from(network_name.as_str()),
+                check_duplicate: true,
+                attachable: true,
+                labels: labels
+                    .iter()
+                    .map(|(k, v)| (Cow::from(k.as_str()), Cow::from(v.as_str())))
+                    .collect(),
+                ..CreateNetworkOptions::default()
+            })
+            .await?;
+        assert!(
+            docker.is_network_up(&network_name).await?,
+            "The network should now be up"
+        );
+        debug!("Network is now up");
+    } else {
+        // This can happen for instance if a script needs to
+        // spawn another image on the same network and creates
+        // that network manually.
+        debug!("Network {} already exists", network_name);
+    }
+
+    // Only execute the `up` script once the network is up,
+    // in case we want to e.g. bring up images that need
+    // that same network.
+    let script_log_dir = config.scripts_logs_dir();
+    match config.up {
+        Some(UpScript::FullUpScript(FullUpScript {
+            before: Some(ref script),
+            ..
+        }))
+        | Some(UpScript::SimpleScript(ref script)) => {
+            let env = config.shared_env_variables()?;
+            telemetry::span(
+                "up script (before)",
+                &[],
+                script.run("up", &script_log_dir, &env),
+            )
+            .await
+            .context("Error running `up` script (before)")?;
+        }
+        _ => {}
+    }
+
+    telemetry::span("start postgres", &[], postgres::start(docker, config)).await?;
+    telemetry::span("start turn", &[], turn::start(docker, config)).await?;
+    telemetry::span("start mas", &[], mas::start(docker, config)).await?;
+    telemetry::span(
+        "start identity server",
+        &[],
+        identity::start(docker, config),
+    )
+    .await?;
+    telemetry::span("start push gateway", &[], push::start(docker, config)).await?;
+    telemetry::span("start email", &[], email::start(docker, config)).await?;
+    telemetry::span("start jaeger", &[], jaeger::start(docker, config)).await?;
+    telemetry::span("start sso", &[], sso::start(docker, config)).await?;
+    telemetry::span("start services", &[], services::start(docker, config)).await?;
+    telemetry::span("start homeserver", &[], start_homeserver(docker, config)).await?;
+    telemetry::span("start federation", &[], federation::up(docker, config)).await?;
+
+    let run_container_name = config.run_container_name();
+
+    let clients = if config.skip_registration {
+        println!("** skipping registration of users and rooms");
+        HashMap::new()
+    } else {
+        let clients = telemetry::span(
+            "register users",
+            &[],
+            register_users(docker, config, &run_container_name),
+        )
+        .await?;
+        telemetry::span("register ghosts", &[], appservice::register_ghosts(config))
+            .await
+            .context("Failed to register ghost users")?;
+        clients
+    };
+    if config.wait_for_background_updates {
+        println!("** waiting for background updates");
+        telemetry::span(
+            "wait for background updates",
+            &[],
+            admin::wait_for_background_updates(config),
+        )
+        .await?;
+        println!("** waiting for background updates success");
+    }
+    telemetry::span("start bots", &[], bots::start(docker, config, &clients)).await?;
+    if let Some(UpScript::FullUpScript(FullUpScript {
+        after: Some(ref script),
+        ..
+    })) = config.up
+    {
+        let env = config.shared_env_variables()?;
+        telemetry::span(
+            "up script (after)",
+            &[],
+            script.run("up", &script_log_dir, &env),
+        )
+        .await
+        .context("Error running `up` script (after)")?;
+    }
+
+    cleanup.disarm();
+
+    println!("* up step: success");
+    Ok(clients)
+}
+
+/// Generate the configuration of the homeserver, patch it and start the homeserver,
+/// then wait until it accepts requests.
+pub(crate) async fn start_homeserver(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let setup_container_name = config.setup_container_name();
+    let run_container_name = config.run_container_name();
+
+    // Create the synapse data directory.
+    // We'll use it as volume.
+    let synapse_data_directory = config.synapse_data_dir();
+    std::fs::create_dir_all(&synapse_data_directory)
+        .with_context(|| format!("Cannot create directory {:#?}", synapse_data_directory))?;
+    if config.artifacts.coverage.is_some() {
+        coverage::prepare(config)?;
+    }
+
+    // Cleanup leftovers.
+    let homeserver_path = config.homeserver_config_path();
+    let _ = std::fs::remove_file(&homeserver_path);
+
+    // Start a container to generate homeserver.yaml.
+    telemetry::span(
+        "generate homeserver.yaml",
+        &[("container", setup_container_name.clone())],
+        with_heartbeat(
+            "homeserver.yaml generation",
+            || describe_container(docker, &setup_container_name),
+            start_synapse_container(
+                docker,
+                config,
+                &setup_container_name,
+                generate_command(config),
+                false,
+            ),
+        ),
+    )
+    .await
+    .context("Couldn't generate homeserver.yaml")?;
+
+    // HACK: I haven't found a way to reuse the container with a different cmd
+    // (the API looks like it supports overriding cmds when creating an
+    // Exec but doesn't seem to actually implement this feature), so
+    // we stop and remove the container, we'll create a new one when
+    // we're ready to start Synapse.
+    debug!("done generating");
+    let runtime = config.docker.runtime.container_runtime();
+    let _ = runtime.stop_container(docker, &setup_container_name).await;
+    let _ = runtime
+        .remove_container(docker, &setup_container_name)
+        .await;
+    docker.wait_container_removed(&setup_container_name).await?;
+
+    appservice::write_registrations(config)
+        .context("Error writing the registrations of application services")?;
+
+    debug!("Updating homeserver.yaml");
+    // Apply config from mx-tester.yml to the homeserver.yaml that was just created
+    config
+        .patch_homeserver_config()
+        .context("Error updating homeserver config")?;
+
+    // Docker has a tendency to return before containers are fully torn down.
+    // Let's make extra-sure by waiting until the container is not running
+    // anymore *and* the ports are free.
+    with_heartbeat(
+        "setup container to stop",
+        || describe_container(docker, &setup_container_name),
+        async {
+            while docker.is_container_running(&setup_container_name).await? {
+                debug!(
+                    "Waiting until docker container {} is down before relaunching it",
+                    setup_container_name
+                );
+                tokio::time::sleep(std::time::Duration::new(5, 0)).await;
+            }
+            Ok::<(), Error>(())
+        },
+    )
+    .await?;
+
+    println!(
+        "** starting Synapse. Logs will be stored at {:?}",
+        config.logs_dir().join("docker").join("up-run-down.log")
+    );
+    telemetry::span(
+        "start homeserver container",
+        &[("container", run_container_name.clone())],
+        start_synapse_container(
+            docker,
+            config,
+            &run_container_name,
+            start_command(config),
+            true,
+        ),
+    )
+    .await
+    .context("Failed to start Synapse")?;
+
+    debug!("Synapse should now be launched");
+
+    // Wait until Synapse actually accepts requests before attempting to register
+    // users, so that a Synapse that never starts is reported as such, rather than
+    // as a registration failure.
+    //
+    // If Synapse crashes for good, i.e. it has exhausted its restart policy,
+    // there is no point waiting, so we fail immediately.
+    telemetry::span(
+        "wait for homeserver",
+        &[],
+        with_heartbeat(
+            "Synapse to accept connections",
+            || describe_container(docker, &run_container_name),
+            async {
+                tokio::select! {
+                    result = wait_until_ready(docker, config) => result,
+                    err = wait_for_crash(docker, &run_container_name) => Err(err),
+                }
+            },
+        ),
+    )
+    .await
+    .context("Synapse did not become ready")?;
+    println!("** Synapse is ready");
+    Ok(())
+}
+
+/// The environment of the scripts generating the configuration of the
+/// homeserver and starting it, with workers of `worker_types` in workers mode.
+pub(crate) fn synapse_env(config: &Config, worker_types: &[String]) -> Vec<String> {
+    let mut env = vec![
+        format!("SYNAPSE_SERVER_NAME={}", config.homeserver.server_name),
+        "SYNAPSE_REPORT_STATS=no".into(),
+        "SYNAPSE_CONFIG_DIR=/data".into(),
+        format!(
+            "SYNAPSE_HTTP_PORT={}",
+            if config.workers.enabled {
+                HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT
+            } else {
+                HARDCODED_GUEST_PORT
+            }
+        ),
+    ];
+    if config.workers.enabled {
+        env.push(format!("SYNAPSE_WORKER_TYPES={}", worker_types.join(",")));
+        env.push("SYNAPSE_WORKERS_WRITE_LOGS_TO_DISK=1".to_string());
+    }
+    env
+}
+
+/// The command generating the configuration of the homeserver.
+fn generate_command(config: &Config) -> Vec<String> {
+    match config.homeserver.kind {
+        HomeserverKind::Synapse if config.workers.enabled => {
+            vec!["/workers_start.py".to_string(), "generate".to_string()]
+        }
+        HomeserverKind::Synapse => vec!["/start.py".to_string(), "generate".to_string()],
+        // The signing key is kept across runs, as the data directory.
+        HomeserverKind::Dendrite => vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!(
+                "([ -f {key} ] || generate-keys --private-key {key}) && generate-config -dir /data -server {server_name} > /data/dendrite.yaml",
+                key = DENDRITE_PRIVATE_KEY,
+                server_name = config.homeserver.server_name,
+            ),
+        ],
+    }
+}
+
+/// The command starting the homeserver.
+fn start_command(config: &Config) -> Vec<String> {
+    match config.homeserver.kind {
+        HomeserverKind::Synapse if config.workers.enabled => {
+            vec!["/workers_start.py".to_string(), "start".to_string()]
+        }
+        // Start Synapse under debugpy, rather than through `start.py`, so that
+        // only Synapse itself listens for a debugger.
+        HomeserverKind::Synapse => match config.debug.python {
+            Some(ref debug) => vec![
+                "/usr/local/bin/python".to_string(),
+                "-m".to_string(),
+                "debugpy".to_string(),
+                "--listen".to_string(),
+                format!("0.0.0.0:{}", debug.port),
+                "-m".to_string(),
+                "synapse.app.homeserver".to_string(),
+                "--config-path".to_string(),
+                "/data/homeserver.yaml".to_string(),
+            ],
+            None => vec!["/start.py".to_string()],
+        },
+        // The binary was renamed from `dendrite-monolith-server` to `dendrite` in Dendrite 0.11.
+        HomeserverKind::Dendrite => vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!(
+                "exec \"$(command -v dendrite || command -v dendrite-monolith-server)\" --config /data/dendrite.yaml --http-bind-address :{}",
+                HARDCODED_GUEST_PORT
+            ),
+        ],
+    }
+}
+
+/// Register users and create rooms, as specified in `users`.
+pub(crate) async fn register_users(
+    docker: &Docker,
+    config: &Config,
+    run_container_name: &str,
+) -> Result<HashMap<String, matrix_sdk::Client>, Error> {
+    // We should now be able to register users.
+    //
+    // The `timeout` should make sure that we fail properly and with an understandable
+    // error message if registration hangs, e.g. because of an error in a module.
+    let registration = with_heartbeat(
+        "Synapse to accept connections and register users",
+        || describe_container(docker, run_container_name),
+        async {
+            tokio::select! {
+                result = handle_user_registration(config) => result.context("Failed to setup users"),
+                err = wait_for_crash(docker, run_container_name) => Err(err),
+            }
+        },
+    );
+
+    let clients = if config.workers.enabled {
+        // With workers, registration is so long that we don't want to timeou.
+        registration.await?
+    } else {
+        match tokio::time::timeout(TIMEOUT_USER_REGISTRATION_SIMPLE, registration).await {
+            Err(_) => {
+                // Timeout.
+                let is_running = docker.is_container_running(run_container_name).await?;
+                let health = docker.container_health(run_container_name).await?;
+                panic!(
+                    "User registration is taking too long. {is_running}",
+                    is_running = match (is_running, health) {
+                        (false, _) => "For some reason, the Docker image has stopped.",
+                        (true, Some(HealthStatusEnum::UNHEALTHY)) =>
+                            "Container is running but Synapse is unhealthy, please check the Synapse logs.",
+                        (true, _) =>
+                            "Container is running, so this is usually an error in Synapse or modules.",
+                    },
+                );
+            }
+            Ok(result) => result,
+        }?
+    };
+    if config.rebuild_user_directory {
+        admin::rebuild_user_directory(config).await?;
+    }
+    Ok(clients)
+}
+
+/// Bring things down.
+pub async fn down(docker: &Docker, config: &Config, status: Status) -> Result<(), Error> {
+    let run_container_name = config.run_container_name();
+    let runtime = config.docker.runtime.container_runtime();
+
+    println!("\n* down step: starting");
+
+    // Store results, we'll report them after we've brought down everything
+    // that we can bring down.
+    let script_log_dir = config.scripts_logs_dir();
+    let script_result = if let Some(ref down_script) = config.down {
+        let env = config.shared_env_variables()?;
+        // First run on_failure/on_success.
+        // Store errors for later.
+        let result = match (status, down_script) {
+            (
+                Status::Failure,
+                DownScript {
+                    failure: Some(ref on_failure),
+                    ..
+                },
+            ) => on_failure
+                .run("on_failure", &script_log_dir, &env)
+                .await
+                .context("Error while running script `down/failure`"),
+            (
+                Status::Success,
+                DownScript {
+                    success: Some(ref on_success),
+                    ..
+                },
+            ) => on_success
+                .run("on_success", &script_log_dir, &env)
+                .await
+                .context("Error while running script `down/success`"),
+            _ => Ok(()),
+        };
+        // Then run on_always.
+        if let Some(ref on_always) = down_script.finally {
+            result.and(
+                on_always
+                    .run("on_always", &script_log_dir, &env)
+                    .await
+                    .context("Error while running script `down/finally`"),
+            )
+        } else {
+            result
+        }
+    } else {
+        Ok(())
+    };
+
+    // Take down bots first, so that they don't spam their logs with
+    // errors once Synapse is down.
+    let bots_result = bots::stop(docker, config).await;
+    let federation_result = federation::down(docker, config).await;
+    let turn_result = turn::stop(docker, config).await;
+    let mas_result = mas::stop(docker, config).await;
+    let identity_result = identity::stop(docker, config).await;
+    let push_result = push::stop(docker, config).await;
+    let email_result = email::stop(docker, config).await;
+    let jaeger_result = jaeger::stop(docker, config).await;
+    let sso_result = sso::stop(docker, config).await;
+    let services_result = services::stop(docker, config).await;
+
+    debug!(target: "mx-tester-down", "Taking down synapse.");
+    let stop_container_result = match runtime.stop_container(docker, &run_container_name).await {
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code,
+        }) if (200..300).contains(&status_code) => {
+            debug!(target: "mx-tester-down", "Synapse container stopped: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 304,
+        }) => {
+            debug!(target: "mx-tester-down", "Synapse container was already down: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 404,
+        }) => {
+            debug!(target: "mx-tester-down", "Synapse container not found for stopping: {}", message);
+            Ok(())
+        }
+        Err(err) => Err(err).context("Error stopping container"),
+        Ok(_) => {
+            debug!(target: "mx-tester-down", "Synapse container stopped");
+            Ok(())
+        }
+    };
+
+    let remove_container_result = match runtime.remove_container(docker, &run_container_name).await
+    {
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code,
+        }) if (200..300).contains(&status_code) => {
+            debug!(target: "mx-tester-down", "Synapse container removed: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 304,
+        }) => {
+            debug!(target: "mx-tester-down", "Synapse container was already removed: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 404,
+        }) => {
+            debug!(target: "mx-tester-down", "Synapse container not found for removing: {}", message);
+            Ok(())
+        }
+        Err(err) => Err(err).context("Error removing container"),
+        Ok(_) => {
+            debug!(target: "mx-tester-down", "Synapse container removed");
+            Ok(())
+        }
+    };
+
+    // Take down the database once Synapse is down, so that it doesn't spam
+    // its logs with errors.
+    let postgres_result = postgres::stop(docker, config).await;
+
+    debug!(target: "mx-tester-down", "Taking down network.");
+    let remove_network_result = match runtime
+        .remove_network(docker, config.network().as_ref())
+        .await
+    {
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code,
+        }) if (200..300).contains(&status_code) => {
+            debug!(target: "mx-tester-down", "Network removed: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 304,
+        }) => {
+            debug!(target: "mx-tester-down", "Network was already removed: {}", message);
+            Ok(())
+        }
+        Err(bollard::errors::Error::DockerResponseServerError {
+            message,
+            status_code: 404,
+        }) => {
+            debug!(target: "mx-tester-down", "Network not found for removing: {}", message);
+            Ok(())
+        }
+        Err(err) => Err(err).context("Error removing network"),
+        Ok(_) => {
+            debug!(target: "mx-tester-down", "Network removed");
+            Ok(())
+        }
+    };
+
+    // Coverage data files are complete once Synapse has exited.
+    let coverage_result = match config.artifacts.coverage {
+        Some(_) => coverage::collect(config).context("Error collecting coverage"),
+        None => Ok(()),
+    };
+
+    // Upload artifacts once everything has been brought down, so that
+    // the logs are complete.
+    let upload_result = artifacts::upload(config, status).await;
+
+    // Check that we haven't left anything behind.
+    let _ = docker.wait_container_removed(&run_container_name).await;
+    let leaks_result = match Leaks::detect(docker, config).await {
+        Ok(leaks) => leaks.report(config.strict_leaks),
+        Err(err) => {
+            warn!("Could not check for leftover resources: {:?}", err);
+            Ok(())
+        }
+    };
+
+    println!("* down step: complete");
+    // Finally, report any problem.
+    script_result
+        .and(bots_result)
+        .and(federation_result)
+        .and(turn_result)
+        .and(mas_result)
+        .and(identity_result)
+        .and(push_result)
+        .and(email_result)
+        .and(jaeger_result)
+        .and(sso_result)
+        .and(services_result)
+        .and(stop_container_result)
+        .and(remove_container_result)
+        .and(postgres_result)
+        .and(remove_network_result)
+        .and(coverage_result)
+        .and(upload_result)
+        .and(leaks_result)
+}
+
+/// Remove any resources left behind by previous runs of the test.
+///
+/// If `check` is `true`, only report them.
+pub async fn clean(docker: &Docker, config: &Config, check: bool) -> Result<(), Error> {
+    println!("\n* clean step: starting");
+    let leaks = Leaks::detect(docker, config).await?;
+    if check {
+        leaks.report(config.strict_leaks)?;
+    } else {
+        leaks.report(false)?;
+        leaks.remove(docker).await?;
+    }
+    println!("* clean step: success");
+    Ok(())
+}
+
+/// Remove the resources left behind by all the tests of the project, not just
+/// this one, e.g. after aborted runs, except for tests currently run by
+/// another process.
+pub async fn clean_all(docker: &Docker, config: &Config, check: bool) -> Result<(), Error> {
+    println!("\n* clean --all step: starting");
+    let leaks = Leaks::detect_all(docker, &config.directories.root).await?;
+    if check {
+        leaks.report(config.strict_leaks)?;
+    } else {
+        leaks.report(false)?;
+        leaks.remove(docker).await?;
+    }
+    println!("* clean --all step: success");
+    Ok(())
+}
+
+/// Run the testing script.
+pub async fn run(docker: &Docker, config: &Config) -> Result<(), Error> {
+    println!("\n* run step: starting");
+    let profiler = profile::start(docker, config).await?;
+    let proxy = recording::start(config).await?;
+    let script_result = match config.run {
+        Some(ref code) => {
+            let env = config.shared_env_variables()?;
+            code.run("run", &config.scripts_logs_dir(), &env)
+                .await
+                .context("Error running `run` script")
+        }
+        None => Ok(()),
+    };
+    // Produce the flamegraph even if `run` failed, it may help understand why.
+    let profile_result = profile::stop(docker, config, profiler)
+        .await
+        .context("Error profiling Synapse");
+    let recording_result = recording::stop(config, proxy)
+        .await
+        .context("Error recording client traffic");
+    #[cfg(feature = "workers")]
+    let nginx_result =
+        crate::nginx::report(config).context("Error summarizing the access log of nginx");
+    #[cfg(not(feature = "workers"))]
+    let nginx_result: Result<(), Error> = Ok(());
+    let assertions_result = assertions::check_synapse_logs(config)
+        .context("Assertion `assertions.no_synapse_errors` failed");
+    script_result
+        .and(profile_result)
+        .and(recording_result)
+        .and(nginx_result)
+        .and(assertions_result)?;
+    println!("* run step: success");
+    Ok(())
+}
+
+/// Connect to the Docker daemon, as configured in `config.docker`.
+///
+/// If the daemon does not respond, retry with exponential backoff.
+pub async fn connect(config: &Config) -> Result<Docker, Error> {
+    let runtime = config.docker.runtime.container_runtime();
+    let docker = runtime.connect(config)?;
+
+    // Test that we can connect to Docker.
+    let mut backoff = std::time::Duration::from_millis(config.docker.connect_backoff_ms);
+    let mut attempt = 0;
+    let version = loop {
+        match docker.version().await {
+            Ok(version) => break version,
+            Err(err) if attempt < config.docker.connect_retries => {
+                attempt += 1;
+                println!(
+                    "** could not connect to {} daemon ({}), retrying in {:?} ({}/{})",
+                    runtime.name(),
+                    err,
+                    backoff,
+                    attempt,
+                    config.docker.connect_retries
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return Err(err).context("Checking connection to docker daemon"),
+        }
+    };
+    println!(
+        "Using {} {}",
+        runtime.name(),
+        version.version.as_deref().unwrap_or("?")
+    );
+    Ok(docker)
+}
+
+/// Wait until a container has stopped for good, i.e. it is not running and
+/// Docker will not restart it, then return the reason.
+async fn wait_for_crash(docker: &Docker, name: &str) -> Error {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let state = match docker.inspect_container(name, None).await {
+            Ok(response) => response.state,
+            Err(err) => return anyhow!("Synapse container has disappeared: {}", err),
+        };
+        let state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        if state.status != Some(ContainerStateStatusEnum::EXITED)
+            && state.status != Some(ContainerStateStatusEnum::DEAD)
+        {
+            continue;
+        }
+        let tail = logs_tail(docker, name).await;
+        return anyhow!(
+            "Synapse has crashed with exit code {}{}, giving up.\nLast lines of logs:\n{}",
+            state.exit_code.unwrap_or_default(),
+            match state.error {
+                Some(ref error) if !error.is_empty() => format!(" ({})", error),
+                _ => String::new(),
+            },
+            tail
+        );
+    }
+}
+
+/// Wait until Synapse accepts requests on the host port and, in workers mode,
+/// until the main process accepts requests within the container.
+///
+/// Fails with the last error encountered if `docker.readiness_timeout_sec` is exceeded.
+async fn wait_until_ready(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let timeout = std::time::Duration::from_secs(config.docker.readiness_timeout_sec);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let client = reqwest::Client::new();
+    let host = config.docker.published_host();
+    let url = match config.homeserver.kind {
+        HomeserverKind::Synapse => {
+            format!("http://{}:{}/health", host, config.homeserver.host_port)
+        }
+        HomeserverKind::Dendrite => format!(
+            "http://{}:{}/_matrix/client/versions",
+            host, config.homeserver.host_port
+        ),
+    };
+    let container_name = config.run_container_name();
+    loop {
+        let err = match check_ready(docker, config, &client, &url).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        debug!("Synapse is not ready yet: {:#}", err);
+        if tokio::time::Instant::now() >= deadline {
+            let state = describe_container(docker, &container_name)
+                .await
+                .unwrap_or_else(|| "container state unknown".to_string());
+            let tail = logs_tail(docker, &container_name).await;
+            return Err(err.context(format!(
+                "Synapse is still not ready after {}s ({}).\nLast lines of logs:\n{}",
+                timeout.as_secs(),
+                state,
+                tail
+            )));
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+/// Check once whether Synapse is ready to accept requests.
+async fn check_ready(
+    docker: &Docker,
+    config: &Config,
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<(), Error> {
+    let response = client
+        .get(url)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .with_context(|| format!("Could not connect to {}", url))?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} responded with {}", url, response.status()));
+    }
+    if config.workers.enabled {
+        // The main process port is not mapped on the host, so check from within the container.
+        let url = format!(
+            "http://localhost:{}/health",
+            HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT
+        );
+        let (exit_code, output) = exec(
+            docker,
+            &config.run_container_name(),
+            vec!["curl", "-fsS", &url],
+        )
+        .await
+        .context("Could not check the main process")?;
+        if exit_code != 0 {
+            return Err(anyhow!(
+                "Main process is not ready at {} (in container): {}",
+                url,
+                output.trim()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that all the host ports we're going to map are available.
+///
+/// If a port is busy, try to name the container or process that holds it.
+async fn check_ports_available(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let ports = doctor::host_ports(config);
+    if let Some((port, users)) = ports
+        .iter()
+        .into_group_map_by(|(_, port)| *port)
+        .into_iter()
+        .find(|(_, users)| users.len() > 1)
+    {
+        return Err(anyhow!(
+            "Host port {} is mapped several times, by {}",
+            port,
+            users.iter().map(|(what, _)| what).join(", ")
+        ));
+    }
+    for (what, port) in ports {
+        let port = u16::try_from(port)
+            .with_context(|| format!("Invalid host port {} for {}", port, what))?;
+        if std::net::TcpListener::bind(("0.0.0.0", port)).is_ok() {
+            continue;
+        }
+        let culprit = match port_owner_container(docker, port).await {
+            Some(container) if container == config.run_container_name() => format!(
+                "by container {} from a previous `mx-tester up`, use `mx-tester down` to stop it",
+                container
+            ),
+            Some(container) => format!("by container {}", container),
+            None => match port_owner_process(port).await {
+                Some(process) => format!("by process {}", process),
+                None => "by another process".to_string(),
+            },
+        };
+        return Err(anyhow!("Host port {} is already in use {}", port, culprit));
+    }
+    Ok(())
+}
+
+/// The name of the container publishing a host port, if any.
+async fn port_owner_container(docker: &Docker, port: u16) -> Option<String> {
+    let containers = docker
+        .list_containers(None::<ListContainersOptions<String>>)
+        .await
+        .ok()?;
+    containers
+        .into_iter()
+        .find(|container| {
+            container
+                .ports
+                .iter()
+                .flatten()
+                .any(|published| published.public_port == Some(i64::from(port)))
+        })?
+        .names?
+        .into_iter()
+        .next()
+        .map(|name| name.trim_start_matches('/').to_string())
+}
+
+/// The process listening on a host port, e.g. `nginx (pid 1234)`, if `lsof` can tell.
+async fn port_owner_process(port: u16) -> Option<String> {
+    let output = tokio::process::Command::new("lsof")
+        .args(["-nP", "-sTCP:LISTEN", "-Fpc"])
+        .arg(format!("-iTCP:{}", port))
+        .output()
+        .await
+        .ok()?;
+    // With `-Fpc`, lsof prints one field per line, prefixed with `p` (pid) or `c` (command).
+    let output = String::from_utf8_lossy(&output.stdout);
+    let pid = output.lines().find_map(|line| line.strip_prefix('p'))?;
+    let command = output
+        .lines()
+        .find_map(|line| line.strip_prefix('c'))
+        .unwrap_or("?");
+    Some(format!("{} (pid {})", command, pid))
+}
+
+/// The last lines of logs of a container, for error messages.
+async fn logs_tail(docker: &Docker, name: &str) -> String {
+    let mut logs = docker.logs(
+        name,
+        Some(LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            tail: CRASH_LOG_LINES.to_string(),
+            ..LogsOptions::default()
+        }),
+    );
+    let mut tail = String::new();
+    while let Some(Ok(line)) = logs.next().await {
+        tail.push_str(&format!("{}", line));
+    }
+    tail
+}
+
+/// Describe the state of a container, for progress messages.
+pub(crate) async fn describe_container(docker: &Docker, name: &str) -> Option<String> {
+    match docker.is_container_running(name).await {
+        Ok(true) => match docker.container_health(name).await {
+            Ok(Some(health)) => Some(format!("container running, {}", health)),
+            _ => Some("container running".to_string()),
+        },
+        Ok(false) => Some("container not running".to_string()),
+        Err(_) => None,
+    }
+}
+
+/// Utility methods for `Docker`.
+#[async_trait::async_trait]
+pub(crate) trait DockerExt {
+    /// Check whether a network exists.
+    async fn is_network_up(&self, name: &str) -> Result<bool, Error>;
+
+    /// Check whether a container is currently running.
+    async fn is_container_running(&self, name: &str) -> Result<bool, Error>;
+
+    /// Check the health of a container, as reported by its healthcheck.
+    ///
+    /// Returns `None` if the container doesn't exist or doesn't have a healthcheck.
+    /// Note that a container may be running while being unhealthy, e.g. if Synapse
+    /// is wedged.
+    async fn container_health(&self, name: &str) -> Result<Option<HealthStatusEnum>, Error>;
+
+    /// Check whether a container is currently running and not unhealthy.
+    ///
+    /// A container without a healthcheck, or whose healthcheck hasn't concluded
+    /// yet, is considered healthy as long as it's running.
+    async fn is_container_healthy(&self, name: &str) -> Result<bool, Error>;
+
+    async fn wait_container_removed(&self, name: &str) -> Result<(), Error>;
+}
+
+#[async_trait::async_trait]
+impl DockerExt for Docker {
+    /// Check whether a network exists.
+    async fn is_network_up(&self, name: &str) -> Result<bool, Error> {
+        let networks = self
+            .list_networks(Some(ListNetworksOptions {
+                filters: vec![("name", vec![name])].into_iter().collect(),
+            }))
+            .await?;
+        // `filters` actually filter by substring, so we need to double-check the result.
+        debug!("is_network_up {:#?}", networks);
+        Ok(networks
+            .into_iter()
+            .filter_map(|network| network.name)
+            .any(|candidate_name| candidate_name.as_str() == name))
+    }
+
+    /// Check whether a container is currently running.
+    async fn is_container_running(&self, name: &str) -> Result<bool, Error> {
+        let containers = self
+            .list_containers(Some(ListContainersOptions {
+                // Check for running containers only
+                all: false,
+                filters: vec![("name", vec![name])].into_iter().collect(),
+                ..ListContainersOptions::default()
+            }))
+            .await?;
+        // `filters` actually filter by substring, so we need to double-check the result.
+        debug!("is_container_running {:#?}", containers);
+        let found = containers
+            .into_iter()
+            .flat_map(|container| container.names)
+            .flat_map(|names| names.into_iter())
+            .any(|container_name| container_name.as_str() == name);
+        Ok(found)
+    }
+
+    /// Check the health of a container, as reported by its healthcheck.
+    async fn container_health(&self, name: &str) -> Result<Option<HealthStatusEnum>, Error> {
+        let response = match self.inspect_container(name, None).await {
+            Ok(response) => response,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not inspect container {}", name))
+            }
+        };
+        debug!("container_health {:#?}", response.state);
+        Ok(response
+            .state
+            .and_then(|state| state.health)
+            .and_then(|health| health.status)
+            .filter(|status| !matches!(status, HealthStatusEnum::EMPTY | HealthStatusEnum::NONE)))
+    }
+
+    /// Check whether a container is currently running and not unhealthy.
+    async fn is_container_healthy(&self, name: &str) -> Result<bool, Error> {
+        if !self.is_container_running(name).await? {
+            return Ok(false);
+        }
+        Ok(!matches!(
+            self.container_health(name).await?,
+            Some(HealthStatusEnum::UNHEALTHY)
+        ))
+    }
+
+    async fn wait_container_removed(&self, name: &str) -> Result<(), Error> {
+        let mut stream = self.wait_container(
+            name,
+            Some(WaitContainerOptions {
+                condition: "removed",
+            }),
+        );
+        'waiting: while let Some(result) = stream.next().await {
+            match result {
+                Ok(bollard::models::ContainerWaitResponse {
+                    error: Some(error), ..
+                }) => {
+                    return Err(anyhow!(
+                        "Error while waiting for container {} to be removed: {:?}",
+                        name,
+                        error
+                    ));
+                }
+                Ok(_) => {
+                    // In progress.
+                }
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 304, ..
+                }) => {
+                    // We started waiting too late, the container is already removed.
+                    break 'waiting;
+                }
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404, ..
+                }) => {
+                    // We started waiting too late, the container is already removed.
+                    break 'waiting;
+                }
+                Err(err) => {
+                    return Err(err)
+                        .context(format!("Waiting for container {} to be removed", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
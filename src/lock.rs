@@ -0,0 +1,155 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Preventing concurrent runs of the same test.
+//!
+//! Two processes running the same configuration share `test_root()` and the
+//! same containers, so they would corrupt each other's data. Each process
+//! holds an advisory lock on `<name>.lock`, next to `test_root()` rather than
+//! inside, since `build` removes `test_root()`. The lock is released when the
+//! process exits, even if it crashes.
+
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use log::debug;
+
+use crate::Config;
+
+/// An exclusive lock on the test root, held until dropped.
+#[derive(Debug)]
+pub struct TestLock {
+    /// The lock file, never read. Closing it releases the lock.
+    _file: std::fs::File,
+    path: PathBuf,
+}
+
+impl TestLock {
+    /// The lock file of a test.
+    pub fn path(config: &Config) -> PathBuf {
+        let test_root = config.test_root();
+        let mut file_name = test_root
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_default();
+        file_name.push(".lock");
+        test_root.with_file_name(file_name)
+    }
+
+    /// Lock the test root.
+    ///
+    /// If another process holds the lock, fail or, if `wait` is `true`, wait
+    /// until it is released.
+    pub async fn acquire(config: &Config, wait: bool) -> Result<Self, Error> {
+        let path = Self::path(config);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create directory {:?}", parent))?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Keep the pid of the current holder, if any, until we hold the lock.
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Could not open lock file {:?}", path))?;
+        let mut file = if try_lock(&file)? {
+            file
+        } else if wait {
+            println!(
+                "* waiting for another mx-tester process ({}) to release {:?}",
+                holder(&path),
+                path
+            );
+            tokio::task::spawn_blocking(move || lock(&file).map(|_| file))
+                .await
+                .context("Could not wait for the lock")??
+        } else {
+            return Err(anyhow!(
+                "Another mx-tester process ({}) is using {:?}, as per lock file {:?}. Use `--wait-lock` to wait until it is done",
+                holder(&path),
+                config.test_root(),
+                path
+            ));
+        };
+        // Let other processes know who holds the lock.
+        file.set_len(0)
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .with_context(|| format!("Could not write lock file {:?}", path))?;
+        debug!("Acquired lock {:?}", path);
+        Ok(TestLock { _file: file, path })
+    }
+
+    /// The lock file.
+    pub fn lock_path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+/// Whether another process currently holds the lock file `path`.
+pub fn is_locked(path: &std::path::Path) -> Result<bool, Error> {
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Could not open lock file {:?}", path))
+        }
+    };
+    // If we get the lock, closing the file releases it immediately.
+    Ok(!try_lock(&file)?)
+}
+
+/// A description of the process holding a lock, as written in the lock file.
+fn holder(path: &std::path::Path) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+        _ => "unknown pid".to_string(),
+    }
+}
+
+/// Attempt to lock a file, returning `false` if another process holds the lock.
+#[cfg(unix)]
+fn try_lock(file: &std::fs::File) -> Result<bool, Error> {
+    use nix::{errno::Errno, fcntl::FlockArg};
+    use std::os::unix::io::AsRawFd;
+    match nix::fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(err) => Err(err).context("Could not lock file"),
+    }
+}
+
+/// Lock a file, waiting until no other process holds the lock.
+#[cfg(unix)]
+fn lock(file: &std::fs::File) -> Result<(), Error> {
+    use nix::fcntl::FlockArg;
+    use std::os::unix::io::AsRawFd;
+    nix::fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusive).context("Could not lock file")
+}
+
+/// Advisory locks are not supported on this platform, so we don't lock.
+#[cfg(not(unix))]
+fn try_lock(_file: &std::fs::File) -> Result<bool, Error> {
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &std::fs::File) -> Result<(), Error> {
+    Ok(())
+}
@@ -12,23 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::borrow::Cow;
-
 use anyhow::Context;
 use clap::command;
 use log::*;
-use mx_tester::*;
+use mx_tester::{
+    commands::{select_commands, Command, Progress},
+    *,
+};
 
 const CONFIG_PATH_AUTOTEST: &str = "[empty]";
 
-#[derive(Debug)]
-enum Command {
-    Build,
-    Up,
-    Run,
-    Down,
-}
-
 #[tokio::main]
 async fn main() {
     use clap::Arg;
@@ -42,7 +35,7 @@ async fn main() {
                 .long("config")
                 .global(true)
                 .default_value("mx-tester.yml")
-                .help("The file containing the test configuration. Pass `[empty]` to run an empty mx-tester.yml, for self-testing."),
+                .help("The file containing the test configuration, in YAML or, if its extension is `.toml` or `.json`, TOML or JSON. Pass `[empty]` to run an empty mx-tester.yml, for self-testing."),
         )
         .arg(
             // Note: `multiple_ocurences` is deprecated but `ArgAction::Append` doesn't actually replace it.
@@ -54,6 +47,28 @@ async fn main() {
                 .value_parser(["up", "run", "down", "build"])
                 .help("The list of commands to run. Order matters and the same command may be repeated."),
         )
+        .arg(
+            Arg::new("only")
+                .long("only")
+                .value_name("COMMAND")
+                .action(clap::ArgAction::Append)
+                .value_parser(["build", "up", "run", "down"])
+                .conflicts_with("skip-build")
+                .help("Only run this command, e.g. `--only run` to iterate on the run script against a homeserver that is already up. May be repeated. If commands are specified, only keep those among them.")
+        )
+        .arg(
+            Arg::new("skip-build")
+                .long("skip-build")
+                .takes_value(false)
+                .requires("command")
+                .help("Do not run `build`, e.g. because the image is already built. Requires an explicit list of commands, as the default commands don't include `build`.")
+        )
+        .arg(
+            Arg::new("skip-registration")
+                .long("skip-registration")
+                .takes_value(false)
+                .help("During `up`, do not register users or create rooms, e.g. because they already exist.")
+        )
         .arg(
             Arg::new("username")
                 .short('u')
@@ -80,6 +95,15 @@ async fn main() {
                 .required(false)
                 .help("A server name for the Docker registry")
         )
+        .arg(
+            Arg::new("registry_profile")
+                .long("registry-profile")
+                .global(true)
+                .takes_value(true)
+                .value_name("NAME")
+                .required(false)
+                .help("Log to the Docker registry with profile NAME of `registry_profiles`. Overridden by `--server`, `--username` and `--password`.")
+        )
         .arg(
             Arg::new("root_dir")
                 .long("root")
@@ -87,7 +111,7 @@ async fn main() {
                 .value_name("PATH")
                 .takes_value(true)
                 .required(false)
-                .help("Write all files in subdirectories of this directory (default: /tmp)")
+                .help("Write all files in subdirectories of this directory (default: `directories.root` or a directory specific to the configuration file in ~/.cache/mx-tester)")
         )
         .arg(
             Arg::new("workers")
@@ -113,54 +137,685 @@ async fn main() {
                 .takes_value(false)
                 .help("If specified, do NOT clean up containers in case of error")
         )
+        .arg(
+            Arg::new("runtime")
+                .long("runtime")
+                .global(true)
+                .value_parser(["docker", "podman"])
+                .help("The container runtime. If `podman`, connect to the Podman API service at `CONTAINER_HOST` or at the default socket, e.g. the rootless socket of the current user. Overrides `docker.runtime`, default `docker`.")
+        )
         .arg(
             Arg::new("docker-ssl")
                 .long("docker-ssl")
                 .global(true)
-                .default_value("detect")
                 .value_parser(["always", "never", "detect"])
-                .help("If `detect`, attempt to auto-detect a SSL configuration and fallback tp HTTP otherwise. This may be broken in your CI. If `always`, fail if there is no Docker SSL configuration. If `never`, ignore any Docker SSL configuration.")
+                .help("If `detect`, attempt to auto-detect a SSL configuration and fallback tp HTTP otherwise. This may be broken in your CI. If `always`, fail if there is no Docker SSL configuration. If `never`, ignore any Docker SSL configuration. Overrides `docker.ssl`, default `detect`.")
+        )
+        .arg(
+            Arg::new("docker-timeout")
+                .long("docker-timeout")
+                .global(true)
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("The timeout for requests to the Docker daemon. Overrides `docker.timeout_sec`, default 600.")
+        )
+        .arg(
+            Arg::new("docker-connect-retries")
+                .long("docker-connect-retries")
+                .global(true)
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(u32))
+                .help("How many times to retry connecting to the Docker daemon. Overrides `docker.connect_retries`, default 0.")
+        )
+        .arg(
+            Arg::new("docker-connect-backoff")
+                .long("docker-connect-backoff")
+                .global(true)
+                .value_name("MILLISECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("How long to wait before the first retry to connect to the Docker daemon, doubling with each retry. Overrides `docker.connect_backoff_ms`, default 1000.")
         )
-         .get_matches();
+        .arg(
+            Arg::new("module-config")
+                .long("module-config")
+                .global(true)
+                .value_name("MODULE=PATH")
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(String))
+                .required(false)
+                .help("Replace the `config` block of module MODULE with the contents of yaml file PATH. Applied during `up`, no need to rebuild. May be repeated.")
+        )
+        .arg(
+            Arg::new("annotate")
+                .long("annotate")
+                .global(true)
+                .value_parser(["none", "github"])
+                .default_value("none")
+                .help("Annotate the output for a CI environment. If `github`, group the output of each step and report failures, including Synapse tracebacks, as GitHub Actions errors.")
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .global(true)
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("The format of the output. If `json`, report the start and end of each step, with durations, errors and log paths, as JSON lines on stdout, and print everything else on stderr.")
+        )
+        .arg(
+            Arg::new("upload-artifacts-on")
+                .long("upload-artifacts-on")
+                .global(true)
+                .value_parser(["always", "failure-only"])
+                .required(false)
+                .help("When to upload artifacts to the bucket specified in `artifacts.upload.url`. Overrides `artifacts.upload.on`.")
+        )
+        .arg(
+            Arg::new("strict-leaks")
+                .long("strict-leaks")
+                .global(true)
+                .takes_value(false)
+                .required(false)
+                .help("If specified, fail if `down` or `clean --check` find containers, networks, images, volumes or temporary files left behind by the test (default: just warn).")
+        )
+        .arg(
+            Arg::new("instance")
+                .long("instance")
+                .global(true)
+                .value_name("ID")
+                .value_parser(clap::value_parser!(String))
+                .required(false)
+                .help("Run instance ID of the test, with its own image, network, containers, test directory and free host ports, so that several instances may run in parallel. With `auto`, a new instance for each run of mx-tester.")
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .global(true)
+                .value_name("SEED")
+                .value_parser(clap::value_parser!(u64))
+                .required(false)
+                .help("Expand placeholders `${random}` and `${seq}` in `users` with this seed, e.g. to reproduce the users and rooms of a previous run (default: a new seed for each `up`).")
+        )
+        .arg(
+            Arg::new("otlp-endpoint")
+                .long("otlp-endpoint")
+                .global(true)
+                .takes_value(true)
+                .required(false)
+                .help("Export OpenTelemetry traces of `build`, `up`, `run` and `down` to this OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces (default: `telemetry.otlp_endpoint` or $OTEL_EXPORTER_OTLP_ENDPOINT).")
+        )
+        .arg(
+            Arg::new("wait-lock")
+                .long("wait-lock")
+                .global(true)
+                .takes_value(false)
+                .required(false)
+                .help("If another mx-tester process is running `build`, `up`, `run` or `down` for the same test, wait until it is done (default: fail immediately).")
+        )
+        .subcommand(
+            clap::Command::new("init")
+                .about("Write a starter mx-tester.yml (or the file passed with `--config`) and example scripts in directory `mx-tester`")
+                .arg(
+                    Arg::new("template")
+                        .long("template")
+                        .value_parser(["module", "bot", "appservice"])
+                        .default_value("module")
+                        .help("What is being tested: a Synapse module, a bot or an application service")
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .help("The name of the test (default: derived from the name of the current directory)")
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .takes_value(false)
+                        .help("Overwrite existing files")
+                )
+        )
+        .subcommand(
+            clap::Command::new("clean")
+                .about("Remove containers, networks, untagged images, volumes and temporary files left behind by previous runs of the test")
+                .arg(
+                    Arg::new("check")
+                        .long("check")
+                        .takes_value(false)
+                        .help("Only report leftovers, do not remove them")
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .takes_value(false)
+                        .help("Remove the leftovers of all the tests of this project, not just this one, including the images built for them, except for tests currently run by another mx-tester process")
+                )
+        )
+        .subcommand(
+            clap::Command::new("bench")
+                .about("Run `bench.script` (or `run`) several times against a homeserver that is up, measure the latency of `bench.endpoints` and compare against the baseline")
+                .arg(
+                    Arg::new("iterations")
+                        .long("iterations")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help("Override `bench.iterations`")
+                )
+                .arg(
+                    Arg::new("update-baseline")
+                        .long("update-baseline")
+                        .takes_value(false)
+                        .help("Store the result as the new baseline rather than comparing against it")
+                )
+        )
+        .subcommand(
+            clap::Command::new("replay")
+                .about("Perform the client calls recorded during a `run` with `recording`, as the same users, against a homeserver that is up")
+                .arg(
+                    Arg::new("recording")
+                        .value_name("FILE")
+                        .required(true)
+                        .value_parser(clap::value_parser!(std::path::PathBuf))
+                        .help("The recording, e.g. `logs/recording.json` in the test root of a previous run")
+                )
+        )
+        .subcommand(
+            clap::Command::new("fuzz-config")
+                .about("Experimental. For each combination of the values of `fuzz_config.axes` in homeserver.yaml, e.g. encryption, presence and room versions, run `up`, `run` and `down` against the image built by `build`, and report which combinations break")
+        )
+        .subcommand(
+            clap::Command::new("abuse")
+                .about("Perform the abusive traffic of `abuse` as throwaway users against a homeserver that is up, e.g. to test anti-abuse modules")
+                .arg(
+                    Arg::new("scenario")
+                        .value_name("NAME")
+                        .multiple_values(true)
+                        .help("The scenarios to perform, in order (default: all of them)")
+                )
+        )
+        .subcommand(
+            clap::Command::new("snapshot")
+                .about("Store the database and media store of a homeserver that is up as snapshot NAME, e.g. once users and rooms are seeded, replacing any previous snapshot with this name")
+                .arg(
+                    Arg::new("name")
+                        .value_name("NAME")
+                        .required(true)
+                        .help("The name of the snapshot, e.g. `seeded`")
+                )
+        )
+        .subcommand(
+            clap::Command::new("restore")
+                .about("Replace the database and media store of a homeserver that is up with snapshot NAME, e.g. after `up --skip-registration`, and wait until Synapse is ready")
+                .arg(
+                    Arg::new("name")
+                        .value_name("NAME")
+                        .required(true)
+                        .help("The name of the snapshot, as passed to `snapshot`")
+                )
+        )
+        .subcommand(
+            clap::Command::new("shell")
+                .about("Open an interactive session in the container of a homeserver that is up, in directory `/data`, e.g. to inspect its data or logs")
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .value_name("USER")
+                        .help("Run as USER, e.g. `root` (default: the user of the container)")
+                )
+                .arg(
+                    Arg::new("command")
+                        .value_name("COMMAND")
+                        .multiple_values(true)
+                        .allow_hyphen_values(true)
+                        .help("The command to run, e.g. `cat homeserver.yaml` (default: bash or sh)")
+                )
+        )
+        .subcommand(
+            clap::Command::new("exec")
+                .about("Run a command in the container of a homeserver that is up, in directory `/data`, with the environment variables of mx-tester, capturing its output in the logs, e.g. `mx-tester exec -- python -m my_module.admin`")
+                .arg(
+                    Arg::new("command")
+                        .value_name("COMMAND")
+                        .required(true)
+                        .multiple_values(true)
+                        .allow_hyphen_values(true)
+                        .help("The command to run")
+                )
+        )
+        .subcommand(
+            clap::Command::new("validate")
+                .alias("check")
+                .about("Check mx-tester.yml for errors, e.g. unknown fields, missing scripts, room members that are not declared in `users` or duplicate aliases, without touching Docker")
+        )
+        .subcommand(
+            clap::Command::new("doctor")
+                .about("Check the environment, i.e. the connection to Docker, whether mx-tester runs in a container (Docker-outside-of-Docker) and how its directories are bind-mounted, the test root, free disk space, ports, memory and user namespaces, and explain how to fix problems")
+        )
+        .subcommand(
+            clap::Command::new("watch")
+                .about("Watch the source code of modules, i.e. their `path`, and, whenever it changes, run their `build` script, reinstall them in the running homeserver and restart it, without bringing anything down. Requires `up`.")
+        )
+        .subcommand(
+            clap::Command::new("admin")
+                .about("Administrative operations on a homeserver that is already up")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("restart")
+                        .about("Restart Synapse, preserving data, e.g. to take into account changes to modules installed in editable mode")
+                )
+                .subcommand(
+                    clap::Command::new("wait-for-background-updates")
+                        .about("Wait until Synapse has completed its background updates, e.g. from a `run` script before asserting on admin APIs")
+                )
+                .subcommand(
+                    clap::Command::new("reports")
+                        .about("Event reports filed with the homeserver, e.g. by moderation modules")
+                        .subcommand_required(true)
+                        .subcommand(
+                            clap::Command::new("list")
+                                .about("Print the event reports as JSON, most recent first")
+                                .arg(
+                                    Arg::new("room")
+                                        .long("room")
+                                        .value_name("ROOM")
+                                        .help("Only list reports about events of ROOM, a room id or alias")
+                                )
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("room-state")
+                        .about("Print the current state of a room as JSON")
+                        .arg(
+                            Arg::new("room")
+                                .value_name("ROOM")
+                                .required(true)
+                                .help("A room id or alias")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("reload-config")
+                        .about("Patch homeserver.yaml and restart Synapse, preserving data")
+                        .arg(
+                            Arg::new("set")
+                                .long("set")
+                                .value_name("KEY=VALUE")
+                                .action(clap::ArgAction::Append)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A change to apply, e.g. `presence.enabled=false`. Values are parsed as YAML. May be repeated.")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("scale-workers")
+                        .about("In workers mode, start or stop workers, regenerating the upstreams of nginx")
+                        .arg(
+                            Arg::new("workers")
+                                .value_name("WORKER_TYPE=COUNT")
+                                .action(clap::ArgAction::Append)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("The number of workers of a type, e.g. `synchrotron=2` or `federation_sender=0`. May be repeated.")
+                        )
+                )
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Inspect configurations")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("compare")
+                        .about("Compare the effective homeserver configuration, Synapse version and module versions of two runs")
+                        .arg(
+                            Arg::new("run_a")
+                                .required(true)
+                                .value_name("RUN_A")
+                                .value_parser(clap::value_parser!(std::path::PathBuf))
+                                .help("The manifest.json of the first run, or the directory containing it")
+                        )
+                        .arg(
+                            Arg::new("run_b")
+                                .required(true)
+                                .value_name("RUN_B")
+                                .value_parser(clap::value_parser!(std::path::PathBuf))
+                                .help("The manifest.json of the second run, or the directory containing it")
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .value_parser(["text", "json"])
+                                .default_value("text")
+                                .help("The output format")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("print")
+                        .about("Print the configuration as YAML, with all defaults filled in")
+                        .arg(
+                            Arg::new("effective")
+                                .long("effective")
+                                .takes_value(false)
+                                .help("Also apply the command-line overrides, e.g. `--workers` or `--synapse-tag`, to print exactly what mx-tester will do")
+                        )
+                )
+        )
+        .subcommand(
+            clap::Command::new("simulate")
+                .about("Act as one of the users declared in `users`, e.g. to test modules or bots reacting to EDUs")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .value_name("LOCALNAME")
+                        .required(true)
+                        .value_parser(clap::value_parser!(String))
+                        .help("The localname of the user, as declared in `users`")
+                )
+                .subcommand(
+                    clap::Command::new("presence")
+                        .about("Set the presence of the user")
+                        .arg(
+                            Arg::new("state")
+                                .required(true)
+                                .value_parser(["online", "offline", "unavailable"])
+                        )
+                        .arg(
+                            Arg::new("status")
+                                .long("status")
+                                .value_name("MESSAGE")
+                                .value_parser(clap::value_parser!(String))
+                                .help("An optional status message")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("typing")
+                        .about("Send a typing notification")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("timeout")
+                                .long("timeout")
+                                .value_name("SECONDS")
+                                .default_value("30")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("How long the user should be shown as typing")
+                        )
+                        .arg(
+                            Arg::new("stop")
+                                .long("stop")
+                                .takes_value(false)
+                                .conflicts_with("timeout")
+                                .help("Stop typing instead of starting")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("call")
+                        .about("Place a complete VoIP call (invite, candidates, answer, hangup) to another user")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("callee")
+                                .long("callee")
+                                .value_name("LOCALNAME")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("The localname of the user answering the call, as declared in `users`")
+                        )
+                        .arg(
+                            Arg::new("sdp")
+                                .long("sdp")
+                                .value_name("PATH")
+                                .required(true)
+                                .value_parser(clap::value_parser!(std::path::PathBuf))
+                                .help("A yaml file containing the SDP `offer`, `answer` and optionally `caller_candidates`, `callee_candidates`")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("redact")
+                        .about("Redact a message seeded during `up` and wait until the redaction is visible")
+                        .arg(
+                            Arg::new("label")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("The label of the message, as declared in `messages`")
+                        )
+                        .arg(
+                            Arg::new("reason")
+                                .long("reason")
+                                .value_parser(clap::value_parser!(String))
+                                .help("An optional reason for the redaction")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("race-state")
+                        .about("Make users send conflicting state events concurrently, e.g. to exercise state resolution")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("with")
+                                .long("with")
+                                .value_name("LOCALNAME")
+                                .action(clap::ArgAction::Append)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("Another user racing, as declared in `users`. May be repeated.")
+                        )
+                        .arg(
+                            Arg::new("state")
+                                .long("state")
+                                .default_value("topic")
+                                .value_parser(["topic", "power-levels"])
+                                .help("The state event to fight over")
+                        )
+                        .arg(
+                            Arg::new("rounds")
+                                .long("rounds")
+                                .default_value("10")
+                                .value_parser(clap::value_parser!(usize))
+                                .help("How many events each user should send")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("knock")
+                        .about("Knock on a room, i.e. request an invite")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("reason")
+                                .long("reason")
+                                .value_parser(clap::value_parser!(String))
+                                .help("An optional reason for knocking")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("approve-knock")
+                        .about("Approve a knock on a room, i.e. invite the user who knocked")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("knocker")
+                                .long("knocker")
+                                .value_name("LOCALNAME")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("The localname of the user who knocked, as declared in `users`")
+                        )
+                )
+                .subcommand(
+                    clap::Command::new("receipt")
+                        .about("Send a read receipt")
+                        .arg(
+                            Arg::new("room")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("A room id or alias")
+                        )
+                        .arg(
+                            Arg::new("event")
+                                .required(true)
+                                .value_parser(clap::value_parser!(String))
+                                .help("The id of the event being read")
+                        )
+                )
+        )
+        .subcommand_precedence_over_arg(true)
+        .get_matches();
+    let reporter = output::Reporter::new(
+        match matches.get_one::<String>("output").unwrap().as_ref() {
+            "text" => output::Format::Text,
+            "json" => output::Format::Json,
+            _ => panic!(), // This should be caught by Clap
+        },
+    )
+    .expect("Could not set up the output");
     let config_path: &String = matches
         .get_one("config")
         .expect("Missing value for `config`");
     let is_self_test = config_path == CONFIG_PATH_AUTOTEST;
 
     let commands = match matches.get_many::<String>("command") {
-        None if is_self_test => vec![],
+        None if is_self_test || matches.subcommand().is_some() => vec![],
+        None if matches.contains_id("only") => {
+            vec![Command::Build, Command::Up, Command::Run, Command::Down]
+        }
         None => vec![Command::Up, Command::Run, Command::Down],
         Some(values) => values
-            .map(|command| match command.as_ref() {
-                "up" => Command::Up,
-                "down" => Command::Down,
-                "run" => Command::Run,
-                "build" => Command::Build,
-                _ => panic!("Invalid command `{}`", command),
-            })
+            .map(|command| command.parse().expect("Invalid command")) // This should be caught by Clap
             .collect(),
     };
+    let commands = select_commands(
+        commands,
+        matches.get_many::<String>("only").map(|only| {
+            only.map(|command| command.parse().expect("Invalid command")) // This should be caught by Clap
+                .collect()
+        }),
+        matches.contains_id("skip-build"),
+        matches.contains_id("skip-registration"),
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Invalid combination of commands: {}", err);
+        std::process::exit(1);
+    });
     debug!("Running {:?}", commands);
 
+    if let Some(("init", matches)) = matches.subcommand() {
+        let template: init::Template = matches
+            .get_one::<String>("template")
+            .unwrap()
+            .parse()
+            .unwrap(); // This should be caught by Clap
+        let name = match matches.get_one::<String>("name") {
+            Some(name) => name.to_string(),
+            None => init::default_name(
+                std::path::Path::new(config_path)
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or_else(|| std::path::Path::new(".")),
+            ),
+        };
+        let paths = init::init(
+            std::path::Path::new(config_path),
+            template,
+            &name,
+            matches.contains_id("force"),
+        )
+        .unwrap_or_else(|err| panic!("{:#}", err));
+        for path in paths {
+            println!("* wrote {}", path.display());
+        }
+        return;
+    }
+    if let Some(("config", matches)) = matches.subcommand() {
+        match matches.subcommand() {
+            Some(("compare", matches)) => {
+                let load = |key: &str| {
+                    let path: &std::path::PathBuf = matches.get_one(key).unwrap();
+                    manifest::Manifest::load_from(path)
+                        .unwrap_or_else(|err| panic!("Could not load {:?}: {:?}", path, err))
+                };
+                let changes = load("run_a").compare(&load("run_b"));
+                match matches.get_one::<String>("format").unwrap().as_ref() {
+                    "json" => println!(
+                        "{}",
+                        serde_json::to_string_pretty(&changes)
+                            .expect("Could not serialize changes")
+                    ),
+                    _ => {
+                        for change in &changes {
+                            println!("{}", change);
+                        }
+                    }
+                }
+            }
+            Some(("print", _)) => {
+                // Handled once the configuration is loaded.
+            }
+            _ => unreachable!(), // This should be caught by Clap
+        }
+        if !matches!(matches.subcommand(), Some(("print", _))) {
+            return;
+        }
+    }
+    let print_config = match matches.subcommand() {
+        Some(("config", matches)) => match matches.subcommand() {
+            Some(("print", matches)) => Some(matches.contains_id("effective")),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    // Whether mx-tester.yml specifies `directories.root`.
+    let mut has_root = false;
+    // The config file as written, for `validate`.
+    let mut config_source = None;
     let mut config = {
         if is_self_test {
             Config::builder()
                 .name("mx-tester-autotest".to_string())
                 .build()
         } else {
-            let config_file = std::fs::File::open(config_path).unwrap_or_else(|err| {
-                panic!("Could not open config file `{}`: {}", config_path, err)
-            });
-            serde_yaml::from_reader(config_file)
-                .unwrap_or_else(|err| panic!("Invalid config file `{}`: {}", config_path, err))
+            let source = read_config_file(std::path::Path::new(config_path))
+                .unwrap_or_else(|err| panic!("{:#}", err));
+            let config: Config = serde_yaml::from_value(source.clone())
+                .unwrap_or_else(|err| panic!("Invalid config file `{}`: {}", config_path, err));
+            has_root = !source["directories"]["root"].is_null();
+            config_source = Some(source);
+            config
         }
     };
     debug!("Config: {:2?}", config);
+    if print_config == Some(false) {
+        print_config_yaml(config);
+        return;
+    }
     for (key, value) in std::env::vars().filter(|(key, _)| key.starts_with("DOCKER_")) {
         debug!("{}={}", key, value);
     }
     debug!("Root: {:?}", config.test_root());
 
+    if let Some(profile) = matches.get_one::<String>("registry_profile") {
+        config
+            .select_registry_profile(profile)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
     if let Some(server) = matches.get_one::<String>("server") {
         config.credentials.serveraddress = Some(server.to_string());
     }
@@ -170,8 +825,72 @@ async fn main() {
     if let Some(username) = matches.get_one::<String>("username") {
         config.credentials.username = Some(username.to_string());
     }
+    if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+        config.telemetry.otlp_endpoint = Some(endpoint.to_string());
+    } else if config.telemetry.otlp_endpoint.is_none() {
+        config.telemetry.otlp_endpoint = std::env::var(telemetry::OTLP_ENDPOINT_ENV).ok();
+    }
     if let Some(root) = matches.get_one::<String>("root_dir") {
         config.directories.root = std::path::Path::new(root).to_path_buf()
+    } else if !has_root && !is_self_test {
+        config.directories.root = Directories::for_config_file(std::path::Path::new(config_path));
+    }
+    for module_config in matches
+        .get_many::<String>("module-config")
+        .into_iter()
+        .flatten()
+    {
+        let (name, path) = module_config.split_once('=').unwrap_or_else(|| {
+            panic!(
+                "Invalid value `{}` for `module-config`, expected MODULE=PATH",
+                module_config
+            )
+        });
+        let file = std::fs::File::open(path)
+            .unwrap_or_else(|err| panic!("Could not open module config file `{}`: {}", path, err));
+        let value = serde_yaml::from_reader(file)
+            .unwrap_or_else(|err| panic!("Invalid module config file `{}`: {}", path, err));
+        config.module_overrides.insert(name.to_string(), value);
+    }
+    if let Some(on) = matches.get_one::<String>("upload-artifacts-on") {
+        let upload = config.artifacts.upload.as_mut().unwrap_or_else(|| {
+            panic!("`--upload-artifacts-on` requires `artifacts.upload.url` in the config file")
+        });
+        upload.on = match on.as_ref() {
+            "always" => artifacts::UploadWhen::Always,
+            "failure-only" => artifacts::UploadWhen::FailureOnly,
+            _ => panic!(), // This should be caught by Clap
+        };
+    }
+    if matches.contains_id("strict-leaks") {
+        config.strict_leaks = true;
+    }
+    if matches.contains_id("skip-registration") {
+        config.skip_registration = true;
+    }
+    if let Some(runtime) = matches.get_one::<String>("runtime") {
+        config.docker.runtime = match runtime.as_ref() {
+            "docker" => Runtime::Docker,
+            "podman" => Runtime::Podman,
+            _ => panic!(), // This should be caught by Clap
+        };
+    }
+    if let Some(ssl) = matches.get_one::<String>("docker-ssl") {
+        config.docker.ssl = match ssl.as_ref() {
+            "never" => DockerSsl::Never,
+            "detect" => DockerSsl::Detect,
+            "always" => DockerSsl::Always,
+            _ => panic!(), // This should be caught by Clap
+        };
+    }
+    if let Some(timeout) = matches.get_one::<u64>("docker-timeout") {
+        config.docker.timeout_sec = *timeout;
+    }
+    if let Some(retries) = matches.get_one::<u32>("docker-connect-retries") {
+        config.docker.connect_retries = *retries;
+    }
+    if let Some(backoff) = matches.get_one::<u64>("docker-connect-backoff") {
+        config.docker.connect_backoff_ms = *backoff;
     }
     let workers = matches.contains_id("workers");
     config.workers.enabled = workers;
@@ -180,16 +899,57 @@ async fn main() {
             tag: format!("matrixdotorg/synapse:{}", synapse_tag),
         };
     }
+    // `validate` reports the problems of the steps below along with the others,
+    // other commands fail on the first one.
+    let mut setup_problems = match matches.subcommand() {
+        Some(("validate", _)) => Some(vec![]),
+        _ => None,
+    };
+    let is_instance = matches.contains_id("instance");
+    if let Some(id) = matches.get_one::<String>("instance") {
+        or_report(instance::namespace(&mut config, id), &mut setup_problems);
+    }
+    if fixtures::has_placeholders(&config) {
+        // Commands other than `up` need the names created by the latest `up`.
+        // `up` picks new names, unless it resumes an interrupted `up`.
+        let previous = or_report(manifest::Manifest::load(&config), &mut setup_problems)
+            .and_then(|manifest| manifest.fixtures);
+        let seed = match (matches.get_one::<u64>("seed"), previous) {
+            (Some(seed), _) => *seed,
+            (None, Some(progress)) if !commands.contains(&Command::Up) || !progress.complete => {
+                progress.seed.unwrap_or_else(fixtures::new_seed)
+            }
+            (None, _) => fixtures::new_seed(),
+        };
+        or_report(fixtures::expand(&mut config, seed), &mut setup_problems);
+        if print_config != Some(true) {
+            println!(
+                "* fixture names generated with seed {}, use `--seed {}` to reproduce",
+                seed, seed
+            );
+        }
+    }
 
-    enum ShouldSsl {
-        Never,
-        Detect,
-        Always,
-    }
-    let should_ssl = match matches.get_one::<String>("docker-ssl").unwrap().as_ref() {
-        "never" => ShouldSsl::Never,
-        "detect" => ShouldSsl::Detect,
-        "always" => ShouldSsl::Always,
+    if print_config == Some(true) {
+        print_config_yaml(config);
+        return;
+    }
+    if let Some(problems) = setup_problems {
+        if let Err(err) = validate::validate_strict(&config, config_source.as_ref(), problems) {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+        println!("* configuration is valid");
+        return;
+    }
+    if let Err(err) = config.validate() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+
+    let annotations = match matches.get_one::<String>("annotate").unwrap().as_ref() {
+        "none" => annotate::Annotations::None,
+        "github" => annotate::Annotations::GitHub,
         _ => panic!(), // This should be caught by Clap
     };
 
@@ -197,85 +957,442 @@ async fn main() {
     // We stop immediately if `build` or `up` fails but if `run` fails,
     // we may need to run some cleanup before stopping.
 
-    if !is_self_test && commands.is_empty() {
+    if !is_self_test && commands.is_empty() && matches.subcommand().is_none() {
         // No need to initialize Docker.
         return;
     }
 
     println!(
-        "mx-tester {version} starting. Logs will be stored at {logs_dir:?}",
+        "mx-tester {version} starting, run {run_id}. Logs will be stored at {logs_dir:?}",
         version = env!("CARGO_PKG_VERSION"),
+        run_id = run_id(),
         logs_dir = config.logs_dir()
     );
-    let has_docker_cert_path = std::env::var("DOCKER_CERT_PATH").is_ok();
-    let mut docker = match (should_ssl, &config.credentials.serveraddress, has_docker_cert_path) {
-        // No server configured => we can only run locally.
-        (ShouldSsl::Never, None, _) | (ShouldSsl::Detect, None, _) => {
-            info!("Using local docker repository");
-            bollard::Docker::connect_with_local_defaults().context("Connecting with local defaults")    
-        }
-        (ShouldSsl::Always, None, _) => {
-            panic!("Option conflict: `--docker-ssl=always` requires option `--server` or an server address in mx-tester.yml")
+    if let Some(("doctor", _)) = matches.subcommand() {
+        // Diagnose connection problems rather than failing to connect.
+        if let Err(err) = doctor::doctor(&config).await {
+            eprintln!("* doctor: {}", err);
+            std::process::exit(1);
         }
-        // Server configured => we can run either with HTTP or SSL.
-        (ShouldSsl::Never, &Some(ref server), _) | (ShouldSsl::Detect, &Some(ref server), false) => {
-            info!("Using docker repository with HTTP {}", server);
-            bollard::Docker::connect_with_http_defaults().context("Connecting with HTTP")            
-        },
-        (ShouldSsl::Always, &Some(ref server), _) | (ShouldSsl::Detect, &Some(ref server), true) => {
-            info!("Using docker repository with SSL {}", server);
-            bollard::Docker::connect_with_ssl_defaults().context("Connecting with SSL")
-        }
-    }.expect("Failed to connect to the Docker daemon");
-    docker.set_timeout(std::time::Duration::from_secs(600));
-
-    // Test that we can connect to Docker.
-    let version = docker
-        .version()
+        return;
+    }
+    let docker = connect(&config)
         .await
-        .expect("Checking connection to docker daemon");
-    println!(
-        "Using docker {}",
-        version.version.map(Cow::from).unwrap_or_else(|| "?".into())
-    );
+        .expect("Failed to connect to the Docker daemon");
 
-    // Store the results of a `run` command in case it's followed by
-    // a `down` command, which needs to decide between a success path
-    // and a failure path.
-    let mut result_run = None;
-    for command in commands {
-        match command {
-            Command::Build => {
-                info!("mx-tester build...");
-                build(&docker, &config).await.expect("Error in `build`");
+    if let Some(("watch", _)) = matches.subcommand() {
+        watch::watch(&docker, &config)
+            .await
+            .expect("Error in `watch`");
+        return;
+    }
+    if let Some(("admin", matches)) = matches.subcommand() {
+        match matches.subcommand() {
+            Some(("reload-config", matches)) => {
+                let changes = matches
+                    .get_many::<String>("set")
+                    .expect("Missing value for `set`")
+                    .map(|change| change.parse())
+                    .collect::<Result<Vec<admin::ConfigChange>, _>>()
+                    .expect("Invalid value for `set`");
+                admin::reload_config(&docker, &config, &changes)
+                    .await
+                    .expect("Error in `admin reload-config`");
             }
-            Command::Up => {
-                info!("mx-tester up...");
-                up(&docker, &config).await.expect("Error in `up`");
+            Some(("restart", _)) => {
+                admin::restart(&docker, &config)
+                    .await
+                    .expect("Error in `admin restart`");
             }
-            Command::Run => {
-                info!("mx-tester run...");
-                result_run = Some(run(&docker, &config).await);
+            Some(("reports", matches)) => match matches.subcommand() {
+                Some(("list", matches)) => {
+                    let room = matches.get_one::<String>("room").map(String::as_str);
+                    admin::print_event_reports(&config, room)
+                        .await
+                        .expect("Error in `admin reports list`");
+                }
+                _ => unreachable!(), // This should be caught by Clap
+            },
+            Some(("room-state", matches)) => {
+                let room = matches.get_one::<String>("room").expect("Missing room");
+                admin::print_room_state(&config, room)
+                    .await
+                    .expect("Error in `admin room-state`");
             }
-            Command::Down => {
-                info!("mx-tester down...");
-                let status = match result_run {
-                    None => Status::Manual,
-                    Some(Ok(_)) => Status::Success,
-                    Some(Err(_)) => Status::Failure,
-                };
-                let result_down = down(&docker, &config, status).await;
-                if let Some(result_run) = result_run.take() {
-                    // Display errors due to `run` before errors due to `down`.
-                    result_run.expect("Error in `run`");
+            Some(("wait-for-background-updates", _)) => {
+                admin::wait_for_background_updates(&config)
+                    .await
+                    .expect("Error in `admin wait-for-background-updates`");
+            }
+            #[cfg(feature = "workers")]
+            Some(("scale-workers", matches)) => {
+                let changes = matches
+                    .get_many::<String>("workers")
+                    .expect("Missing value for `workers`")
+                    .map(|change| change.parse())
+                    .collect::<Result<Vec<workers::WorkerScale>, _>>()
+                    .expect("Invalid value for `workers`");
+                workers::scale(&docker, &config, &changes)
+                    .await
+                    .expect("Error in `admin scale-workers`");
+            }
+            #[cfg(not(feature = "workers"))]
+            Some(("scale-workers", _)) => {
+                panic!("`admin scale-workers` requires mx-tester built with feature `workers`");
+            }
+            _ => unreachable!(), // This should be caught by Clap
+        }
+        return;
+    }
+    if let Some(("bench", matches)) = matches.subcommand() {
+        if let Some(iterations) = matches.get_one::<u32>("iterations") {
+            config.bench.iterations = *iterations;
+        }
+        bench::bench(&config, matches.contains_id("update-baseline"))
+            .await
+            .expect("Error in `bench`");
+        return;
+    }
+    if let Some(("replay", matches)) = matches.subcommand() {
+        let path = matches
+            .get_one::<std::path::PathBuf>("recording")
+            .expect("Missing recording");
+        recording::replay(&config, path)
+            .await
+            .expect("Error in `replay`");
+        return;
+    }
+    if let Some(("fuzz-config", _)) = matches.subcommand() {
+        let lock = lock::TestLock::acquire(&config, matches.contains_id("wait-lock"))
+            .await
+            .expect("Could not lock the test directory");
+        if is_instance {
+            instance::save_ports(&mut config, &lock)
+                .expect("Could not record the ports of instance");
+        }
+        if let Err(err) = fuzz::fuzz_config(&docker, &mut config).await {
+            eprintln!("* fuzz-config: error: {:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(("abuse", matches)) = matches.subcommand() {
+        let names: Vec<&str> = matches
+            .get_many::<String>("scenario")
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        abuse::run(&config, &names).await.expect("Error in `abuse`");
+        return;
+    }
+    if let Some(("snapshot", matches)) = matches.subcommand() {
+        let name = matches
+            .get_one::<String>("name")
+            .expect("Missing snapshot name");
+        mx_tester::snapshot(&docker, &config, name)
+            .await
+            .expect("Error in `snapshot`");
+        return;
+    }
+    if let Some(("restore", matches)) = matches.subcommand() {
+        let name = matches
+            .get_one::<String>("name")
+            .expect("Missing snapshot name");
+        mx_tester::restore(&docker, &config, name)
+            .await
+            .expect("Error in `restore`");
+        return;
+    }
+    if let Some(("shell", matches)) = matches.subcommand() {
+        let command: Vec<String> = matches
+            .get_many::<String>("command")
+            .map(|command| command.cloned().collect())
+            .unwrap_or_default();
+        let user = matches.get_one::<String>("user").map(String::as_str);
+        let code = shell::shell(&docker, &config, &command, user)
+            .await
+            .expect("Error in `shell`");
+        std::process::exit(code as i32);
+    }
+    if let Some(("exec", matches)) = matches.subcommand() {
+        let command: Vec<String> = matches
+            .get_many::<String>("command")
+            .expect("Missing command")
+            .cloned()
+            .collect();
+        let code = shell::exec(&docker, &config, &command)
+            .await
+            .expect("Error in `exec`");
+        std::process::exit(code as i32);
+    }
+    if let Some(("clean", matches)) = matches.subcommand() {
+        let check = matches.contains_id("check");
+        if matches.contains_id("all") {
+            clean_all(&docker, &config, check).await
+        } else {
+            clean(&docker, &config, check).await
+        }
+        .expect("Error in `clean`");
+        return;
+    }
+    if let Some(("simulate", matches)) = matches.subcommand() {
+        simulate(&config, matches)
+            .await
+            .expect("Error in `simulate`");
+        return;
+    }
+
+    // Prevent another process from running the same test in the same
+    // directory until we're done.
+    let _lock = if commands.is_empty() {
+        None
+    } else {
+        let lock = lock::TestLock::acquire(&config, matches.contains_id("wait-lock"))
+            .await
+            .expect("Could not lock the test directory");
+        if is_instance {
+            instance::save_ports(&mut config, &lock)
+                .expect("Could not record the ports of instance");
+        }
+        Some(lock)
+    };
+    if !commands.is_empty() {
+        telemetry::init(&config).expect("Could not set up telemetry");
+    }
+    // Once a step has failed, skip every further step but `down`, so
+    // that we don't leave the environment up, then report all failures.
+    let mut progress = Progress::default();
+    let steps = commands
+        .iter()
+        .map(|command| command.name())
+        .collect::<Vec<_>>();
+    reporter.emit(&output::Event::RunStarted {
+        run_id: run_id(),
+        version: env!("CARGO_PKG_VERSION"),
+        test: &config.name,
+        steps: steps.clone(),
+        logs_dir: &config.logs_dir(),
+    });
+    let run_start = std::time::Instant::now();
+    let _ = telemetry::span(
+        "mx-tester",
+        &[("mx_tester.steps", steps.join(","))],
+        async {
+            for command in commands {
+                if progress.skips(command) {
+                    println!(
+                        "* {} step: skipped because of previous failure",
+                        command.name()
+                    );
+                    reporter.emit(&output::Event::StepSkipped {
+                        step: command.name(),
+                    });
+                    continue;
                 }
-                result_down.expect("Error during teardown");
+                let _group = annotations.group(&format!("mx-tester {}", command.name()));
+                info!("mx-tester {}...", command.name());
+                reporter.emit(&output::Event::StepStarted {
+                    step: command.name(),
+                });
+                let step_start = std::time::Instant::now();
+                let result = telemetry::span(command.name(), &[], async {
+                    match command {
+                        Command::Build => build(&docker, &config).await,
+                        Command::Up => up(&docker, &config).await,
+                        Command::Run => run(&docker, &config).await,
+                        Command::Down => down(&docker, &config, progress.down_status()).await,
+                    }
+                })
+                .await;
+                annotations.report(&config, command.name(), &result);
+                reporter.emit(&output::Event::StepFinished {
+                    step: command.name(),
+                    success: result.is_ok(),
+                    duration_ms: step_start.elapsed().as_millis() as u64,
+                    error: result.as_ref().err().map(|err| format!("{:#}", err)),
+                    logs: output::step_logs(&config, command.name()),
+                });
+                progress.record(command, result);
+            }
+            match progress.failures.len() {
+                0 => Ok(()),
+                failed => Err(anyhow::anyhow!("{} step(s) failed", failed)),
+            }
+        },
+    )
+    .await;
+    telemetry::shutdown();
+    reporter.emit(&output::Event::RunFinished {
+        run_id: run_id(),
+        success: progress.failures.is_empty(),
+        duration_ms: run_start.elapsed().as_millis() as u64,
+    });
+    if !progress.failures.is_empty() {
+        for (command, err) in progress.failures {
+            eprintln!("* {} step: error: {:?}", command.name(), err);
+        }
+        println!("* mx-tester failure, run {}", run_id());
+        std::process::exit(1);
+    }
+    println!("* mx-tester success, run {}", run_id());
+}
+
+/// Handle an error while preparing the configuration: collect it into `problems`,
+/// if specified, otherwise panic.
+fn or_report<T>(result: Result<T, anyhow::Error>, problems: &mut Option<Vec<String>>) -> Option<T> {
+    match (result, problems) {
+        (Ok(value), _) => Some(value),
+        (Err(err), Some(problems)) => {
+            problems.push(format!("{:#}", err));
+            None
+        }
+        (Err(err), None) => panic!("{:#}", err),
+    }
+}
+
+/// Handle subcommand `config print`.
+///
+/// Secrets are redacted, as the output typically ends up in CI logs.
+fn print_config_yaml(mut config: Config) {
+    for credentials in
+        std::iter::once(&mut config.credentials).chain(config.registry_profiles.values_mut())
+    {
+        for secret in [
+            &mut credentials.password,
+            &mut credentials.auth,
+            &mut credentials.identitytoken,
+            &mut credentials.registrytoken,
+        ] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
             }
         }
     }
-    if let Some(result) = result_run {
-        // We haven't consumed the result of run().
-        result.expect("Error in `run`");
+    config.homeserver.registration_shared_secret = "<redacted>".to_string();
+    print!(
+        "{}",
+        serde_yaml::to_string(&config).expect("Could not serialize config")
+    );
+}
+
+/// Handle subcommand `simulate`.
+async fn simulate(config: &Config, matches: &clap::ArgMatches) -> Result<(), anyhow::Error> {
+    use matrix_sdk::ruma::{presence::PresenceState, EventId};
+    let localname: &String = matches.get_one("user").expect("Missing value for `user`");
+    let client = registration::user_client(config, localname).await?;
+    match matches.subcommand() {
+        Some(("presence", matches)) => {
+            let state: &String = matches.get_one("state").expect("Missing value for `state`");
+            let status = matches.get_one::<String>("status");
+            helpers::set_presence(
+                &client,
+                PresenceState::from(state.as_str()),
+                status.map(String::as_str),
+            )
+            .await
+        }
+        Some(("typing", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let room_id = helpers::resolve_room(&client, room).await?;
+            let duration = if matches.contains_id("stop") {
+                None
+            } else {
+                let timeout: &u64 = matches
+                    .get_one("timeout")
+                    .expect("Missing value for `timeout`");
+                Some(std::time::Duration::from_secs(*timeout))
+            };
+            helpers::send_typing(&client, &room_id, duration).await
+        }
+        Some(("call", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let callee: &String = matches
+                .get_one("callee")
+                .expect("Missing value for `callee`");
+            let sdp: &std::path::PathBuf = matches.get_one("sdp").expect("Missing value for `sdp`");
+            let fixture: helpers::CallFixture = serde_yaml::from_reader(
+                std::fs::File::open(sdp).with_context(|| format!("Could not open {:?}", sdp))?,
+            )
+            .with_context(|| format!("Invalid SDP fixture {:?}", sdp))?;
+            let callee = registration::user_client(config, callee).await?;
+            let room_id = helpers::resolve_room(&client, room).await?;
+            let call_id = helpers::simulate_call(&client, &callee, &room_id, &fixture).await?;
+            println!("Call {} complete", call_id);
+            Ok(())
+        }
+        Some(("redact", matches)) => {
+            let label: &String = matches.get_one("label").expect("Missing value for `label`");
+            let reason = matches.get_one::<String>("reason");
+            let manifest = manifest::Manifest::load(config)?;
+            let event = manifest.event(label)?;
+            helpers::redact(
+                &client,
+                &event.room_id,
+                &event.event_id,
+                reason.map(String::as_str),
+            )
+            .await?;
+            helpers::wait_for_redaction(
+                &client,
+                &event.room_id,
+                &event.event_id,
+                std::time::Duration::from_secs(30),
+            )
+            .await
+        }
+        Some(("race-state", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let race = match matches
+                .get_one::<String>("state")
+                .expect("Missing value for `state`")
+                .as_str()
+            {
+                "topic" => helpers::StateRace::Topic,
+                "power-levels" => helpers::StateRace::PowerLevels,
+                _ => unreachable!(), // This should be caught by Clap
+            };
+            let rounds: &usize = matches
+                .get_one("rounds")
+                .expect("Missing value for `rounds`");
+            let room_id = helpers::resolve_room(&client, room).await?;
+            let mut clients = vec![client];
+            for localname in matches
+                .get_many::<String>("with")
+                .expect("Missing value for `with`")
+            {
+                clients.push(registration::user_client(config, localname).await?);
+            }
+            let state = helpers::race_state(&clients, &room_id, race, *rounds).await?;
+            println!("Final state: {}", state);
+            Ok(())
+        }
+        Some(("knock", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let reason = matches.get_one::<String>("reason");
+            helpers::knock(&client, room, reason.map(String::as_str)).await?;
+            Ok(())
+        }
+        Some(("approve-knock", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let knocker: &String = matches
+                .get_one("knocker")
+                .expect("Missing value for `knocker`");
+            let knocker = registration::user_client(config, knocker).await?;
+            let knocker_id = knocker
+                .user_id()
+                .context("Cannot determine user id of knocker")?;
+            let room_id = helpers::resolve_room(&client, room).await?;
+            helpers::approve_knock(&client, &room_id, knocker_id).await
+        }
+        Some(("receipt", matches)) => {
+            let room: &String = matches.get_one("room").expect("Missing value for `room`");
+            let event: &String = matches.get_one("event").expect("Missing value for `event`");
+            let room_id = helpers::resolve_room(&client, room).await?;
+            let event_id = EventId::parse(event)
+                .map_err(|err| anyhow::anyhow!("Invalid event id {}: {}", event, err))?;
+            helpers::send_read_receipt(&client, &room_id, &event_id).await
+        }
+        _ => unreachable!(), // This should be caught by Clap
     }
-    println!("* mx-tester success");
 }
@@ -0,0 +1,380 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A machine-readable description of what a test actually ran against.
+//!
+//! The manifest is stored as `manifest.json` in the test root, next to the logs,
+//! so that CI artifacts show exactly what was tested.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use ruma::{OwnedEventId, OwnedRoomId};
+use serde::{Deserialize, Serialize};
+
+use crate::Config;
+
+/// A Python package installed in the image.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Package {
+    /// The version of the package, as reported by pip.
+    pub version: String,
+
+    /// If the package was installed from a directory or file, its url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Information on the contents of the Docker image, collected at the end of `build`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImageInfo {
+    /// The tag of the image.
+    pub tag: String,
+
+    /// The Synapse image we built upon, as configured, e.g. `matrixdotorg/synapse:latest`.
+    #[serde(default)]
+    pub base_image: String,
+
+    /// The digest of the Synapse image we built upon, e.g. `matrixdotorg/synapse@sha256:...`,
+    /// if we could find it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_digest: Option<String>,
+
+    /// The version of Synapse installed in the image, if we could find it.
+    pub synapse_version: Option<String>,
+
+    /// The version of each module listed in mx-tester.yml, indexed by module name.
+    pub modules: BTreeMap<String, Package>,
+
+    /// All the Python packages installed in the image, indexed by package name.
+    pub packages: BTreeMap<String, Package>,
+
+    /// In workers mode, the resources used to generate the configuration of
+    /// workers, indexed by file name, e.g. `nginx.conf.j2`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub templates: BTreeMap<String, TemplateInfo>,
+}
+
+/// Where a resource of workers mode comes from.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateSource {
+    /// The file embedded in mx-tester.
+    Embedded,
+
+    /// A file of `workers.templates_dir`.
+    User,
+}
+
+/// A resource of workers mode, as used during `build`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TemplateInfo {
+    pub source: TemplateSource,
+
+    /// The SHA-1 of the contents, in lowercase hexadecimal.
+    pub sha1: String,
+}
+
+impl ImageInfo {
+    /// If the same (unpinned) base image, e.g. `matrixdotorg/synapse:latest`, now
+    /// resolves to a different digest than in a previous build, explain how.
+    pub fn base_drift(&self, previous: &ImageInfo) -> Option<String> {
+        if self.base_image != previous.base_image || self.base_image.contains('@') {
+            return None;
+        }
+        match (&previous.base_digest, &self.base_digest) {
+            (Some(before), Some(after)) if before != after => Some(format!(
+                "{} has changed since the last recorded build, from {} to {}. To always test against the same image, pin it in mx-tester.yml with `synapse: docker: tag: {}`",
+                self.base_image, before, after, after
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of `manifest.json`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    /// The version of mx-tester that last wrote this manifest.
+    pub mx_tester_version: String,
+
+    /// The id of the run of mx-tester that last wrote this manifest, see
+    /// `crate::run_id()`.
+    #[serde(default)]
+    pub run_id: String,
+
+    /// The contents of the image, if it has been built.
+    #[serde(default)]
+    pub image: Option<ImageInfo>,
+
+    /// The effective homeserver.yaml, as last written by `up` or `admin reload-config`.
+    #[serde(default)]
+    pub homeserver_config: Option<serde_json::Value>,
+
+    /// How clients may discover the homeserver, as last written by `up`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery: Option<Discovery>,
+
+    /// The labelled messages seeded during `up`, indexed by label.
+    #[serde(default)]
+    pub events: BTreeMap<String, SeededEvent>,
+
+    /// The rooms created during the last `up`, which lets a later `up` continue
+    /// from where a failed `up` left off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixtures: Option<FixtureProgress>,
+}
+
+/// How clients may discover the homeserver, through `/.well-known/matrix/client`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Discovery {
+    /// The domain from which clients discover the homeserver, i.e. the server name
+    /// in user ids.
+    pub domain: String,
+
+    /// The URL served as `m.homeserver.base_url`, i.e. `public_baseurl`.
+    pub base_url: String,
+
+    /// The URL of the well-known file, as served by the homeserver.
+    pub well_known_url: String,
+}
+
+impl Discovery {
+    pub fn new(config: &Config) -> Self {
+        let base_url = config.homeserver.public_baseurl.trim_end_matches('/');
+        Discovery {
+            domain: config.homeserver.server_name.clone(),
+            base_url: base_url.to_string(),
+            well_known_url: format!("{}/.well-known/matrix/client", base_url),
+        }
+    }
+}
+
+/// The progress of the creation of rooms during `up`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FixtureProgress {
+    /// A fingerprint of `users` in mx-tester.yml. If `users` has changed,
+    /// we cannot resume.
+    pub fingerprint: String,
+
+    /// The rooms fully created, i.e. with members and messages, indexed by
+    /// `localname/index`, where `index` is the position of the room in the
+    /// `rooms` of user `localname`.
+    pub rooms: BTreeMap<String, CreatedRoom>,
+
+    /// Whether all rooms have been created.
+    pub complete: bool,
+
+    /// The seed used to expand placeholders such as `${random}` in `users`,
+    /// if any. Pass it to `--seed` to recreate the same users and rooms.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// A room fully created during `up`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CreatedRoom {
+    pub room_id: OwnedRoomId,
+
+    /// The labelled messages seeded in this room, indexed by label.
+    pub events: BTreeMap<String, SeededEvent>,
+}
+
+/// A message seeded during `up`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SeededEvent {
+    /// The room in which the message was sent.
+    pub room_id: OwnedRoomId,
+
+    /// The id of the message.
+    pub event_id: OwnedEventId,
+}
+
+impl Manifest {
+    /// The path to the manifest for a test.
+    pub fn path(config: &Config) -> PathBuf {
+        config.test_root().join("manifest.json")
+    }
+
+    /// Load the manifest for a test, or an empty manifest if there is none yet.
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        let path = Self::path(config);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::load_from(&path)
+    }
+
+    /// Load a manifest from a file, or from file `manifest.json` if `path` is a directory,
+    /// e.g. the test root of a previous run.
+    ///
+    /// Fails if there is no such file.
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        let path = if path.is_dir() {
+            path.join("manifest.json")
+        } else {
+            path.to_path_buf()
+        };
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Could not open manifest {:?}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Invalid manifest {:?}", path))
+    }
+
+    /// Write the manifest for a test.
+    pub fn save(&mut self, config: &Config) -> Result<(), Error> {
+        self.mx_tester_version = env!("CARGO_PKG_VERSION").to_string();
+        self.run_id = crate::run_id().to_string();
+        let path = Self::path(config);
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("Could not create manifest {:?}", path))?;
+        serde_json::to_writer_pretty(file, self)
+            .with_context(|| format!("Could not write manifest {:?}", path))
+    }
+
+    /// Record the effective homeserver.yaml and how clients may discover the homeserver.
+    pub fn record_homeserver_config(
+        config: &Config,
+        content: &serde_yaml::Mapping,
+    ) -> Result<(), Error> {
+        let content =
+            serde_json::to_value(content).context("Could not convert homeserver config")?;
+        let discovery = Discovery::new(config);
+        Self::update(config, |manifest| {
+            manifest.homeserver_config = Some(content);
+            manifest.discovery = Some(discovery);
+        })
+    }
+
+    /// Compare two manifests, e.g. from two runs, typically to find out why
+    /// a test started failing.
+    ///
+    /// This compares the version of Synapse, the version of modules and the
+    /// effective homeserver config.
+    pub fn compare(&self, other: &Manifest) -> Vec<Change> {
+        let mut changes = vec![];
+        diff(
+            "image.synapse_version",
+            &serde_json::json!(self.image.as_ref().map(|image| &image.synapse_version)),
+            &serde_json::json!(other.image.as_ref().map(|image| &image.synapse_version)),
+            &mut changes,
+        );
+        diff(
+            "image.base_digest",
+            &serde_json::json!(self.image.as_ref().map(|image| &image.base_digest)),
+            &serde_json::json!(other.image.as_ref().map(|image| &image.base_digest)),
+            &mut changes,
+        );
+        diff(
+            "image.modules",
+            &serde_json::json!(self.image.as_ref().map(|image| &image.modules)),
+            &serde_json::json!(other.image.as_ref().map(|image| &image.modules)),
+            &mut changes,
+        );
+        diff(
+            "homeserver",
+            &serde_json::json!(self.homeserver_config),
+            &serde_json::json!(other.homeserver_config),
+            &mut changes,
+        );
+        changes
+    }
+
+    /// Find a message seeded during `up` from its label.
+    pub fn event(&self, label: &str) -> Result<&SeededEvent, Error> {
+        self.events
+            .get(label)
+            .ok_or_else(|| anyhow::anyhow!("No seeded message labelled {}", label))
+    }
+
+    /// Load the manifest, apply changes and write it back.
+    pub fn update<F>(config: &Config, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Manifest),
+    {
+        let mut manifest = Self::load(config)?;
+        f(&mut manifest);
+        manifest.save(config)
+    }
+}
+
+/// A difference between two manifests.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Change {
+    /// The path to the value, e.g. `homeserver.presence.enabled`.
+    pub path: String,
+
+    /// The value in the first manifest, if any.
+    pub before: Option<serde_json::Value>,
+
+    /// The value in the second manifest, if any.
+    pub after: Option<serde_json::Value>,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.before, &self.after) {
+            (Some(before), Some(after)) => write!(f, "~ {}: {} -> {}", self.path, before, after),
+            (None, Some(after)) => write!(f, "+ {}: {}", self.path, after),
+            (Some(before), None) => write!(f, "- {}: {}", self.path, before),
+            (None, None) => write!(f, "  {}", self.path),
+        }
+    }
+}
+
+/// Compare two json values recursively, descending into objects.
+fn diff(
+    path: &str,
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+    changes: &mut Vec<Change>,
+) {
+    use serde_json::Value;
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let keys: std::collections::BTreeSet<&String> =
+                before.keys().chain(after.keys()).collect();
+            for key in keys {
+                let path = format!("{}.{}", path, key);
+                match (before.get(key), after.get(key)) {
+                    (Some(b), Some(a)) => diff(&path, b, a, changes),
+                    (b, a) => changes.push(Change {
+                        path,
+                        before: b.cloned(),
+                        after: a.cloned(),
+                    }),
+                }
+            }
+        }
+        _ if before == after => {}
+        (Value::Null, _) => changes.push(Change {
+            path: path.to_string(),
+            before: None,
+            after: Some(after.clone()),
+        }),
+        (_, Value::Null) => changes.push(Change {
+            path: path.to_string(),
+            before: Some(before.clone()),
+            after: None,
+        }),
+        _ => changes.push(Change {
+            path: path.to_string(),
+            before: Some(before.clone()),
+            after: Some(after.clone()),
+        }),
+    }
+}
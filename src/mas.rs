@@ -0,0 +1,533 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! matrix-authentication-service (MAS), as specified in `mas`.
+//!
+//! MAS runs in its own container on the Docker network, next to a PostgreSQL
+//! container for its database, and Synapse delegates authentication to it, as
+//! per MSC3861. Synapse then refuses passwords and shared-secret registration,
+//! so users are registered with `mas-cli` and log in with compatibility tokens.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::{Config, HARDCODED_GUEST_PORT};
+
+#[cfg(feature = "docker")]
+pub use self::docker::{login, start, stop};
+
+/// The port on which MAS listens, within its container.
+pub const MAS_PORT: u16 = 8080;
+
+/// The client id of Synapse in MAS. Client ids are ULIDs.
+const SYNAPSE_CLIENT_ID: &str = "0000000000000000000SYNAPSE";
+
+/// The credentials of the database of MAS.
+const DATABASE_USER: &str = "mas";
+
+/// Configuring matrix-authentication-service.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct MasConfig {
+    /// The Docker image of MAS.
+    ///
+    /// Defaults to `ghcr.io/element-hq/matrix-authentication-service:latest`.
+    #[serde(default = "MasConfig::default_image")]
+    #[builder(default = MasConfig::default_image())]
+    pub image: String,
+
+    /// The Docker image of the PostgreSQL database of MAS.
+    ///
+    /// Defaults to `postgres:16-alpine`.
+    #[serde(default = "MasConfig::default_database_image")]
+    #[builder(default = MasConfig::default_database_image())]
+    pub database_image: String,
+
+    /// The port on which MAS is published on the host, e.g. for browsers
+    /// and clients running on the host.
+    ///
+    /// Defaults to 9990.
+    #[serde(default = "MasConfig::default_host_port")]
+    #[builder(default = MasConfig::default_host_port())]
+    pub host_port: u16,
+
+    /// The secret with which Synapse authenticates to MAS.
+    #[serde(default = "MasConfig::default_client_secret")]
+    #[builder(default = MasConfig::default_client_secret())]
+    pub client_secret: String,
+
+    /// The secret with which MAS authenticates to Synapse, which Synapse
+    /// also accepts as an admin access token.
+    #[serde(default = "MasConfig::default_admin_token")]
+    #[builder(default = MasConfig::default_admin_token())]
+    pub admin_token: String,
+
+    /// Additional fields for the config.yaml of MAS, overriding those
+    /// generated by mx-tester.
+    #[serde(default, serialize_with = "crate::util::serialize_sorted")]
+    #[builder(default)]
+    pub config: HashMap<String, serde_yaml::Value>,
+
+    /// Additional hostnames of MAS on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl MasConfig {
+    fn default_image() -> String {
+        "ghcr.io/element-hq/matrix-authentication-service:latest".to_string()
+    }
+    fn default_database_image() -> String {
+        "postgres:16-alpine".to_string()
+    }
+    fn default_host_port() -> u16 {
+        9990
+    }
+    fn default_client_secret() -> String {
+        "MX_TESTER_MAS_CLIENT_SECRET_DEFAULT".to_string()
+    }
+    fn default_admin_token() -> String {
+        "MX_TESTER_MAS_ADMIN_TOKEN_DEFAULT".to_string()
+    }
+
+    /// The base URL of MAS, as seen from the host.
+    pub fn public_base(&self) -> String {
+        format!("http://localhost:{}/", self.host_port)
+    }
+}
+
+/// The name of the container running MAS.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-mas-{}", config.name)
+}
+
+/// The name of the container running the database of MAS.
+pub fn database_container_name(config: &Config) -> String {
+    format!("mx-tester-mas-db-{}", config.name)
+}
+
+/// The issuer of MAS, i.e. its base URL as seen from the Docker network.
+pub fn issuer(config: &Config) -> String {
+    format!("http://{}:{}/", container_name(config), MAS_PORT)
+}
+
+/// The directory containing the config.yaml of MAS, on the host.
+pub fn mas_dir(config: &Config) -> PathBuf {
+    config.test_root().join("mas")
+}
+
+/// Patch homeserver.yaml to delegate authentication to MAS (MSC3861).
+///
+/// Fields specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    mas: &MasConfig,
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    let has_msc3861 = extra_fields
+        .get("experimental_features")
+        .and_then(|features| features.get("msc3861"))
+        .is_some();
+    if !has_msc3861 {
+        let issuer = issuer(config);
+        content
+            .entry("experimental_features".into())
+            .or_insert_with(|| serde_yaml::Value::Mapping(serde_yaml::Mapping::new()))
+            .as_mapping_mut()
+            .ok_or_else(|| {
+                anyhow!("In homeserver.yaml, expected a mapping for key `experimental_features`")
+            })?
+            .insert(
+                "msc3861".into(),
+                yaml!({
+                    "enabled" => true,
+                    "issuer" => issuer.clone(),
+                    // MAS advertises its public base, which is not reachable from Synapse.
+                    "introspection_endpoint" => format!("{}oauth2/introspect", issuer),
+                    "client_id" => SYNAPSE_CLIENT_ID,
+                    "client_auth_method" => "client_secret_basic",
+                    "client_secret" => mas.client_secret.clone(),
+                    "admin_token" => mas.admin_token.clone(),
+                    "account_management_url" => format!("{}account", mas.public_base())
+                }),
+            );
+    }
+    // MSC3861 is incompatible with registration and password login by Synapse.
+    for (key, value) in [
+        ("enable_registration", yaml!(false)),
+        ("password_config", yaml!({ "enabled" => false })),
+    ] {
+        if !extra_fields.contains_key(key) {
+            content.insert(key.into(), value);
+        }
+    }
+    for key in [
+        "registration_shared_secret",
+        "enable_registration_without_verification",
+    ] {
+        if !extra_fields.contains_key(key) {
+            content.remove(key);
+        }
+    }
+    Ok(())
+}
+
+/// Patch the config.yaml generated by `mas-cli config generate`.
+///
+/// Secrets and keys are kept, everything that lets MAS talk to its database,
+/// to Synapse and to clients is replaced, then `mas.config` is applied.
+pub fn patch_mas_config(
+    config: &Config,
+    mas: &MasConfig,
+    mut content: serde_yaml::Mapping,
+) -> Result<serde_yaml::Mapping, Error> {
+    let resources = [
+        "discovery",
+        "human",
+        "oauth",
+        "compat",
+        "graphql",
+        "assets",
+        "health",
+    ]
+    .iter()
+    .map(|name| yaml!({ "name" => *name }))
+    .collect::<Vec<_>>();
+    let http = content
+        .entry("http".into())
+        .or_insert_with(|| serde_yaml::Value::Mapping(serde_yaml::Mapping::new()))
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("In the config.yaml of MAS, expected a mapping for key `http`"))?;
+    http.insert("public_base".into(), yaml!(mas.public_base()));
+    http.insert("issuer".into(), yaml!(issuer(config)));
+    http.insert(
+        "listeners".into(),
+        yaml!([yaml!({
+            "name" => "web",
+            "resources" => serde_yaml::Value::Sequence(resources),
+            "binds" => yaml!([yaml!({ "address" => format!("[::]:{}", MAS_PORT) })])
+        })]),
+    );
+    content.insert(
+        "database".into(),
+        yaml!({
+            "uri" => format!(
+                "postgresql://{user}:{user}@{host}/{user}",
+                user = DATABASE_USER,
+                host = database_container_name(config)
+            )
+        }),
+    );
+    content.insert(
+        "matrix".into(),
+        yaml!({
+            "homeserver" => config.homeserver.server_name.clone(),
+            "secret" => mas.admin_token.clone(),
+            "endpoint" => format!("http://{}:{}/", config.run_container_name(), HARDCODED_GUEST_PORT)
+        }),
+    );
+    content.insert(
+        "clients".into(),
+        yaml!([yaml!({
+            "client_id" => SYNAPSE_CLIENT_ID,
+            "client_auth_method" => "client_secret_basic",
+            "client_secret" => mas.client_secret.clone()
+        })]),
+    );
+    content
+        .entry("passwords".into())
+        .or_insert_with(|| serde_yaml::Value::Mapping(serde_yaml::Mapping::new()))
+        .as_mapping_mut()
+        .ok_or_else(|| {
+            anyhow!("In the config.yaml of MAS, expected a mapping for key `passwords`")
+        })?
+        .insert("enabled".into(), yaml!(true));
+    for (key, value) in &mas.config {
+        content.insert(key.clone().into(), value.clone());
+    }
+    Ok(content)
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    use anyhow::Context;
+    use bollard::{
+        container::{
+            Config as BollardContainerConfig, CreateContainerOptions, LogsOptions,
+            WaitContainerOptions,
+        },
+        Docker,
+    };
+    use futures_util::stream::StreamExt;
+
+    use crate::{
+        environment::Environment,
+        registration::User,
+        services::{stop_container, Container, ContainerPort},
+        util::{exec, wait_until},
+    };
+
+    /// The path of config.yaml, within the MAS container.
+    const GUEST_CONFIG_PATH: &str = "/mx-tester/mas/config.yaml";
+
+    /// How long to wait for PostgreSQL and MAS to accept connections.
+    const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+    /// Start the database of MAS and MAS, if `mas` is specified.
+    ///
+    /// This must happen before Synapse starts, as Synapse needs MAS to authenticate users.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let mas = match config.mas {
+            None => return Ok(()),
+            Some(ref mas) => mas,
+        };
+        let database_container_name = database_container_name(config);
+        let container_name = container_name(config);
+
+        // Start the database. It is not persisted, so users are registered again
+        // during each `up`.
+        Container {
+            label: "MAS database".to_string(),
+            name: database_container_name.clone(),
+            image: mas.database_image.clone(),
+            env: vec![
+                format!("POSTGRES_USER={}", DATABASE_USER),
+                format!("POSTGRES_PASSWORD={}", DATABASE_USER),
+                format!("POSTGRES_DB={}", DATABASE_USER),
+            ],
+            log_name: "mas-db.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await?;
+
+        // During initialization, PostgreSQL only listens on its unix socket.
+        wait_until(READINESS_TIMEOUT, "MAS database", || async {
+            let (code, _) = exec(
+                docker,
+                &database_container_name,
+                vec!["pg_isready", "-h", "localhost", "-U", DATABASE_USER],
+            )
+            .await?;
+            Ok(code == 0)
+        })
+        .await?;
+
+        // Generate secrets and keys, then patch the configuration.
+        let generated = run_to_completion(
+            docker,
+            config,
+            &mas.image,
+            &format!("{}-generate", container_name),
+            vec!["config".to_string(), "generate".to_string()],
+        )
+        .await
+        .context("Could not generate the configuration of MAS")?;
+        let content: serde_yaml::Mapping = serde_yaml::from_str(&generated)
+            .context("The configuration generated by MAS is invalid")?;
+        let content = patch_mas_config(config, mas, content)?;
+        let dir = mas_dir(config);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:?}", dir))?;
+        let config_path = dir.join("config.yaml");
+        serde_yaml::to_writer(std::fs::File::create(&config_path)?, &content)
+            .context("Could not write the configuration of MAS")?;
+        let host_config_path = Environment::detect(docker).await?.host_path(&config_path)?;
+
+        // Start MAS. It migrates its database and registers Synapse as a client on startup.
+        Container {
+            label: "MAS".to_string(),
+            name: container_name,
+            image: mas.image.clone(),
+            cmd: Some(vec![
+                "server".to_string(),
+                format!("--config={}", GUEST_CONFIG_PATH),
+            ]),
+            ports: vec![ContainerPort::tcp(MAS_PORT, Some(u64::from(mas.host_port)))],
+            binds: vec![format!(
+                "{}:{}:ro",
+                host_config_path.to_string_lossy(),
+                GUEST_CONFIG_PATH
+            )],
+            aliases: mas.aliases.clone(),
+            log_name: "mas.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await?;
+
+        let health_url = format!(
+            "http://{}:{}/health",
+            config.docker.published_host(),
+            mas.host_port
+        );
+        wait_until(READINESS_TIMEOUT, "MAS", || async {
+            Ok(matches!(reqwest::get(&health_url).await, Ok(response) if response.status().is_success()))
+        })
+        .await?;
+        println!("** MAS is ready");
+        Ok(())
+    }
+
+    /// Stop and remove MAS and its database, if `mas` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.mas.is_none() {
+            return Ok(());
+        }
+        let mas_result = stop_container(docker, config, &container_name(config), "MAS").await;
+        let database_name = database_container_name(config);
+        let database_result = stop_container(docker, config, &database_name, "MAS database").await;
+        mas_result.and(database_result)
+    }
+
+    /// Register a user with MAS, unless it already exists, and login with a
+    /// compatibility token.
+    ///
+    /// Admins receive a token that grants them admin privileges in Synapse.
+    pub async fn login(config: &Config, user: &User) -> Result<matrix_sdk::Client, Error> {
+        let docker = config.docker.runtime.container_runtime().connect(config)?;
+        let container_name = container_name(config);
+
+        let mut register = vec![
+            "mas-cli",
+            "manage",
+            "register-user",
+            "--yes",
+            "--ignore-password-complexity",
+            "--password",
+            &user.password,
+        ];
+        if user.admin {
+            register.push("--admin");
+        }
+        register.push(&user.localname);
+        let (code, output) = exec(&docker, &container_name, register).await?;
+        if code != 0 && !output.contains("already exists") {
+            return Err(anyhow!(
+                "Could not register user {} with MAS: {}",
+                user.localname,
+                output
+            ));
+        }
+
+        let device_id = format!("MXTESTER{:08X}", rand::random::<u32>());
+        let mut issue = vec![
+            "mas-cli",
+            "manage",
+            "issue-compatibility-token",
+            &user.localname,
+            &device_id,
+        ];
+        if user.admin {
+            issue.push("--yes-i-want-to-grant-synapse-admin-privileges");
+        }
+        let (code, output) = exec(&docker, &container_name, issue).await?;
+        let access_token = match (code, output.find("mct_")) {
+            (0, Some(start)) => output[start..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect::<String>(),
+            _ => {
+                return Err(anyhow!(
+                    "Could not issue a token for user {} with MAS: {}",
+                    user.localname,
+                    output
+                ))
+            }
+        };
+
+        let user_id = matrix_sdk::ruma::UserId::parse_with_server_name(
+            user.localname.as_str(),
+            <&matrix_sdk::ruma::ServerName>::try_from(config.homeserver.server_name.as_str())?,
+        )
+        .with_context(|| format!("Invalid localname {}", user.localname))?;
+        let client = matrix_sdk::Client::builder()
+            .homeserver_url(reqwest::Url::parse(&config.homeserver.public_baseurl)?)
+            .build()
+            .await?;
+        client
+            .restore_login(matrix_sdk::Session {
+                access_token,
+                refresh_token: None,
+                user_id,
+                device_id: device_id.into(),
+            })
+            .await
+            .with_context(|| format!("Could not login as {}", user.localname))?;
+        Ok(client)
+    }
+
+    /// Run a container to completion, returning its standard output.
+    async fn run_to_completion(
+        docker: &Docker,
+        config: &Config,
+        image: &str,
+        container_name: &str,
+        cmd: Vec<String>,
+    ) -> Result<String, Error> {
+        let _ = docker.remove_container(container_name, None).await;
+        docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: container_name,
+                }),
+                BollardContainerConfig {
+                    image: Some(image.to_string()),
+                    cmd: Some(cmd),
+                    labels: Some(config.docker_labels()),
+                    ..BollardContainerConfig::default()
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to create container {}", container_name))?;
+        docker
+            .start_container::<String>(container_name, None)
+            .await
+            .with_context(|| format!("Failed to start container {}", container_name))?;
+        let mut wait = docker.wait_container(
+            container_name,
+            Some(WaitContainerOptions {
+                condition: "not-running",
+            }),
+        );
+        let mut result = Ok(());
+        while let Some(next) = wait.next().await {
+            if let Err(err) = next {
+                result = Err(err);
+            }
+        }
+        let mut stdout = String::new();
+        let mut logs = docker.logs(
+            container_name,
+            Some(LogsOptions::<String> {
+                stdout: true,
+                ..LogsOptions::default()
+            }),
+        );
+        while let Some(next) = logs.next().await {
+            stdout.push_str(&format!("{}", next?));
+        }
+        let _ = docker.remove_container(container_name, None).await;
+        result.with_context(|| format!("Container {} failed", container_name))?;
+        Ok(stdout)
+    }
+}
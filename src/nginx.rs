@@ -0,0 +1,286 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics on the traffic dispatched by nginx to workers.
+//!
+//! In workers mode, nginx logs which upstream served each request (see
+//! `res/workers/nginx.conf.j2`). Once `run` is over, we summarize this log
+//! per endpoint, per upstream and per status code, which makes it easy to
+//! spot endpoints routed to the wrong worker or 502s from crashed workers.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{workers::workers, Config, HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT};
+
+/// The access log written by nginx, within `logs/nginx`.
+pub const ACCESS_LOG: &str = "mx-tester-access.log";
+
+/// The upstream of requests that nginx answered itself.
+const NO_UPSTREAM: &str = "nginx";
+
+/// Path segments followed by variable segments, and how many, e.g.
+/// `send/{eventType}/{txnId}`. Identifiers are recognized by themselves.
+const VARIABLE_SEGMENTS: &[(&str, usize)] = &[
+    ("account_data", 1),
+    ("devices", 1),
+    ("download", 2),
+    ("filter", 1),
+    ("redact", 2),
+    ("send", 2),
+    ("sendToDevice", 2),
+    ("state", 2),
+    ("tags", 1),
+    ("thumbnail", 2),
+];
+
+/// An entry of the access log, e.g.
+///
+/// `502 "127.0.0.1:18009" "GET /_matrix/client/v3/sync?timeout=0 HTTP/1.1"`
+#[derive(Debug, PartialEq)]
+pub struct AccessLogEntry<'a> {
+    pub status: u16,
+
+    /// The address of the upstream that served the request, if any.
+    ///
+    /// If nginx tried several upstreams, the last one.
+    pub upstream: Option<&'a str>,
+    pub method: &'a str,
+
+    /// The path, without query string.
+    pub path: &'a str,
+}
+
+/// Parse an entry of the access log. Returns `None` for anything else.
+pub fn parse_access_log_line(line: &str) -> Option<AccessLogEntry<'_>> {
+    let (status, rest) = line.trim().split_once(' ')?;
+    let status = status.parse().ok()?;
+    let mut quoted = rest.split('"').skip(1).step_by(2);
+    let upstream = quoted
+        .next()?
+        .rsplit(", ")
+        .next()
+        .map(str::trim)
+        .filter(|upstream| !upstream.is_empty() && *upstream != "-");
+    let mut request = quoted.next()?.split(' ');
+    let method = request.next()?;
+    let path = request.next()?.split('?').next()?;
+    Some(AccessLogEntry {
+        status,
+        upstream,
+        method,
+        path,
+    })
+}
+
+/// Replace the variable segments of a path with `*`, so that calls to the
+/// same endpoint are counted together, e.g.
+/// `/_matrix/client/v3/rooms/!abc:localhost/send/m.room.message/1` becomes
+/// `/_matrix/client/v3/rooms/*/send/*/*`.
+pub fn normalize_path(path: &str) -> String {
+    let mut remaining_variables = 0;
+    path.split('/')
+        .map(|segment| {
+            let is_variable = remaining_variables > 0
+                || segment.starts_with(['!', '@', '#', '$', '+'].as_ref())
+                || ["%21", "%40", "%23", "%24"]
+                    .iter()
+                    .any(|sigil| segment.starts_with(sigil))
+                || segment.contains(':')
+                || segment.contains("%3A")
+                || (!segment.is_empty() && segment.bytes().all(|byte| byte.is_ascii_digit()));
+            if remaining_variables > 0 {
+                remaining_variables -= 1;
+            } else if let Some((_, count)) = VARIABLE_SEGMENTS
+                .iter()
+                .find(|(keyword, _)| *keyword == segment)
+            {
+                remaining_variables = *count;
+            }
+            if is_variable {
+                "*"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The requests to an endpoint.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct EndpointStats {
+    pub requests: usize,
+
+    /// The number of requests served by each upstream.
+    pub upstreams: BTreeMap<String, usize>,
+
+    /// The number of responses with each status code.
+    pub statuses: BTreeMap<u16, usize>,
+}
+
+/// The requests served by an upstream.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct UpstreamStats {
+    pub requests: usize,
+
+    /// The number of responses with each status code.
+    pub statuses: BTreeMap<u16, usize>,
+}
+
+/// A summary of the access log of nginx, as stored in `logs/nginx-stats.json`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct NginxStats {
+    pub requests: usize,
+
+    /// The number of responses with each status code.
+    pub statuses: BTreeMap<u16, usize>,
+
+    /// Indexed by upstream: `main` for the main process, the name of a worker,
+    /// `nginx` for requests that nginx answered itself or the address of the
+    /// upstream if it is not known.
+    pub upstreams: BTreeMap<String, UpstreamStats>,
+
+    /// Indexed by method and normalized path, e.g. `GET /_matrix/client/v3/sync`.
+    pub endpoints: BTreeMap<String, EndpointStats>,
+}
+
+impl NginxStats {
+    /// Summarize an access log.
+    ///
+    /// `workers` maps the port of each worker to its name.
+    pub fn from_log(content: &str, workers: &HashMap<u16, String>) -> NginxStats {
+        let mut stats = NginxStats::default();
+        for entry in content.lines().filter_map(parse_access_log_line) {
+            let upstream = match entry.upstream {
+                None => NO_UPSTREAM.to_string(),
+                Some(address) => {
+                    let port = address
+                        .rsplit(':')
+                        .next()
+                        .and_then(|port| port.parse::<u16>().ok());
+                    match port {
+                        Some(port)
+                            if u64::from(port) == HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT =>
+                        {
+                            "main".to_string()
+                        }
+                        Some(port) => workers
+                            .get(&port)
+                            .cloned()
+                            .unwrap_or_else(|| address.to_string()),
+                        None => address.to_string(),
+                    }
+                }
+            };
+            stats.requests += 1;
+            *stats.statuses.entry(entry.status).or_default() += 1;
+
+            let upstream_stats = stats.upstreams.entry(upstream.clone()).or_default();
+            upstream_stats.requests += 1;
+            *upstream_stats.statuses.entry(entry.status).or_default() += 1;
+
+            let endpoint = format!("{} {}", entry.method, normalize_path(entry.path));
+            let endpoint_stats = stats.endpoints.entry(endpoint).or_default();
+            endpoint_stats.requests += 1;
+            *endpoint_stats.upstreams.entry(upstream).or_default() += 1;
+            *endpoint_stats.statuses.entry(entry.status).or_default() += 1;
+        }
+        stats
+    }
+
+    /// A human-readable summary: the status codes, the traffic of each upstream
+    /// and the upstreams that failed with a gateway error (502, 504), which
+    /// typically means that a worker crashed.
+    pub fn summary(&self) -> Vec<String> {
+        let statuses = |statuses: &BTreeMap<u16, usize>| {
+            statuses
+                .iter()
+                .map(|(status, count)| format!("{}: {}", status, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut lines = vec![format!(
+            "{} requests ({})",
+            self.requests,
+            statuses(&self.statuses)
+        )];
+        for (upstream, stats) in &self.upstreams {
+            lines.push(format!(
+                "{}: {} requests ({})",
+                upstream,
+                stats.requests,
+                statuses(&stats.statuses)
+            ));
+        }
+        for (upstream, stats) in &self.upstreams {
+            let gateway_errors: usize = [502, 504]
+                .iter()
+                .filter_map(|status| stats.statuses.get(status))
+                .sum();
+            if gateway_errors > 0 {
+                lines.push(format!(
+                    "WARNING: {} gateway errors from {}, did it crash?",
+                    gateway_errors, upstream
+                ));
+            }
+        }
+        lines
+    }
+}
+
+/// The file storing the statistics of the latest run, as part of the artifacts.
+pub fn stats_path(config: &Config) -> PathBuf {
+    config.logs_dir().join("nginx-stats.json")
+}
+
+/// The name of each worker, indexed by port.
+pub fn worker_ports(config: &Config) -> Result<HashMap<u16, String>, Error> {
+    Ok(workers(config)?
+        .into_iter()
+        .flat_map(|worker| {
+            let name = worker.name;
+            worker
+                .ports
+                .into_iter()
+                .map(move |port| (port, name.clone()))
+        })
+        .collect())
+}
+
+/// In workers mode, summarize the access log of nginx into `logs/nginx-stats.json`.
+pub fn report(config: &Config) -> Result<(), Error> {
+    if !config.workers.enabled {
+        return Ok(());
+    }
+    let log_path = config.logs_dir().join("nginx").join(ACCESS_LOG);
+    let content = match std::fs::read_to_string(&log_path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", log_path)),
+    };
+    let stats = NginxStats::from_log(&content, &worker_ports(config)?);
+    for line in stats.summary() {
+        println!("** nginx: {}", line);
+    }
+    let stats_path = stats_path(config);
+    std::fs::write(&stats_path, serde_json::to_string_pretty(&stats)?)
+        .with_context(|| format!("Could not write {:?}", stats_path))?;
+    Ok(())
+}
@@ -0,0 +1,134 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Machine-readable output: `--output json`.
+//!
+//! In JSON mode, stdout only contains events, one JSON object per line, and
+//! everything else that mx-tester and its scripts print goes to stderr.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use log::warn;
+use serde::Serialize;
+
+use crate::Config;
+
+/// The format of the output of mx-tester.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human-readable text.
+    #[default]
+    Text,
+
+    /// Events as JSON lines on stdout, human-readable text on stderr.
+    Json,
+}
+
+/// An event of a run of mx-tester.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The steps are about to be executed.
+    RunStarted {
+        run_id: &'a str,
+        version: &'a str,
+        test: &'a str,
+        steps: Vec<&'a str>,
+        logs_dir: &'a Path,
+    },
+
+    /// A step, e.g. `up`, is starting.
+    StepStarted { step: &'a str },
+
+    /// A step was skipped because of the failure of a previous step.
+    StepSkipped { step: &'a str },
+
+    /// A step is over.
+    StepFinished {
+        step: &'a str,
+        success: bool,
+        duration_ms: u64,
+        /// The error, with its causes, if the step failed.
+        error: Option<String>,
+        /// The logs written during the step, e.g. those of Synapse.
+        logs: Vec<PathBuf>,
+    },
+
+    /// All the steps are over.
+    RunFinished {
+        run_id: &'a str,
+        success: bool,
+        duration_ms: u64,
+    },
+}
+
+/// Emitting events, if the format is `Format::Json`.
+pub struct Reporter {
+    /// The original stdout, if the format is `Format::Json`.
+    events: Option<std::fs::File>,
+}
+
+impl Reporter {
+    /// Set up the output. In JSON mode, this redirects stdout to stderr,
+    /// keeping the original stdout for events.
+    pub fn new(format: Format) -> Result<Self, Error> {
+        let events = match format {
+            Format::Text => None,
+            Format::Json => {
+                use std::os::unix::io::{AsRawFd, FromRawFd};
+                std::io::stdout().flush()?;
+                let stdout = std::io::stdout().as_raw_fd();
+                let fd = nix::unistd::dup(stdout).context("Could not duplicate stdout")?;
+                nix::unistd::dup2(std::io::stderr().as_raw_fd(), stdout)
+                    .context("Could not redirect stdout to stderr")?;
+                // Safety: `fd` was just created by `dup`, nothing else owns it.
+                Some(unsafe { std::fs::File::from_raw_fd(fd) })
+            }
+        };
+        Ok(Reporter { events })
+    }
+
+    /// Emit an event, as a line of JSON.
+    pub fn emit(&self, event: &Event) {
+        let mut events = match self.events {
+            Some(ref events) => events,
+            None => return,
+        };
+        let result = serde_json::to_string(event)
+            .map_err(Error::from)
+            .and_then(|line| Ok(writeln!(events, "{}", line)?))
+            .and_then(|()| Ok(events.flush()?));
+        if let Err(err) = result {
+            warn!("Could not emit event {:?}: {:?}", event, err);
+        }
+    }
+}
+
+/// The logs that `step` writes, if they exist.
+pub fn step_logs(config: &Config, step: &str) -> Vec<PathBuf> {
+    let docker_log = config.logs_dir().join("docker").join(if step == "build" {
+        "build.log"
+    } else {
+        "up-run-down.log"
+    });
+    let script_log = config.scripts_logs_dir().join(format!("{}.log", step));
+    [docker_log, script_log]
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect()
+}
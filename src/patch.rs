@@ -0,0 +1,339 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Patching the configuration of a homeserver.
+//!
+//! This is the part of mx-tester that rewrites the homeserver.yaml generated by
+//! Synapse (or the dendrite.yaml generated by Dendrite). It works on in-memory
+//! mappings and has no side effect, so it may be used by other tools,
+//! independently from the rest of mx-tester.
+
+use anyhow::{anyhow, Error};
+use serde_yaml::{Mapping, Value as YAML};
+
+use crate::{
+    util::YamlExt, HomeserverConfig, HARDCODED_GUEST_PORT,
+    HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT,
+};
+
+const LISTENERS: &str = "listeners";
+const MODULES: &str = "modules";
+
+/// Patch the contents of a homeserver.yaml.
+///
+/// - `config`: the homeserver.yaml, typically as generated by Synapse;
+/// - `homeserver`: the values to inject, including any extra fields;
+/// - `workers`: whether Synapse runs with workers, which requires redis,
+///   postgres and different listeners;
+/// - `modules`: the entries to append to `modules`.
+///
+/// Unless specified otherwise in `homeserver`, rate limits are raised considerably
+/// and, with `homeserver.minimal`, background work such as presence is turned off.
+pub fn patch_homeserver_config(
+    mut config: Mapping,
+    homeserver: &HomeserverConfig,
+    workers: bool,
+    modules: &[YAML],
+) -> Result<Mapping, Error> {
+    for (key, value) in [
+        ("public_baseurl", &homeserver.public_baseurl),
+        ("server_name", &homeserver.server_name),
+        (
+            "registration_shared_secret",
+            &homeserver.registration_shared_secret,
+        ),
+    ] {
+        config.insert(key.into(), value.to_string().into());
+    }
+    config.insert(
+        "enable_registration_without_verification".into(),
+        true.into(),
+    );
+    // Let clients discover `public_baseurl` from `/.well-known/matrix/client`.
+    config.insert("serve_client_wellknown".into(), true.into());
+
+    // Copy extra fields.
+    // Note: This may include `modules` or `listeners`.
+    for (key, value) in &homeserver.extra_fields {
+        config.insert(YAML::from(key.clone()), value.clone());
+    }
+
+    if homeserver.minimal {
+        // Turn off background work that most tests don't need, unless the
+        // author of mx-tester.yml has specified otherwise.
+        for (key, value) in std::iter::IntoIterator::into_iter([
+            ("presence", yaml!({ "enabled" => false })),
+            ("url_preview_enabled", yaml!(false)),
+            ("update_user_directory", yaml!(false)),
+            ("stats", yaml!({ "enabled" => false })),
+            ("trusted_key_servers", yaml!([])),
+            ("suppress_key_server_warning", yaml!(true)),
+        ]) {
+            if !homeserver.extra_fields.contains_key(key) {
+                config.insert(yaml!(key), value);
+            }
+        }
+    }
+
+    // Setup large default rate limits.
+    let large_rate_limit: serde_yaml::Value = yaml!({
+        "per_second" => 1_000_000_000,
+        "burst_count" => 1_000_000_000,
+    });
+    for (key, rate_limit) in &[
+        ("rc_message", large_rate_limit.clone()),
+        ("rc_registration", large_rate_limit.clone()),
+        ("rc_admin_redaction", large_rate_limit.clone()),
+        (
+            "rc_login",
+            yaml!({
+                "address" => large_rate_limit.clone(),
+                "account" => large_rate_limit.clone(),
+                "failed_attempts" => large_rate_limit.clone(),
+            }),
+        ),
+        (
+            "rc_invites",
+            yaml!({
+                "per_room" => large_rate_limit.clone(),
+                "per_user" => large_rate_limit.clone(),
+                "per_sender" => large_rate_limit,
+            }),
+        ),
+    ] {
+        if !config.contains_key(key) {
+            // Setup a large default rate limit.
+            config.insert(key.to_string().into(), rate_limit.clone());
+        } else if config[key].is_default() {
+            // ...or the Synapse default rate limit.
+            config.remove(key);
+        } else {
+            // Otherwise, assume that the author of mx-tester.yaml knows what they're doing.
+        }
+    }
+
+    // Make sure that we listen on the appropriate port.
+    // For some reason, `start.py generate` tends to put port 4153 instead of HARDCODED_GUEST_PORT.
+    let listeners = config.entry(LISTENERS.into()).or_insert_with(|| yaml!([]));
+    *listeners = yaml!([yaml!({
+        "port" => if workers { HARDCODED_MAIN_PROCESS_HTTP_LISTENER_PORT } else { HARDCODED_GUEST_PORT },
+        "tls" => false,
+        "type" => "http",
+        "bind_addresses" => yaml!(["::"]),
+        "x_forwarded" => false,
+        "resources" => yaml!([
+            yaml!({
+                "names" => yaml!(["client"]),
+                "compress" => true
+            }),
+            yaml!({
+                "names" => yaml!(["federation"]),
+                "compress" => false
+            })
+        ]),
+    })]);
+    if workers {
+        // Setup the replication port.
+        listeners
+            .as_sequence_mut()
+            .unwrap() // We just set it up as a sequence
+            .push(yaml!({
+                "port" => 9093,
+                    "bind_address" => "127.0.0.1",
+                    "type" => "http",
+                    "resources" => yaml!([
+                        yaml!({
+                            "names" => yaml!(["replication"])
+                        })
+                    ])
+            }));
+    }
+
+    // Copy modules config.
+    let modules_root = config
+        .entry(MODULES.into())
+        .or_insert_with(|| yaml!([]))
+        .to_seq_mut()
+        .ok_or_else(|| anyhow!("In homeserver.yaml, expected a sequence for key `modules`"))?;
+    modules_root.extend(modules.iter().cloned());
+
+    if workers {
+        for (key, value) in std::iter::IntoIterator::into_iter([
+            // No worker support without redis.
+            (
+                "redis",
+                yaml!({
+                    "enabled" => true,
+                }),
+            ),
+            // No worker support without postgresql
+            (
+                "database",
+                yaml!({
+                    "name" => "psycopg2",
+                    "txn_limit" => 10_000,
+                    "args" => yaml!({
+                        "user" => "synapse",
+                        "password" => "password",
+                        "host" => "localhost",
+                        "port" => 5432,
+                        "cp_min" => 5,
+                        "cp_max" => 10
+                    })
+                }),
+            ),
+            // Deactivate a few features in the main process
+            // and let a worker take over them.
+            ("notify_appservices", yaml!(false)),
+            ("send_federation", yaml!(false)),
+            ("update_user_directory", yaml!(false)),
+            ("start_pushers", yaml!(false)),
+            ("url_preview_enabled", yaml!(false)),
+            (
+                "url_preview_ip_range_blacklist",
+                yaml!(["255.255.255.255/32",]),
+            ),
+            // Also, let's get rid of that warning, it pollutes logs.
+            ("suppress_key_server_warning", yaml!(true)),
+        ]) {
+            config.insert(yaml!(key), value);
+        }
+    }
+
+    Ok(config)
+}
+
+/// The path of the signing key of Dendrite, generated during `up`, within the container.
+pub(crate) const DENDRITE_PRIVATE_KEY: &str = "/data/matrix_key.pem";
+
+/// Patch the contents of a dendrite.yaml.
+///
+/// - `config`: the dendrite.yaml, typically as generated by `generate-config`;
+/// - `homeserver`: the values to inject, including any extra fields.
+///
+/// As the configuration of Dendrite is organized by component, extra fields are
+/// merged recursively, e.g. `client_api: { registration_disabled: false }`
+/// only changes `registration_disabled`.
+///
+/// Unless specified otherwise in `homeserver`, rate limiting is disabled.
+pub fn patch_dendrite_config(
+    mut config: Mapping,
+    homeserver: &HomeserverConfig,
+) -> Result<Mapping, Error> {
+    for (path, value) in [
+        (["global", "server_name"], &homeserver.server_name),
+        (["global", "private_key"], &DENDRITE_PRIVATE_KEY.to_string()),
+        // Let clients discover `public_baseurl` from `/.well-known/matrix/client`.
+        (
+            ["global", "well_known_client_name"],
+            &homeserver.public_baseurl,
+        ),
+        (
+            ["client_api", "registration_shared_secret"],
+            &homeserver.registration_shared_secret,
+        ),
+    ] {
+        *entry_mut(&mut config, &path)? = value.to_string().into();
+    }
+    *entry_mut(&mut config, &["client_api", "rate_limiting", "enabled"])? = false.into();
+
+    // Merge extra fields.
+    for (key, value) in &homeserver.extra_fields {
+        merge(
+            config.entry(YAML::from(key.clone())).or_insert(YAML::Null),
+            value,
+        );
+    }
+    Ok(config)
+}
+
+/// Patch the contents of a logging configuration of Synapse, e.g.
+/// `localhost:9999.log.config` or, with workers, `synchrotron1.log.config`.
+///
+/// - `config`: the logging configuration, as generated by Synapse;
+/// - `loggers`: the level of each logger to set, e.g. `("my_module", "DEBUG")`.
+///
+/// Other fields of existing loggers, e.g. their handlers, are kept.
+pub fn patch_log_config(
+    mut config: Mapping,
+    loggers: &[(String, String)],
+) -> Result<Mapping, Error> {
+    if loggers.is_empty() {
+        return Ok(config);
+    }
+    let section = config
+        .entry("loggers".into())
+        .or_insert_with(|| YAML::Mapping(Mapping::new()));
+    if section.is_null() {
+        *section = YAML::Mapping(Mapping::new());
+    }
+    let section = section
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("In log config, expected a mapping for key `loggers`"))?;
+    for (name, level) in loggers {
+        let logger = section.entry(name.as_str().into()).or_insert(YAML::Null);
+        if logger.is_null() {
+            *logger = YAML::Mapping(Mapping::new());
+        }
+        logger
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("In log config, expected a mapping for logger `{}`", name))?
+            .insert("level".into(), level.clone().into());
+    }
+    Ok(config)
+}
+
+/// Find or create the entry at `path`, creating intermediate mappings as needed.
+fn entry_mut<'a>(config: &'a mut Mapping, path: &[&str]) -> Result<&'a mut YAML, Error> {
+    let (last, parents) = path.split_last().expect("Empty path");
+    let mut mapping = config;
+    for key in parents {
+        let value = mapping.entry(YAML::from(*key)).or_insert(YAML::Null);
+        if value.is_null() {
+            *value = YAML::Mapping(Mapping::new());
+        }
+        mapping = value
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("In dendrite.yaml, expected a mapping for key `{}`", key))?;
+    }
+    Ok(mapping.entry(YAML::from(*last)).or_insert(YAML::Null))
+}
+
+/// Merge `value` into `target`, recursively for mappings.
+fn merge(target: &mut YAML, value: &YAML) {
+    match (target.as_mapping_mut(), value.as_mapping()) {
+        (Some(target), Some(value)) => {
+            for (key, value) in value {
+                merge(target.entry(key.clone()).or_insert(YAML::Null), value);
+            }
+        }
+        _ => *target = value.clone(),
+    }
+}
+
+/// Utility trait: determine whether a yaml value is a stand-in for "please use the default"
+/// value provided by Synapse.
+trait IsDefault {
+    fn is_default(&self) -> bool;
+}
+impl IsDefault for serde_yaml::Value {
+    fn is_default(&self) -> bool {
+        if let Some(str) = self.as_str() {
+            if str == "synapse-default" {
+                return true;
+            }
+        }
+        false
+    }
+}
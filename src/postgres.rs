@@ -0,0 +1,384 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The database of Synapse in a dedicated PostgreSQL container, as specified
+//! in `postgres`.
+//!
+//! By default, Synapse uses SQLite or, in workers mode, a PostgreSQL server
+//! running in its own container. With `postgres`, Synapse uses a PostgreSQL
+//! server running in its own container on the same Docker network, in either
+//! mode. The database is not persisted, so users are registered again during
+//! each `up`.
+//!
+//! With `postgres.host`, Synapse uses an already-running PostgreSQL server
+//! instead, e.g. a database provisioned by CI, optionally emptied before `up`.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+
+/// The port of PostgreSQL, within its container.
+const POSTGRES_PORT: u16 = 5432;
+
+/// Configuring the database of Synapse.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct PostgresConfig {
+    /// The Docker image of PostgreSQL.
+    ///
+    /// Defaults to `postgres:16-alpine`.
+    #[serde(default = "PostgresConfig::default_image")]
+    #[builder(default = PostgresConfig::default_image())]
+    pub image: String,
+
+    /// The user owning the database.
+    ///
+    /// Defaults to `synapse`.
+    #[serde(default = "PostgresConfig::default_user")]
+    #[builder(default = PostgresConfig::default_user())]
+    pub user: String,
+
+    /// The password of `user`.
+    ///
+    /// Defaults to `password`.
+    #[serde(default = "PostgresConfig::default_password")]
+    #[builder(default = PostgresConfig::default_password())]
+    pub password: String,
+
+    /// The name of the database.
+    ///
+    /// Defaults to `synapse`.
+    #[serde(default = "PostgresConfig::default_database")]
+    #[builder(default = PostgresConfig::default_database())]
+    pub database: String,
+
+    /// If specified, publish PostgreSQL on this port of the host, e.g. to
+    /// inspect the database from scripts.
+    ///
+    /// Not supported with `host`.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub host_port: Option<u16>,
+
+    /// Additional hostnames of PostgreSQL on the Docker network.
+    ///
+    /// Not supported with `host`.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+
+    /// If specified, use the already-running PostgreSQL server of this host,
+    /// as seen from Synapse, e.g. `host.docker.internal`, instead of starting
+    /// a container. `user`, `password` and `database` must already exist.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub host: Option<String>,
+
+    /// The port of PostgreSQL.
+    ///
+    /// Defaults to 5432. Only meaningful with `host`.
+    #[serde(default = "PostgresConfig::default_port")]
+    #[builder(default = PostgresConfig::default_port())]
+    pub port: u16,
+
+    /// If `true`, drop all the tables of `database` before `up`, so that each
+    /// test starts from an empty database.
+    ///
+    /// Only meaningful with `host`, as the database of the container is
+    /// always empty.
+    #[serde(default)]
+    #[builder(default)]
+    pub reset: bool,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl PostgresConfig {
+    fn default_image() -> String {
+        "postgres:16-alpine".to_string()
+    }
+    fn default_user() -> String {
+        "synapse".to_string()
+    }
+    fn default_password() -> String {
+        "password".to_string()
+    }
+    fn default_database() -> String {
+        "synapse".to_string()
+    }
+    fn default_port() -> u16 {
+        POSTGRES_PORT
+    }
+
+    /// Whether Synapse uses an already-running PostgreSQL server, i.e. `host`
+    /// is specified.
+    pub fn is_external(&self) -> bool {
+        self.host.is_some()
+    }
+
+    /// The host and port of PostgreSQL, as seen from Synapse.
+    pub fn server(&self, config: &Config) -> (String, u16) {
+        match self.host {
+            Some(ref host) => (host.clone(), self.port),
+            None => (container_name(config), POSTGRES_PORT),
+        }
+    }
+
+    /// The URL of the database, as seen from the host, if `host_port` is specified.
+    ///
+    /// With `host`, the URL of the database, as specified.
+    pub fn host_url(&self) -> Option<String> {
+        let (host, port) = match self.host {
+            Some(ref host) => (host.as_str(), self.port),
+            None => ("localhost", self.host_port?),
+        };
+        Some(format!(
+            "postgresql://{}:{}@{}:{}/{}",
+            self.user, self.password, host, port, self.database
+        ))
+    }
+}
+
+/// Check that the options of `postgres` are consistent.
+pub fn check(postgres: &PostgresConfig, problems: &mut Vec<String>) {
+    if postgres.is_external() {
+        if postgres.host_port.is_some() {
+            problems.push("`postgres.host_port` is not supported with `postgres.host`".to_string());
+        }
+        if !postgres.aliases.is_empty() {
+            problems.push("`postgres.aliases` is not supported with `postgres.host`".to_string());
+        }
+    } else if postgres.reset {
+        problems.push("`postgres.reset` requires `postgres.host`".to_string());
+    }
+}
+
+/// The name of the container running PostgreSQL.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-postgres-{}", config.name)
+}
+
+/// Patch the `database` section of homeserver.yaml to use PostgreSQL.
+///
+/// If `database` is specified in mx-tester.yml, it is not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    postgres: &PostgresConfig,
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    let key = "database";
+    if !extra_fields.contains_key(key) {
+        let (host, port) = postgres.server(config);
+        content.insert(
+            key.into(),
+            yaml!({
+                "name" => "psycopg2",
+                "txn_limit" => 10_000,
+                "args" => yaml!({
+                    "user" => postgres.user.clone(),
+                    "password" => postgres.password.clone(),
+                    "database" => postgres.database.clone(),
+                    "host" => host,
+                    "port" => port,
+                    "cp_min" => 5,
+                    "cp_max" => 10
+                })
+            }),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::{
+        container::{
+            Config as BollardContainerConfig, CreateContainerOptions, WaitContainerOptions,
+        },
+        models::HostConfig,
+        Docker,
+    };
+    use futures_util::stream::StreamExt;
+
+    use crate::{
+        lifecycle::{pull_image_if_missing, write_container_logs},
+        services::{stop_container, Container, ContainerPort},
+        util::{exec, wait_until},
+    };
+
+    /// How long we're willing to wait for PostgreSQL to accept connections.
+    const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+    /// Start PostgreSQL, if `postgres` is specified, and wait until it accepts connections.
+    ///
+    /// With `postgres.host`, only reset the database, if `postgres.reset` is specified.
+    ///
+    /// This must happen before Synapse starts.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let postgres = match config.postgres {
+            None => return Ok(()),
+            Some(ref postgres) if postgres.is_external() => {
+                if postgres.reset {
+                    reset(docker, config, postgres).await?;
+                }
+                return Ok(());
+            }
+            Some(ref postgres) => postgres,
+        };
+        let container_name = container_name(config);
+        Container {
+            label: "PostgreSQL".to_string(),
+            name: container_name.clone(),
+            image: postgres.image.clone(),
+            env: vec![
+                format!("POSTGRES_USER={}", postgres.user),
+                format!("POSTGRES_PASSWORD={}", postgres.password),
+                format!("POSTGRES_DB={}", postgres.database),
+                // Synapse refuses databases with any other collation.
+                "POSTGRES_INITDB_ARGS=--encoding=UTF8 --lc-collate=C --lc-ctype=C".to_string(),
+            ],
+            ports: vec![ContainerPort::tcp(
+                POSTGRES_PORT,
+                postgres.host_port.map(u64::from),
+            )],
+            aliases: postgres.aliases.clone(),
+            log_name: "postgres.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await?;
+
+        // During initialization, PostgreSQL only listens on its unix socket.
+        wait_until(READINESS_TIMEOUT, "PostgreSQL", || async {
+            let (code, _) = exec(
+                docker,
+                &container_name,
+                vec![
+                    "pg_isready",
+                    "-h",
+                    "localhost",
+                    "-U",
+                    &postgres.user,
+                    "-d",
+                    &postgres.database,
+                ],
+            )
+            .await?;
+            Ok(code == 0)
+        })
+        .await
+    }
+
+    /// Drop all the tables, sequences, etc. of an already-running PostgreSQL
+    /// server, by recreating schema `public`.
+    ///
+    /// `psql` runs in a short-lived container of `postgres.image`, so that it
+    /// doesn't need to be installed on the host.
+    async fn reset(
+        docker: &Docker,
+        config: &Config,
+        postgres: &PostgresConfig,
+    ) -> Result<(), Error> {
+        let container_name = format!("mx-tester-postgres-reset-{}", config.name);
+        let runtime = config.docker.runtime.container_runtime();
+        let (host, port) = postgres.server(config);
+        pull_image_if_missing(docker, config, &postgres.image).await?;
+        println!(
+            "** resetting database {} on {}:{}",
+            postgres.database, host, port
+        );
+
+        let _ = docker.remove_container(&container_name, None).await;
+        docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: container_name.as_str(),
+                }),
+                BollardContainerConfig {
+                    image: Some(postgres.image.clone()),
+                    env: Some(vec![format!("PGPASSWORD={}", postgres.password)]),
+                    cmd: Some(vec![
+                        "psql".to_string(),
+                        "--set=ON_ERROR_STOP=1".to_string(),
+                        format!("--host={}", host),
+                        format!("--port={}", port),
+                        format!("--username={}", postgres.user),
+                        format!("--dbname={}", postgres.database),
+                        format!(
+                            "--command=DROP SCHEMA public CASCADE; CREATE SCHEMA public AUTHORIZATION \"{}\";",
+                            postgres.user
+                        ),
+                    ]),
+                    labels: Some(config.docker_labels()),
+                    host_config: Some(HostConfig {
+                        network_mode: Some(config.network()),
+                        extra_hosts: runtime.extra_hosts(),
+                        ..HostConfig::default()
+                    }),
+                    ..BollardContainerConfig::default()
+                },
+            )
+            .await
+            .context("Failed to create container to reset PostgreSQL")?;
+        docker
+            .start_container::<String>(&container_name, None)
+            .await
+            .context("Failed to start container to reset PostgreSQL")?;
+        let logs_path = config.logs_dir().join("docker").join("postgres-reset.log");
+        write_container_logs(docker, &container_name, &logs_path).await?;
+        let mut wait = docker.wait_container(
+            &container_name,
+            Some(WaitContainerOptions {
+                condition: "not-running",
+            }),
+        );
+        let mut result = Ok(());
+        while let Some(next) = wait.next().await {
+            if let Err(err) = next {
+                result = Err(err);
+            }
+        }
+        let _ = docker.remove_container(&container_name, None).await;
+        result.with_context(|| {
+            format!(
+                "Could not reset database {}, see {:?}",
+                postgres.database, logs_path
+            )
+        })
+    }
+
+    /// Stop and remove PostgreSQL, if `postgres` is specified, unless it runs
+    /// outside of mx-tester.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        match config.postgres {
+            Some(ref postgres) if !postgres.is_external() => {}
+            _ => return Ok(()),
+        }
+        stop_container(docker, config, &container_name(config), "PostgreSQL").await
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Profiling Synapse during `run`, as specified in `profile`.
+//!
+//! During `build`, py-spy is installed in the image. During `run`, py-spy
+//! samples Synapse (and its workers) in the Synapse container until the `run`
+//! script is complete, then writes a flamegraph, which is moved to
+//! `logs/profile` so that it is part of the artifacts. py-spy needs to ptrace
+//! Synapse, so the container is granted `SYS_PTRACE`.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop, Running};
+
+/// The name of the flamegraph.
+const FLAMEGRAPH: &str = "flamegraph.svg";
+
+/// The profiler to run against Synapse during `run`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Profiler {
+    /// Sample Synapse with py-spy and produce a flamegraph.
+    #[serde(rename = "py-spy")]
+    PySpy,
+}
+
+/// The Dockerfile instructions installing the profiler.
+pub fn dockerfile(profiler: Profiler) -> String {
+    match profiler {
+        Profiler::PySpy => "
+# Profile Synapse during `run`, as per `profile`.
+RUN /usr/local/bin/python -m pip install py-spy
+"
+        .to_string(),
+    }
+}
+
+/// The capabilities the Synapse container needs for profiling.
+pub fn capabilities(config: &Config) -> Option<Vec<String>> {
+    config
+        .profile
+        .map(|Profiler::PySpy| vec!["SYS_PTRACE".to_string()])
+}
+
+/// The directory in which the flamegraph is stored once `run` is complete.
+pub fn profile_dir(config: &Config) -> PathBuf {
+    config.logs_dir().join("profile")
+}
+
+/// The flamegraph, once `run` is complete.
+pub fn flamegraph_path(config: &Config) -> PathBuf {
+    profile_dir(config).join(FLAMEGRAPH)
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::{anyhow, Context, Error};
+    use bollard::Docker;
+    use futures_util::stream::StreamExt;
+    use log::debug;
+    use tokio::task::JoinHandle;
+
+    use crate::util::{exec_output, exec_with, move_file, ExecOptions};
+
+    /// The directory in which py-spy writes, within the Synapse container.
+    const GUEST_PROFILE_DIR: &str = "/data/profile";
+
+    /// The directory in which the profiler writes, on the host.
+    fn host_profile_dir(config: &Config) -> PathBuf {
+        config.synapse_data_dir().join("profile")
+    }
+
+    /// A profiler running in the Synapse container.
+    pub struct Running {
+        /// Complete once the profiler has exited.
+        task: JoinHandle<Result<(), Error>>,
+    }
+
+    /// Start profiling Synapse, if `profile` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<Option<Running>, Error> {
+        let Profiler::PySpy = match config.profile {
+            None => return Ok(None),
+            Some(profiler) => profiler,
+        };
+        for dir in [host_profile_dir(config), profile_dir(config)] {
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+        let dir = host_profile_dir(config);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:?}", dir))?;
+
+        // Synapse is the first process of the container, workers are its subprocesses.
+        // Keep track of the pid of py-spy, to be able to interrupt it in `stop`.
+        let cmd = format!(
+            "echo $$ > {dir}/py-spy.pid && exec /usr/local/bin/py-spy record --pid 1 --subprocesses --format flamegraph --output {dir}/{flamegraph}",
+            dir = GUEST_PROFILE_DIR,
+            flamegraph = FLAMEGRAPH,
+        );
+        let (_, output) = exec_output(
+            docker,
+            &config.run_container_name(),
+            vec!["sh", "-c", &cmd],
+            &as_synapse(),
+        )
+        .await
+        .context("Could not start py-spy")?;
+        let log_path = config.logs_dir().join("docker").join("py-spy.log");
+        let task = tokio::task::spawn(async move {
+            let mut output = output;
+            let mut log = String::new();
+            while let Some(data) = output.next().await {
+                let data = data.context("Error while reading the output of py-spy")?;
+                debug!(target: "py-spy", "{}", data);
+                log.push_str(&format!("{}", data));
+            }
+            std::fs::write(&log_path, log)
+                .with_context(|| format!("Could not write {:?}", log_path))?;
+            Ok(())
+        });
+        println!("** started py-spy against Synapse");
+        Ok(Some(Running { task }))
+    }
+
+    /// Stop profiling Synapse and move the flamegraph to the logs directory.
+    pub async fn stop(
+        docker: &Docker,
+        config: &Config,
+        running: Option<Running>,
+    ) -> Result<(), Error> {
+        let running = match running {
+            None => return Ok(()),
+            Some(running) => running,
+        };
+        // py-spy writes the flamegraph once interrupted.
+        exec_with(
+            docker,
+            &config.run_container_name(),
+            vec![
+                "sh",
+                "-c",
+                &format!("kill -INT $(cat {}/py-spy.pid)", GUEST_PROFILE_DIR),
+            ],
+            &as_synapse(),
+        )
+        .await
+        .context("Could not stop py-spy")?;
+        running
+            .task
+            .await
+            .context("Error while waiting for py-spy")??;
+
+        let source = host_profile_dir(config).join(FLAMEGRAPH);
+        if !source.exists() {
+            return Err(anyhow!(
+                "py-spy did not produce a flamegraph, see {:?}",
+                config.logs_dir().join("docker").join("py-spy.log")
+            ));
+        }
+        let dir = profile_dir(config);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:?}", dir))?;
+        let dest = flamegraph_path(config);
+        move_file(&source, &dest)?;
+        println!("** flamegraph stored at {:?}", dest);
+        Ok(())
+    }
+
+    /// Execute commands in the Synapse container as the user running Synapse, so
+    /// that py-spy may write to the data directory.
+    fn as_synapse() -> ExecOptions {
+        ExecOptions {
+            as_host_user: true,
+            ..ExecOptions::default()
+        }
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A push gateway (Sygnal) and a stub push receiver, as specified in `push`.
+//!
+//! Both run in their own containers on the same Docker network as Synapse.
+//! Sygnal forwards notifications to the apps of `push.apps`, e.g. for tests
+//! against actual push providers. The stub receiver implements the Push Gateway
+//! API itself and keeps every notification it receives, so that tests may
+//! register a pusher against it and check, with `notifications`, that
+//! Synapse actually dispatches push notifications.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{notifications, start, stop};
+
+/// The port on which Sygnal listens, within its container.
+const SYGNAL_PORT: u16 = 5000;
+
+/// The port on which the stub receiver listens, within its container.
+const RECEIVER_PORT: u16 = 8080;
+
+/// The path of the Push Gateway API.
+const NOTIFY_PATH: &str = "/_matrix/push/v1/notify";
+
+/// Configuring the push gateway.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct PushConfig {
+    /// The Docker image of Sygnal.
+    ///
+    /// Defaults to `matrixdotorg/sygnal:latest`.
+    #[serde(default = "PushConfig::default_image")]
+    #[builder(default = PushConfig::default_image())]
+    pub image: String,
+
+    /// The Docker image running the stub receiver, which requires Python 3.
+    ///
+    /// Defaults to `python:3-alpine`.
+    #[serde(default = "PushConfig::default_receiver_image")]
+    #[builder(default = PushConfig::default_receiver_image())]
+    pub receiver_image: String,
+
+    /// The apps of Sygnal, indexed by app id, copied as is to sygnal.yaml.
+    #[serde(default, serialize_with = "crate::util::serialize_sorted")]
+    #[builder(default)]
+    pub apps: HashMap<String, serde_yaml::Value>,
+
+    /// If specified, publish the port of Sygnal on this port of the host.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub host_port: Option<u16>,
+
+    /// Additional hostnames of Sygnal on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl PushConfig {
+    fn default_image() -> String {
+        "matrixdotorg/sygnal:latest".to_string()
+    }
+    fn default_receiver_image() -> String {
+        "python:3-alpine".to_string()
+    }
+}
+
+/// The name of the container running Sygnal.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-sygnal-{}", config.name)
+}
+
+/// The name of the container running the stub receiver.
+pub fn receiver_container_name(config: &Config) -> String {
+    format!("mx-tester-push-receiver-{}", config.name)
+}
+
+/// The URL of the Push Gateway API of Sygnal, as seen from the Docker network,
+/// i.e. the `url` of pushers.
+pub fn gateway_url(config: &Config) -> String {
+    format!(
+        "http://{}:{}{}",
+        container_name(config),
+        SYGNAL_PORT,
+        NOTIFY_PATH
+    )
+}
+
+/// The URL of the Push Gateway API of the stub receiver, as seen from the Docker network.
+pub fn receiver_url(config: &Config) -> String {
+    format!(
+        "http://{}:{}{}",
+        receiver_container_name(config),
+        RECEIVER_PORT,
+        NOTIFY_PATH
+    )
+}
+
+/// The directory containing sygnal.yaml, on the host.
+pub fn sygnal_dir(config: &Config) -> PathBuf {
+    config.test_root().join("sygnal")
+}
+
+/// The contents of sygnal.yaml. Sygnal uses its defaults for anything else.
+pub fn sygnal_config(push: &PushConfig) -> serde_yaml::Value {
+    let mut apps = serde_yaml::Mapping::new();
+    for (app_id, app) in &push.apps {
+        apps.insert(app_id.clone().into(), app.clone());
+    }
+    yaml!({
+        "http" => yaml!({
+            "bind_addresses" => yaml!(["0.0.0.0"]),
+            "port" => SYGNAL_PORT
+        }),
+        "apps" => serde_yaml::Value::Mapping(apps)
+    })
+}
+
+/// Patch homeserver.yaml to let Synapse reach the push gateways, which live
+/// on a private network.
+///
+/// Fields specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    let key = "ip_range_whitelist";
+    if !extra_fields.contains_key(key) {
+        content.insert(
+            key.into(),
+            yaml!(["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"]),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::{container::LogsOptions, Docker};
+    use futures_util::stream::StreamExt;
+
+    use crate::environment::Environment;
+    use crate::services::{stop_container, Container, ContainerPort};
+
+    /// The path of sygnal.yaml, within the container.
+    const GUEST_CONFIG_PATH: &str = "/sygnal.yaml";
+
+    /// The stub receiver: accept every notification, print it on stdout, one
+    /// per line, and reject no pushkey.
+    const RECEIVER_SCRIPT: &str = r#"
+import http.server, json
+
+class Handler(http.server.BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        print(json.dumps(json.loads(body)), flush=True)
+        response = b'{"rejected": []}'
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(response)))
+        self.end_headers()
+        self.wfile.write(response)
+
+http.server.HTTPServer(("0.0.0.0", PORT), Handler).serve_forever()
+"#;
+
+    /// Start Sygnal and the stub receiver, if `push` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let push = match config.push {
+            None => return Ok(()),
+            Some(ref push) => push,
+        };
+
+        let dir = sygnal_dir(config);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:?}", dir))?;
+        let config_path = dir.join("sygnal.yaml");
+        serde_yaml::to_writer(std::fs::File::create(&config_path)?, &sygnal_config(push))
+            .context("Could not write the configuration of Sygnal")?;
+        let host_config_path = Environment::detect(docker).await?.host_path(&config_path)?;
+        Container {
+            label: "Sygnal".to_string(),
+            name: container_name(config),
+            image: push.image.clone(),
+            env: vec![format!("SYGNAL_CONF={}", GUEST_CONFIG_PATH)],
+            ports: vec![ContainerPort::tcp(
+                SYGNAL_PORT,
+                push.host_port.map(u64::from),
+            )],
+            binds: vec![format!(
+                "{}:{}:ro",
+                host_config_path.to_string_lossy(),
+                GUEST_CONFIG_PATH
+            )],
+            aliases: push.aliases.clone(),
+            log_name: "sygnal.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await?;
+
+        Container {
+            label: "push receiver".to_string(),
+            name: receiver_container_name(config),
+            image: push.receiver_image.clone(),
+            cmd: Some(vec![
+                "python3".to_string(),
+                "-c".to_string(),
+                RECEIVER_SCRIPT.replace("PORT", &RECEIVER_PORT.to_string()),
+            ]),
+            ports: vec![ContainerPort::tcp(RECEIVER_PORT, None)],
+            log_name: "push-receiver.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await
+    }
+
+    /// The notifications received so far by the stub receiver, oldest first,
+    /// e.g. `{ "notification": { "event_id": ..., "devices": [...] } }`.
+    pub async fn notifications(
+        docker: &Docker,
+        config: &Config,
+    ) -> Result<Vec<serde_json::Value>, Error> {
+        let mut logs = docker.logs(
+            &receiver_container_name(config),
+            Some(LogsOptions::<String> {
+                stdout: true,
+                ..LogsOptions::default()
+            }),
+        );
+        let mut stdout = String::new();
+        while let Some(next) = logs.next().await {
+            if let bollard::container::LogOutput::StdOut { message } =
+                next.context("Could not read the notifications of the push receiver")?
+            {
+                stdout.push_str(&String::from_utf8_lossy(&message));
+            }
+        }
+        stdout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line).with_context(|| format!("Invalid notification {}", line))
+            })
+            .collect()
+    }
+
+    /// Stop and remove Sygnal and the stub receiver, if `push` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.push.is_none() {
+            return Ok(());
+        }
+        let sygnal_result = stop_container(docker, config, &container_name(config), "Sygnal").await;
+        let receiver_name = receiver_container_name(config);
+        let receiver_result = stop_container(docker, config, &receiver_name, "push receiver").await;
+        sygnal_result.and(receiver_result)
+    }
+}
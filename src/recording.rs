@@ -0,0 +1,517 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording the Matrix client traffic of `run` and replaying it: `mx-tester replay`.
+//!
+//! With `recording`, `mx-tester run` starts a proxy in front of the homeserver
+//! and passes its URL to scripts as `MX_TEST_RECORDING_PROXY`. Every call that
+//! goes through the proxy and may change the state of the homeserver, i.e. any
+//! call but `GET`, `HEAD` and `OPTIONS`, is recorded, along with the user who
+//! performed it and the response, in `logs/recording.json`.
+//!
+//! `mx-tester replay` then performs the same calls, as the same users, against
+//! another environment, replacing the ids of rooms and events of the original
+//! environment with their counterparts in the new one.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::manifest::Manifest;
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{replay, start, stop, Proxy};
+
+/// The fields of responses that hold the id of something created by a call,
+/// e.g. the `room_id` returned by `createRoom`.
+const CREATED_ID_FIELDS: [&str; 3] = ["room_id", "event_id", "content_uri"];
+
+/// Configuring the recording of client traffic during `run`.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct RecordingConfig {
+    /// The port of the host on which the proxy listens.
+    ///
+    /// Defaults to 9980.
+    #[serde(default = "RecordingConfig::default_port")]
+    #[builder(default = RecordingConfig::default_port())]
+    pub port: u16,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl RecordingConfig {
+    fn default_port() -> u16 {
+        9980
+    }
+}
+
+/// The contents of a recording, as stored in `logs/recording.json`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Recording {
+    /// The ids of the rooms and events created during `up`, as per `fixture_ids`.
+    #[serde(default)]
+    pub fixtures: BTreeMap<String, String>,
+
+    /// The calls, in the order in which they were performed.
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+/// A call to the Client-Server API.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Action {
+    /// The user who performed the call, if it was authenticated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+
+    /// e.g. `PUT`.
+    pub method: String,
+
+    /// The path and query string, e.g. `/_matrix/client/v3/rooms/!abc:localhost/send/m.room.message/1`.
+    pub path: String,
+
+    /// The content type of the request, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+
+    /// The body of the request, if it is JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+
+    /// The body of the request, encoded as base64, if it is not JSON, e.g. a media upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_body: Option<String>,
+
+    /// The HTTP status of the response.
+    pub status: u16,
+
+    /// The body of the response, if it is JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<serde_json::Value>,
+}
+
+/// The file in which the recording of the latest `run` is stored, as part of the artifacts.
+pub fn recording_path(config: &Config) -> PathBuf {
+    config.logs_dir().join("recording.json")
+}
+
+/// The ids of the rooms and events created during `up`, indexed by a key that
+/// does not depend on the environment, e.g. `room:alice/0` or `event:greeting`.
+pub fn fixture_ids(manifest: &Manifest) -> BTreeMap<String, String> {
+    let mut ids = BTreeMap::new();
+    if let Some(ref fixtures) = manifest.fixtures {
+        for (key, room) in &fixtures.rooms {
+            ids.insert(format!("room:{}", key), room.room_id.to_string());
+        }
+    }
+    for (label, event) in &manifest.events {
+        ids.insert(format!("event:{}", label), event.event_id.to_string());
+    }
+    ids
+}
+
+/// Whether a call should be recorded.
+///
+/// Calls that do not change the state of the homeserver are skipped, as are
+/// calls that manage sessions, as `replay` logs in by itself.
+pub fn is_recorded(method: &str, path: &str) -> bool {
+    if ["GET", "HEAD", "OPTIONS"]
+        .iter()
+        .any(|skipped| method.eq_ignore_ascii_case(skipped))
+    {
+        return false;
+    }
+    let path = path.split('?').next().unwrap_or(path);
+    !path
+        .split('/')
+        .any(|segment| ["login", "logout", "register", "refresh"].contains(&segment))
+}
+
+/// Percent-encode a string as a path segment, as clients typically do for
+/// room ids and event ids, e.g. `!abc:localhost` => `%21abc%3Alocalhost`.
+fn percent_encode(source: &str) -> String {
+    let mut encoded = String::new();
+    for byte in source.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Replace ids of the original environment with their counterparts in the new
+/// one, both as is and percent-encoded.
+pub fn substitute(source: &str, ids: &HashMap<String, String>) -> String {
+    let mut result = source.to_string();
+    for (old, new) in ids {
+        result = result.replace(old.as_str(), new.as_str());
+        let encoded = percent_encode(old);
+        if encoded != *old {
+            result = result.replace(&encoded, &percent_encode(new));
+        }
+    }
+    result
+}
+
+/// Match the ids of things created by a call in the original environment,
+/// e.g. the `room_id` returned by `createRoom`, with their counterparts in the new one.
+pub fn learn_ids(
+    recorded: &serde_json::Value,
+    replayed: &serde_json::Value,
+    ids: &mut HashMap<String, String>,
+) {
+    for field in CREATED_ID_FIELDS {
+        if let (Some(old), Some(new)) = (
+            recorded.get(field).and_then(serde_json::Value::as_str),
+            replayed.get(field).and_then(serde_json::Value::as_str),
+        ) {
+            if old != new {
+                ids.insert(old.to_string(), new.to_string());
+            }
+        }
+    }
+}
+
+/// The URL of the proxy, as passed to scripts.
+pub fn proxy_url(recording: &RecordingConfig) -> String {
+    format!("http://localhost:{}", recording.port)
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use std::{convert::Infallible, net::SocketAddr, path::Path, sync::Arc};
+
+    use anyhow::{anyhow, Context, Error};
+    use data_encoding::BASE64;
+    use hyper::{
+        header::{AUTHORIZATION, CONNECTION, CONTENT_TYPE, HOST, TRANSFER_ENCODING},
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Request, Response,
+    };
+    use log::debug;
+    use tokio::sync::{oneshot, Mutex};
+
+    /// The state shared by the calls going through the proxy.
+    struct State {
+        /// The homeserver, e.g. `http://localhost:9999`.
+        base_url: String,
+        client: reqwest::Client,
+        recording: Mutex<Recording>,
+        /// The user behind each access token, as per `whoami`.
+        users: Mutex<HashMap<String, Option<String>>>,
+    }
+
+    impl State {
+        /// The user behind an access token.
+        async fn user_id(&self, access_token: &str) -> Option<String> {
+            let mut users = self.users.lock().await;
+            if let Some(user_id) = users.get(access_token) {
+                return user_id.clone();
+            }
+            let user_id = match self
+                .client
+                .get(format!(
+                    "{}/_matrix/client/v3/account/whoami",
+                    self.base_url
+                ))
+                .bearer_auth(access_token)
+                .send()
+                .await
+            {
+                Ok(response) => response
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|whoami| whoami.get("user_id")?.as_str().map(str::to_string)),
+                Err(err) => {
+                    debug!("Could not determine user of access token: {}", err);
+                    None
+                }
+            };
+            users.insert(access_token.to_string(), user_id.clone());
+            user_id
+        }
+    }
+
+    /// The access token of a call, either in the headers or in the query string.
+    fn access_token(headers: &HeaderMap, path: &str) -> Option<String> {
+        if let Some(token) = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            return Some(token.to_string());
+        }
+        let (_, query) = path.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("access_token="))
+            .map(str::to_string)
+    }
+
+    /// Forward a call to the homeserver, recording it if necessary.
+    async fn forward(state: &State, request: Request<Body>) -> Result<Response<Body>, Error> {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/")
+            .to_string();
+        let mut forwarded = state
+            .client
+            .request(parts.method.clone(), format!("{}{}", state.base_url, path))
+            .body(body.clone());
+        for (name, value) in &parts.headers {
+            if name != HOST {
+                forwarded = forwarded.header(name, value);
+            }
+        }
+        let response = forwarded.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let response_body = response.bytes().await?;
+
+        if is_recorded(parts.method.as_str(), &path) {
+            let user_id = match access_token(&parts.headers, &path) {
+                Some(token) => state.user_id(&token).await,
+                None => None,
+            };
+            let (json_body, raw_body) = if body.is_empty() {
+                (None, None)
+            } else {
+                match serde_json::from_slice(&body) {
+                    Ok(json) => (Some(json), None),
+                    Err(_) => (None, Some(BASE64.encode(&body))),
+                }
+            };
+            let action = Action {
+                user_id,
+                method: parts.method.to_string(),
+                path,
+                content_type: parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string),
+                body: json_body,
+                raw_body,
+                status: status.as_u16(),
+                response: serde_json::from_slice(&response_body).ok(),
+            };
+            state.recording.lock().await.actions.push(action);
+        }
+
+        let mut builder = Response::builder().status(status);
+        for (name, value) in &headers {
+            if name != TRANSFER_ENCODING && name != CONNECTION {
+                builder = builder.header(name, value);
+            }
+        }
+        Ok(builder.body(Body::from(response_body))?)
+    }
+
+    /// A running proxy.
+    pub struct Proxy {
+        state: Arc<State>,
+        shutdown: oneshot::Sender<()>,
+        task: tokio::task::JoinHandle<Result<(), hyper::Error>>,
+    }
+
+    /// Start the proxy, if `recording` is specified.
+    pub async fn start(config: &Config) -> Result<Option<Proxy>, Error> {
+        let recording = match config.recording {
+            None => return Ok(None),
+            Some(ref recording) => recording,
+        };
+        let state = Arc::new(State {
+            base_url: config
+                .homeserver
+                .public_baseurl
+                .trim_end_matches('/')
+                .to_string(),
+            client: reqwest::Client::new(),
+            recording: Mutex::new(Recording {
+                fixtures: fixture_ids(&Manifest::load(config)?),
+                actions: vec![],
+            }),
+            users: Mutex::new(HashMap::new()),
+        });
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move {
+                        match forward(&state, request).await {
+                            Ok(response) => Ok::<_, Infallible>(response),
+                            Err(err) => Ok(Response::builder()
+                                .status(502)
+                                .body(Body::from(format!("mx-tester recording proxy: {:#}", err)))
+                                .unwrap()),
+                        }
+                    }
+                }))
+            }
+        });
+        let addr = SocketAddr::from(([127, 0, 0, 1], recording.port));
+        let server = hyper::Server::try_bind(&addr)
+            .with_context(|| format!("Could not start recording proxy on port {}", recording.port))?
+            .serve(make_service);
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let task = tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = shutdown_receiver.await;
+        }));
+        println!(
+            "** recording client traffic through {}",
+            proxy_url(recording)
+        );
+        Ok(Some(Proxy {
+            state,
+            shutdown,
+            task,
+        }))
+    }
+
+    /// Stop the proxy and store the recording.
+    pub async fn stop(config: &Config, proxy: Option<Proxy>) -> Result<(), Error> {
+        let proxy = match proxy {
+            None => return Ok(()),
+            Some(proxy) => proxy,
+        };
+        let _ = proxy.shutdown.send(());
+        proxy
+            .task
+            .await
+            .context("Recording proxy panicked")?
+            .context("Error in recording proxy")?;
+        let recording = proxy.state.recording.lock().await;
+        let path = recording_path(config);
+        std::fs::write(&path, serde_json::to_string_pretty(&*recording)?)
+            .with_context(|| format!("Could not write recording {:?}", path))?;
+        println!(
+            "** recorded {} calls at {:?}",
+            recording.actions.len(),
+            path
+        );
+        Ok(())
+    }
+
+    /// Replay a recording against a homeserver that is up.
+    ///
+    /// Calls are performed as the same users, who must be declared in `users`,
+    /// and must have the same status as when they were recorded.
+    pub async fn replay(config: &Config, path: &Path) -> Result<(), Error> {
+        println!("\n* replay step: starting");
+        let recording: Recording = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read recording {:?}", path))?,
+        )
+        .with_context(|| format!("Invalid recording {:?}", path))?;
+
+        // Start with the rooms and events created during `up`.
+        let current = fixture_ids(&Manifest::load(config)?);
+        let mut ids: HashMap<String, String> = recording
+            .fixtures
+            .iter()
+            .filter_map(|(key, old)| {
+                let new = current.get(key)?;
+                if old == new {
+                    None
+                } else {
+                    Some((old.clone(), new.clone()))
+                }
+            })
+            .collect();
+
+        let base_url = config.homeserver.public_baseurl.trim_end_matches('/');
+        let http = reqwest::Client::new();
+        let mut access_tokens: HashMap<String, String> = HashMap::new();
+        for (index, action) in recording.actions.iter().enumerate() {
+            let path = substitute(&action.path, &ids);
+            let description = format!("call {} ({} {})", index + 1, action.method, path);
+            debug!("Replaying {}", description);
+            let method = reqwest::Method::from_bytes(action.method.as_bytes())
+                .with_context(|| format!("Invalid method in {}", description))?;
+            let mut request = http.request(method, format!("{}{}", base_url, path));
+            if let Some(ref user_id) = action.user_id {
+                if !access_tokens.contains_key(user_id) {
+                    let localname = user_id
+                        .trim_start_matches('@')
+                        .split(':')
+                        .next()
+                        .unwrap_or_default();
+                    let client = crate::registration::user_client(config, localname)
+                        .await
+                        .with_context(|| format!("Could not login as {}", user_id))?;
+                    let token = client
+                        .access_token()
+                        .ok_or_else(|| anyhow!("No access token for {}", user_id))?;
+                    access_tokens.insert(user_id.clone(), token);
+                }
+                request = request.bearer_auth(&access_tokens[user_id]);
+            }
+            if let Some(ref content_type) = action.content_type {
+                request = request.header(CONTENT_TYPE, content_type);
+            }
+            if let Some(ref body) = action.body {
+                request = request.body(substitute(&body.to_string(), &ids));
+            } else if let Some(ref raw_body) = action.raw_body {
+                request = request.body(
+                    BASE64
+                        .decode(raw_body.as_bytes())
+                        .with_context(|| format!("Invalid body in {}", description))?,
+                );
+            }
+            let response = request
+                .send()
+                .await
+                .with_context(|| format!("Error in {}", description))?;
+            let status = response.status().as_u16();
+            let text = response.text().await?;
+            if status != action.status {
+                return Err(anyhow!(
+                    "Unexpected status in {}: expected {}, got {}: {}",
+                    description,
+                    action.status,
+                    status,
+                    text
+                ));
+            }
+            if let (Some(ref recorded), Ok(replayed)) =
+                (&action.response, serde_json::from_str(&text))
+            {
+                learn_ids(recorded, &replayed, &mut ids);
+            }
+        }
+        println!(
+            "* replay step: success, {} calls replayed",
+            recording.actions.len()
+        );
+        Ok(())
+    }
+}
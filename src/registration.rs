@@ -12,49 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{
-    collections::{HashMap, HashSet},
-    convert::TryFrom,
-};
+use std::collections::{HashMap, HashSet};
 
-use anyhow::{anyhow, Context, Error};
-use data_encoding::HEXLOWER;
-use hmac::{Hmac, Mac};
-use log::debug;
-use matrix_sdk::{
-    ruma::{api::client::error::ErrorKind, RoomAliasId},
-    HttpError,
+use ruma::{
+    api::client::push::RuleKind,
+    push::{Action, PushCondition},
 };
-use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use sha1::Sha1;
 use typed_builder::TypedBuilder;
 
-use crate::util::{AsRumaError, Retry};
-
-type HmacSha1 = Hmac<Sha1>;
-
-/// The maximal number of attempts when registering a user..
-const RETRY_ATTEMPTS: u64 = 10;
-const TIMEOUT_SEC: u64 = 15;
+#[cfg(feature = "matrix-client")]
+pub use self::client::{
+    admin_client, handle_user_registration, register_user, retry_attempts, throwaway_client,
+    user_client, RegisteredUser, Registration, RegistrationError,
+};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum RateLimit {
     /// Leave the rate limit unchanged.
-    #[serde(alias = "default")]
+    #[serde(rename = "default", alias = "Default")]
+    #[default]
     Default,
 
     /// Specify that the user shouldn't be rate-limited.
-    #[serde(alias = "unlimited")]
+    #[serde(rename = "unlimited", alias = "Unlimited")]
     Unlimited,
 }
-impl Default for RateLimit {
-    fn default() -> Self {
-        RateLimit::Default
-    }
-}
 
-#[derive(Clone, TypedBuilder, Debug, Deserialize)]
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
 pub struct User {
     /// Create user as admin?
     #[serde(default)]
@@ -68,6 +53,22 @@ pub struct User {
     #[builder(default = User::default_password())]
     pub password: String,
 
+    /// The type of user, e.g. `bot` or `support`, which some modules special-case.
+    /// If unspecified, a regular user.
+    ///
+    /// Only applied when the user is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default)]
+    pub user_type: Option<String>,
+
+    /// If the user already exists with a different password, e.g. from a previous
+    /// run with a different configuration, reset its password through the admin API.
+    ///
+    /// Otherwise, fail.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub reset_password: bool,
+
     #[serde(default)]
     #[builder(default)]
     pub rooms: Vec<Room>,
@@ -77,6 +78,17 @@ pub struct User {
     #[serde(default)]
     #[builder(default)]
     pub rate_limit: RateLimit,
+
+    /// Global account data to set for this user, indexed by event type,
+    /// e.g. `m.direct` or `m.ignored_user_list`.
+    #[serde(default, serialize_with = "crate::util::serialize_sorted")]
+    #[builder(default)]
+    pub account_data: HashMap<String, serde_json::Value>,
+
+    /// Push rules to set for this user.
+    #[serde(default)]
+    #[builder(default)]
+    pub push_rules: Vec<PushRule>,
 }
 
 impl User {
@@ -85,8 +97,181 @@ impl User {
     }
 }
 
+/// Check that `users` and their rooms are consistent, e.g. that room members
+/// are declared, adding a description of each problem to `problems`.
+pub(crate) fn check_users(users: &[User], problems: &mut Vec<String>) {
+    let mut localnames = HashSet::new();
+    for user in users {
+        if !localnames.insert(user.localname.as_str()) {
+            problems.push(format!(
+                "User {} is declared more than once",
+                user.localname
+            ));
+        }
+        if user.user_type.as_deref() == Some("") {
+            problems.push(format!(
+                "User {}: empty `user_type`, omit it for a regular user",
+                user.localname
+            ));
+        }
+    }
+    let mut aliases = HashSet::new();
+    let mut spaces = HashSet::new();
+    let mut labels = HashSet::new();
+    for user in users {
+        for (index, room) in user.rooms.iter().enumerate() {
+            let what = match (&room.alias, &room.name) {
+                (Some(alias), _) => format!("Room {} of user {}", alias, user.localname),
+                (None, Some(name)) => format!("Room {:?} of user {}", name, user.localname),
+                (None, None) => format!("Room #{} of user {}", index, user.localname),
+            };
+            let is_member = |localname: &str| {
+                localname == user.localname || room.members.iter().any(|m| m == localname)
+            };
+            for member in &room.members {
+                if !localnames.contains(member.as_str()) {
+                    problems.push(format!(
+                        "{}: member {} is not declared in `users`",
+                        what, member
+                    ));
+                }
+            }
+            for moderator in &room.moderators {
+                if !localnames.contains(moderator.as_str()) {
+                    problems.push(format!(
+                        "{}: moderator {} is not declared in `users`",
+                        what, moderator
+                    ));
+                }
+            }
+            if let Some(ref alias) = room.alias {
+                if !aliases.insert(alias.as_str()) {
+                    problems.push(format!("{}: alias {} is already in use", what, alias));
+                }
+            }
+            match room.join_rule {
+                Some(JoinRule::Restricted) if room.allow.is_empty() => problems.push(format!(
+                    "{}: restricted rooms need at least one space in `allow`",
+                    what
+                )),
+                Some(JoinRule::Restricted) => {}
+                _ if !room.allow.is_empty() => problems.push(format!(
+                    "{}: `allow` is only meaningful for restricted rooms",
+                    what
+                )),
+                _ => {}
+            }
+            for space in &room.allow {
+                if !spaces.contains(space.as_str()) {
+                    problems.push(format!(
+                        "{}: space {} in `allow` must be declared before this room",
+                        what, space
+                    ));
+                }
+            }
+            if room.space {
+                if let Some(ref alias) = room.alias {
+                    spaces.insert(alias.as_str());
+                }
+            }
+            for rule in &room.policy_rules {
+                if rule.entity.is_empty() {
+                    problems.push(format!(
+                        "{}: policy rule {} needs an `entity`",
+                        what,
+                        rule.kind.event_type()
+                    ));
+                }
+            }
+            // Labels may only refer to earlier messages in the same room.
+            let mut room_labels = HashSet::new();
+            for message in &room.messages {
+                let references = message
+                    .thread
+                    .iter()
+                    .chain(message.replaces.iter())
+                    .chain(message.reaction.iter().map(|reaction| &reaction.to));
+                for label in references {
+                    if !room_labels.contains(label.as_str()) {
+                        problems.push(format!(
+                            "{}: message {} must be declared earlier in the same room",
+                            what, label
+                        ));
+                    }
+                }
+                if message.body.is_none() && message.reaction.is_none() {
+                    problems.push(format!("{}: missing `body` in message {:?}", what, message));
+                }
+                let relations = [
+                    message.thread.is_some(),
+                    message.replaces.is_some(),
+                    message.reaction.is_some(),
+                ];
+                if relations.iter().filter(|is_some| **is_some).count() > 1 {
+                    problems.push(format!(
+                        "{}: conflicting `thread`, `replaces`, `reaction` in message {:?}",
+                        what, message
+                    ));
+                }
+                let senders = message.sender.iter().chain(
+                    message
+                        .redacted
+                        .iter()
+                        .filter_map(|redaction| redaction.by.as_ref()),
+                );
+                for sender in senders {
+                    if !is_member(sender) {
+                        problems.push(format!(
+                            "{}: user {} is not a member of the room",
+                            what, sender
+                        ));
+                    }
+                }
+                if let Some(ref label) = message.label {
+                    if !labels.insert(label.as_str()) {
+                        problems.push(format!(
+                            "{}: message label {} is already in use",
+                            what, label
+                        ));
+                    }
+                    room_labels.insert(label.as_str());
+                }
+            }
+        }
+    }
+}
+
+/// A push rule, as per the Client-Server API.
+///
+/// The rule is created in the global scope.
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
+pub struct PushRule {
+    /// The kind of rule, e.g. `override`, `underride`, `sender`, `room`, `content`.
+    pub kind: RuleKind,
+
+    /// The identifier for the rule.
+    pub rule_id: String,
+
+    /// The actions to perform when this rule is matched.
+    pub actions: Vec<Action>,
+
+    /// The conditions that must hold for this rule to apply.
+    ///
+    /// Only applicable to `override` and `underride` rules.
+    #[serde(default)]
+    #[builder(default)]
+    pub conditions: Vec<PushCondition>,
+
+    /// The glob-style pattern to match against.
+    ///
+    /// Only applicable to `content` rules.
+    #[serde(default)]
+    #[builder(default)]
+    pub pattern: Option<String>,
+}
+
 /// Instructions for creating a room.
-#[derive(Clone, TypedBuilder, Debug, Deserialize)]
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
 pub struct Room {
     /// Whether the room should be public.
     #[serde(default)]
@@ -100,6 +285,15 @@ pub struct Room {
     #[builder(default)]
     pub members: Vec<String>,
 
+    /// Users to promote to moderators (power level 50) once the room
+    /// has been created, e.g. a bot under test or an admin.
+    ///
+    /// These must have been created by mx-tester. They do not need
+    /// to be members of the room.
+    #[serde(default)]
+    #[builder(default)]
+    pub moderators: Vec<String>,
+
     /// A name for the room.
     #[serde(default)]
     #[builder(default)]
@@ -114,337 +308,1139 @@ pub struct Room {
     #[serde(default)]
     #[builder(default)]
     pub topic: Option<String>,
+
+    /// Whether the room should be published in the public room directory.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub publish_to_directory: bool,
+
+    /// Policy rules to publish in this room, making it a policy list
+    /// (aka ban list), e.g. for testing Mjolnir-style moderation.
+    ///
+    /// Members of the room are the users subscribed to the policy list.
+    #[serde(default)]
+    #[builder(default)]
+    pub policy_rules: Vec<PolicyRule>,
+
+    /// The join rule of the room. If unspecified, `public` for public rooms,
+    /// `invite` otherwise.
+    #[serde(default)]
+    #[builder(default)]
+    pub join_rule: Option<JoinRule>,
+
+    /// For `restricted` rooms, the aliases of the spaces whose members may join
+    /// the room, as specified in their `alias`.
+    ///
+    /// These spaces must be declared before this room in mx-tester.yml.
+    #[serde(default)]
+    #[builder(default)]
+    pub allow: Vec<String>,
+
+    /// Whether the room should be created as a space.
+    #[serde(default)]
+    #[builder(default = false)]
+    pub space: bool,
+
+    /// Messages to send once the room has been created and all members have joined,
+    /// in order.
+    #[serde(default)]
+    #[builder(default)]
+    pub messages: Vec<Message>,
 }
 
-/// Register a user using the admin api and a registration shared secret.
-/// The base_url is the Scheme and Authority of the URL to access synapse via.
-/// Returns a RegistrationResponse if registration succeeded, otherwise returns an error.
-async fn register_user(
-    base_url: &str,
-    registration_shared_secret: &str,
-    user: &User,
-) -> Result<(), Error> {
-    #[derive(Debug, Deserialize)]
-    struct GetRegisterResponse {
-        nonce: String,
-    }
-    let registration_url = format!("{}/_synapse/admin/v1/register", base_url);
-    debug!(
-        "Registration shared secret: {}, url: {}, user: {:#?}",
-        registration_shared_secret, registration_url, user
-    );
-    let client = reqwest::Client::new();
-    let nonce = client
-        .get(&registration_url)
-        .auto_retry(RETRY_ATTEMPTS)
-        .await?
-        .json::<GetRegisterResponse>()
-        .await?
-        .nonce;
-    // We use map_err here because Hmac::InvalidKeyLength doesn't implement the std::error::Error trait.
-    let mut mac =
-        HmacSha1::new_from_slice(registration_shared_secret.as_bytes()).map_err(|err| {
-            anyhow!(
-                "Couldn't use the provided registration shared secret to create a hmac: {}",
-                err
-            )
-        })?;
-    mac.update(
-        format!(
-            "{nonce}\0{username}\0{password}\0{admin}",
-            nonce = nonce,
-            username = user.localname,
-            password = user.password,
-            admin = if user.admin { "admin" } else { "notadmin" }
-        )
-        .as_bytes(),
-    );
-
-    #[derive(Debug, Serialize)]
-    struct RegistrationPayload {
-        nonce: String,
-        username: String,
-        displayname: String,
-        password: String,
-        admin: bool,
-        mac: String,
-    }
+/// The rule determining who may join a room.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum JoinRule {
+    #[serde(rename = "public")]
+    Public,
+    #[serde(rename = "invite")]
+    Invite,
+    /// Users may request an invite.
+    #[serde(rename = "knock")]
+    Knock,
+    /// Members of the spaces listed in `allow` may join.
+    #[serde(rename = "restricted")]
+    Restricted,
+}
 
-    let registration_payload = RegistrationPayload {
-        nonce,
-        username: user.localname.to_string(),
-        displayname: user.localname.to_string(),
-        password: user.password.to_string(),
-        admin: user.admin,
-        mac: HEXLOWER.encode(&mac.finalize().into_bytes()),
-    };
-    debug!(
-        "Sending payload {:#?}",
-        serde_json::to_string_pretty(&registration_payload)
-    );
-
-    #[derive(Debug, Deserialize)]
-    struct ErrorResponse {
-        errcode: String,
-        error: String,
+impl JoinRule {
+    /// The value of `join_rule` in `m.room.join_rules`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            JoinRule::Public => "public",
+            JoinRule::Invite => "invite",
+            JoinRule::Knock => "knock",
+            JoinRule::Restricted => "restricted",
+        }
     }
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&registration_url)
-        .json(&registration_payload)
-        .auto_retry(RETRY_ATTEMPTS)
-        .await?;
-    match response.status() {
-        StatusCode::OK => Ok(()),
-        _ => {
-            let body = response.json::<ErrorResponse>().await?;
-            Err(anyhow!(
-                "Homeserver responded with errcode: {}, error: {}",
-                body.errcode,
-                body.error
-            ))
+}
+
+/// The kind of entity to which a policy rule applies.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum PolicyRuleKind {
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "room")]
+    Room,
+    #[serde(rename = "server")]
+    Server,
+}
+impl PolicyRuleKind {
+    /// The type of state event used to publish a rule of this kind.
+    pub fn event_type(&self) -> &'static str {
+        match *self {
+            PolicyRuleKind::User => "m.policy.rule.user",
+            PolicyRuleKind::Room => "m.policy.rule.room",
+            PolicyRuleKind::Server => "m.policy.rule.server",
         }
     }
 }
 
-/// Try to login with the user details provided. If login fails, try to register that user.
-/// If registration then fails, returns an error explaining why, otherwise returns the login details.
-async fn ensure_user_exists(
-    base_url: &str,
-    registration_shared_secret: &str,
-    user: &User,
-) -> Result<matrix_sdk::Client, Error> {
-    debug!(
-        "ensure_user_exists at {}: user {} with password {}",
-        base_url, user.localname, user.password
-    );
-    use matrix_sdk::ruma::api::client::error::*;
-    let homeserver_url = reqwest::Url::parse(base_url)?;
-    let request_config = matrix_sdk::config::RequestConfig::new()
-        .retry_limit(RETRY_ATTEMPTS)
-        .retry_timeout(std::time::Duration::new(TIMEOUT_SEC, 0));
-    let client = matrix_sdk::Client::builder()
-        .request_config(request_config)
-        .homeserver_url(homeserver_url)
-        .build()
-        .await?;
-    match client
-        .login_username(&user.localname, &user.password)
-        .send()
-        .await
+/// A rule in a policy list.
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
+pub struct PolicyRule {
+    /// The kind of entity to which this rule applies.
+    pub kind: PolicyRuleKind,
+
+    /// The entity affected by this rule, possibly with glob characters,
+    /// e.g. `@spammer:*`.
+    pub entity: String,
+
+    /// The recommendation. If unspecified, `m.ban`.
+    #[serde(default = "PolicyRule::default_recommendation")]
+    #[builder(default = PolicyRule::default_recommendation())]
+    pub recommendation: String,
+
+    /// A human-readable reason for this rule.
+    #[serde(default)]
+    #[builder(default)]
+    pub reason: String,
+}
+impl PolicyRule {
+    fn default_recommendation() -> String {
+        "m.ban".to_string()
+    }
+}
+
+/// A message to seed in a room.
+///
+/// By default, this is a plain text message. Use `thread`, `replaces` or `reaction`
+/// to create relations to previous messages.
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
+pub struct Message {
+    /// A label for this message, used to refer to it from later messages
+    /// and from test code. Labels are global to mx-tester.yml.
+    #[serde(default)]
+    #[builder(default)]
+    pub label: Option<String>,
+
+    /// The localname of the sender. If unspecified, the creator of the room.
+    #[serde(default)]
+    #[builder(default)]
+    pub sender: Option<String>,
+
+    /// The text of the message. Required, except for reactions.
+    #[serde(default)]
+    #[builder(default)]
+    pub body: Option<String>,
+
+    /// If specified, the label of the root of a thread, in which this message is posted.
+    #[serde(default)]
+    #[builder(default)]
+    pub thread: Option<String>,
+
+    /// If specified, the label of a message edited by this message.
+    #[serde(default)]
+    #[builder(default)]
+    pub replaces: Option<String>,
+
+    /// If specified, this message is a reaction to another message.
+    #[serde(default)]
+    #[builder(default)]
+    pub reaction: Option<Reaction>,
+
+    /// If specified, redact this message once all messages of the room have been sent.
+    #[serde(default)]
+    #[builder(default)]
+    pub redacted: Option<Redaction>,
+}
+
+/// The redaction of a seeded message.
+#[derive(Clone, TypedBuilder, Debug, Default, Deserialize, Serialize)]
+pub struct Redaction {
+    /// The localname of the user redacting the message, e.g. a moderator of the room.
+    /// If unspecified, the sender of the message.
+    #[serde(default)]
+    #[builder(default)]
+    pub by: Option<String>,
+
+    /// A reason for the redaction.
+    #[serde(default)]
+    #[builder(default)]
+    pub reason: Option<String>,
+}
+
+/// A reaction to a message.
+#[derive(Clone, TypedBuilder, Debug, Deserialize, Serialize)]
+pub struct Reaction {
+    /// The label of the message.
+    pub to: String,
+
+    /// The reaction itself, typically an emoji.
+    pub key: String,
+}
+
+#[cfg(feature = "matrix-client")]
+mod client {
+    use super::*;
+
+    use std::{collections::BTreeMap, convert::TryFrom};
+
+    use anyhow::{anyhow, Context, Error};
+    use data_encoding::HEXLOWER;
+    use hmac::{Hmac, Mac};
+    use log::debug;
+    use matrix_sdk::{
+        ruma::{
+            api::client::{error::ErrorKind, push::set_pushrule, room::Visibility},
+            serde::Raw,
+            OwnedEventId, RoomAliasId, RoomId,
+        },
+        HttpError,
+    };
+    use reqwest::StatusCode;
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        helpers::{get_state_event, send_state_event},
+        manifest::{CreatedRoom, FixtureProgress, Manifest, SeededEvent},
+        util::{retry_rate_limited, AsRumaError, Retry},
+    };
+
+    type HmacSha1 = Hmac<Sha1>;
+
+    /// The maximal number of attempts when registering a user..
+    const RETRY_ATTEMPTS: u64 = 10;
+
+    const TIMEOUT_SEC: u64 = 15;
+
+    /// The power level granted to `moderators` of a room.
+    const MODERATOR_POWER_LEVEL: i64 = 50;
+
+    /// The maximal number of attempts when creating a room, e.g. in case of network errors.
+    const ROOM_CREATION_ATTEMPTS: u64 = 3;
+
+    /// How long to wait before the second attempt at creating a room, then twice as long
+    /// before the third, etc.
+    const ROOM_CREATION_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// Run `request`, with the number of the attempt, starting at 1, retrying on any
+    /// error up to `attempts` times, after `delay` times the number of failed attempts.
+    ///
+    /// Use this only for requests that have no effect when they fail, or that may check
+    /// whether a previous attempt had an effect.
+    pub async fn retry_attempts<T, F, Fut>(
+        what: &str,
+        attempts: u64,
+        delay: std::time::Duration,
+        mut request: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut(u64) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
     {
-        Ok(_) => return Ok(client),
-        Err(err) => {
-            match err.as_ruma_error() {
-                Some(err) if err.kind == ErrorKind::Forbidden => {
-                    debug!("Could not authenticate {}", err);
-                    // Proceed with registration.
+        let mut attempt = 1;
+        loop {
+            match request(attempt).await {
+                Err(err) if attempt < attempts => {
+                    println!(
+                        "** {} failed ({:#}), retrying ({}/{})",
+                        what, err, attempt, attempts
+                    );
+                    tokio::time::sleep(delay * attempt as u32).await;
+                    attempt += 1;
                 }
-                _ => return Err(err).context("Error attempting to login"),
+                result => return result,
             }
         }
     }
-    register_user(base_url, registration_shared_secret, user).await?;
-    client
-        .login_username(&user.localname, &user.password)
-        .send()
-        .await?;
-    Ok(client)
-}
 
-pub async fn handle_user_registration(config: &crate::Config) -> Result<(), Error> {
-    // Create an admin user. We'll need it later to unthrottle users.
-    let admin = ensure_user_exists(
-        &config.homeserver.public_baseurl,
-        &config.homeserver.registration_shared_secret,
-        &User::builder()
-            .admin(true)
-            .localname("mx-tester-admin".to_string())
-            .build(),
-    )
-    .await?;
-
-    let mut clients = HashMap::new();
-    // Create users
-    for user in &config.users {
-        let client = ensure_user_exists(
-            &config.homeserver.public_baseurl,
-            &config.homeserver.registration_shared_secret,
-            user,
-        )
-        .await
-        .with_context(|| format!("Could not setup user {}", user.localname))?;
+    /// The localname of the admin user that mx-tester uses for its own operations.
+    const ADMIN_LOCALNAME: &str = "mx-tester-admin";
 
-        // If the user is not rate limited, remove the rate limit.
-        if let RateLimit::Unlimited = user.rate_limit {
-            use override_rate_limits::*;
-            let user_id = client.user_id().expect("Client doesn't have a user id");
-            let request = Request::new(user_id, Some(0), Some(0));
-            let _ = admin.send(request, None).await?;
+    /// Apply `account_data` and `push_rules` to a user that is already logged in.
+    async fn setup_user_state(client: &matrix_sdk::Client, user: &User) -> Result<(), Error> {
+        for (event_type, content) in &user.account_data {
+            let raw = Raw::new(content)
+                .with_context(|| format!("Invalid account data {}", event_type))?
+                .cast();
+            client
+                .account()
+                .set_account_data_raw(event_type.as_str().into(), raw)
+                .await
+                .with_context(|| format!("Could not set account data {}", event_type))?;
         }
-
-        clients.insert(user.localname.clone(), client);
+        for rule in &user.push_rules {
+            let mut request = set_pushrule::v3::Request::new(
+                "global",
+                rule.kind.clone(),
+                &rule.rule_id,
+                &rule.actions,
+            );
+            request.conditions = &rule.conditions;
+            request.pattern = rule.pattern.as_deref();
+            client
+                .send(request, None)
+                .await
+                .with_context(|| format!("Could not set push rule {}", rule.rule_id))?;
+        }
+        Ok(())
     }
 
-    // Create rooms
-    let mut aliases = HashSet::new();
-    for user in &config.users {
-        if user.rooms.is_empty() {
-            continue;
-        }
-        let client = clients.get(&user.localname).unwrap(); // We just inserted it.
-        let my_user_id = client.user_id().ok_or_else(|| {
-            anyhow!(
-                "Cannot determine full user id for own user {}.",
-                user.localname
-            )
-        })?;
-        for room in &user.rooms {
-            let mut request = matrix_sdk::ruma::api::client::room::create_room::v3::Request::new();
-            if room.public {
-                request.preset = Some(
-                    matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::PublicChat,
-                );
-            } else {
-                request.preset = Some(
-                    matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::PrivateChat,
-                );
-            }
-            if let Some(ref name) = room.name {
-                request.name = Some(name.as_str());
-            }
-            if let Some(ref alias) = room.alias {
-                if !aliases.insert(alias) {
-                    return Err(anyhow!(
-                        "Attempting to create more than one room with alias {}",
-                        alias
-                    ));
+    /// Send the messages of a room, recording labelled messages in `seeded`.
+    async fn seed_messages(
+        clients: &HashMap<String, matrix_sdk::Client>,
+        creator: &str,
+        room_id: &RoomId,
+        messages: &[Message],
+        seeded: &mut BTreeMap<String, SeededEvent>,
+    ) -> Result<(), Error> {
+        let get_client = |localname: &str| {
+            clients.get(localname).ok_or_else(|| {
+                anyhow!(
+                    "Cannot act as user {}: we haven't created this user.",
+                    localname
+                )
+            })
+        };
+        // The latest event of each thread, used for reply fallbacks, indexed by root.
+        let mut thread_latest: HashMap<OwnedEventId, OwnedEventId> = HashMap::new();
+        let mut redactions = vec![];
+        for message in messages {
+            let sender = message.sender.as_deref().unwrap_or(creator);
+            let client = get_client(sender)?;
+            let find = |label: &str| -> Result<OwnedEventId, Error> {
+                match seeded.get(label) {
+                    Some(event) if event.room_id == room_id => Ok(event.event_id.clone()),
+                    Some(_) => Err(anyhow!("Message {} is in a different room", label)),
+                    None => Err(anyhow!(
+                        "Unknown message {}, messages must be labelled before they are referenced",
+                        label
+                    )),
                 }
-                request.room_alias_name = Some(alias.as_ref());
-                // If the alias is already taken, we may need to remove it.
-                let full_alias = format!("#{}:{}", alias, config.homeserver.server_name);
-                debug!("Attempting to register alias {}, this may require unregistering previous instances first.", full_alias);
-                let room_alias_id = <&RoomAliasId as TryFrom<&str>>::try_from(full_alias.as_ref())?;
-                match client
-                    .send(
-                        matrix_sdk::ruma::api::client::alias::delete_alias::v3::Request::new(
-                            room_alias_id,
-                        ),
-                        None,
+            };
+            let text = || -> Result<String, Error> {
+                message
+                    .body
+                    .clone()
+                    .ok_or_else(|| anyhow!("Missing `body` in message {:?}", message))
+            };
+            let (event_type, content) = match (
+                &message.thread,
+                &message.replaces,
+                &message.reaction,
+            ) {
+                (None, None, None) => (
+                    "m.room.message",
+                    serde_json::json!({ "msgtype": "m.text", "body": text()? }),
+                ),
+                (Some(root), None, None) => {
+                    let root = find(root)?;
+                    let in_reply_to = thread_latest.get(&root).unwrap_or(&root).clone();
+                    (
+                        "m.room.message",
+                        serde_json::json!({
+                            "msgtype": "m.text",
+                            "body": text()?,
+                            "m.relates_to": {
+                                "rel_type": "m.thread",
+                                "event_id": root,
+                                "is_falling_back": true,
+                                "m.in_reply_to": { "event_id": in_reply_to },
+                            },
+                        }),
                     )
-                    .await
-                {
-                    // Room alias was successfully removed.
-                    Ok(_) => Ok(()),
-                    // Room alias wasn't removed because it didn't exist.
-                    Err(HttpError::Server(ref code)) if code.as_u16() == 404 => Ok(()),
-                    Err(err) => {
-                        match err.as_ruma_error() {
-                            Some(err) if err.kind == ErrorKind::NotFound => Ok(()),
-                            // Room alias wasn't removed for any other reason.
-                            _ => Err(err),
-                        }
-                    }
                 }
-                .context("Error while attempting to unregister existing alias")?;
+                (None, Some(replaces), None) => {
+                    let body = text()?;
+                    (
+                        "m.room.message",
+                        serde_json::json!({
+                            "msgtype": "m.text",
+                            "body": format!("* {}", body),
+                            "m.new_content": { "msgtype": "m.text", "body": body },
+                            "m.relates_to": { "rel_type": "m.replace", "event_id": find(replaces)? },
+                        }),
+                    )
+                }
+                (None, None, Some(reaction)) => (
+                    "m.reaction",
+                    serde_json::json!({
+                        "m.relates_to": {
+                            "rel_type": "m.annotation",
+                            "event_id": find(&reaction.to)?,
+                            "key": reaction.key,
+                        },
+                    }),
+                ),
+                _ => {
+                    return Err(anyhow!(
+                        "Conflicting `thread`, `replaces`, `reaction` in message {:?}",
+                        message
+                    ))
+                }
+            };
+            let event_id =
+                crate::helpers::send_raw_message_event(client, room_id, event_type, &content)
+                    .await?;
+            if let Some(ref root) = message.thread {
+                thread_latest.insert(find(root)?, event_id.clone());
             }
-            if let Some(ref topic) = room.topic {
-                request.topic = Some(topic.as_ref());
+            if let Some(ref redaction) = message.redacted {
+                let by = redaction.by.as_deref().unwrap_or(sender);
+                redactions.push((get_client(by)?, event_id.clone(), redaction));
             }
-
-            // Place invites.
-            let mut invites = vec![];
-            for member in &room.members {
-                let member_client = clients.get(member).ok_or_else(|| {
-                    anyhow!(
-                        "Cannot invite user {}: we haven't created this user.",
-                        member
-                    )
-                })?;
-                let user_id = member_client
-                    .user_id()
-                    .ok_or_else(|| anyhow!("Cannot determine full user id for user {}.", member))?;
-                if my_user_id == user_id {
-                    // Don't invite oneself.
-                    continue;
+            if let Some(ref label) = message.label {
+                let previous = seeded.insert(
+                    label.clone(),
+                    SeededEvent {
+                        room_id: room_id.to_owned(),
+                        event_id,
+                    },
+                );
+                if previous.is_some() {
+                    return Err(anyhow!("More than one message labelled {}", label));
                 }
-                invites.push(user_id.to_owned());
             }
-            request.invite = &invites;
-            let room_id = client.create_room(request).await?.room_id;
+        }
+        for (client, event_id, redaction) in redactions {
+            crate::helpers::redact(client, room_id, &event_id, redaction.reason.as_deref()).await?;
+            crate::helpers::wait_for_redaction(
+                client,
+                room_id,
+                &event_id,
+                std::time::Duration::from_secs(TIMEOUT_SEC),
+            )
+            .await?;
+        }
+        Ok(())
+    }
 
-            // Respond to invites.
-            for member in &room.members {
-                let member_client = clients.get(member).unwrap(); // We checked this a few lines ago.
-                member_client.join_room_by_id(&room_id).await?;
+    /// Options for registering a user with [`register_user`].
+    #[derive(Clone, Debug, TypedBuilder)]
+    pub struct Registration {
+        pub localname: String,
+
+        /// The password for this user. If unspecified, we use `"password"` as password.
+        #[builder(default = User::default_password())]
+        pub password: String,
+
+        /// Create user as admin?
+        #[builder(default = false)]
+        pub admin: bool,
+
+        /// The display name for this user. If unspecified, we use the localname.
+        #[builder(default, setter(strip_option))]
+        pub displayname: Option<String>,
+
+        /// The type of user, e.g. `bot` or `support`. If unspecified, a regular user.
+        #[builder(default, setter(strip_option))]
+        pub user_type: Option<String>,
+    }
+
+    impl From<&User> for Registration {
+        fn from(user: &User) -> Self {
+            Registration {
+                localname: user.localname.clone(),
+                password: user.password.clone(),
+                admin: user.admin,
+                displayname: None,
+                user_type: user.user_type.clone(),
             }
         }
     }
-    Ok(())
-}
 
-mod override_rate_limits {
-    use matrix_sdk::ruma::api::ruma_api;
-    use matrix_sdk::ruma::UserId;
-    use serde::{Deserialize, Serialize};
+    /// An error returned by the homeserver during [`register_user`].
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct RegistrationError {
+        /// The Matrix error code, e.g. `M_USER_IN_USE`.
+        pub errcode: String,
+        pub error: String,
+    }
 
-    ruma_api! {
-        metadata: {
-            description: "Override rate limits",
-            method: POST,
-            name: "override_rate_limit",
-            unstable_path: "/_synapse/admin/v1/users/:user_id/override_ratelimit",
-            rate_limited: false,
-            authentication: AccessToken,
+    impl std::fmt::Display for RegistrationError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "Homeserver responded with errcode: {}, error: {}",
+                self.errcode, self.error
+            )
         }
+    }
 
-        request: {
-            /// user ID
-            #[ruma_api(path)]
-            pub user_id: &'a UserId,
+    impl std::error::Error for RegistrationError {}
 
-            /// The number of actions that can be performed in a second. Defaults to 0.
-            #[serde(default, skip_serializing_if = "Option::is_none")]
-            pub messages_per_second: Option<u32>,
+    /// A user freshly registered with [`register_user`].
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct RegisteredUser {
+        pub user_id: matrix_sdk::ruma::OwnedUserId,
+        pub access_token: String,
+        #[serde(default)]
+        pub device_id: Option<String>,
+    }
 
-            /// How many actions that can be performed before being limited. Defaults to 0.
-            #[serde(default, skip_serializing_if = "Option::is_none")]
-            pub burst_count: Option<u32>
+    /// Register a user using the admin api and a registration shared secret.
+    ///
+    /// The base_url is the Scheme and Authority of the URL to access synapse via,
+    /// e.g. `config.homeserver.public_baseurl`, and the secret is typically
+    /// `config.homeserver.registration_shared_secret`.
+    ///
+    /// This may be used to create extra users mid-test, e.g.
+    ///
+    /// ```no_run
+    /// # async fn example(config: &mx_tester::Config) -> Result<(), anyhow::Error> {
+    /// use mx_tester::registration::{register_user, Registration};
+    /// let user = register_user(
+    ///     &config.homeserver.public_baseurl,
+    ///     &config.homeserver.registration_shared_secret,
+    ///     &Registration::builder()
+    ///         .localname("bot".to_string())
+    ///         .user_type("bot".to_string())
+    ///         .build(),
+    /// )
+    /// .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn register_user(
+        base_url: &str,
+        registration_shared_secret: &str,
+        registration: &Registration,
+    ) -> Result<RegisteredUser, Error> {
+        #[derive(Debug, Deserialize)]
+        struct GetRegisterResponse {
+            nonce: String,
+        }
+        let registration_url = format!("{}/_synapse/admin/v1/register", base_url);
+        debug!(
+            "Registration shared secret: {}, url: {}, user: {:#?}",
+            registration_shared_secret, registration_url, registration
+        );
+        let client = reqwest::Client::new();
+        let nonce = client
+            .get(&registration_url)
+            .auto_retry(RETRY_ATTEMPTS)
+            .await?
+            .json::<GetRegisterResponse>()
+            .await?
+            .nonce;
+        // We use map_err here because Hmac::InvalidKeyLength doesn't implement the std::error::Error trait.
+        let mut mac =
+            HmacSha1::new_from_slice(registration_shared_secret.as_bytes()).map_err(|err| {
+                anyhow!(
+                    "Couldn't use the provided registration shared secret to create a hmac: {}",
+                    err
+                )
+            })?;
+        mac.update(
+            format!(
+                "{nonce}\0{username}\0{password}\0{admin}",
+                nonce = nonce,
+                username = registration.localname,
+                password = registration.password,
+                admin = if registration.admin {
+                    "admin"
+                } else {
+                    "notadmin"
+                }
+            )
+            .as_bytes(),
+        );
+        if let Some(ref user_type) = registration.user_type {
+            mac.update(format!("\0{}", user_type).as_bytes());
+        }
+
+        #[derive(Debug, Serialize)]
+        struct RegistrationPayload {
+            nonce: String,
+            username: String,
+            displayname: String,
+            password: String,
+            admin: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            user_type: Option<String>,
+            mac: String,
         }
 
-        response: {
-            /// Details about the user.
-            #[ruma_api(body)]
-            pub limits: UserLimits,
+        let registration_payload = RegistrationPayload {
+            nonce,
+            username: registration.localname.to_string(),
+            displayname: registration
+                .displayname
+                .clone()
+                .unwrap_or_else(|| registration.localname.to_string()),
+            password: registration.password.to_string(),
+            admin: registration.admin,
+            user_type: registration.user_type.clone(),
+            mac: HEXLOWER.encode(&mac.finalize().into_bytes()),
+        };
+        debug!(
+            "Sending payload {:#?}",
+            serde_json::to_string_pretty(&registration_payload)
+        );
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(&registration_url)
+            .json(&registration_payload)
+            .auto_retry(RETRY_ATTEMPTS)
+            .await?;
+        match response.status() {
+            StatusCode::OK => Ok(response
+                .json::<RegisteredUser>()
+                .await
+                .context("Invalid response to registration")?),
+            _ => Err(response.json::<RegistrationError>().await?.into()),
         }
     }
 
-    #[derive(Serialize, Deserialize, Clone, Debug)]
-    pub struct UserLimits {
-        pub messages_per_second: u32,
-        pub burst_count: u32,
+    /// Try to login with the user details provided. If login fails, try to register that user.
+    /// If registration then fails, returns an error explaining why, otherwise returns the login details.
+    ///
+    /// If the user already exists (e.g. because registration raced with another `up`),
+    /// login again. If the password doesn't match and `user.reset_password` is set,
+    /// reset the password using `admin`.
+    async fn ensure_user_exists(
+        base_url: &str,
+        registration_shared_secret: &str,
+        user: &User,
+        admin: Option<&matrix_sdk::Client>,
+    ) -> Result<matrix_sdk::Client, Error> {
+        debug!(
+            "ensure_user_exists at {}: user {} with password {}",
+            base_url, user.localname, user.password
+        );
+        let homeserver_url = reqwest::Url::parse(base_url)?;
+        let request_config = matrix_sdk::config::RequestConfig::new()
+            .retry_limit(RETRY_ATTEMPTS)
+            .retry_timeout(std::time::Duration::new(TIMEOUT_SEC, 0));
+        let client = matrix_sdk::Client::builder()
+            .request_config(request_config)
+            .homeserver_url(homeserver_url)
+            .build()
+            .await?;
+        if try_login(&client, user).await? {
+            return Ok(client);
+        }
+        // Proceed with registration.
+        match register_user(
+            base_url,
+            registration_shared_secret,
+            &Registration::from(user),
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => match err.downcast_ref::<RegistrationError>() {
+                Some(RegistrationError { errcode, .. }) if errcode == "M_USER_IN_USE" => {
+                    debug!("User {} already exists", user.localname);
+                    // Either someone registered the user in the meantime, or the
+                    // user exists with another password.
+                    if try_login(&client, user).await? {
+                        return Ok(client);
+                    }
+                    reset_password(user, admin).await?;
+                }
+                _ => return Err(err),
+            },
+        }
+        retry_rate_limited("login", || {
+            client
+                .login_username(&user.localname, &user.password)
+                .send()
+        })
+        .await?;
+        Ok(client)
     }
 
-    impl<'a> Request<'a> {
-        /// Creates an `Request` with the given user ID.
-        pub fn new(
-            user_id: &'a UserId,
-            messages_per_second: Option<u32>,
-            burst_count: Option<u32>,
-        ) -> Self {
-            Self {
-                user_id,
-                messages_per_second,
-                burst_count,
+    /// Login as a user, registering it if necessary.
+    ///
+    /// If `mas` is specified, Synapse does not handle registration or passwords,
+    /// so go through MAS.
+    async fn login(
+        config: &crate::Config,
+        user: &User,
+        admin: Option<&matrix_sdk::Client>,
+    ) -> Result<matrix_sdk::Client, Error> {
+        if config.mas.is_some() {
+            #[cfg(feature = "docker")]
+            return crate::mas::login(config, user).await;
+            #[cfg(not(feature = "docker"))]
+            return Err(anyhow!("`mas` requires feature `docker`"));
+        }
+        ensure_user_exists(
+            &config.homeserver.public_baseurl,
+            &config.homeserver.registration_shared_secret,
+            user,
+            admin,
+        )
+        .await
+    }
+
+    /// Attempt to login, returning `false` if the homeserver rejects the credentials.
+    async fn try_login(client: &matrix_sdk::Client, user: &User) -> Result<bool, Error> {
+        use matrix_sdk::ruma::api::client::error::*;
+        match retry_rate_limited("login", || {
+            client
+                .login_username(&user.localname, &user.password)
+                .send()
+        })
+        .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) => match err.as_ruma_error() {
+                Some(err) if err.kind == ErrorKind::Forbidden => {
+                    debug!("Could not authenticate {}", err);
+                    Ok(false)
+                }
+                _ => Err(err).context("Error attempting to login"),
+            },
+        }
+    }
+
+    /// Reset the password of an existing user, if the user allows it.
+    async fn reset_password(user: &User, admin: Option<&matrix_sdk::Client>) -> Result<(), Error> {
+        let admin = match admin {
+            Some(admin) if user.reset_password => admin,
+            _ => {
+                return Err(anyhow!(
+                    "User {} already exists with a different password, set `reset_password: true` to reset it",
+                    user.localname
+                ))
+            }
+        };
+        let server_name = admin
+            .user_id()
+            .ok_or_else(|| anyhow!("Cannot determine server name"))?
+            .server_name();
+        let user_id =
+            matrix_sdk::ruma::UserId::parse_with_server_name(user.localname.as_str(), server_name)
+                .with_context(|| format!("Invalid localname {}", user.localname))?;
+        println!("** resetting password of user {}", user_id);
+        admin
+            .send(
+                crate::synapse_admin::reset_password::Request::new(&user_id, &user.password),
+                None,
+            )
+            .await
+            .with_context(|| format!("Could not reset password of user {}", user_id))?;
+        Ok(())
+    }
+
+    /// Login as the admin user that mx-tester uses for its own operations,
+    /// creating this user if necessary.
+    pub async fn admin_client(config: &crate::Config) -> Result<matrix_sdk::Client, Error> {
+        login(
+            config,
+            &User::builder()
+                .admin(true)
+                .localname(ADMIN_LOCALNAME.to_string())
+                .build(),
+            None,
+        )
+        .await
+        .context("Could not login as mx-tester admin")
+    }
+
+    /// Login as one of the users declared in `users`.
+    pub async fn user_client(
+        config: &crate::Config,
+        localname: &str,
+    ) -> Result<matrix_sdk::Client, Error> {
+        let user = config
+            .users
+            .iter()
+            .find(|user| user.localname == localname)
+            .ok_or_else(|| anyhow!("User {} is not declared in `users`", localname))?;
+        let admin = if user.reset_password {
+            Some(admin_client(config).await?)
+        } else {
+            None
+        };
+        login(config, user, admin.as_ref())
+            .await
+            .with_context(|| format!("Could not login as {}", localname))
+    }
+
+    /// Login as a user that is not declared in `users`, e.g. a throwaway user
+    /// of `abuse`, registering it if necessary.
+    pub async fn throwaway_client(
+        config: &crate::Config,
+        localname: &str,
+    ) -> Result<matrix_sdk::Client, Error> {
+        login(
+            config,
+            &User::builder().localname(localname.to_string()).build(),
+            None,
+        )
+        .await
+        .with_context(|| format!("Could not login as {}", localname))
+    }
+
+    /// Register the users declared in `users` and create their rooms.
+    ///
+    /// Returns the logged-in client of each user, indexed by localname.
+    pub async fn handle_user_registration(
+        config: &crate::Config,
+    ) -> Result<HashMap<String, matrix_sdk::Client>, Error> {
+        // Create an admin user. We'll need it later to unthrottle users.
+        let admin = admin_client(config).await?;
+
+        let mut clients = HashMap::new();
+        // Create users
+        for user in &config.users {
+            let setup = async {
+                let client = login(config, user, Some(&admin))
+                    .await
+                    .with_context(|| format!("Could not setup user {}", user.localname))?;
+
+                // If the user is not rate limited, remove the rate limit.
+                //
+                // Dendrite has no such API, but mx-tester disables its rate limiting altogether.
+                if let (RateLimit::Unlimited, crate::HomeserverKind::Synapse) =
+                    (&user.rate_limit, config.homeserver.kind)
+                {
+                    use crate::synapse_admin::override_rate_limits::*;
+                    let user_id = client.user_id().expect("Client doesn't have a user id");
+                    let request = Request::new(user_id, Some(0), Some(0));
+                    let _ = admin.send(request, None).await?;
+                }
+
+                setup_user_state(&client, user).await.with_context(|| {
+                    format!("Could not setup state for user {}", user.localname)
+                })?;
+                Ok::<_, Error>(client)
+            };
+            let client =
+                crate::telemetry::span("register user", &[("user", user.localname.clone())], setup)
+                    .await?;
+
+            clients.insert(user.localname.clone(), client);
+        }
+
+        // Create rooms
+        //
+        // If a previous `up` failed halfway through, continue from where it left off.
+        let fingerprint = fixtures_fingerprint(config)?;
+        let mut progress = match Manifest::load(config)?.fixtures {
+            Some(progress) if !progress.complete && progress.fingerprint == fingerprint => {
+                println!(
+                    "** resuming creation of rooms, {} room(s) already created",
+                    progress.rooms.len()
+                );
+                progress
+            }
+            _ => FixtureProgress {
+                fingerprint,
+                seed: config.fixtures_seed,
+                ..FixtureProgress::default()
+            },
+        };
+        let mut seeded = progress
+            .rooms
+            .values()
+            .flat_map(|room| room.events.clone())
+            .collect::<BTreeMap<_, _>>();
+        let mut room_ids_by_alias = HashMap::new();
+        for user in &config.users {
+            if user.rooms.is_empty() {
+                continue;
+            }
+            let client = clients.get(&user.localname).unwrap(); // We just inserted it.
+            let my_user_id = client.user_id().ok_or_else(|| {
+                anyhow!(
+                    "Cannot determine full user id for own user {}.",
+                    user.localname
+                )
+            })?;
+            for (index, room) in user.rooms.iter().enumerate() {
+                let key = format!("{}/{}", user.localname, index);
+                let room_id = match progress.rooms.get(&key) {
+                    Some(created) => {
+                        debug!("Room {} already created as {}", key, created.room_id);
+                        created.room_id.clone()
+                    }
+                    None => {
+                        let mut events = BTreeMap::new();
+                        let room_id = crate::telemetry::span(
+                            "create room",
+                            &[("room", key.clone())],
+                            create_room(
+                                config,
+                                &admin,
+                                &clients,
+                                user,
+                                my_user_id,
+                                room,
+                                &room_ids_by_alias,
+                                &mut events,
+                            ),
+                        )
+                        .await
+                        .with_context(|| format!("Could not create room {}", key))?;
+                        seeded.extend(events.clone());
+                        progress.rooms.insert(
+                            key,
+                            CreatedRoom {
+                                room_id: room_id.clone(),
+                                events,
+                            },
+                        );
+                        Manifest::update(config, |manifest| {
+                            manifest.fixtures = Some(progress.clone())
+                        })?;
+                        room_id
+                    }
+                };
+                if let Some(ref alias) = room.alias {
+                    room_ids_by_alias.insert(alias.as_str(), room_id);
+                }
             }
         }
+        progress.complete = true;
+        Manifest::update(config, |manifest| {
+            manifest.events = seeded;
+            manifest.fixtures = Some(progress);
+        })?;
+        Ok(clients)
     }
 
-    impl Response {
-        /// Creates a new `Response` with all parameters defaulted.
-        #[allow(dead_code)]
-        pub fn new(limits: UserLimits) -> Self {
-            Self { limits }
+    /// A fingerprint of the users and rooms to create, to find out whether we can
+    /// resume from a previous `up`.
+    fn fixtures_fingerprint(config: &crate::Config) -> Result<String, Error> {
+        let users = serde_json::to_vec(&config.users).context("Could not serialize users")?;
+        Ok(HEXLOWER.encode(&Sha1::digest(&users)))
+    }
+
+    /// Create a room, as specified by `user` in `config`, and seed its messages.
+    #[allow(clippy::too_many_arguments)]
+    async fn create_room(
+        config: &crate::Config,
+        admin: &matrix_sdk::Client,
+        clients: &HashMap<String, matrix_sdk::Client>,
+        user: &User,
+        my_user_id: &matrix_sdk::ruma::UserId,
+        room: &Room,
+        room_ids_by_alias: &HashMap<&str, matrix_sdk::ruma::OwnedRoomId>,
+        seeded: &mut BTreeMap<String, SeededEvent>,
+    ) -> Result<matrix_sdk::ruma::OwnedRoomId, Error> {
+        let client = clients.get(&user.localname).unwrap(); // Checked by the caller.
+        let mut request = matrix_sdk::ruma::api::client::room::create_room::v3::Request::new();
+        if room.public {
+            request.preset =
+                Some(matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::PublicChat);
+        } else {
+            request.preset =
+                Some(matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::PrivateChat);
+        }
+        if let Some(ref name) = room.name {
+            request.name = Some(name.as_str());
+        }
+        let full_alias = room
+            .alias
+            .as_ref()
+            .map(|alias| format!("#{}:{}", alias, config.homeserver.server_name));
+        let room_alias_id = match full_alias {
+            Some(ref full_alias) => Some(<&RoomAliasId as TryFrom<&str>>::try_from(
+                full_alias.as_str(),
+            )?),
+            None => None,
+        };
+        if let (Some(ref alias), Some(room_alias_id)) = (&room.alias, room_alias_id) {
+            request.room_alias_name = Some(alias.as_ref());
+            // If the alias is already taken, we may need to remove it.
+            // The alias may have been created by another user, e.g. during
+            // a previous run with a different configuration, so only
+            // an admin can be trusted to remove it.
+            debug!("Attempting to register alias {}, this may require unregistering previous instances first.", room_alias_id);
+            match admin
+                .send(
+                    matrix_sdk::ruma::api::client::alias::delete_alias::v3::Request::new(
+                        room_alias_id,
+                    ),
+                    None,
+                )
+                .await
+            {
+                // Room alias was successfully removed.
+                Ok(_) => Ok(()),
+                // Room alias wasn't removed because it didn't exist.
+                Err(HttpError::Server(ref code)) if code.as_u16() == 404 => Ok(()),
+                Err(err) => {
+                    match err.as_ruma_error() {
+                        Some(err) if err.kind == ErrorKind::NotFound => Ok(()),
+                        // Room alias wasn't removed for any other reason.
+                        _ => Err(err),
+                    }
+                }
+            }
+            .context("Error while attempting to unregister existing alias")?;
+        }
+        if let Some(ref topic) = room.topic {
+            request.topic = Some(topic.as_ref());
+        }
+        if room.publish_to_directory {
+            request.visibility = Visibility::Public;
+        }
+        if room.space {
+            request.creation_content =
+                Some(Raw::new(&serde_json::json!({ "type": "m.space" }))?.cast());
         }
+        let join_rule = match room.join_rule {
+            None if room.allow.is_empty() => None,
+            Some(JoinRule::Restricted) if !room.allow.is_empty() => {
+                let mut allow = vec![];
+                for alias in &room.allow {
+                    let space_id = room_ids_by_alias.get(alias.as_str()).ok_or_else(|| {
+                        anyhow!(
+                            "Unknown space {} in `allow`, it must be declared before this room",
+                            alias
+                        )
+                    })?;
+                    allow.push(
+                        serde_json::json!({ "type": "m.room_membership", "room_id": space_id }),
+                    );
+                }
+                Some(serde_json::json!({ "join_rule": "restricted", "allow": allow }))
+            }
+            Some(JoinRule::Restricted) => {
+                return Err(anyhow!(
+                    "Restricted room {:?} needs at least one space in `allow`",
+                    room.alias
+                ));
+            }
+            Some(rule) if room.allow.is_empty() => {
+                Some(serde_json::json!({ "join_rule": rule.as_str() }))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "`allow` is only meaningful for restricted rooms, in room {:?}",
+                    room.alias
+                ));
+            }
+        };
+
+        // Place invites.
+        let mut invites = vec![];
+        for member in &room.members {
+            let member_client = clients.get(member).ok_or_else(|| {
+                anyhow!(
+                    "Cannot invite user {}: we haven't created this user.",
+                    member
+                )
+            })?;
+            let user_id = member_client
+                .user_id()
+                .ok_or_else(|| anyhow!("Cannot determine full user id for user {}.", member))?;
+            if my_user_id == user_id {
+                // Don't invite oneself.
+                continue;
+            }
+            invites.push(user_id.to_owned());
+        }
+        request.invite = &invites;
+        // Only retry `createRoom` itself: once the room exists, retrying
+        // would create a second room and move the alias to it.
+        let request = &request;
+        let room_id = retry_attempts(
+            "createRoom",
+            ROOM_CREATION_ATTEMPTS,
+            ROOM_CREATION_DELAY,
+            |attempt| async move {
+                if attempt > 1 {
+                    // The previous attempt may have created the room before failing,
+                    // e.g. if the response timed out.
+                    if let Some(room_alias_id) = room_alias_id {
+                        if let Ok(response) = client.resolve_room_alias(room_alias_id).await {
+                            debug!(
+                                "Room {} was created by a previous attempt as {}",
+                                room_alias_id, response.room_id
+                            );
+                            return Ok(response.room_id);
+                        }
+                    }
+                }
+                Ok(
+                    retry_rate_limited("createRoom", || client.create_room(request.clone()))
+                        .await?
+                        .room_id,
+                )
+            },
+        )
+        .await?;
+        if let Some(ref content) = join_rule {
+            send_state_event(client, &room_id, "m.room.join_rules", "", content).await?;
+        }
+
+        // Publish policy rules.
+        for rule in &room.policy_rules {
+            send_state_event(
+                client,
+                &room_id,
+                rule.kind.event_type(),
+                &format!("rule:{}", rule.entity),
+                &serde_json::json!({
+                    "entity": rule.entity,
+                    "recommendation": rule.recommendation,
+                    "reason": rule.reason,
+                }),
+            )
+            .await?;
+        }
+
+        // Respond to invites.
+        for member in &room.members {
+            let member_client = clients.get(member).unwrap(); // We checked this a few lines ago.
+            retry_rate_limited("join", || member_client.join_room_by_id(&room_id)).await?;
+        }
+
+        // Promote moderators.
+        if !room.moderators.is_empty() {
+            let mut power_levels =
+                get_state_event(client, &room_id, "m.room.power_levels", "").await?;
+            let users = power_levels
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("Invalid m.room.power_levels in room {}", room_id))?
+                .entry("users")
+                .or_insert_with(|| serde_json::json!({}))
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("Invalid m.room.power_levels in room {}", room_id))?;
+            for moderator in &room.moderators {
+                let user_id = clients
+                    .get(moderator)
+                    .and_then(|client| client.user_id())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Cannot promote user {}: we haven't created this user.",
+                            moderator
+                        )
+                    })?;
+                let level = users
+                    .entry(user_id.as_str())
+                    .or_insert_with(|| serde_json::json!(0));
+                // Never demote anyone, in particular the creator of the room.
+                if level.as_i64().unwrap_or(0) < MODERATOR_POWER_LEVEL {
+                    *level = serde_json::json!(MODERATOR_POWER_LEVEL);
+                }
+            }
+            send_state_event(client, &room_id, "m.room.power_levels", "", &power_levels)
+                .await
+                .with_context(|| format!("Could not promote moderators in room {}", room_id))?;
+        }
+
+        seed_messages(clients, &user.localname, &room_id, &room.messages, seeded)
+            .await
+            .with_context(|| format!("Could not seed messages in room {}", room_id))?;
+        Ok(room_id)
     }
 }
@@ -0,0 +1,219 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The container runtime running Synapse and bots, as specified in `docker.runtime`.
+//!
+//! Both Docker and Podman serve the Docker Engine API, so all requests go through
+//! `bollard`. What differs is how to reach the daemon and how containers must be
+//! set up to reach the host and to share files with the host's user.
+//!
+//! `build`, `up`, `down` and `Cleanup` connect, then stop and remove their containers,
+//! network and image through the runtime, so that these steps may be adapted to
+//! each runtime.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Error};
+use async_trait::async_trait;
+use bollard::{Docker, API_DEFAULT_VERSION};
+
+use crate::{Config, DockerSsl, Runtime};
+
+/// The timeout for requests, until it is replaced by `docker.timeout_sec`.
+const CONNECT_TIMEOUT_SEC: u64 = 120;
+
+/// A container runtime.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// A human-readable name, e.g. `docker`.
+    fn name(&self) -> &'static str;
+
+    /// Connect to the local daemon.
+    fn connect_local(&self) -> Result<Docker, Error>;
+
+    /// Connect to the daemon, as configured in `config.docker` and `config.credentials`,
+    /// without checking that the daemon responds.
+    fn connect(&self, config: &Config) -> Result<Docker, Error> {
+        let has_docker_cert_path = std::env::var("DOCKER_CERT_PATH").is_ok();
+        let mut docker = match (
+            config.docker.ssl,
+            &config.credentials.serveraddress,
+            has_docker_cert_path,
+        ) {
+            // No server configured => we can only run locally.
+            (DockerSsl::Never, None, _) | (DockerSsl::Detect, None, _) => {
+                log::info!("Using local {} repository", self.name());
+                self.connect_local()
+            }
+            (DockerSsl::Always, None, _) => Err(anyhow!(
+                "Option conflict: docker ssl `always` requires a server address, e.g. option `--server`"
+            )),
+            // Server configured => we can run either with HTTP or SSL.
+            (DockerSsl::Never, Some(server), _) | (DockerSsl::Detect, Some(server), false) => {
+                log::info!("Using docker repository with HTTP {}", server);
+                Docker::connect_with_http_defaults().context("Connecting with HTTP")
+            }
+            (DockerSsl::Always, Some(server), _) | (DockerSsl::Detect, Some(server), true) => {
+                log::info!("Using docker repository with SSL {}", server);
+                Docker::connect_with_ssl_defaults().context("Connecting with SSL")
+            }
+        }
+        .with_context(|| format!("Failed to connect to the {} daemon", self.name()))?;
+        docker.set_timeout(std::time::Duration::from_secs(config.docker.timeout_sec));
+        Ok(docker)
+    }
+
+    /// Stop a container.
+    async fn stop_container(
+        &self,
+        docker: &Docker,
+        name: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        docker.stop_container(name, None).await
+    }
+
+    /// Remove a stopped container.
+    async fn remove_container(
+        &self,
+        docker: &Docker,
+        name: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        docker.remove_container(name, None).await
+    }
+
+    /// Remove a network, once its containers have been removed.
+    async fn remove_network(
+        &self,
+        docker: &Docker,
+        name: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        docker.remove_network(name).await
+    }
+
+    /// Remove an image, e.g. before rebuilding it.
+    async fn remove_image(
+        &self,
+        docker: &Docker,
+        name: &str,
+    ) -> Result<(), bollard::errors::Error> {
+        docker.remove_image(name, None, None).await.map(|_| ())
+    }
+
+    /// The socket of the local daemon, if it is reached through a socket.
+    fn socket(&self) -> Option<PathBuf>;
+
+    /// The entries to add to `/etc/hosts` in containers, so that they can
+    /// reach the host as `host.docker.internal`.
+    fn extra_hosts(&self) -> Option<Vec<String>>;
+
+    /// The user namespace of containers, if the default is not appropriate.
+    fn userns_mode(&self) -> Option<String>;
+}
+
+/// The Docker daemon.
+pub struct DockerRuntime;
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+    fn name(&self) -> &'static str {
+        "docker"
+    }
+    fn connect_local(&self) -> Result<Docker, Error> {
+        Docker::connect_with_local_defaults().context("Connecting with local defaults")
+    }
+    fn socket(&self) -> Option<PathBuf> {
+        match std::env::var("DOCKER_HOST") {
+            Ok(host) => host.strip_prefix("unix://").map(PathBuf::from),
+            Err(_) => Some(PathBuf::from("/var/run/docker.sock")),
+        }
+    }
+    fn extra_hosts(&self) -> Option<Vec<String>> {
+        // On macOS and Windows, this is expected to be transparent but
+        // on Linux, an option needs to be added.
+        if cfg!(target_os = "linux") {
+            Some(vec!["host.docker.internal:host-gateway".to_string()])
+        } else {
+            None
+        }
+    }
+    fn userns_mode(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The Podman API service, e.g. `podman system service`.
+pub struct PodmanRuntime;
+impl PodmanRuntime {
+    /// The socket of the Podman API service, either from `CONTAINER_HOST`,
+    /// or the default socket for the current user.
+    fn default_socket() -> PathBuf {
+        if let Ok(host) = std::env::var("CONTAINER_HOST") {
+            if let Some(path) = host.strip_prefix("unix://") {
+                return PathBuf::from(path);
+            }
+        }
+        if Self::is_rootless() {
+            if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
+                return PathBuf::from(dir).join("podman").join("podman.sock");
+            }
+        }
+        PathBuf::from("/run/podman/podman.sock")
+    }
+
+    fn is_rootless() -> bool {
+        !nix::unistd::getuid().is_root()
+    }
+}
+#[async_trait]
+impl ContainerRuntime for PodmanRuntime {
+    fn name(&self) -> &'static str {
+        "podman"
+    }
+    fn connect_local(&self) -> Result<Docker, Error> {
+        let socket = Self::default_socket();
+        Docker::connect_with_unix(
+            &socket.to_string_lossy(),
+            CONNECT_TIMEOUT_SEC,
+            API_DEFAULT_VERSION,
+        )
+        .with_context(|| format!("Connecting to podman socket {:?}", socket))
+    }
+    fn socket(&self) -> Option<PathBuf> {
+        Some(Self::default_socket())
+    }
+    fn extra_hosts(&self) -> Option<Vec<String>> {
+        // Podman adds `host.containers.internal` (and, in recent versions,
+        // `host.docker.internal`) itself, and does not support `host-gateway` until 5.0.
+        None
+    }
+    fn userns_mode(&self) -> Option<String> {
+        // With rootless Podman, the uid of the container's user maps to a subuid
+        // on the host, so keep the uid of the host's user to be able to read and
+        // remove files written by Synapse.
+        if Self::is_rootless() {
+            Some("keep-id".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+impl Runtime {
+    /// The implementation of this runtime.
+    pub fn container_runtime(self) -> &'static dyn ContainerRuntime {
+        match self {
+            Runtime::Docker => &DockerRuntime,
+            Runtime::Podman => &PodmanRuntime,
+        }
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arbitrary helper containers, as declared in `services`.
+//!
+//! Each service runs in its own container, on the same Docker network as
+//! Synapse, e.g. a database, a bridge or a mock of a third-party API. Services
+//! are started during `up`, before the homeserver, in the order of their
+//! `depends_on`, and stopped during `down`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{util, Config, PortMapping};
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+#[cfg(feature = "docker")]
+pub(crate) use self::docker::{stop_container, Container, ContainerPort};
+
+/// A helper container, running alongside Synapse.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Service {
+    /// A name for the service, unique within this test.
+    ///
+    /// Used to name its container and logs.
+    pub name: String,
+
+    /// The Docker image to run, e.g. `postgres:14`.
+    pub image: String,
+
+    /// If specified, override the command of the image.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+
+    /// Additional environment variables for the service.
+    #[serde(default, serialize_with = "util::serialize_sorted")]
+    pub env: HashMap<String, String>,
+
+    /// Ports of the container published on the host.
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+
+    /// Directories or files of the host mounted in the container.
+    #[serde(default)]
+    pub volumes: Vec<ServiceVolume>,
+
+    /// The names of services to start before this one.
+    ///
+    /// If a dependency has a healthcheck, wait until it is healthy.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// Additional hostnames of the service on the Docker network.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+/// A directory or file of the host mounted in the container of a service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServiceVolume {
+    /// The path on the host, relative to the current directory.
+    pub host: PathBuf,
+
+    /// The path in the container.
+    pub guest: PathBuf,
+
+    /// If `true`, mount read-only.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// The name of the container running a service.
+pub fn container_name(config: &Config, service: &Service) -> String {
+    format!("mx-tester-service-{}-{}", config.name, service.name)
+}
+
+/// The services, in the order in which they must be started, i.e. each
+/// service after the services it depends on.
+///
+/// Fails if a service depends on an unknown service or if dependencies
+/// form a cycle.
+pub fn start_order(services: &[Service]) -> Result<Vec<&Service>, Error> {
+    let by_name: HashMap<&str, &Service> = services
+        .iter()
+        .map(|service| (service.name.as_str(), service))
+        .collect();
+    let mut order: Vec<&Service> = vec![];
+    // Depth-first traversal, `visiting` detects cycles.
+    fn visit<'a>(
+        service: &'a Service,
+        by_name: &HashMap<&str, &'a Service>,
+        visiting: &mut Vec<&'a str>,
+        order: &mut Vec<&'a Service>,
+    ) -> Result<(), Error> {
+        if order.iter().any(|done| done.name == service.name) {
+            return Ok(());
+        }
+        if visiting.contains(&service.name.as_str()) {
+            return Err(anyhow!(
+                "Service {}: circular `depends_on` ({} -> {})",
+                service.name,
+                visiting.join(" -> "),
+                service.name
+            ));
+        }
+        visiting.push(&service.name);
+        for dependency in &service.depends_on {
+            let dependency = by_name.get(dependency.as_str()).ok_or_else(|| {
+                anyhow!(
+                    "Service {}: depends on unknown service {}",
+                    service.name,
+                    dependency
+                )
+            })?;
+            visit(dependency, by_name, visiting, order)?;
+        }
+        visiting.pop();
+        order.push(service);
+        Ok(())
+    }
+    for service in services {
+        visit(service, &by_name, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::{
+        container::{Config as BollardContainerConfig, CreateContainerOptions},
+        models::{HealthStatusEnum, HostConfig, PortBinding},
+        Docker,
+    };
+    use log::debug;
+
+    use crate::{
+        environment::Environment,
+        lifecycle::{networking_config, pull_image_if_missing, write_container_logs, DockerExt},
+    };
+
+    /// How long we're willing to wait for a service to become healthy before
+    /// starting the services that depend on it.
+    const TIMEOUT_HEALTHY: std::time::Duration = std::time::Duration::from_secs(120);
+
+    /// Start all services, each once the services it depends on are up.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        for service in start_order(&config.services)? {
+            for dependency in config
+                .services
+                .iter()
+                .filter(|candidate| service.depends_on.contains(&candidate.name))
+            {
+                wait_healthy(docker, &container_name(config, dependency))
+                    .await
+                    .with_context(|| {
+                        format!("Service {}: dependency {}", service.name, dependency.name)
+                    })?;
+            }
+            start_service(docker, config, service)
+                .await
+                .with_context(|| format!("Could not start service {}", service.name))?;
+        }
+        Ok(())
+    }
+
+    /// Wait until a container is healthy, if it has a healthcheck.
+    async fn wait_healthy(docker: &Docker, container_name: &str) -> Result<(), Error> {
+        util::wait_until(TIMEOUT_HEALTHY, container_name, || async {
+            match docker.container_health(container_name).await? {
+                None | Some(HealthStatusEnum::HEALTHY) => Ok(true),
+                Some(HealthStatusEnum::UNHEALTHY) => {
+                    Err(anyhow!("Container {} is unhealthy", container_name))
+                }
+                Some(_) => Ok(false),
+            }
+        })
+        .await
+    }
+
+    async fn start_service(
+        docker: &Docker,
+        config: &Config,
+        service: &Service,
+    ) -> Result<(), Error> {
+        let mut binds = vec![];
+        if !service.volumes.is_empty() {
+            let environment = Environment::detect(docker).await?;
+            for volume in &service.volumes {
+                binds.push(format!(
+                    "{}:{}:{}",
+                    environment.host_path(&volume.host)?.to_string_lossy(),
+                    volume.guest.to_string_lossy(),
+                    if volume.read_only { "ro" } else { "rw" }
+                ));
+            }
+        }
+        Container {
+            label: format!("service {}", service.name),
+            name: container_name(config, service),
+            image: service.image.clone(),
+            cmd: service.command.clone(),
+            env: service
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+            ports: service
+                .ports
+                .iter()
+                .map(|mapping| ContainerPort::tcp(mapping.guest, Some(mapping.host)))
+                .collect(),
+            binds,
+            aliases: service.aliases.clone(),
+            log_name: format!("service-{}.log", service.name),
+        }
+        .start(docker, config)
+        .await
+    }
+
+    /// A container running alongside Synapse, either a service or a sidecar
+    /// of mx-tester, e.g. coturn or Sydent.
+    #[derive(Default)]
+    pub(crate) struct Container {
+        /// A human-readable name, used in messages, e.g. `Sydent`.
+        pub label: String,
+
+        /// The name of the container.
+        pub name: String,
+
+        /// The Docker image to run.
+        pub image: String,
+
+        /// If specified, override the command of the image.
+        pub cmd: Option<Vec<String>>,
+
+        /// Environment variables, e.g. `KEY=value`.
+        pub env: Vec<String>,
+
+        /// Ports of the container, published on the host if specified.
+        pub ports: Vec<ContainerPort>,
+
+        /// Directories or files of the host mounted in the container, e.g. `/host:/guest:ro`.
+        pub binds: Vec<String>,
+
+        /// Additional hostnames of the container on the Docker network.
+        pub aliases: Vec<String>,
+
+        /// The name of the file in which to store the logs, in the Docker logs directory.
+        pub log_name: String,
+    }
+
+    /// A port of a container, e.g. `8090/tcp`.
+    pub(crate) struct ContainerPort {
+        /// The port and protocol, e.g. `8090/tcp`.
+        pub key: String,
+
+        /// The port on the host, if the port should be published.
+        pub host: Option<u64>,
+    }
+
+    impl ContainerPort {
+        /// A TCP port, published on the host if `host` is specified.
+        pub fn tcp(guest: impl Into<u64>, host: Option<u64>) -> Self {
+            ContainerPort {
+                key: format!("{}/tcp", guest.into()),
+                host,
+            }
+        }
+
+        /// A UDP port, published on the host if `host` is specified.
+        pub fn udp(guest: impl Into<u64>, host: Option<u64>) -> Self {
+            ContainerPort {
+                key: format!("{}/udp", guest.into()),
+                host,
+            }
+        }
+    }
+
+    impl Container {
+        /// Pull the image if necessary, remove any leftover from a previous run,
+        /// then create and start the container and store its logs.
+        pub async fn start(self, docker: &Docker, config: &Config) -> Result<(), Error> {
+            let runtime = config.docker.runtime.container_runtime();
+            pull_image_if_missing(docker, config, &self.image).await?;
+
+            // Remove any leftover from a previous run.
+            let _ = runtime.stop_container(docker, &self.name).await;
+            let _ = runtime.remove_container(docker, &self.name).await;
+
+            let mut exposed_ports = HashMap::new();
+            let mut port_bindings = HashMap::new();
+            for port in &self.ports {
+                exposed_ports.insert(port.key.clone(), HashMap::new());
+                if let Some(host) = port.host {
+                    port_bindings.insert(
+                        port.key.clone(),
+                        Some(vec![PortBinding {
+                            host_port: Some(format!("{}", host)),
+                            ..PortBinding::default()
+                        }]),
+                    );
+                }
+            }
+
+            debug!("Creating container {}", self.name);
+            docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: self.name.as_str(),
+                    }),
+                    BollardContainerConfig {
+                        env: Some(self.env),
+                        image: Some(self.image),
+                        cmd: self.cmd,
+                        exposed_ports: Some(exposed_ports),
+                        labels: Some(config.docker_labels()),
+                        networking_config: networking_config(config, &self.aliases),
+                        host_config: Some(HostConfig {
+                            network_mode: Some(config.network()),
+                            binds: Some(self.binds),
+                            port_bindings: Some(port_bindings),
+                            extra_hosts: runtime.extra_hosts(),
+                            userns_mode: runtime.userns_mode(),
+                            ..HostConfig::default()
+                        }),
+                        ..BollardContainerConfig::default()
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to create {} container", self.label))?;
+            docker
+                .start_container::<String>(&self.name, None)
+                .await
+                .with_context(|| format!("Failed to start {} container", self.label))?;
+
+            let logs_path = config.logs_dir().join("docker").join(&self.log_name);
+            println!(
+                "** started {}. Logs will be stored at {:?}",
+                self.label, logs_path
+            );
+            write_container_logs(docker, &self.name, &logs_path).await
+        }
+    }
+
+    /// Stop and remove a container started with `Container::start`, if it exists.
+    pub(crate) async fn stop_container(
+        docker: &Docker,
+        config: &Config,
+        container_name: &str,
+        label: &str,
+    ) -> Result<(), Error> {
+        let runtime = config.docker.runtime.container_runtime();
+        debug!(target: "mx-tester-down", "Taking down {}", container_name);
+        let _ = runtime.stop_container(docker, container_name).await;
+        match runtime.remove_container(docker, container_name).await {
+            Ok(())
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Error removing {} container", label)),
+        }
+    }
+
+    /// Stop and remove all services, dependents first.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let mut result = Ok(());
+        let order = start_order(&config.services)?;
+        for service in order.into_iter().rev() {
+            let label = format!("service {}", service.name);
+            result = result.and(
+                stop_container(docker, config, &container_name(config, service), &label).await,
+            );
+        }
+        result
+    }
+}
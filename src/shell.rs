@@ -0,0 +1,348 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running commands in the container of the homeserver, interactively with
+//! `mx-tester shell` or with output captured in the logs with `mx-tester exec`.
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{
+    container::LogOutput,
+    exec::{CreateExecOptions, ResizeExecOptions, StartExecResults},
+    Docker,
+};
+use futures_util::StreamExt;
+use log::debug;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    lifecycle::{synapse_env, DockerExt},
+    log_header,
+    util::{exec_output, ExecOptions},
+    Config, HARDCODED_GUEST_PORT,
+};
+
+/// The command run by default: bash if the image has it, e.g. Synapse,
+/// otherwise sh, e.g. Dendrite.
+const DEFAULT_COMMAND: [&str; 3] = [
+    "sh",
+    "-c",
+    "if command -v bash > /dev/null; then exec bash; else exec sh; fi",
+];
+
+/// Run `command`, or a shell if empty, in the container of the homeserver,
+/// attached to the terminal, and return its exit code.
+///
+/// The session starts in `/data`, the data directory of the homeserver.
+pub async fn shell(
+    docker: &Docker,
+    config: &Config,
+    command: &[String],
+    user: Option<&str>,
+) -> Result<i64, Error> {
+    let container_name = config.run_container_name();
+    if !docker.is_container_running(&container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running, please run `mx-tester up` first",
+            container_name
+        ));
+    }
+    let cmd: Vec<String> = if command.is_empty() {
+        DEFAULT_COMMAND.iter().map(|s| s.to_string()).collect()
+    } else {
+        command.to_vec()
+    };
+    let tty = atty_stdin();
+    let exec = docker
+        .create_exec(
+            &container_name,
+            CreateExecOptions {
+                cmd: Some(cmd),
+                attach_stdin: Some(true),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                tty: Some(tty),
+                user: user.map(str::to_string),
+                working_dir: Some("/data".to_string()),
+                env: Some(vec![format!(
+                    "TERM={}",
+                    std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string())
+                )]),
+                ..CreateExecOptions::default()
+            },
+        )
+        .await
+        .with_context(|| format!("Could not open a session in {}", container_name))?;
+    let (mut output, mut input) = match docker.start_exec(&exec.id, None).await? {
+        StartExecResults::Attached { output, input } => (output, input),
+        StartExecResults::Detached => {
+            return Err(anyhow!("Could not attach to {}", container_name))
+        }
+    };
+
+    // Restored when dropped, i.e. once the session is over.
+    let _raw_mode = if tty {
+        let raw_mode = RawMode::enable()?;
+        resize(docker, &exec.id).await;
+        watch_resize(docker.clone(), exec.id.clone());
+        Some(raw_mode)
+    } else {
+        None
+    };
+
+    tokio::task::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        if let Err(err) = tokio::io::copy(&mut stdin, &mut input).await {
+            debug!("Stopped forwarding input: {}", err);
+        }
+        let _ = input.shutdown().await;
+    });
+    let mut stdout = tokio::io::stdout();
+    while let Some(data) = output.next().await {
+        let data = data.context("Error during session")?;
+        stdout.write_all(&data.into_bytes()).await?;
+        stdout.flush().await?;
+    }
+
+    let code = docker
+        .inspect_exec(&exec.id)
+        .await
+        .context("Could not get the exit code of the session")?
+        .exit_code
+        .unwrap_or_default();
+    debug!("Session in {} exited with code {}", container_name, code);
+    Ok(code)
+}
+
+/// The environment variables of `exec`: those passed to scripts, as they
+/// make sense from the guest, and those of Synapse.
+fn guest_env(config: &Config) -> Result<Vec<String>, Error> {
+    let mut env: Vec<String> = config
+        .shared_env_variables()?
+        .into_iter()
+        // Directories of the host.
+        .filter(|(key, _)| {
+            ![
+                crate::MX_TEST_SYNAPSE_DIR.as_os_str(),
+                crate::MX_TEST_SCRIPT_TMPDIR.as_os_str(),
+                crate::MX_TEST_CWD.as_os_str(),
+                crate::MX_TEST_APPSERVICES_DIR.as_os_str(),
+                crate::MX_TEST_HOMESERVER_URL.as_os_str(),
+            ]
+            .contains(key)
+        })
+        .map(|(key, value)| format!("{}={}", key.to_string_lossy(), value.to_string_lossy()))
+        .collect();
+    env.push(format!(
+        "{}=http://localhost:{}",
+        crate::MX_TEST_HOMESERVER_URL.to_string_lossy(),
+        HARDCODED_GUEST_PORT
+    ));
+    env.extend(synapse_env(config, &config.workers.types));
+    env.sort();
+    Ok(env)
+}
+
+/// Run `command` in the container of the homeserver, with the environment
+/// variables of mx-tester, and return its exit code.
+///
+/// In addition to the terminal, stdout and stderr are appended to `exec.out`
+/// and `exec.log` in `scripts_logs_dir()`. `MX_TEST_HOMESERVER_URL` is the
+/// address of the homeserver from within the container, variables that are
+/// directories of the host are not set.
+pub async fn exec(docker: &Docker, config: &Config, command: &[String]) -> Result<i64, Error> {
+    if command.is_empty() {
+        return Err(anyhow!("Missing command to execute"));
+    }
+    let container_name = config.run_container_name();
+    if !docker.is_container_running(&container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running, please run `mx-tester up` first",
+            container_name
+        ));
+    }
+    let log_dir = config.scripts_logs_dir();
+    tokio::fs::create_dir_all(&log_dir)
+        .await
+        .with_context(|| format!("Could not create directory {:?}", log_dir))?;
+    let open = |extension: &str| {
+        let path = log_dir.join("exec").with_extension(extension);
+        async move {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+                .with_context(|| format!("Could not open log file {:?}", path))?;
+            file.write_all(
+                format!("\n{}command: {}\n", log_header("exec"), command.join(" ")).as_bytes(),
+            )
+            .await
+            .with_context(|| format!("Could not write log file {:?}", path))?;
+            Ok::<_, Error>(file)
+        }
+    };
+    let mut stdout_log = open("out").await?;
+    let mut stderr_log = open("log").await?;
+    println!(
+        "** executing `{}` in {}. See stdout and stderr captures in {:?}",
+        command.join(" "),
+        container_name,
+        log_dir.join("exec")
+    );
+
+    let (id, mut output) = exec_output(
+        docker,
+        &container_name,
+        command.iter().map(String::as_str).collect(),
+        &ExecOptions {
+            env: guest_env(config)?,
+            working_dir: Some("/data".to_string()),
+            ..ExecOptions::default()
+        },
+    )
+    .await?;
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    while let Some(data) = output.next().await {
+        match data.context("Error during execution")? {
+            LogOutput::StdErr { message } => {
+                stderr.write_all(&message).await?;
+                stderr_log.write_all(&message).await?;
+            }
+            other => {
+                let message = other.into_bytes();
+                stdout.write_all(&message).await?;
+                stdout_log.write_all(&message).await?;
+            }
+        }
+    }
+    stdout.flush().await?;
+    stderr.flush().await?;
+    stdout_log.flush().await?;
+    stderr_log.flush().await?;
+
+    let code = docker
+        .inspect_exec(&id)
+        .await
+        .context("Could not get the exit code of the command")?
+        .exit_code
+        .unwrap_or_default();
+    println!("** executing `{}`: exit code {}", command.join(" "), code);
+    Ok(code)
+}
+
+/// Whether stdin is a terminal.
+#[cfg(unix)]
+fn atty_stdin() -> bool {
+    nix::unistd::isatty(nix::libc::STDIN_FILENO).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn atty_stdin() -> bool {
+    false
+}
+
+/// The terminal in raw mode, i.e. passing keystrokes such as Ctrl-C to the
+/// container rather than interpreting them, until dropped.
+#[cfg(unix)]
+struct RawMode {
+    original: nix::sys::termios::Termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Result<Self, Error> {
+        use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+        let original =
+            tcgetattr(nix::libc::STDIN_FILENO).context("Could not read terminal mode")?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(nix::libc::STDIN_FILENO, SetArg::TCSANOW, &raw)
+            .context("Could not set terminal to raw mode")?;
+        Ok(RawMode { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        use nix::sys::termios::{tcsetattr, SetArg};
+        let _ = tcsetattr(nix::libc::STDIN_FILENO, SetArg::TCSANOW, &self.original);
+    }
+}
+
+#[cfg(not(unix))]
+struct RawMode;
+
+#[cfg(not(unix))]
+impl RawMode {
+    fn enable() -> Result<Self, Error> {
+        Ok(RawMode)
+    }
+}
+
+#[cfg(unix)]
+nix::ioctl_read_bad!(get_window_size, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
+
+/// The size of the terminal, as (height, width).
+#[cfg(unix)]
+fn window_size() -> Option<(u16, u16)> {
+    let mut size = nix::pty::Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // Safety: `size` is a valid `Winsize` for the duration of the call.
+    unsafe { get_window_size(nix::libc::STDOUT_FILENO, &mut size) }.ok()?;
+    Some((size.ws_row, size.ws_col))
+}
+
+#[cfg(not(unix))]
+fn window_size() -> Option<(u16, u16)> {
+    None
+}
+
+/// Give the session the size of the terminal.
+async fn resize(docker: &Docker, exec_id: &str) {
+    if let Some((height, width)) = window_size() {
+        if let Err(err) = docker
+            .resize_exec(exec_id, ResizeExecOptions { height, width })
+            .await
+        {
+            debug!("Could not resize session: {}", err);
+        }
+    }
+}
+
+/// Resize the session whenever the terminal is resized.
+#[cfg(unix)]
+fn watch_resize(docker: Docker, exec_id: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+    tokio::task::spawn(async move {
+        let mut signals = match signal(SignalKind::window_change()) {
+            Ok(signals) => signals,
+            Err(err) => {
+                debug!("Could not watch terminal size: {}", err);
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            resize(&docker, &exec_id).await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn watch_resize(_docker: Docker, _exec_id: String) {}
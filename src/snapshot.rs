@@ -0,0 +1,505 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots of the data of Synapse, to restore instead of registering users
+//! and creating rooms during each `up`.
+//!
+//! A snapshot contains the database, i.e. a copy of the SQLite file or a dump
+//! of PostgreSQL, and the media store. Snapshots are stored in
+//! `Config::snapshots_dir`, which survives `build`, so a typical suite runs
+//! `up` and `snapshot` once, then `up --skip-registration` and `restore`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{
+    container::{DownloadFromContainerOptions, UploadToContainerOptions},
+    Docker,
+};
+use futures_util::stream::StreamExt;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin,
+    lifecycle::DockerExt,
+    postgres,
+    util::{exec, exec_checked, ExecOptions},
+    Config, HomeserverKind,
+};
+
+/// The data directory of Synapse, within its container.
+const GUEST_DATA_DIR: &str = "/data/";
+
+/// The database of Synapse if `database` is not specified in homeserver.yaml.
+const DEFAULT_SQLITE_DATABASE: &str = "/data/homeserver.db";
+
+/// The media store, within the data directory.
+const MEDIA_STORE: &str = "media_store";
+
+/// The description of a snapshot, within the snapshot.
+const SNAPSHOT_INFO: &str = "snapshot.json";
+
+/// The dump of PostgreSQL, within a snapshot.
+const POSTGRES_DUMP: &str = "database.sql";
+
+/// Where we dump PostgreSQL, within its container.
+const GUEST_DUMP_DIR: &str = "/tmp";
+
+/// The suffixes of the files of an SQLite database, i.e. the database itself
+/// and its journals.
+const SQLITE_SUFFIXES: &[&str] = &["", "-wal", "-shm", "-journal"];
+
+/// Where the database of Synapse lives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Database {
+    /// An SQLite file, as seen from the host.
+    Sqlite(PathBuf),
+
+    /// A PostgreSQL database, dumped and restored from within a container.
+    Postgres {
+        container: String,
+        user: String,
+        password: String,
+        database: String,
+    },
+}
+
+impl Database {
+    pub fn kind(&self) -> DatabaseKind {
+        match self {
+            Database::Sqlite(_) => DatabaseKind::Sqlite,
+            Database::Postgres { .. } => DatabaseKind::Postgres,
+        }
+    }
+}
+
+/// The kind of database a snapshot was taken from. A snapshot can only be
+/// restored into the same kind of database.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseKind {
+    Sqlite,
+    Postgres,
+}
+
+/// The contents of `snapshot.json`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotInfo {
+    pub database: DatabaseKind,
+
+    /// The tag of the image of Synapse.
+    ///
+    /// Synapse upgrades its database upon startup, but cannot downgrade it.
+    pub image: String,
+
+    /// The version of mx-tester that took the snapshot.
+    pub mx_tester_version: String,
+}
+
+/// The database of Synapse, as configured in homeserver.yaml during `up`.
+pub fn database(config: &Config) -> Result<Database, Error> {
+    if config.homeserver.kind == HomeserverKind::Dendrite {
+        return Err(anyhow!("Dendrite does not support snapshots"));
+    }
+    let path = config.homeserver_config_path();
+    let file = std::fs::File::open(&path)
+        .with_context(|| format!("Could not open {:?}, please run `mx-tester up` first", path))?;
+    let content: serde_yaml::Value =
+        serde_yaml::from_reader(file).with_context(|| format!("Could not parse {:?}", path))?;
+    let section = &content["database"];
+    match section["name"].as_str().unwrap_or("sqlite3") {
+        "sqlite3" => {
+            let guest_path = section["args"]["database"]
+                .as_str()
+                .unwrap_or(DEFAULT_SQLITE_DATABASE);
+            let relative = guest_path.strip_prefix(GUEST_DATA_DIR).ok_or_else(|| {
+                anyhow!(
+                    "Snapshots require the SQLite database to be in {}, found {}",
+                    GUEST_DATA_DIR,
+                    guest_path
+                )
+            })?;
+            Ok(Database::Sqlite(config.synapse_data_dir().join(relative)))
+        }
+        "psycopg2" => match config.postgres {
+            Some(ref postgres) if !postgres.is_external() => Ok(Database::Postgres {
+                container: postgres::container_name(config),
+                user: postgres.user.clone(),
+                password: postgres.password.clone(),
+                database: postgres.database.clone(),
+            }),
+            // The database of workers mode, see `res/workers/postgres.sql`.
+            None if config.workers.enabled => Ok(Database::Postgres {
+                container: config.run_container_name(),
+                user: "synapse".to_string(),
+                password: "password".to_string(),
+                database: "synapse".to_string(),
+            }),
+            _ => Err(anyhow!(
+                "Snapshots only support PostgreSQL with `postgres` (without `postgres.host`) or in workers mode"
+            )),
+        },
+        name => Err(anyhow!("Snapshots do not support database {}", name)),
+    }
+}
+
+/// The directory of a snapshot.
+///
+/// Fails if `name` is not a valid snapshot name, i.e. letters, digits, `-`
+/// and `_`.
+pub fn snapshot_dir(config: &Config, name: &str) -> Result<PathBuf, Error> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Invalid snapshot name `{}`, expected letters, digits, `-` and `_`",
+            name
+        ));
+    }
+    Ok(config.snapshots_dir().join(name))
+}
+
+/// Take a snapshot of the data of a homeserver that is up, replacing any
+/// previous snapshot with the same name.
+///
+/// With SQLite, Synapse is paused while we copy its files.
+pub async fn snapshot(docker: &Docker, config: &Config, name: &str) -> Result<(), Error> {
+    let dir = snapshot_dir(config, name)?;
+    let database = database(config)?;
+    let run_container_name = ensure_running(docker, config).await?;
+    println!("\n* snapshot: starting");
+
+    // Prepare the snapshot aside, so that a failure doesn't destroy a previous snapshot.
+    let tmp_dir = config.snapshots_dir().join(format!(".{}.tmp", name));
+    let _ = std::fs::remove_dir_all(&tmp_dir);
+    std::fs::create_dir_all(&tmp_dir)
+        .with_context(|| format!("Could not create directory {:?}", tmp_dir))?;
+    match database {
+        Database::Sqlite(ref path) => {
+            docker
+                .pause_container(&run_container_name)
+                .await
+                .context("Could not pause Synapse")?;
+            let result =
+                copy_sqlite(path, &tmp_dir).and_then(|_| copy_media_store(config, &tmp_dir, true));
+            docker
+                .unpause_container(&run_container_name)
+                .await
+                .context("Could not resume Synapse")?;
+            result?;
+        }
+        Database::Postgres { .. } => {
+            dump(docker, &database, &tmp_dir.join(POSTGRES_DUMP)).await?;
+            copy_media_store(config, &tmp_dir, true)?;
+        }
+    }
+    let info = SnapshotInfo {
+        database: database.kind(),
+        image: config.tag(),
+        mx_tester_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let info_path = tmp_dir.join(SNAPSHOT_INFO);
+    std::fs::write(&info_path, serde_json::to_string_pretty(&info)?)
+        .with_context(|| format!("Could not write {:?}", info_path))?;
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).with_context(|| format!("Could not remove {:?}", dir))?;
+    }
+    std::fs::rename(&tmp_dir, &dir)
+        .with_context(|| format!("Could not move {:?} to {:?}", tmp_dir, dir))?;
+    println!("* snapshot: success, stored in {:?}", dir);
+    Ok(())
+}
+
+/// Replace the data of a homeserver that is up with a snapshot, then wait
+/// until Synapse is ready again.
+pub async fn restore(docker: &Docker, config: &Config, name: &str) -> Result<(), Error> {
+    let dir = snapshot_dir(config, name)?;
+    let info_path = dir.join(SNAPSHOT_INFO);
+    let info: SnapshotInfo = match std::fs::read_to_string(&info_path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Could not parse {:?}", info_path))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "No snapshot `{}` in {:?}",
+                name,
+                config.snapshots_dir()
+            ))
+        }
+        Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", info_path)),
+    };
+    let database = database(config)?;
+    if info.database != database.kind() {
+        return Err(anyhow!(
+            "Snapshot `{}` contains a {:?} database, but Synapse uses {:?}",
+            name,
+            info.database,
+            database.kind()
+        ));
+    }
+    if info.image != config.tag() {
+        warn!(
+            "Snapshot `{}` was taken with image {}, restoring it into {}",
+            name,
+            info.image,
+            config.tag()
+        );
+    }
+    let run_container_name = ensure_running(docker, config).await?;
+    println!("\n* restore: starting");
+
+    match database {
+        Database::Sqlite(ref path) => {
+            docker
+                .stop_container(&run_container_name, None)
+                .await
+                .context("Could not stop Synapse")?;
+            restore_sqlite(&dir, path)?;
+            copy_media_store(config, &dir, false)?;
+            docker
+                .start_container::<String>(&run_container_name, None)
+                .await
+                .context("Could not start Synapse")?;
+        }
+        Database::Postgres { ref container, .. } if *container == run_container_name => {
+            // In workers mode, PostgreSQL runs alongside Synapse, so we only
+            // stop the processes managed by supervisord.
+            supervisorctl(docker, &run_container_name, "stop all").await?;
+            load(docker, &database, &dir.join(POSTGRES_DUMP)).await?;
+            copy_media_store(config, &dir, false)?;
+            supervisorctl(docker, &run_container_name, "start all").await?;
+        }
+        Database::Postgres { .. } => {
+            docker
+                .stop_container(&run_container_name, None)
+                .await
+                .context("Could not stop Synapse")?;
+            load(docker, &database, &dir.join(POSTGRES_DUMP)).await?;
+            copy_media_store(config, &dir, false)?;
+            docker
+                .start_container::<String>(&run_container_name, None)
+                .await
+                .context("Could not start Synapse")?;
+        }
+    }
+    admin::wait_until_ready(docker, config).await?;
+    println!("* restore: success");
+    Ok(())
+}
+
+/// Check that Synapse is up, returning the name of its container.
+async fn ensure_running(docker: &Docker, config: &Config) -> Result<String, Error> {
+    let run_container_name = config.run_container_name();
+    if !docker.is_container_healthy(&run_container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running or is unhealthy, please run `mx-tester up` first",
+            run_container_name
+        ));
+    }
+    Ok(run_container_name)
+}
+
+/// The path of each file of an SQLite database, with its file name.
+fn sqlite_files(path: &Path) -> Result<Vec<(PathBuf, String)>, Error> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Invalid SQLite database {:?}", path))?;
+    Ok(SQLITE_SUFFIXES
+        .iter()
+        .map(|suffix| {
+            let name = format!("{}{}", file_name, suffix);
+            (path.with_file_name(&name), name)
+        })
+        .collect())
+}
+
+/// Copy the files of an SQLite database into a snapshot.
+fn copy_sqlite(path: &Path, snapshot_dir: &Path) -> Result<(), Error> {
+    if !path.exists() {
+        return Err(anyhow!(
+            "Could not find the database of Synapse at {:?}",
+            path
+        ));
+    }
+    for (source, name) in sqlite_files(path)? {
+        if source.exists() {
+            let dest = snapshot_dir.join(name);
+            std::fs::copy(&source, &dest)
+                .with_context(|| format!("Could not copy {:?} to {:?}", source, dest))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the files of an SQLite database with those of a snapshot.
+fn restore_sqlite(snapshot_dir: &Path, path: &Path) -> Result<(), Error> {
+    for (dest, name) in sqlite_files(path)? {
+        if dest.exists() {
+            std::fs::remove_file(&dest).with_context(|| format!("Could not remove {:?}", dest))?;
+        }
+        let source = snapshot_dir.join(name);
+        if source.exists() {
+            std::fs::copy(&source, &dest)
+                .with_context(|| format!("Could not copy {:?} to {:?}", source, dest))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the media store into a snapshot (`to_snapshot`) or back, replacing
+/// the destination.
+fn copy_media_store(config: &Config, snapshot_dir: &Path, to_snapshot: bool) -> Result<(), Error> {
+    let data_media_store = config.synapse_data_dir().join(MEDIA_STORE);
+    let snapshot_media_store = snapshot_dir.join(MEDIA_STORE);
+    let (source, dest) = if to_snapshot {
+        (data_media_store, snapshot_media_store)
+    } else {
+        (snapshot_media_store, data_media_store)
+    };
+    if dest.exists() {
+        std::fs::remove_dir_all(&dest).with_context(|| format!("Could not remove {:?}", dest))?;
+    }
+    // Nothing was ever uploaded.
+    if !source.exists() {
+        return Ok(());
+    }
+    dircpy::CopyBuilder::new(&source, &dest)
+        .overwrite(true)
+        .run()
+        .with_context(|| format!("Could not copy {:?} to {:?}", source, dest))
+}
+
+/// Control the processes of workers mode.
+async fn supervisorctl(docker: &Docker, container: &str, command: &str) -> Result<(), Error> {
+    debug!("supervisorctl {} in {}", command, container);
+    exec_checked(
+        docker,
+        container,
+        vec!["sh", "-c", &format!("supervisorctl {}", command)],
+        &ExecOptions::default(),
+    )
+    .await
+    .with_context(|| format!("Could not `supervisorctl {}`", command))?;
+    Ok(())
+}
+
+/// The arguments of `pg_dump` and `psql` to connect to a database, as
+/// `env`, so that the password is passed as an environment variable.
+fn postgres_command(database: &Database, program: &str) -> Vec<String> {
+    match database {
+        Database::Postgres {
+            user,
+            password,
+            database,
+            ..
+        } => vec![
+            "env".to_string(),
+            format!("PGPASSWORD={}", password),
+            program.to_string(),
+            "--host=localhost".to_string(),
+            format!("--username={}", user),
+            format!("--dbname={}", database),
+        ],
+        Database::Sqlite(_) => unreachable!("Not a PostgreSQL database"),
+    }
+}
+
+/// Dump PostgreSQL into a file of the host.
+async fn dump(docker: &Docker, database: &Database, dest: &Path) -> Result<(), Error> {
+    let container = match database {
+        Database::Postgres { container, .. } => container,
+        Database::Sqlite(_) => unreachable!("Not a PostgreSQL database"),
+    };
+    let guest_path = format!("{}/{}", GUEST_DUMP_DIR, POSTGRES_DUMP);
+    let mut cmd = postgres_command(database, "pg_dump");
+    // Let `psql` drop the tables that exist during `restore`.
+    cmd.push("--clean".to_string());
+    cmd.push("--if-exists".to_string());
+    cmd.push("--no-owner".to_string());
+    cmd.push(format!("--file={}", guest_path));
+    exec_checked(
+        docker,
+        container,
+        cmd.iter().map(String::as_str).collect(),
+        &ExecOptions::default(),
+    )
+    .await
+    .context("Could not dump PostgreSQL")?;
+
+    // Docker sends files as a tar archive.
+    let mut archive = vec![];
+    let mut stream = docker.download_from_container(
+        container,
+        Some(DownloadFromContainerOptions {
+            path: guest_path.as_str(),
+        }),
+    );
+    while let Some(chunk) = stream.next().await {
+        archive.extend_from_slice(&chunk.context("Could not download the dump of PostgreSQL")?);
+    }
+    let _ = exec(docker, container, vec!["rm", "-f", &guest_path]).await;
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| anyhow!("Empty dump of PostgreSQL"))??;
+    entry
+        .unpack(dest)
+        .with_context(|| format!("Could not write {:?}", dest))?;
+    Ok(())
+}
+
+/// Load a dump of PostgreSQL from a file of the host.
+async fn load(docker: &Docker, database: &Database, source: &Path) -> Result<(), Error> {
+    let container = match database {
+        Database::Postgres { container, .. } => container,
+        Database::Sqlite(_) => unreachable!("Not a PostgreSQL database"),
+    };
+    let mut archive = tar::Builder::new(vec![]);
+    archive
+        .append_path_with_name(source, POSTGRES_DUMP)
+        .with_context(|| format!("Could not read {:?}", source))?;
+    let archive = archive.into_inner()?;
+    docker
+        .upload_to_container(
+            container,
+            Some(UploadToContainerOptions {
+                path: GUEST_DUMP_DIR,
+                ..UploadToContainerOptions::default()
+            }),
+            archive.into(),
+        )
+        .await
+        .context("Could not upload the dump of PostgreSQL")?;
+    let guest_path = format!("{}/{}", GUEST_DUMP_DIR, POSTGRES_DUMP);
+    let mut cmd = postgres_command(database, "psql");
+    cmd.push("--set=ON_ERROR_STOP=1".to_string());
+    cmd.push("--quiet".to_string());
+    cmd.push(format!("--file={}", guest_path));
+    let result = exec_checked(
+        docker,
+        container,
+        cmd.iter().map(String::as_str).collect(),
+        &ExecOptions::default(),
+    )
+    .await
+    .map(|_| ())
+    .context("Could not restore PostgreSQL");
+    let _ = exec(docker, container, vec!["rm", "-f", &guest_path]).await;
+    result
+}
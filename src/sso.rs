@@ -0,0 +1,337 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single sign-on through an OpenID Connect provider (Keycloak), as specified in `sso`.
+//!
+//! Keycloak runs in its own container on the same Docker network as Synapse,
+//! with a realm containing a client for Synapse and the identities of `sso.users`.
+//! Browsers reach Keycloak on the host, while Synapse reaches it on the Docker
+//! network, so Synapse is configured without discovery, with the endpoints that
+//! each of them can reach.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+
+/// The port on which Keycloak listens, within its container.
+const KEYCLOAK_PORT: u16 = 8080;
+
+/// The realm created for the test.
+const REALM: &str = "mx-tester";
+
+/// The id of the client of Synapse, in the realm.
+const CLIENT_ID: &str = "synapse";
+
+/// The id of the identity provider, in Synapse.
+pub const IDP_ID: &str = "mx-tester";
+
+/// The kind of single sign-on.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum SsoKind {
+    /// OpenID Connect.
+    #[default]
+    #[serde(rename = "oidc")]
+    Oidc,
+}
+
+/// An identity provisioned in the identity provider.
+#[derive(Clone, Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct SsoUser {
+    /// The username, which becomes the localpart of the Matrix user upon
+    /// first login.
+    pub username: String,
+
+    /// The password, to log in with the identity provider.
+    #[serde(default = "SsoUser::default_password")]
+    #[builder(default = SsoUser::default_password())]
+    pub password: String,
+
+    /// The email address. Defaults to `{username}@mx-tester.test`.
+    #[serde(default)]
+    #[builder(default)]
+    pub email: Option<String>,
+
+    /// The display name. Defaults to the username.
+    #[serde(default)]
+    #[builder(default)]
+    pub display_name: Option<String>,
+}
+
+impl SsoUser {
+    fn default_password() -> String {
+        "password".to_string()
+    }
+}
+
+/// Configuring single sign-on.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct SsoConfig {
+    /// The kind of single sign-on. Only `oidc` is supported.
+    #[serde(default)]
+    #[builder(default)]
+    pub kind: SsoKind,
+
+    /// The Docker image of Keycloak.
+    ///
+    /// Defaults to `quay.io/keycloak/keycloak:latest`.
+    #[serde(default = "SsoConfig::default_image")]
+    #[builder(default = SsoConfig::default_image())]
+    pub image: String,
+
+    /// The port of the host on which Keycloak is published, for browsers.
+    ///
+    /// Defaults to 9970.
+    #[serde(default = "SsoConfig::default_host_port")]
+    #[builder(default = SsoConfig::default_host_port())]
+    pub host_port: u16,
+
+    /// The secret with which Synapse authenticates to Keycloak.
+    #[serde(default = "SsoConfig::default_client_secret")]
+    #[builder(default = SsoConfig::default_client_secret())]
+    pub client_secret: String,
+
+    /// The identities to provision in Keycloak.
+    #[serde(default)]
+    #[builder(default)]
+    pub users: Vec<SsoUser>,
+
+    /// Additional hostnames of Keycloak on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl Default for SsoConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl SsoConfig {
+    fn default_image() -> String {
+        "quay.io/keycloak/keycloak:latest".to_string()
+    }
+    fn default_host_port() -> u16 {
+        9970
+    }
+    fn default_client_secret() -> String {
+        "MX_TESTER_SSO_CLIENT_SECRET_DEFAULT".to_string()
+    }
+
+    /// The base URL of Keycloak, as seen from the host.
+    pub fn public_base(&self) -> String {
+        format!("http://localhost:{}", self.host_port)
+    }
+
+    /// The issuer of the realm, i.e. its URL as seen from the host.
+    pub fn issuer(&self) -> String {
+        format!("{}/realms/{}", self.public_base(), REALM)
+    }
+}
+
+/// The name of the container running Keycloak.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-keycloak-{}", config.name)
+}
+
+/// The URL of the OpenID Connect endpoints of the realm, as seen from the Docker network.
+fn internal_endpoints(config: &Config) -> String {
+    format!(
+        "http://{}:{}/realms/{}/protocol/openid-connect",
+        container_name(config),
+        KEYCLOAK_PORT,
+        REALM
+    )
+}
+
+/// The directory containing the realm to import, on the host.
+pub fn sso_dir(config: &Config) -> PathBuf {
+    config.test_root().join("sso")
+}
+
+/// The realm to import in Keycloak: the client of Synapse and the identities of `sso.users`.
+pub fn realm(config: &Config, sso: &SsoConfig) -> serde_json::Value {
+    let public_baseurl = config.homeserver.public_baseurl.trim_end_matches('/');
+    let users: Vec<serde_json::Value> = sso
+        .users
+        .iter()
+        .map(|user| {
+            serde_json::json!({
+                "username": user.username,
+                "email": user
+                    .email
+                    .clone()
+                    .unwrap_or_else(|| format!("{}@mx-tester.test", user.username)),
+                "emailVerified": true,
+                "enabled": true,
+                // Keycloak asks users to fill in missing names upon login.
+                "firstName": user.display_name.as_deref().unwrap_or(&user.username),
+                "lastName": "mx-tester",
+                "credentials": [{
+                    "type": "password",
+                    "value": user.password,
+                    "temporary": false,
+                }],
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "realm": REALM,
+        "enabled": true,
+        "sslRequired": "none",
+        "clients": [{
+            "clientId": CLIENT_ID,
+            "secret": sso.client_secret,
+            "enabled": true,
+            "protocol": "openid-connect",
+            "publicClient": false,
+            "standardFlowEnabled": true,
+            "redirectUris": [format!("{}/_synapse/client/oidc/callback", public_baseurl)],
+        }],
+        "users": users,
+    })
+}
+
+/// Patch homeserver.yaml to let users log in through Keycloak.
+///
+/// If `oidc_providers` is specified in mx-tester.yml, it is not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    sso: &SsoConfig,
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    let key = "oidc_providers";
+    if extra_fields.contains_key(key) {
+        return Ok(());
+    }
+    let internal = internal_endpoints(config);
+    content.insert(
+        key.into(),
+        yaml!([yaml!({
+            "idp_id" => IDP_ID,
+            "idp_name" => "mx-tester",
+            "issuer" => sso.issuer(),
+            // The endpoints advertised by Keycloak are those of the host,
+            // which Synapse cannot reach.
+            "discover" => false,
+            "authorization_endpoint" => format!("{}/protocol/openid-connect/auth", sso.issuer()),
+            "token_endpoint" => format!("{}/token", internal),
+            "userinfo_endpoint" => format!("{}/userinfo", internal),
+            "jwks_uri" => format!("{}/certs", internal),
+            "client_id" => CLIENT_ID,
+            "client_secret" => sso.client_secret.clone(),
+            "scopes" => yaml!(["openid", "profile", "email"]),
+            "user_mapping_provider" => yaml!({
+                "config" => yaml!({
+                    "localpart_template" => "{{ user.preferred_username }}",
+                    "display_name_template" => "{{ user.given_name }}",
+                    "email_template" => "{{ user.email }}"
+                })
+            })
+        })]),
+    );
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use anyhow::Context;
+    use bollard::Docker;
+
+    use crate::{
+        environment::Environment,
+        services::{stop_container, Container, ContainerPort},
+        util::wait_until,
+    };
+
+    /// The path of the realm to import, within the container.
+    const GUEST_REALM_PATH: &str = "/opt/keycloak/data/import/mx-tester.json";
+
+    /// How long to wait for Keycloak to start.
+    const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(180);
+
+    /// Start Keycloak, if `sso` is specified, and wait until the realm is ready.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let sso = match config.sso {
+            None => return Ok(()),
+            Some(ref sso) => sso,
+        };
+
+        let dir = sso_dir(config);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {:?}", dir))?;
+        let realm_path = dir.join("mx-tester.json");
+        std::fs::write(
+            &realm_path,
+            serde_json::to_string_pretty(&realm(config, sso))?,
+        )
+        .with_context(|| format!("Could not write realm {:?}", realm_path))?;
+        let host_realm_path = Environment::detect(docker).await?.host_path(&realm_path)?;
+
+        Container {
+            label: "Keycloak".to_string(),
+            name: container_name(config),
+            image: sso.image.clone(),
+            cmd: Some(vec![
+                "start-dev".to_string(),
+                "--import-realm".to_string(),
+                format!("--http-port={}", KEYCLOAK_PORT),
+            ]),
+            env: vec![
+                // Tokens are issued for the host, whichever URL Synapse uses.
+                format!("KC_HOSTNAME={}", sso.public_base()),
+                "KC_HOSTNAME_BACKCHANNEL_DYNAMIC=true".to_string(),
+            ],
+            ports: vec![ContainerPort::tcp(
+                KEYCLOAK_PORT,
+                Some(u64::from(sso.host_port)),
+            )],
+            binds: vec![format!(
+                "{}:{}:ro",
+                host_realm_path.to_string_lossy(),
+                GUEST_REALM_PATH
+            )],
+            aliases: sso.aliases.clone(),
+            log_name: "keycloak.log".to_string(),
+        }
+        .start(docker, config)
+        .await?;
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", sso.issuer());
+        wait_until(READINESS_TIMEOUT, "Keycloak", || async {
+            Ok(matches!(reqwest::get(&discovery_url).await, Ok(response) if response.status().is_success()))
+        })
+        .await?;
+        println!("** Keycloak is ready");
+        Ok(())
+    }
+
+    /// Stop and remove Keycloak, if `sso` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.sso.is_none() {
+            return Ok(());
+        }
+        stop_container(docker, config, &container_name(config), "Keycloak").await
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed requests for the admin API of Synapse.
+//!
+//! All the requests to the admin API that mx-tester sends are defined here.
+//! Send them with the client of an admin, e.g. `registration::admin_client`.
+//! See also the helpers built upon them, e.g. `helpers::event_reports`.
+
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+/// A report of an event by a user, e.g. with `/rooms/{roomId}/report/{eventId}`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventReport {
+    /// The id of the report, in Synapse.
+    pub id: u64,
+
+    /// When the report was received, in milliseconds since the epoch.
+    pub received_ts: u64,
+
+    pub room_id: OwnedRoomId,
+
+    /// The name of the room, if any.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The canonical alias of the room, if any.
+    #[serde(default)]
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+
+    /// The reported event.
+    pub event_id: OwnedEventId,
+
+    /// The user who reported the event.
+    pub user_id: OwnedUserId,
+
+    /// The user who sent the reported event.
+    pub sender: OwnedUserId,
+
+    #[serde(default)]
+    pub reason: Option<String>,
+
+    /// From -100 (most offensive) to 0 (inoffensive), if specified.
+    #[serde(default)]
+    pub score: Option<i64>,
+}
+
+/// List event reports, most recent first.
+pub mod event_reports {
+    use matrix_sdk::ruma::{api::ruma_api, RoomId, UInt, UserId};
+
+    use super::EventReport;
+
+    ruma_api! {
+        metadata: {
+            description: "List event reports",
+            method: GET,
+            name: "event_reports",
+            unstable_path: "/_synapse/admin/v1/event_reports",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// Only list reports about events of this room.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub room_id: Option<&'a RoomId>,
+
+            /// Only list reports filed by this user.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub user_id: Option<&'a UserId>,
+
+            /// The offset of the first report to list, as per `next_token`.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<UInt>,
+
+            /// The maximal number of reports to list.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub event_reports: Vec<EventReport>,
+
+            /// The value of `from` to list the next reports, if any.
+            #[serde(default)]
+            pub next_token: Option<UInt>,
+
+            /// The number of reports matching the request.
+            pub total: UInt,
+        }
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a `Request` listing all reports.
+        pub fn new() -> Self {
+            Self {
+                room_id: None,
+                user_id: None,
+                from: None,
+                limit: None,
+            }
+        }
+    }
+
+    impl<'a> Default for Request<'a> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// Fetch the current state of a room, whether or not the admin is a member.
+pub mod room_state {
+    use matrix_sdk::ruma::{api::ruma_api, events::AnyStateEvent, serde::Raw, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the state of a room",
+            method: GET,
+            name: "room_state",
+            unstable_path: "/_synapse/admin/v1/rooms/:room_id/state",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+        }
+
+        response: {
+            /// The state events of the room.
+            pub state: Vec<Raw<AnyStateEvent>>,
+        }
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a `Request` for the state of `room_id`.
+        pub fn new(room_id: &'a RoomId) -> Self {
+            Self { room_id }
+        }
+    }
+}
+
+/// Whether a room is blocked, e.g. after a shutdown.
+pub mod room_block_status {
+    use matrix_sdk::ruma::{api::ruma_api, OwnedUserId, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the block status of a room",
+            method: GET,
+            name: "room_block_status",
+            unstable_path: "/_synapse/admin/v1/rooms/:room_id/block",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+        }
+
+        response: {
+            pub block: bool,
+
+            /// The admin who blocked the room, if it is blocked.
+            #[serde(default)]
+            pub user_id: Option<OwnedUserId>,
+        }
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a `Request` for the block status of `room_id`.
+        pub fn new(room_id: &'a RoomId) -> Self {
+            Self { room_id }
+        }
+    }
+}
+
+/// List the current members of a room, whether or not the admin is a member.
+pub mod room_members {
+    use matrix_sdk::ruma::{api::ruma_api, OwnedUserId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the members of a room",
+            method: GET,
+            name: "room_members",
+            unstable_path: "/_synapse/admin/v1/rooms/:room_id/members",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+        }
+
+        response: {
+            /// The users currently joined to the room.
+            pub members: Vec<OwnedUserId>,
+
+            pub total: UInt,
+        }
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a `Request` for the members of `room_id`.
+        pub fn new(room_id: &'a RoomId) -> Self {
+            Self { room_id }
+        }
+    }
+}
+
+/// The status of background updates, e.g. database migrations.
+pub mod background_updates_status {
+    use std::collections::BTreeMap;
+
+    use matrix_sdk::ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "Get the status of background updates",
+            method: GET,
+            name: "background_updates_status",
+            unstable_path: "/_synapse/admin/v1/background_updates/status",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            /// Whether background updates are enabled.
+            pub enabled: bool,
+
+            /// The background updates currently running, indexed by database.
+            #[serde(default)]
+            pub current_updates: BTreeMap<String, serde_json::Value>,
+        }
+    }
+
+    impl Request {
+        /// Creates a new `Request`.
+        pub fn new() -> Self {
+            Self {}
+        }
+    }
+
+    impl Default for Request {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// Start a background job, e.g. rebuilding the user directory.
+pub mod start_background_job {
+    use matrix_sdk::ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "Start a background job",
+            method: POST,
+            name: "start_background_job",
+            unstable_path: "/_synapse/admin/v1/background_updates/start_job",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The job to start, e.g. `regenerate_directory`.
+            pub job_name: &'a str,
+        }
+
+        response: {}
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` for the given job.
+        pub fn new(job_name: &'a str) -> Self {
+            Self { job_name }
+        }
+    }
+}
+
+/// Reset the password of a user.
+pub mod reset_password {
+    use matrix_sdk::ruma::api::ruma_api;
+    use matrix_sdk::ruma::UserId;
+
+    ruma_api! {
+        metadata: {
+            description: "Reset the password of a user",
+            method: POST,
+            name: "reset_password",
+            unstable_path: "/_synapse/admin/v1/reset_password/:user_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// user ID
+            #[ruma_api(path)]
+            pub user_id: &'a UserId,
+
+            /// The new password.
+            pub new_password: &'a str,
+
+            /// Whether to logout all the devices of the user.
+            pub logout_devices: bool,
+        }
+
+        #[derive(Default)]
+        response: {}
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a `Request` with the given user ID and new password.
+        pub fn new(user_id: &'a UserId, new_password: &'a str) -> Self {
+            Self {
+                user_id,
+                new_password,
+                logout_devices: false,
+            }
+        }
+    }
+}
+
+/// Override the rate limits of a user, e.g. to remove them.
+pub mod override_rate_limits {
+    use matrix_sdk::ruma::api::ruma_api;
+    use matrix_sdk::ruma::UserId;
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata: {
+            description: "Override rate limits",
+            method: POST,
+            name: "override_rate_limit",
+            unstable_path: "/_synapse/admin/v1/users/:user_id/override_ratelimit",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// user ID
+            #[ruma_api(path)]
+            pub user_id: &'a UserId,
+
+            /// The number of actions that can be performed in a second. Defaults to 0.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub messages_per_second: Option<u32>,
+
+            /// How many actions that can be performed before being limited. Defaults to 0.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub burst_count: Option<u32>
+        }
+
+        response: {
+            /// Details about the user.
+            #[ruma_api(body)]
+            pub limits: UserLimits,
+        }
+    }
+
+    /// The rate limits of a user, 0 meaning unlimited.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct UserLimits {
+        pub messages_per_second: u32,
+        pub burst_count: u32,
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates an `Request` with the given user ID.
+        pub fn new(
+            user_id: &'a UserId,
+            messages_per_second: Option<u32>,
+            burst_count: Option<u32>,
+        ) -> Self {
+            Self {
+                user_id,
+                messages_per_second,
+                burst_count,
+            }
+        }
+    }
+}
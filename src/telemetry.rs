@@ -0,0 +1,171 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exporting OpenTelemetry traces of a test run.
+//!
+//! Steps, phases of `up`, Docker calls and fixture operations are wrapped in
+//! spans. Unless an OTLP endpoint is configured, no tracer is installed and
+//! spans cost nothing.
+
+use std::{collections::HashMap, future::Future};
+
+#[cfg(feature = "telemetry")]
+use anyhow::Context as _;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+/// The environment variable used if `telemetry.otlp_endpoint` is not specified.
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Exporting traces of the test run.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct TelemetryConfig {
+    /// The URL of an OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`
+    /// for a local Jaeger.
+    ///
+    /// If unspecified, `OTEL_EXPORTER_OTLP_ENDPOINT` or `--otlp-endpoint`.
+    /// If none of them is specified, traces are not exported.
+    #[serde(default)]
+    #[builder(default)]
+    pub otlp_endpoint: Option<String>,
+
+    /// The name of the service in traces.
+    #[serde(default = "TelemetryConfig::default_service_name")]
+    #[builder(default = TelemetryConfig::default_service_name())]
+    pub service_name: String,
+
+    /// Additional attributes of every trace, e.g. the CI job.
+    #[serde(default, serialize_with = "crate::util::serialize_sorted")]
+    #[builder(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl TelemetryConfig {
+    fn default_service_name() -> String {
+        "mx-tester".to_string()
+    }
+}
+
+#[cfg(feature = "telemetry")]
+/// Install an OTLP exporter, if `telemetry.otlp_endpoint` is specified.
+///
+/// Call `shutdown` before exiting to flush the traces.
+pub fn init(config: &Config) -> Result<(), Error> {
+    use opentelemetry::{sdk::Resource, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = match config.telemetry.otlp_endpoint {
+        Some(ref endpoint) => endpoint.clone(),
+        None => return Ok(()),
+    };
+    let mut attributes = vec![
+        KeyValue::new("service.name", config.telemetry.service_name.clone()),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        KeyValue::new("mx_tester.test", config.name.clone()),
+        KeyValue::new("mx_tester.run_id", crate::run_id()),
+    ];
+    attributes.extend(
+        config
+            .telemetry
+            .attributes
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+    );
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_trace_config(
+            opentelemetry::sdk::trace::config().with_resource(Resource::new(attributes)),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .with_context(|| format!("Could not export traces to {}", endpoint))?;
+    println!("* exporting traces to {}", endpoint);
+    Ok(())
+}
+
+#[cfg(not(feature = "telemetry"))]
+/// Install an OTLP exporter, if `telemetry.otlp_endpoint` is specified.
+///
+/// mx-tester was built without feature `telemetry`, so this fails if an
+/// endpoint is specified.
+pub fn init(config: &Config) -> Result<(), Error> {
+    match config.telemetry.otlp_endpoint {
+        Some(_) => Err(anyhow::anyhow!(
+            "Cannot export traces: mx-tester was built without feature `telemetry`"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Flush the traces, if any.
+pub fn shutdown() {
+    #[cfg(feature = "telemetry")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Execute `future` in a span `name`, child of the current span, marking the
+/// span as failed if `future` fails.
+pub async fn span<F, T>(
+    name: &str,
+    attributes: &[(&'static str, String)],
+    future: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    #[cfg(feature = "telemetry")]
+    {
+        use opentelemetry::{
+            global,
+            trace::{FutureExt, StatusCode, TraceContextExt, Tracer},
+            Context, KeyValue,
+        };
+        let tracer = global::tracer("mx-tester");
+        let span = tracer
+            .span_builder(name.to_string())
+            .with_attributes(
+                attributes
+                    .iter()
+                    .map(|(key, value)| KeyValue::new(*key, value.clone()))
+                    .collect::<Vec<_>>(),
+            )
+            .start(&tracer);
+        let cx = Context::current_with_span(span);
+        let result = future.with_context(cx.clone()).await;
+        if let Err(ref err) = result {
+            cx.span()
+                .set_status(StatusCode::Error, format!("{:#}", err));
+        }
+        cx.span().end();
+        result
+    }
+    #[cfg(not(feature = "telemetry"))]
+    {
+        let _ = (name, attributes);
+        future.await
+    }
+}
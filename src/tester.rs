@@ -0,0 +1,421 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Driving a test from Rust code.
+//!
+//! A `Tester` runs the same steps as the command-line, but retains the
+//! logged-in client of each user once `up` is complete, so that test code
+//! doesn't need to login again.
+//!
+//! With `SharedTester` or `Tester::shared`, the tests of a test binary share
+//! a single environment, so that each test doesn't pay for `build`, `up` and
+//! `down`. The environment is torn down once the last `SharedTester` is
+//! dropped or, with `Tester::shared`, when the test binary exits.
+
+use std::{collections::HashMap, future::Future, path::Path, sync::Arc, sync::Mutex};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::Docker;
+use lazy_static::lazy_static;
+
+use crate::{
+    lifecycle,
+    registration::{register_user, RegisteredUser, Registration},
+    Config, Directories, Status,
+};
+
+/// A test, driven from Rust code.
+pub struct Tester {
+    docker: Docker,
+    config: Config,
+
+    /// The logged-in client of each user, indexed by localname.
+    ///
+    /// Populated by `up`, emptied by `down`.
+    clients: HashMap<String, matrix_sdk::Client>,
+}
+
+impl Tester {
+    pub fn new(docker: Docker, config: Config) -> Self {
+        Tester {
+            docker,
+            config,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn docker(&self) -> &Docker {
+        &self.docker
+    }
+
+    /// Rebuild the Synapse image with modules.
+    pub async fn build(&self) -> Result<(), Error> {
+        lifecycle::build(&self.docker, &self.config).await
+    }
+
+    /// Bring things up, then keep the client of each user declared in `users`.
+    ///
+    /// If registration is skipped, no client is available.
+    pub async fn up(&mut self) -> Result<(), Error> {
+        self.clients = lifecycle::up_with_clients(&self.docker, &self.config).await?;
+        Ok(())
+    }
+
+    /// Run the `run` script.
+    pub async fn run(&self) -> Result<(), Error> {
+        lifecycle::run(&self.docker, &self.config).await
+    }
+
+    /// Run a command in the container of the homeserver, returning its exit
+    /// code, see `shell::exec`.
+    pub async fn exec(&self, command: &[String]) -> Result<i64, Error> {
+        crate::shell::exec(&self.docker, &self.config, command).await
+    }
+
+    /// Bring things down.
+    pub async fn down(&mut self, status: Status) -> Result<(), Error> {
+        self.clients.clear();
+        lifecycle::down(&self.docker, &self.config, status).await
+    }
+
+    /// The logged-in client of one of the users declared in `users`.
+    pub fn client(&self, localname: &str) -> Result<&matrix_sdk::Client, Error> {
+        self.clients.get(localname).ok_or_else(|| {
+            anyhow!(
+                "No client for user {}, it must be declared in `users` and registered during `up`",
+                localname
+            )
+        })
+    }
+
+    /// The logged-in clients of all the users declared in `users`, indexed by localname.
+    pub fn clients(&self) -> &HashMap<String, matrix_sdk::Client> {
+        &self.clients
+    }
+
+    /// Run `test` against the environment described by configuration file
+    /// `config_path`, shared by all the tests of this test binary.
+    ///
+    /// As `SharedTester::get_or_init`, except that the environment is not torn
+    /// down once `test` is over, but when the test binary exits, once all its
+    /// tests are over, so that tests running one after the other share it.
+    ///
+    /// If the test binary is killed, e.g. by Ctrl-C, or on platforms other than
+    /// unix, the environment remains up until the next test binary replaces it or
+    /// `mx-tester down` stops it.
+    ///
+    /// `test` runs on the runtime of the environment, so call this from a
+    /// regular `#[test]` rather than from a `#[tokio::test]`:
+    ///
+    /// ```no_run
+    /// #[test]
+    /// fn test_whoami() -> Result<(), anyhow::Error> {
+    ///     mx_tester::Tester::shared("mx-tester.yml", |tester| async move {
+    ///         tester.client("regular-user")?.whoami().await?;
+    ///         Ok(())
+    ///     })
+    /// }
+    /// ```
+    pub fn shared<P, F, Fut, T>(config_path: P, test: F) -> Result<T, Error>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&'static Tester) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let config = load_config(config_path.as_ref())?;
+        let shared = SharedTester::acquire(config, true)?;
+        let pinned = shared
+            .pinned
+            .expect("We have just pinned the shared environment");
+        pinned.runtime.block_on(test(&pinned.tester))
+    }
+}
+
+/// An environment shared by the tests of a test binary.
+struct Shared {
+    /// The runtime on which the environment was brought up. The Docker and
+    /// Matrix clients may only be used on this runtime.
+    runtime: tokio::runtime::Runtime,
+    tester: Tester,
+}
+
+impl Shared {
+    /// Remove any leftover of a previous test binary, then build and bring
+    /// up the environment.
+    fn start(config: Config) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .context("Could not create runtime")?;
+        let tester = runtime.block_on(async {
+            let docker = lifecycle::connect(&config).await?;
+            lifecycle::clean(&docker, &config, false).await?;
+            let mut tester = Tester::new(docker, config);
+            tester.build().await?;
+            tester.up().await?;
+            Ok::<_, Error>(tester)
+        })?;
+        Ok(Shared { runtime, tester })
+    }
+}
+
+/// A shared environment, as tracked in `SHARED`.
+struct SharedEntry {
+    shared: Arc<Shared>,
+
+    /// The number of live `SharedTester`s for this environment.
+    users: usize,
+
+    /// If `Tester::shared` has been used, the environment is only torn down
+    /// when the process exits, see `PINNED`.
+    pinned: Option<&'static Shared>,
+}
+
+/// The state of one shared environment: `None` until it is brought up and
+/// once it is torn down, otherwise the environment or the reason why it could
+/// not be brought up.
+///
+/// Each environment has its own lock, so that bringing up or tearing down one
+/// environment doesn't block the tests of another.
+type Slot = Arc<Mutex<Option<Result<SharedEntry, String>>>>;
+
+lazy_static! {
+    /// The shared environments, indexed by test name.
+    static ref SHARED: Mutex<HashMap<String, Slot>> = Mutex::new(HashMap::new());
+
+    /// The environments pinned by `Tester::shared`, by test name, to tear down
+    /// when the process exits.
+    static ref PINNED: Mutex<Vec<(String, Arc<Shared>)>> = Mutex::new(vec![]);
+}
+
+/// Tear down the environments pinned by `Tester::shared`.
+#[cfg(unix)]
+extern "C" fn tear_down_pinned() {
+    let pinned = std::mem::take(&mut *PINNED.lock().unwrap_or_else(|err| err.into_inner()));
+    for (name, shared) in pinned {
+        tear_down(&name, shared);
+    }
+}
+
+/// Pin an environment, tearing it down when the process exits.
+fn pin_until_exit(name: &str, shared: &Arc<Shared>) -> &'static Shared {
+    #[cfg(unix)]
+    {
+        static AT_EXIT: std::sync::Once = std::sync::Once::new();
+        AT_EXIT.call_once(|| {
+            // Safety: `tear_down_pinned` doesn't unwind, as `tear_down` catches panics.
+            if unsafe { nix::libc::atexit(tear_down_pinned) } != 0 {
+                log::warn!("Shared environments will not be torn down when the process exits");
+            }
+        });
+    }
+    PINNED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push((name.to_string(), shared.clone()));
+    // Leaked on purpose, as the environment lives until the process exits.
+    &**Box::leak(Box::new(shared.clone()))
+}
+
+/// Bring down a shared environment.
+///
+/// Both `block_on` and dropping the runtime panic within an async context, e.g.
+/// if the last handle is dropped by a `#[tokio::test]`, so tear down on a thread
+/// of our own.
+fn tear_down(name: &str, shared: Arc<Shared>) {
+    let teardown = std::thread::spawn(move || {
+        let tester = &shared.tester;
+        shared.runtime.block_on(lifecycle::down(
+            tester.docker(),
+            tester.config(),
+            Status::Manual,
+        ))
+    });
+    match teardown.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::error!("Could not tear down shared environment {}: {:?}", name, err),
+        Err(_) => log::error!("Panic while tearing down shared environment {}", name),
+    }
+}
+
+/// A handle on an environment shared by the tests of a test binary.
+///
+/// The first handle brings the environment up, while other tests wait. The
+/// environment is torn down once the last handle is dropped, so that tests
+/// running in parallel share it. Each handle has its own namespace, to let
+/// each test create users or rooms that don't collide with those of other
+/// tests.
+///
+/// All the async code using the environment needs to run on the runtime of
+/// the environment, with `block_on`, so use `SharedTester` from regular
+/// `#[test]`s rather than from `#[tokio::test]`s:
+///
+/// ```no_run
+/// #[test]
+/// fn test_register() -> Result<(), anyhow::Error> {
+///     let config: mx_tester::Config = serde_yaml::from_str("name: my-test")?;
+///     let shared = mx_tester::SharedTester::get_or_init(config)?;
+///     shared.block_on(async {
+///         let user = shared.register_user("alice").await?;
+///         assert_eq!(user.user_id.localpart(), shared.unique("alice"));
+///         Ok(())
+///     })
+/// }
+/// ```
+pub struct SharedTester {
+    name: String,
+    slot: Slot,
+
+    /// Only `None` while the handle is being dropped.
+    shared: Option<Arc<Shared>>,
+    pinned: Option<&'static Shared>,
+    namespace: String,
+}
+
+impl SharedTester {
+    /// Get a handle on the shared environment for `config`, bringing it up if
+    /// this is the first handle.
+    ///
+    /// If the environment could not be brought up, later calls fail without
+    /// trying again.
+    pub fn get_or_init(config: Config) -> Result<Self, Error> {
+        Self::acquire(config, false)
+    }
+
+    fn acquire(config: Config, pin: bool) -> Result<Self, Error> {
+        let name = config.name.clone();
+        let slot = SHARED
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(name.clone())
+            .or_default()
+            .clone();
+        // Only the tests of this environment wait while it's brought up.
+        let mut state = slot.lock().unwrap_or_else(|err| err.into_inner());
+        let entry = state.get_or_insert_with(|| {
+            Shared::start(config)
+                .map(|shared| SharedEntry {
+                    shared: Arc::new(shared),
+                    users: 0,
+                    pinned: None,
+                })
+                .map_err(|err| format!("{:?}", err))
+        });
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                return Err(anyhow!(
+                    "The shared environment of test {} could not be brought up: {}",
+                    name,
+                    err
+                ))
+            }
+        };
+        if pin && entry.pinned.is_none() {
+            entry.pinned = Some(pin_until_exit(&name, &entry.shared));
+        }
+        entry.users += 1;
+        let shared = entry.shared.clone();
+        let pinned = entry.pinned;
+        drop(state);
+        Ok(SharedTester {
+            name,
+            slot,
+            shared: Some(shared),
+            pinned,
+            namespace: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+        })
+    }
+
+    fn shared(&self) -> &Shared {
+        self.shared
+            .as_ref()
+            .expect("The shared environment is only released when the handle is dropped")
+    }
+
+    pub fn tester(&self) -> &Tester {
+        &self.shared().tester
+    }
+
+    pub fn config(&self) -> &Config {
+        self.shared().tester.config()
+    }
+
+    /// Run a future on the runtime of the environment.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.shared().runtime.block_on(future)
+    }
+
+    /// A short id, distinct for each handle.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// A name that doesn't collide with those of other tests, e.g. for
+    /// the localpart of a user or the alias of a room.
+    pub fn unique(&self, name: &str) -> String {
+        format!("{}-{}", name, self.namespace)
+    }
+
+    /// Register user `unique(localname)`.
+    pub async fn register_user(&self, localname: &str) -> Result<RegisteredUser, Error> {
+        let homeserver = &self.config().homeserver;
+        register_user(
+            &homeserver.public_baseurl,
+            &homeserver.registration_shared_secret,
+            &Registration::builder()
+                .localname(self.unique(localname))
+                .build(),
+        )
+        .await
+    }
+}
+
+impl Drop for SharedTester {
+    fn drop(&mut self) {
+        let shared = match self.shared.take() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut state = self.slot.lock().unwrap_or_else(|err| err.into_inner());
+        let entry = match state.as_mut() {
+            Some(Ok(entry)) => entry,
+            _ => return,
+        };
+        entry.users -= 1;
+        if entry.users > 0 || entry.pinned.is_some() {
+            return;
+        }
+        // Last handle, tear down while holding the lock, so that the next
+        // `get_or_init` doesn't start while we're still tearing down.
+        *state = None;
+        tear_down(&self.name, shared);
+    }
+}
+
+/// Load a configuration file, with the same default root directory as the
+/// `mx-tester` binary.
+fn load_config(path: &Path) -> Result<Config, Error> {
+    let value = crate::read_config_file(path)?;
+    let mut config: Config = serde_yaml::from_value(value.clone())
+        .with_context(|| format!("Invalid config file {:?}", path))?;
+    if value["directories"]["root"].is_null() {
+        config.directories.root = Directories::for_config_file(path);
+    }
+    Ok(config)
+}
@@ -0,0 +1,161 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A TURN server for VoIP tests, as specified in `turn`.
+//!
+//! coturn runs in its own container on the same Docker network as Synapse,
+//! with a shared secret, and Synapse hands out credentials for it through
+//! `/voip/turnServer`, as it would in production.
+
+use std::collections::HashMap;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::Config;
+
+#[cfg(feature = "docker")]
+pub use self::docker::{start, stop};
+
+/// The port on which coturn listens, within its container.
+const TURN_PORT: u16 = 3478;
+
+/// Configuring the TURN server.
+#[derive(Debug, TypedBuilder, Deserialize, Serialize)]
+pub struct TurnConfig {
+    /// The Docker image of coturn.
+    ///
+    /// Defaults to `coturn/coturn:latest`.
+    #[serde(default = "TurnConfig::default_image")]
+    #[builder(default = TurnConfig::default_image())]
+    pub image: String,
+
+    /// The secret shared by Synapse and coturn.
+    #[serde(default = "TurnConfig::default_shared_secret")]
+    #[builder(default = TurnConfig::default_shared_secret())]
+    pub shared_secret: String,
+
+    /// The realm of coturn.
+    ///
+    /// Defaults to `mx-tester`.
+    #[serde(default = "TurnConfig::default_realm")]
+    #[builder(default = TurnConfig::default_realm())]
+    pub realm: String,
+
+    /// If specified, publish the TURN port (TCP and UDP) on this port of the host,
+    /// e.g. for clients running on the host.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub host_port: Option<u16>,
+
+    /// Additional hostnames of coturn on the Docker network.
+    #[serde(default)]
+    #[builder(default)]
+    pub aliases: Vec<String>,
+}
+
+impl TurnConfig {
+    fn default_image() -> String {
+        "coturn/coturn:latest".to_string()
+    }
+    fn default_shared_secret() -> String {
+        "MX_TESTER_TURN_SECRET_DEFAULT".to_string()
+    }
+    fn default_realm() -> String {
+        "mx-tester".to_string()
+    }
+}
+
+/// The name of the container running coturn.
+pub fn container_name(config: &Config) -> String {
+    format!("mx-tester-turn-{}", config.name)
+}
+
+/// The TURN URIs handed out by Synapse, reachable from the Docker network.
+pub fn uris(config: &Config) -> Vec<String> {
+    let host = container_name(config);
+    ["udp", "tcp"]
+        .iter()
+        .map(|transport| format!("turn:{}:{}?transport={}", host, TURN_PORT, transport))
+        .collect()
+}
+
+/// Patch homeserver.yaml to hand out credentials for coturn.
+///
+/// Fields specified in mx-tester.yml are not overridden.
+pub fn patch_homeserver_config(
+    config: &Config,
+    turn: &TurnConfig,
+    content: &mut serde_yaml::Mapping,
+    extra_fields: &HashMap<String, serde_yaml::Value>,
+) -> Result<(), Error> {
+    for (key, value) in [
+        ("turn_uris", serde_yaml::to_value(uris(config))?),
+        ("turn_shared_secret", yaml!(turn.shared_secret.clone())),
+    ] {
+        if !extra_fields.contains_key(key) {
+            content.insert(key.into(), value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    use super::*;
+
+    use bollard::Docker;
+
+    use crate::services::{stop_container, Container, ContainerPort};
+
+    /// Start coturn, if `turn` is specified.
+    pub async fn start(docker: &Docker, config: &Config) -> Result<(), Error> {
+        let turn = match config.turn {
+            None => return Ok(()),
+            Some(ref turn) => turn,
+        };
+        Container {
+            label: "coturn".to_string(),
+            name: container_name(config),
+            image: turn.image.clone(),
+            cmd: Some(vec![
+                "-n".to_string(),
+                "--log-file=stdout".to_string(),
+                "--no-cli".to_string(),
+                format!("--listening-port={}", TURN_PORT),
+                "--use-auth-secret".to_string(),
+                format!("--static-auth-secret={}", turn.shared_secret),
+                format!("--realm={}", turn.realm),
+            ]),
+            ports: vec![
+                ContainerPort::tcp(TURN_PORT, turn.host_port.map(u64::from)),
+                ContainerPort::udp(TURN_PORT, turn.host_port.map(u64::from)),
+            ],
+            aliases: turn.aliases.clone(),
+            log_name: "turn.log".to_string(),
+            ..Container::default()
+        }
+        .start(docker, config)
+        .await
+    }
+
+    /// Stop and remove coturn, if `turn` is specified.
+    pub async fn stop(docker: &Docker, config: &Config) -> Result<(), Error> {
+        if config.turn.is_none() {
+            return Ok(());
+        }
+        stop_container(docker, config, &container_name(config), "coturn").await
+    }
+}
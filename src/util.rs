@@ -1,6 +1,10 @@
-use async_trait::async_trait;
-use log::debug;
-use rand::Rng;
+#[cfg(feature = "docker")]
+pub use self::docker::{
+    exec, exec_checked, exec_output, exec_with, wait_until, with_heartbeat, ExecOptions, ExecOutput,
+};
+
+#[cfg(feature = "matrix-client")]
+pub use self::client::{retry_rate_limited, AsRumaError, Retry};
 
 /// A generic syntax for dict-like structures.
 ///
@@ -205,71 +209,336 @@ pub fn true_() -> bool {
     true
 }
 
-pub trait AsRumaError {
-    fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error>;
+/// Utility function: move a file, even across filesystems, e.g. if the
+/// data directory is a volume.
+pub fn move_file(source: &std::path::Path, dest: &std::path::Path) -> Result<(), anyhow::Error> {
+    use anyhow::Context;
+    if std::fs::rename(source, dest).is_err() {
+        std::fs::copy(source, dest)
+            .with_context(|| format!("Could not copy {:?} to {:?}", source, dest))?;
+        std::fs::remove_file(source).with_context(|| format!("Could not remove {:?}", source))?;
+    }
+    Ok(())
 }
-impl AsRumaError for matrix_sdk::HttpError {
-    fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error> {
-        match *self {
-            matrix_sdk::HttpError::Api(
-                matrix_sdk::ruma::api::error::FromHttpResponseError::Server(
-                    matrix_sdk::ruma::api::error::ServerError::Known(
-                        matrix_sdk::RumaApiError::ClientApi(ref err),
-                    ),
-                ),
-            ) => Some(err),
-            _ => None,
+
+/// Utility function: serialize a `HashMap` with its keys in order, so that
+/// serializing the same configuration always produces the same file.
+pub fn serialize_sorted<S, K, V>(
+    map: &std::collections::HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    K: serde::Serialize + Ord,
+    V: serde::Serialize,
+{
+    serializer.collect_map(map.iter().collect::<std::collections::BTreeMap<_, _>>())
+}
+
+#[cfg(feature = "docker")]
+mod docker {
+    /// The interval between two progress messages while waiting for a long operation.
+    const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+    /// Run `future` to completion, printing a progress line every `HEARTBEAT_INTERVAL`.
+    ///
+    /// Some operations (pulling images, starting workers, registering users) can take
+    /// minutes without any output, which makes CI jobs look hung.
+    ///
+    /// `status` is called whenever we print a progress line and may return
+    /// additional details, e.g. "container running".
+    pub async fn with_heartbeat<F, S, SF>(what: &str, status: S, future: F) -> F::Output
+    where
+        F: std::future::Future,
+        S: Fn() -> SF,
+        SF: std::future::Future<Output = Option<String>>,
+    {
+        let start = std::time::Instant::now();
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + HEARTBEAT_INTERVAL,
+            HEARTBEAT_INTERVAL,
+        );
+        tokio::pin!(future);
+        loop {
+            tokio::select! {
+                result = &mut future => return result,
+                _ = interval.tick() => {
+                    let elapsed = start.elapsed().as_secs();
+                    match status().await {
+                        Some(status) => println!("** still waiting for {}, {}s elapsed, {}", what, elapsed, status),
+                        None => println!("** still waiting for {}, {}s elapsed", what, elapsed),
+                    }
+                }
+            }
         }
     }
-}
-impl AsRumaError for matrix_sdk::Error {
-    fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error> {
-        match *self {
-            matrix_sdk::Error::Http(ref err) => err.as_ruma_error(),
-            _ => None,
+
+    /// Poll `ready` every second until it returns `true`, for at most `timeout`.
+    pub async fn wait_until<F, Fut>(
+        timeout: std::time::Duration,
+        what: &str,
+        mut ready: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<bool, anyhow::Error>>,
+    {
+        let start = std::time::Instant::now();
+        loop {
+            if ready().await? {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(anyhow::anyhow!(
+                    "{} did not become ready in {:?}",
+                    what,
+                    timeout
+                ));
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
         }
     }
-}
 
-#[async_trait]
-pub trait Retry {
-    async fn auto_retry(&self, attempts: u64) -> Result<reqwest::Response, anyhow::Error>;
+    /// How to execute a command in a container, see `exec_with`.
+    #[derive(Debug, Default)]
+    pub struct ExecOptions {
+        /// Environment variables, as `KEY=value`.
+        pub env: Vec<String>,
+
+        /// If specified, the directory in which the command runs.
+        pub working_dir: Option<String>,
+
+        /// Run as the user running mx-tester rather than as the user of the
+        /// container, e.g. so that files written to mounted directories belong
+        /// to the user. Ignored on other platforms than Unix.
+        pub as_host_user: bool,
+    }
+
+    /// The output of a command executed in a container, as it comes.
+    pub type ExecOutput = std::pin::Pin<
+        Box<
+            dyn futures_util::Stream<
+                    Item = Result<bollard::container::LogOutput, bollard::errors::Error>,
+                > + Send,
+        >,
+    >;
+
+    /// Start executing a command in a container.
+    ///
+    /// Returns the id of the execution, e.g. to inspect its exit code once it's
+    /// complete, and its output, as it comes.
+    pub async fn exec_output(
+        docker: &bollard::Docker,
+        container_name: &str,
+        cmd: Vec<&str>,
+        options: &ExecOptions,
+    ) -> Result<(String, ExecOutput), anyhow::Error> {
+        use anyhow::Context;
+        use bollard::exec::{CreateExecOptions, StartExecResults};
+
+        #[cfg(unix)]
+        let user = options
+            .as_host_user
+            .then(|| format!("{}", nix::unistd::getuid()));
+        #[cfg(not(unix))]
+        let user: Option<String> = None;
+        log::debug!("Executing `{}` in {}", cmd.join(" "), container_name);
+        let exec = docker
+            .create_exec(
+                container_name,
+                CreateExecOptions {
+                    cmd: Some(cmd),
+                    env: Some(options.env.iter().map(String::as_str).collect()),
+                    working_dir: options.working_dir.as_deref(),
+                    user: user.as_deref(),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    ..CreateExecOptions::default()
+                },
+            )
+            .await
+            .with_context(|| format!("Could not execute command in {}", container_name))?;
+        match docker.start_exec(&exec.id, None).await? {
+            StartExecResults::Attached { output, input: _ } => Ok((exec.id, output)),
+            StartExecResults::Detached => Err(anyhow::anyhow!(
+                "Unexpected detached execution in {}",
+                container_name
+            )),
+        }
+    }
+
+    /// Execute a command in a container, returning its exit code and output.
+    pub async fn exec_with(
+        docker: &bollard::Docker,
+        container_name: &str,
+        cmd: Vec<&str>,
+        options: &ExecOptions,
+    ) -> Result<(i64, String), anyhow::Error> {
+        use futures_util::stream::StreamExt;
+
+        let (id, mut stream) = exec_output(docker, container_name, cmd, options).await?;
+        let mut output = String::new();
+        while let Some(data) = stream.next().await {
+            output.push_str(&format!("{}", data?));
+        }
+        let code = docker
+            .inspect_exec(&id)
+            .await?
+            .exit_code
+            .unwrap_or_default();
+        log::debug!("In {}, exit code {}: {}", container_name, code, output);
+        Ok((code, output))
+    }
+
+    /// Execute a command in a container, as its default user, returning its exit
+    /// code and output.
+    pub async fn exec(
+        docker: &bollard::Docker,
+        container_name: &str,
+        cmd: Vec<&str>,
+    ) -> Result<(i64, String), anyhow::Error> {
+        exec_with(docker, container_name, cmd, &ExecOptions::default()).await
+    }
+
+    /// Execute a command in a container, failing if it fails, and returning its
+    /// output otherwise.
+    pub async fn exec_checked(
+        docker: &bollard::Docker,
+        container_name: &str,
+        cmd: Vec<&str>,
+        options: &ExecOptions,
+    ) -> Result<String, anyhow::Error> {
+        let description = cmd.join(" ");
+        let (code, output) = exec_with(docker, container_name, cmd, options).await?;
+        if code != 0 {
+            return Err(anyhow::anyhow!(
+                "`{}` failed in {} with exit code {}:\n{}",
+                description,
+                container_name,
+                code,
+                output
+            ));
+        }
+        Ok(output)
+    }
 }
 
-#[async_trait]
-impl Retry for reqwest::RequestBuilder {
-    async fn auto_retry(&self, max_attempts: u64) -> Result<reqwest::Response, anyhow::Error> {
-        /// The duration of the retry will be picked randomly within this interval,
-        /// plus an exponential backoff.
-        const BASE_INTERVAL_MS: std::ops::Range<u64> = 300..1000;
+#[cfg(feature = "matrix-client")]
+mod client {
+    use async_trait::async_trait;
+    use log::debug;
+    use rand::Rng;
 
+    pub trait AsRumaError {
+        fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error>;
+    }
+
+    impl AsRumaError for matrix_sdk::HttpError {
+        fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error> {
+            match *self {
+                matrix_sdk::HttpError::Api(
+                    matrix_sdk::ruma::api::error::FromHttpResponseError::Server(
+                        matrix_sdk::ruma::api::error::ServerError::Known(
+                            matrix_sdk::RumaApiError::ClientApi(ref err),
+                        ),
+                    ),
+                ) => Some(err),
+                _ => None,
+            }
+        }
+    }
+
+    impl AsRumaError for matrix_sdk::Error {
+        fn as_ruma_error(&self) -> Option<&matrix_sdk::ruma::api::client::Error> {
+            match *self {
+                matrix_sdk::Error::Http(ref err) => err.as_ruma_error(),
+                _ => None,
+            }
+        }
+    }
+
+    /// The maximal number of attempts of a request that is rate-limited.
+    const RATE_LIMITED_MAX_ATTEMPTS: u32 = 10;
+
+    /// How long to wait before retrying a rate-limited request, if the homeserver doesn't tell.
+    const RATE_LIMITED_DEFAULT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// Run a matrix-sdk request, retrying as long as the homeserver rate-limits it
+    /// (429 or `M_LIMIT_EXCEEDED`), after the delay requested by the homeserver.
+    ///
+    /// Other errors are returned immediately.
+    pub async fn retry_rate_limited<T, E, F, Fut>(what: &str, mut request: F) -> Result<T, E>
+    where
+        E: AsRumaError,
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T, E>>,
+    {
+        use matrix_sdk::ruma::api::client::error::ErrorKind;
         let mut attempt = 1;
         loop {
-            match self
-                .try_clone()
-                .expect("Cannot auto-retry non-clonable requests")
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    debug!("auto_retry success");
-                    break Ok(response);
+            let err = match request().await {
+                Err(err) if attempt < RATE_LIMITED_MAX_ATTEMPTS => err,
+                result => return result,
+            };
+            let delay = match err.as_ruma_error() {
+                Some(matrix_sdk::ruma::api::client::Error {
+                    kind: ErrorKind::LimitExceeded { retry_after_ms },
+                    ..
+                }) => retry_after_ms.unwrap_or(RATE_LIMITED_DEFAULT_DELAY),
+                Some(err) if err.status_code == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    RATE_LIMITED_DEFAULT_DELAY
                 }
-                Err(err) => {
-                    debug!("auto_retry error {:?} => {:?}", err, err.status());
-                    // FIXME: Is this the right way to decide when to retry?
-                    let should_retry = attempt < max_attempts
-                        && (err.is_connect() || err.is_timeout() || err.is_request());
-
-                    if should_retry {
-                        let duration =
-                            (attempt * attempt) * rand::thread_rng().gen_range(BASE_INTERVAL_MS);
-                        attempt += 1;
-                        debug!("auto_retry: sleeping {}ms", duration);
-                        tokio::time::sleep(std::time::Duration::from_millis(duration)).await;
-                    } else {
-                        debug!("auto_retry: giving up!");
-                        return Err(err.into());
+                _ => return Err(err),
+            };
+            debug!(
+                "{} is rate-limited, retrying in {:?} ({}/{})",
+                what, delay, attempt, RATE_LIMITED_MAX_ATTEMPTS
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    #[async_trait]
+    pub trait Retry {
+        async fn auto_retry(&self, attempts: u64) -> Result<reqwest::Response, anyhow::Error>;
+    }
+
+    #[async_trait]
+    impl Retry for reqwest::RequestBuilder {
+        async fn auto_retry(&self, max_attempts: u64) -> Result<reqwest::Response, anyhow::Error> {
+            /// The duration of the retry will be picked randomly within this interval,
+            /// plus an exponential backoff.
+            const BASE_INTERVAL_MS: std::ops::Range<u64> = 300..1000;
+
+            let mut attempt = 1;
+            loop {
+                match self
+                    .try_clone()
+                    .expect("Cannot auto-retry non-clonable requests")
+                    .send()
+                    .await
+                {
+                    Ok(response) => {
+                        debug!("auto_retry success");
+                        break Ok(response);
+                    }
+                    Err(err) => {
+                        debug!("auto_retry error {:?} => {:?}", err, err.status());
+                        // FIXME: Is this the right way to decide when to retry?
+                        let should_retry = attempt < max_attempts
+                            && (err.is_connect() || err.is_timeout() || err.is_request());
+
+                        if should_retry {
+                            let duration = (attempt * attempt)
+                                * rand::thread_rng().gen_range(BASE_INTERVAL_MS);
+                            attempt += 1;
+                            debug!("auto_retry: sleeping {}ms", duration);
+                            tokio::time::sleep(std::time::Duration::from_millis(duration)).await;
+                        } else {
+                            debug!("auto_retry: giving up!");
+                            return Err(err.into());
+                        }
                     }
                 }
             }
@@ -0,0 +1,226 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Strict checks of mx-tester.yml, on top of `Config::validate`: `mx-tester validate`.
+//!
+//! Serde ignores unknown fields, so a typo such as `pasword` silently falls back
+//! to the default. To find them, we compare the keys of mx-tester.yml with those
+//! of the parsed configuration, serialized back, which contains every known field.
+//! We also check that the files that mx-tester and the scripts of the host use
+//! exist, relative to the current directory.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+use crate::{Config, ModuleSource, Script, SynapseVersion, UpScript};
+
+/// File extensions of scripts, used to recognize the files that a script line uses.
+const SCRIPT_EXTENSIONS: [&str; 6] = ["sh", "py", "js", "ts", "rb", "pl"];
+
+/// The fields of `source`, i.e. the content of mx-tester.yml, that are not
+/// fields of `config`, i.e. the configuration parsed from `source`, as paths,
+/// e.g. `users[0].pasword`.
+pub fn unknown_fields(source: &serde_yaml::Value, config: &Config) -> Result<Vec<String>, Error> {
+    let known = serde_yaml::to_value(config).context("Could not serialize config")?;
+    let mut unknown = vec![];
+    collect_unknown_fields("", source, &known, &mut unknown);
+    Ok(unknown)
+}
+
+fn collect_unknown_fields(
+    path: &str,
+    source: &serde_yaml::Value,
+    known: &serde_yaml::Value,
+    unknown: &mut Vec<String>,
+) {
+    use serde_yaml::Value;
+    match (source, known) {
+        (Value::Mapping(source), Value::Mapping(known)) => {
+            for (key, value) in source {
+                let name = match key {
+                    Value::String(name) => name.clone(),
+                    _ => format!("{:?}", key),
+                };
+                let field = if path.is_empty() {
+                    name
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                match known.get(key) {
+                    Some(known) => collect_unknown_fields(&field, value, known, unknown),
+                    // Optional fields that are not set are not serialized.
+                    None if value.is_null() => {}
+                    None => unknown.push(field),
+                }
+            }
+        }
+        (Value::Sequence(source), Value::Sequence(known)) => {
+            for (index, (source, known)) in source.iter().zip(known).enumerate() {
+                collect_unknown_fields(&format!("{}[{}]", path, index), source, known, unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The files used by a line of a script of the host, i.e. relative paths
+/// starting with `./` or ending with a script extension, e.g. `mx-tester/build.sh`.
+///
+/// This is a heuristic: words containing variables or options are ignored.
+fn script_files(line: &str) -> Vec<PathBuf> {
+    line.split_whitespace()
+        .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|word| !word.contains('$') && !word.starts_with('-'))
+        .filter(|word| {
+            word.starts_with("./")
+                || Path::new(word)
+                    .extension()
+                    .map_or(false, |ext| SCRIPT_EXTENSIONS.iter().any(|e| ext == *e))
+        })
+        .map(PathBuf::from)
+        .filter(|path| path.is_relative())
+        .collect()
+}
+
+/// The files and directories that `config` uses on the host, with what uses them.
+fn host_files(config: &Config) -> Vec<(String, PathBuf)> {
+    let mut files = vec![];
+    let mut scripts: Vec<(String, &Script)> = vec![];
+    for module in &config.modules {
+        match module.source {
+            ModuleSource::Build => {
+                scripts.push((format!("module {}: `build`", module.name), &module.build))
+            }
+            ModuleSource::Wheel { ref path, .. } => files.push((
+                format!("module {}: `source.wheel.path`", module.name),
+                path.clone(),
+            )),
+        }
+        if let Some(ref path) = module.path {
+            files.push((format!("module {}: `path`", module.name), path.clone()));
+        }
+    }
+    match config.up {
+        Some(UpScript::FullUpScript(ref up)) => {
+            scripts.extend(
+                up.before
+                    .iter()
+                    .map(|script| ("`up.before`".to_string(), script)),
+            );
+            scripts.extend(
+                up.after
+                    .iter()
+                    .map(|script| ("`up.after`".to_string(), script)),
+            );
+        }
+        Some(UpScript::SimpleScript(ref script)) => scripts.push(("`up`".to_string(), script)),
+        None => {}
+    }
+    scripts.extend(
+        config
+            .run
+            .iter()
+            .map(|script| ("`run`".to_string(), script)),
+    );
+    if let Some(ref down) = config.down {
+        for (name, script) in [
+            ("`down.success`", &down.success),
+            ("`down.failure`", &down.failure),
+            ("`down.finally`", &down.finally),
+        ] {
+            scripts.extend(script.iter().map(|script| (name.to_string(), script)));
+        }
+    }
+    scripts.extend(
+        config
+            .bench
+            .script
+            .iter()
+            .map(|script| ("`bench.script`".to_string(), script)),
+    );
+    for (what, script) in scripts {
+        for line in &script.lines {
+            files.extend(
+                script_files(line)
+                    .into_iter()
+                    .map(|path| (format!("{}, line `{}`", what, line), path)),
+            );
+        }
+    }
+    for bot in &config.bots {
+        if let Some(ref build) = bot.build {
+            files.push((
+                format!("bot {}: `build`", bot.name),
+                build.join("Dockerfile"),
+            ));
+        }
+    }
+    for service in &config.services {
+        for volume in &service.volumes {
+            files.push((
+                format!("service {}: `volumes`", service.name),
+                volume.host.clone(),
+            ));
+        }
+    }
+    if let Some(ref dir) = config.workers.templates_dir {
+        files.push(("`workers.templates_dir`".to_string(), dir.clone()));
+    }
+    if let SynapseVersion::Local { ref path, .. } = config.synapse {
+        files.push(("`synapse.local.path`".to_string(), path.clone()));
+    }
+    files
+}
+
+/// Check that the files that `config` uses on the host exist, adding a
+/// description of each problem to `problems`.
+pub fn check_files(config: &Config, problems: &mut Vec<String>) {
+    for (what, path) in host_files(config) {
+        if !path.exists() {
+            problems.push(format!("{}: {:?} does not exist", what, path));
+        }
+    }
+}
+
+/// Check `config` strictly: the checks of `Config::validate`, the fields of
+/// `source`, i.e. the parsed config file if any, that mx-tester does not
+/// know and the files that `config` uses on the host.
+///
+/// `problems` are those found while preparing `config`, e.g. while expanding
+/// placeholders, reported along with the others.
+pub fn validate_strict(
+    config: &Config,
+    source: Option<&serde_yaml::Value>,
+    mut problems: Vec<String>,
+) -> Result<(), Error> {
+    problems.extend(config.problems());
+    if let Some(source) = source {
+        // Compare against the configuration as written, without the overrides
+        // of the command line or the generated fixtures.
+        let unknown = serde_yaml::from_value(source.clone())
+            .context("Invalid config file")
+            .and_then(|parsed: Config| unknown_fields(source, &parsed));
+        match unknown {
+            Ok(fields) => problems.extend(
+                fields
+                    .into_iter()
+                    .map(|field| format!("Unknown field `{}`, is this a typo?", field)),
+            ),
+            Err(err) => problems.push(format!("{:#}", err)),
+        }
+    }
+    check_files(config, &mut problems);
+    crate::report_problems(problems)
+}
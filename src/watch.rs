@@ -0,0 +1,263 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reinstalling modules in the running homeserver as their source code
+//! changes: `mx-tester watch`.
+//!
+//! We poll the `path` of each module rather than rely on filesystem
+//! notifications, which don't work across e.g. Docker Desktop bind mounts.
+//! Once a change has settled, the `build` script of the module is executed
+//! again, or its wheel is copied again, the result is copied into the running
+//! container and installed with `pip`, then Synapse is restarted. The network,
+//! the other containers and the users and rooms registered during `up` are left
+//! alone.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::{container::UploadToContainerOptions, Docker};
+use log::debug;
+
+use crate::{
+    admin,
+    lifecycle::{prepare_module, DockerExt},
+    util::{exec_checked, ExecOptions},
+    Config, InstallMode, ModuleConfig,
+};
+
+/// How often we check for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the source code must remain unchanged before we rebuild, so that
+/// e.g. saving several files or a `git checkout` only causes one rebuild.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// The directory of the modules in the container, as per the Dockerfile.
+const GUEST_MODULES_DIR: &str = "/mx-tester";
+
+/// The directories written by `build` scripts and Python tooling, which we
+/// don't watch, as rebuilding would otherwise trigger another rebuild.
+const IGNORED_DIRS: [&str; 3] = ["__pycache__", "build", "dist"];
+
+/// The last modification time of each file in `dir`, recursively.
+///
+/// Hidden files, build outputs and Python caches are ignored, as are files
+/// that disappear while we walk, e.g. temporary files of editors.
+fn snapshot(dir: &Path, files: &mut BTreeMap<PathBuf, SystemTime>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Could not read {:?}: {}", dir, err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || name.ends_with(".pyc") {
+            continue;
+        }
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                if !IGNORED_DIRS.contains(&name.as_ref()) && !name.ends_with(".egg-info") {
+                    snapshot(&path, files);
+                }
+            }
+            Ok(metadata) => {
+                if let Ok(modified) = metadata.modified() {
+                    files.insert(path, modified);
+                }
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// The modules to watch, with their source code on the host.
+fn watched_modules(config: &Config) -> Result<Vec<(&ModuleConfig, PathBuf)>, Error> {
+    let mut watched = vec![];
+    for module in &config.modules {
+        match module.path {
+            Some(_) => watched.push((module, module.host_path()?)),
+            None => println!(
+                "** module {} has no `path`, it will not be watched",
+                module.name
+            ),
+        }
+    }
+    if watched.is_empty() {
+        return Err(anyhow!(
+            "Nothing to watch, please specify the `path` of the source code of modules"
+        ));
+    }
+    Ok(watched)
+}
+
+/// Execute the `build` script of `module` again, or copy its wheel again,
+/// then copy the result into the running container and install it.
+///
+/// Modules installed in editable mode are mounted from the host, so there is
+/// nothing to do.
+async fn reinstall(docker: &Docker, config: &Config, module: &ModuleConfig) -> Result<(), Error> {
+    if let InstallMode::Editable = module.install_mode {
+        return Ok(());
+    }
+    let synapse_root = config.synapse_root();
+    let path = synapse_root.join(&module.name);
+    // Build scripts typically `cp -r` into `MX_TEST_MODULE_DIR`, which must not exist yet.
+    if path.exists() {
+        std::fs::remove_dir_all(&path).with_context(|| format!("Could not clean up {:?}", path))?;
+    }
+    let mut env = config.shared_env_variables()?;
+    prepare_module(config, module, &mut env).await?;
+
+    // Same layout as `COPY` in the Dockerfile.
+    let mut archive = tar::Builder::new(vec![]);
+    archive
+        .append_dir_all(&module.name, &path)
+        .with_context(|| format!("Could not read {:?}", path))?;
+    for (dest, source) in &module.copy {
+        let source = synapse_root.join(source);
+        let dest = Path::new(&module.name).join(dest);
+        if source.is_dir() {
+            archive.append_dir_all(&dest, &source)
+        } else {
+            archive.append_path_with_name(&source, &dest)
+        }
+        .with_context(|| format!("Could not read {:?}", source))?;
+    }
+    let archive = archive.into_inner()?;
+
+    let guest_path = format!("{}/{}", GUEST_MODULES_DIR, module.name);
+    let container_name = config.run_container_name();
+    let options = ExecOptions::default();
+    exec_checked(
+        docker,
+        &container_name,
+        vec!["rm", "-rf", &guest_path],
+        &options,
+    )
+    .await?;
+    docker
+        .upload_to_container(
+            &container_name,
+            Some(UploadToContainerOptions {
+                path: GUEST_MODULES_DIR,
+                ..UploadToContainerOptions::default()
+            }),
+            archive.into(),
+        )
+        .await
+        .with_context(|| format!("Could not copy module {} into the container", module.name))?;
+    exec_checked(
+        docker,
+        &container_name,
+        vec![
+            "/usr/local/bin/python",
+            "-m",
+            "pip",
+            "install",
+            "--force-reinstall",
+            "--no-deps",
+            &module.guest_install_path(),
+        ],
+        &options,
+    )
+    .await
+    .with_context(|| format!("Could not install module {}", module.name))?;
+    Ok(())
+}
+
+/// Watch the source code of modules and, whenever it changes, reinstall them
+/// in the running homeserver and restart it, until interrupted.
+pub async fn watch(docker: &Docker, config: &Config) -> Result<(), Error> {
+    let run_container_name = config.run_container_name();
+    if !docker.is_container_running(&run_container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running, please run `mx-tester up` first",
+            run_container_name
+        ));
+    }
+    let watched = watched_modules(config)?;
+    let snapshot_of = |path: &Path| {
+        let mut files = BTreeMap::new();
+        snapshot(path, &mut files);
+        files
+    };
+    let mut snapshots: Vec<_> = watched.iter().map(|(_, path)| snapshot_of(path)).collect();
+    for (module, path) in &watched {
+        println!("* watching module {} at {:?}", module.name, path);
+    }
+    println!("* press Ctrl-C to stop");
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                println!("* stopped watching");
+                return Ok(());
+            }
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+        let changed: Vec<usize> = (0..watched.len())
+            .filter(|&index| snapshot_of(&watched[index].1) != snapshots[index])
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+        // Wait until the changes have settled.
+        loop {
+            let before: Vec<_> = changed
+                .iter()
+                .map(|&index| snapshot_of(&watched[index].1))
+                .collect();
+            tokio::time::sleep(SETTLE_DELAY).await;
+            let after: Vec<_> = changed
+                .iter()
+                .map(|&index| snapshot_of(&watched[index].1))
+                .collect();
+            if before == after {
+                for (&index, files) in changed.iter().zip(after) {
+                    snapshots[index] = files;
+                }
+                break;
+            }
+        }
+
+        let mut result = Ok(());
+        for &index in &changed {
+            let module = watched[index].0;
+            println!("\n* module {} changed, reinstalling", module.name);
+            result = reinstall(docker, config, module)
+                .await
+                .with_context(|| format!("Could not reinstall module {}", module.name));
+            if result.is_err() {
+                break;
+            }
+        }
+        let result = match result {
+            Ok(()) => admin::restart(docker, config).await,
+            Err(err) => Err(err),
+        };
+        // Keep watching, the next change may fix the problem.
+        match result {
+            Ok(()) => println!("* modules reinstalled, watching"),
+            Err(err) => println!("* error: {:#}, watching", err),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The workers of Synapse, in workers mode, and scaling them mid-test.
+//!
+//! `workers_start.py` names each worker after its type and an index, e.g.
+//! `synchrotron1`, and assigns ports in the order of `SYNAPSE_WORKER_TYPES`.
+//! To scale, we regenerate the configuration of workers, nginx and supervisord
+//! with the new list of worker types, then let supervisord start and stop
+//! processes and nginx reload its upstreams.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use data_encoding::HEXLOWER;
+use sha1::{Digest, Sha1};
+
+use anyhow::{anyhow, Context, Error};
+use bollard::Docker;
+
+use crate::manifest::{TemplateInfo, TemplateSource};
+use crate::Config;
+
+/// The worker types supported by `workers_start.py`.
+pub const WORKER_TYPES: &[&str] = &[
+    "appservice",
+    "background_worker",
+    "event_creator",
+    "event_persister",
+    "federation_inbound",
+    "federation_reader",
+    "federation_sender",
+    "frontend_proxy",
+    "media_repository",
+    "pusher",
+    "synchrotron",
+    "user_dir",
+];
+
+/// The resources of workers mode embedded in mx-tester, by file name.
+///
+/// These files are used by `workers_start.py` to generate the configuration
+/// of workers. They have been copied manually from Synapse's git repo, except
+/// `workers_start.py` itself, which is adapted from it, and `postgres.sql`,
+/// which sets up the user and database of PostgreSQL.
+pub const TEMPLATES: &[(&str, &str)] = &[
+    (
+        "worker.yaml.j2",
+        include_str!("../res/workers/worker.yaml.j2"),
+    ),
+    (
+        "shared.yaml.j2",
+        include_str!("../res/workers/shared.yaml.j2"),
+    ),
+    (
+        "supervisord.conf.j2",
+        include_str!("../res/workers/supervisord.conf.j2"),
+    ),
+    (
+        "nginx.conf.j2",
+        include_str!("../res/workers/nginx.conf.j2"),
+    ),
+    ("log.config", include_str!("../res/workers/log.config")),
+    (
+        "workers_start.py",
+        include_str!("../res/workers/workers_start.py"),
+    ),
+    ("postgres.sql", include_str!("../res/workers/postgres.sql")),
+];
+
+/// A resource of workers mode, ready to be written to the build context.
+#[derive(Clone, Debug)]
+pub struct Template {
+    /// The file name, e.g. `nginx.conf.j2`.
+    pub name: &'static str,
+    pub content: String,
+    pub info: TemplateInfo,
+}
+
+/// The resources of workers mode, each from `workers.templates_dir` if it
+/// contains a file with the same name, otherwise embedded in mx-tester.
+///
+/// Fails if `workers.templates_dir` contains any other file, which is most
+/// likely a typo.
+pub fn templates(config: &Config) -> Result<Vec<Template>, Error> {
+    if let Some(ref dir) = config.workers.templates_dir {
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("Could not read {:?}", dir))?;
+        for entry in entries {
+            let name = entry?.file_name();
+            if !TEMPLATES.iter().any(|(template, _)| name == *template) {
+                return Err(anyhow!(
+                    "Unexpected file {:?} in `workers.templates_dir`, expected any of {}",
+                    name,
+                    TEMPLATES
+                        .iter()
+                        .map(|(template, _)| *template)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+    }
+    TEMPLATES
+        .iter()
+        .map(|(name, embedded)| {
+            let user_path = config
+                .workers
+                .templates_dir
+                .as_ref()
+                .map(|dir| dir.join(name))
+                .filter(|path| path.exists());
+            let (source, content) = match user_path {
+                Some(path) => (
+                    TemplateSource::User,
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("Could not read {:?}", path))?,
+                ),
+                None => (TemplateSource::Embedded, embedded.to_string()),
+            };
+            let sha1 = HEXLOWER.encode(&Sha1::digest(content.as_bytes()));
+            Ok(Template {
+                name,
+                content,
+                info: TemplateInfo { source, sha1 },
+            })
+        })
+        .collect()
+}
+
+/// How long we're willing to wait for Synapse to come back after scaling.
+const TIMEOUT_SCALE: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// A worker, as configured in `synapse_workers_dir`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Worker {
+    /// The name of the worker, e.g. `synchrotron1`.
+    pub name: String,
+
+    /// The ports on which the worker listens.
+    pub ports: Vec<u16>,
+}
+
+impl Worker {
+    /// The type of the worker, e.g. `synchrotron` for `synchrotron1`.
+    pub fn worker_type(&self) -> &str {
+        self.name.trim_end_matches(|c: char| c.is_ascii_digit())
+    }
+}
+
+/// The workers currently configured, in the order of their ports, i.e. in the
+/// order of `SYNAPSE_WORKER_TYPES`.
+pub fn workers(config: &Config) -> Result<Vec<Worker>, Error> {
+    let dir = config.synapse_workers_dir();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut workers = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("yaml") {
+            continue;
+        }
+        let content =
+            std::fs::read_to_string(&path).with_context(|| format!("Could not read {:?}", path))?;
+        let worker: serde_yaml::Value = match serde_yaml::from_str(&content) {
+            Ok(worker) => worker,
+            // Not a worker configuration file, e.g. a template not expanded yet.
+            Err(_) => continue,
+        };
+        let name = match worker["worker_name"].as_str() {
+            Some(name) => name.to_string(),
+            // e.g. shared.yaml.
+            None => continue,
+        };
+        let ports = worker["worker_listeners"]
+            .as_sequence()
+            .into_iter()
+            .flatten()
+            .filter_map(|listener| listener["port"].as_u64())
+            .map(|port| port as u16)
+            .collect();
+        workers.push(Worker { name, ports });
+    }
+    workers.sort_by_key(|worker| worker.ports.first().copied());
+    Ok(workers)
+}
+
+/// A change of the number of workers of a type, e.g. `synchrotron=2`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerScale {
+    pub worker_type: String,
+    pub count: usize,
+}
+
+impl FromStr for WorkerScale {
+    type Err = Error;
+
+    /// Parse a change from `worker_type=count`.
+    fn from_str(source: &str) -> Result<Self, Error> {
+        let (worker_type, count) = source
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid change `{}`, expected `worker_type=count`", source))?;
+        if !WORKER_TYPES.contains(&worker_type) {
+            return Err(anyhow!(
+                "Unknown worker type `{}`, expected one of {}",
+                worker_type,
+                WORKER_TYPES.join(", ")
+            ));
+        }
+        let count = count
+            .parse()
+            .with_context(|| format!("Invalid count `{}` in change `{}`", count, source))?;
+        Ok(WorkerScale {
+            worker_type: worker_type.to_string(),
+            count,
+        })
+    }
+}
+
+/// Apply changes to a list of worker types.
+///
+/// New instances are appended and the instances with the highest indices
+/// are removed first, so that the name and port of other workers are
+/// preserved whenever possible.
+pub fn scaled_types(current: &[String], changes: &[WorkerScale]) -> Vec<String> {
+    let mut types = current.to_vec();
+    for change in changes {
+        let mut count = types
+            .iter()
+            .filter(|worker_type| **worker_type == change.worker_type)
+            .count();
+        while count < change.count {
+            types.push(change.worker_type.clone());
+            count += 1;
+        }
+        while count > change.count {
+            let last = types
+                .iter()
+                .rposition(|worker_type| *worker_type == change.worker_type)
+                .expect("We have just counted this worker type");
+            types.remove(last);
+            count -= 1;
+        }
+    }
+    types
+}
+
+/// Scale the workers of a homeserver that is up, e.g. start an additional
+/// synchrotron or stop the federation sender.
+///
+/// Processes whose configuration is unchanged keep running. If the
+/// configuration shared by all processes changes, e.g. because of sharding,
+/// Synapse and all its workers are restarted.
+pub async fn scale(docker: &Docker, config: &Config, changes: &[WorkerScale]) -> Result<(), Error> {
+    use crate::{
+        lifecycle::DockerExt,
+        util::{exec_checked, ExecOptions},
+    };
+
+    if !config.workers.enabled {
+        return Err(anyhow!("Scaling workers requires `workers.enabled`"));
+    }
+    let run_container_name = config.run_container_name();
+    if !docker.is_container_healthy(&run_container_name).await? {
+        return Err(anyhow!(
+            "Container {} is not running or is unhealthy, please run `mx-tester up` first",
+            run_container_name
+        ));
+    }
+    println!("\n* scale-workers: starting");
+    let workers_dir = config.synapse_workers_dir();
+    let shared_path = workers_dir.join("shared.yaml");
+    let read_shared = || {
+        std::fs::read_to_string(&shared_path)
+            .with_context(|| format!("Could not read {:?}", shared_path))
+    };
+
+    let before = workers(config)?;
+    let current: Vec<String> = before
+        .iter()
+        .map(|worker| worker.worker_type().to_string())
+        .collect();
+    let types = scaled_types(&current, changes);
+    let shared_before = read_shared()?;
+
+    // `workers_start.py` doesn't remove the configuration of workers that are
+    // not needed anymore.
+    for worker in &before {
+        for file in [
+            format!("{}.yaml", worker.name),
+            format!("{}.log.config", worker.name),
+        ] {
+            let path = workers_dir.join(file);
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Could not remove {:?}", path))?;
+            }
+        }
+    }
+    // As the user running Synapse, so that the files written belong to the user.
+    let as_synapse = ExecOptions {
+        as_host_user: true,
+        ..ExecOptions::default()
+    };
+    exec_checked(
+        docker,
+        &run_container_name,
+        vec!["sh", "-c", "/workers_start.py generate"],
+        &ExecOptions {
+            env: crate::lifecycle::synapse_env(config, &types),
+            as_host_user: true,
+            ..ExecOptions::default()
+        },
+    )
+    .await
+    .context("Could not regenerate the configuration of workers")?;
+    // The log configs of workers were regenerated too.
+    let homeserver_path = config.homeserver_config_path();
+    let homeserver: serde_yaml::Mapping = serde_yaml::from_str(
+        &std::fs::read_to_string(&homeserver_path)
+            .with_context(|| format!("Could not read {:?}", homeserver_path))?,
+    )
+    .with_context(|| format!("Invalid homeserver config {:?}", homeserver_path))?;
+    config.patch_log_configs(&homeserver)?;
+
+    // Start new workers, stop removed ones and reload the upstreams of nginx.
+    exec_checked(
+        docker,
+        &run_container_name,
+        vec![
+            "sh",
+            "-c",
+            "supervisorctl reread && supervisorctl update && supervisorctl signal HUP nginx",
+        ],
+        &as_synapse,
+    )
+    .await
+    .context("Could not update supervisord")?;
+
+    // Restart the processes whose configuration changed.
+    let after = workers(config)?;
+    let ports_before: HashMap<&str, &Vec<u16>> = before
+        .iter()
+        .map(|worker| (worker.name.as_str(), &worker.ports))
+        .collect();
+    let mut restart: Vec<String> = if read_shared()? != shared_before {
+        std::iter::once("synapse_main".to_string())
+            .chain(
+                after
+                    .iter()
+                    .map(|worker| format!("synapse_{}", worker.name)),
+            )
+            .collect()
+    } else {
+        after
+            .iter()
+            .filter(|worker| {
+                ports_before
+                    .get(worker.name.as_str())
+                    .map(|ports| **ports != worker.ports)
+                    .unwrap_or(false)
+            })
+            .map(|worker| format!("synapse_{}", worker.name))
+            .collect()
+    };
+    if !restart.is_empty() {
+        restart.insert(0, "supervisorctl restart".to_string());
+        exec_checked(
+            docker,
+            &run_container_name,
+            vec!["sh", "-c", &restart.join(" ")],
+            &as_synapse,
+        )
+        .await
+        .context("Could not restart Synapse processes")?;
+    }
+
+    let versions_url = format!(
+        "{}/_matrix/client/versions",
+        config.homeserver.public_baseurl
+    );
+    let client = reqwest::Client::new();
+    crate::util::wait_until(TIMEOUT_SCALE, "Synapse", || async {
+        Ok(matches!(client.get(&versions_url).send().await, Ok(response) if response.status().is_success()))
+    })
+    .await?;
+    println!(
+        "* scale-workers: success, workers: {}",
+        after
+            .iter()
+            .map(|worker| worker.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(())
+}
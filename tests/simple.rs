@@ -185,6 +185,2638 @@ async fn test_create_users() {
         .expect("Failed in step `down`");
 }
 
+/// Simple test: `Tester` retains a logged-in client for each user after `up`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tester_clients() {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let docker = DOCKER.clone();
+    let admin = User::builder()
+        .admin(true)
+        .localname(format!("admin-{}", uuid::Uuid::new_v4()))
+        .build();
+    let regular_user = User::builder()
+        .localname(format!("regular-user-{}", uuid::Uuid::new_v4()))
+        .password(format!("{}", uuid::Uuid::new_v4()))
+        .build();
+
+    let config = Config::builder()
+        .name("test-tester-clients".into())
+        .users(vec![admin.clone(), regular_user.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tokio::time::timeout(std::time::Duration::from_secs(1800), async {
+        tester.up().await.expect("Failed in step `up`")
+    })
+    .await
+    .expect("Timeout in step `up`");
+
+    assert_eq!(tester.clients().len(), 2);
+    for user in [&admin, &regular_user] {
+        let user_id = tester
+            .client(&user.localname)
+            .expect("Missing client")
+            .whoami()
+            .await
+            .expect("Could not request whoami")
+            .user_id;
+        assert!(
+            user_id.as_str().contains(&user.localname),
+            "Expected to find local name {} in user_id {}",
+            user.localname,
+            user_id
+        );
+    }
+    assert!(tester.client("not-a-user").is_err());
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    assert!(tester.clients().is_empty());
+}
+/// Regression test: an alias created by a user during a previous run
+/// can be reused by another user.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_alias_reuse() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("alias-{}", uuid::Uuid::new_v4());
+    let config_for = |localname: &str| {
+        Config::builder()
+            .name("test-alias-reuse".into())
+            .users(vec![User::builder()
+                .localname(format!("{}-{}", localname, uuid::Uuid::new_v4()))
+                .rooms(vec![registration::Room::builder()
+                    .alias(Some(alias.clone()))
+                    .build()])
+                .build()])
+            .build()
+            .assign_port()
+    };
+
+    // The data directory is shared between both runs, so the alias survives `down`.
+    let first = config_for("first-owner");
+    let _ = Cleanup::new(&first);
+    mx_tester::build(&docker, &first)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &first)
+        .await
+        .expect("Failed in step `up` (first owner)");
+    mx_tester::down(&docker, &first, Status::Manual)
+        .await
+        .expect("Failed in step `down` (first owner)");
+
+    let second = config_for("second-owner");
+    let mut tester = Tester::new(docker, second);
+    tester
+        .up()
+        .await
+        .expect("Failed in step `up` (second owner)");
+    let localname = tester.config().users[0].localname.clone();
+    let client = tester.client(&localname).unwrap();
+    let alias_id = matrix_sdk::ruma::RoomAliasId::parse(format!(
+        "#{}:{}",
+        alias,
+        tester.config().homeserver.server_name
+    ))
+    .unwrap();
+    let room_id = client
+        .resolve_room_alias(&alias_id)
+        .await
+        .expect("Could not resolve alias")
+        .room_id;
+    let joined_rooms = client
+        .send(
+            matrix_sdk::ruma::api::client::membership::joined_rooms::v3::Request::new(),
+            None,
+        )
+        .await
+        .expect("Could not list joined rooms")
+        .joined_rooms;
+    assert!(
+        joined_rooms.contains(&room_id),
+        "The alias should now point to the room of the second owner"
+    );
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down` (second owner)");
+}
+
+/// Test: the helpers checking that rooms are blocked or emptied.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_room_helpers() {
+    use std::time::Duration;
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("room-helpers-{}", uuid::Uuid::new_v4());
+    let creator = User::builder()
+        .localname(format!("creator-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .alias(Some(alias.clone()))
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-room-helpers".into())
+        .users(vec![creator.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let client = tester.client(&creator.localname).unwrap();
+    let user_id = client.user_id().unwrap().to_owned();
+    let alias_id = matrix_sdk::ruma::RoomAliasId::parse(format!(
+        "#{}:{}",
+        alias,
+        tester.config().homeserver.server_name
+    ))
+    .unwrap();
+    let room_id = client
+        .resolve_room_alias(&alias_id)
+        .await
+        .expect("Could not resolve alias")
+        .room_id;
+    let admin = registration::admin_client(tester.config())
+        .await
+        .expect("Could not login as admin");
+
+    assert_eq!(
+        helpers::room_members(&admin, &room_id)
+            .await
+            .expect("Could not fetch members"),
+        vec![user_id.clone()]
+    );
+    assert!(!helpers::is_room_blocked(&admin, &room_id)
+        .await
+        .expect("Could not fetch block status"));
+    helpers::wait_for_room_blocked(&admin, &room_id, Duration::from_secs(2))
+        .await
+        .expect_err("The room is not blocked, this should time out");
+
+    client
+        .send(
+            matrix_sdk::ruma::api::client::membership::leave_room::v3::Request::new(&room_id),
+            None,
+        )
+        .await
+        .expect("Could not leave room");
+    helpers::wait_for_members_removed(&admin, &room_id, &[&user_id], Duration::from_secs(10))
+        .await
+        .expect("The creator should have left the room");
+    assert!(helpers::room_members(&admin, &room_id)
+        .await
+        .expect("Could not fetch members")
+        .is_empty());
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Resolve a room declared in `users` from its alias.
+async fn resolve_alias(
+    tester: &Tester,
+    localname: &str,
+    alias: &str,
+) -> matrix_sdk::ruma::OwnedRoomId {
+    helpers::resolve_room(
+        tester.client(localname).unwrap(),
+        &format!("#{}:{}", alias, tester.config().homeserver.server_name),
+    )
+    .await
+    .expect("Could not resolve alias")
+}
+
+/// Test: policy rules are published as state events of their room.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_policy_rules() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("ban-list-{}", uuid::Uuid::new_v4());
+    let moderator = User::builder()
+        .localname(format!("moderator-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .alias(Some(alias.clone()))
+            .policy_rules(vec![registration::PolicyRule::builder()
+                .kind(registration::PolicyRuleKind::User)
+                .entity("@spammer:*".to_string())
+                .reason("spam".to_string())
+                .build()])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-policy-rules".into())
+        .users(vec![moderator.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let room_id = resolve_alias(&tester, &moderator.localname, &alias).await;
+    let rule = helpers::get_state_event(
+        tester.client(&moderator.localname).unwrap(),
+        &room_id,
+        "m.policy.rule.user",
+        "rule:@spammer:*",
+    )
+    .await
+    .expect("Missing policy rule");
+    assert_eq!(
+        rule,
+        serde_json::json!({
+            "entity": "@spammer:*",
+            "recommendation": "m.ban",
+            "reason": "spam",
+        })
+    );
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: with `rebuild_user_directory`, users who share a room find each other
+/// in the user directory.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rebuild_user_directory() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let bob = User::builder()
+        .localname(format!("bob-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .members(vec![bob.localname.clone()])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-rebuild-user-directory".into())
+        .users(vec![alice.clone(), bob.clone()])
+        .rebuild_user_directory(true)
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let bob_id = tester
+        .client(&bob.localname)
+        .unwrap()
+        .user_id()
+        .unwrap()
+        .to_owned();
+    helpers::wait_for_user_in_directory(
+        tester.client(&alice.localname).unwrap(),
+        &bob_id,
+        &bob.localname,
+        std::time::Duration::from_secs(30),
+    )
+    .await
+    .expect("Bob should be in the user directory of Alice");
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: rooms with `publish_to_directory` and only these appear in the room directory.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_publish_to_directory() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let published = format!("published-{}", uuid::Uuid::new_v4());
+    let unlisted = format!("unlisted-{}", uuid::Uuid::new_v4());
+    let creator = User::builder()
+        .localname(format!("creator-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![
+            registration::Room::builder()
+                .alias(Some(published.clone()))
+                .publish_to_directory(true)
+                .build(),
+            registration::Room::builder()
+                .alias(Some(unlisted.clone()))
+                .build(),
+        ])
+        .build();
+    let config = Config::builder()
+        .name("test-publish-to-directory".into())
+        .users(vec![creator.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let client = tester.client(&creator.localname).unwrap();
+    let published_id = resolve_alias(&tester, &creator.localname, &published).await;
+    let unlisted_id = resolve_alias(&tester, &creator.localname, &unlisted).await;
+    helpers::wait_for_room_in_directory(client, &published_id, std::time::Duration::from_secs(30))
+        .await
+        .expect("The room should be published");
+    assert!(!helpers::public_rooms(client)
+        .await
+        .expect("Could not list public rooms")
+        .contains(&unlisted_id));
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: simulating presence, typing notifications and read receipts as users of `users`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_simulate_activity() {
+    use matrix_sdk::ruma::{api::client::presence::get_presence, presence::PresenceState};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("activity-{}", uuid::Uuid::new_v4());
+    let bob = User::builder()
+        .localname(format!("bob-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .alias(Some(alias.clone()))
+            .members(vec![bob.localname.clone()])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-simulate-activity".into())
+        .users(vec![alice.clone(), bob.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    registration::user_client(&config, "not-a-user")
+        .await
+        .expect_err("Only users declared in `users` may be simulated");
+    let alice_client = registration::user_client(&config, &alice.localname)
+        .await
+        .expect("Could not login as Alice");
+    let bob_client = registration::user_client(&config, &bob.localname)
+        .await
+        .expect("Could not login as Bob");
+    let room_id = helpers::resolve_room(
+        &alice_client,
+        &format!("#{}:{}", alias, config.homeserver.server_name),
+    )
+    .await
+    .expect("Could not resolve alias");
+
+    helpers::set_presence(
+        &alice_client,
+        PresenceState::Unavailable,
+        Some("Gone fishing"),
+    )
+    .await
+    .expect("Could not set presence");
+    let presence = bob_client
+        .send(
+            get_presence::v3::Request::new(alice_client.user_id().unwrap()),
+            None,
+        )
+        .await
+        .expect("Could not get presence");
+    assert_eq!(presence.presence, PresenceState::Unavailable);
+    assert_eq!(presence.status_msg.as_deref(), Some("Gone fishing"));
+
+    helpers::send_typing(
+        &alice_client,
+        &room_id,
+        Some(std::time::Duration::from_secs(10)),
+    )
+    .await
+    .expect("Could not start typing");
+    helpers::send_typing(&alice_client, &room_id, None)
+        .await
+        .expect("Could not stop typing");
+
+    let event_id = helpers::send_raw_message_event(
+        &bob_client,
+        &room_id,
+        "m.room.message",
+        &serde_json::json!({ "msgtype": "m.text", "body": "Hello" }),
+    )
+    .await
+    .expect("Could not send message");
+    helpers::send_read_receipt(&alice_client, &room_id, &event_id)
+        .await
+        .expect("Could not send read receipt");
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: seeded messages are recorded in the manifest, with their relations.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_room_messages() {
+    use matrix_sdk::ruma::api::client::room::get_room_event;
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .messages(vec![
+                registration::Message::builder()
+                    .label(Some("root".to_string()))
+                    .body(Some("Root".to_string()))
+                    .build(),
+                registration::Message::builder()
+                    .label(Some("in-thread".to_string()))
+                    .thread(Some("root".to_string()))
+                    .body(Some("In thread".to_string()))
+                    .build(),
+                registration::Message::builder()
+                    .label(Some("edit".to_string()))
+                    .replaces(Some("root".to_string()))
+                    .body(Some("Root, edited".to_string()))
+                    .build(),
+                registration::Message::builder()
+                    .label(Some("reaction".to_string()))
+                    .reaction(Some(
+                        registration::Reaction::builder()
+                            .to("root".to_string())
+                            .key("👍".to_string())
+                            .build(),
+                    ))
+                    .build(),
+            ])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-room-messages".into())
+        .users(vec![alice.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let manifest = manifest::Manifest::load(tester.config()).expect("Could not load manifest");
+    let root = manifest.event("root").expect("Missing root");
+    let client = tester.client(&alice.localname).unwrap();
+    for (label, rel_type) in [
+        ("in-thread", "m.thread"),
+        ("edit", "m.replace"),
+        ("reaction", "m.annotation"),
+    ] {
+        let seeded = manifest.event(label).expect("Missing message");
+        assert_eq!(seeded.room_id, root.room_id);
+        let event: serde_json::Value = client
+            .send(
+                get_room_event::v3::Request::new(&seeded.room_id, &seeded.event_id),
+                None,
+            )
+            .await
+            .expect("Could not fetch message")
+            .event
+            .deserialize_as()
+            .expect("Invalid message");
+        let relates_to = &event["content"]["m.relates_to"];
+        assert_eq!(relates_to["rel_type"], rel_type, "{}", event);
+        assert_eq!(relates_to["event_id"], root.event_id.as_str(), "{}", event);
+    }
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: redacting seeded messages, during `up` and from test code.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_redacted_messages() {
+    use std::time::Duration;
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let moderator = User::builder()
+        .localname(format!("moderator-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .members(vec![moderator.localname.clone()])
+            .moderators(vec![moderator.localname.clone()])
+            .messages(vec![
+                registration::Message::builder()
+                    .label(Some("spam".to_string()))
+                    .body(Some("Buy now".to_string()))
+                    .redacted(Some(
+                        registration::Redaction::builder()
+                            .by(Some(moderator.localname.clone()))
+                            .reason(Some("Spam".to_string()))
+                            .build(),
+                    ))
+                    .build(),
+                registration::Message::builder()
+                    .label(Some("later".to_string()))
+                    .body(Some("Redacted later".to_string()))
+                    .build(),
+            ])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-redacted-messages".into())
+        .users(vec![alice.clone(), moderator.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let manifest = manifest::Manifest::load(tester.config()).expect("Could not load manifest");
+    let client = tester.client(&alice.localname).unwrap();
+    let spam = manifest.event("spam").expect("Missing message");
+    helpers::wait_for_redaction(
+        client,
+        &spam.room_id,
+        &spam.event_id,
+        Duration::from_secs(10),
+    )
+    .await
+    .expect("The message should have been redacted during `up`");
+
+    let later = manifest.event("later").expect("Missing message");
+    helpers::wait_for_redaction(
+        client,
+        &later.room_id,
+        &later.event_id,
+        Duration::from_secs(1),
+    )
+    .await
+    .expect_err("The message should not be redacted yet");
+    helpers::redact(
+        tester.client(&moderator.localname).unwrap(),
+        &later.room_id,
+        &later.event_id,
+        Some("Later"),
+    )
+    .await
+    .expect("Could not redact");
+    helpers::wait_for_redaction(
+        client,
+        &later.room_id,
+        &later.event_id,
+        Duration::from_secs(10),
+    )
+    .await
+    .expect("The message should now be redacted");
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: racing conflicting state events between users.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_race_state() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("race-{}", uuid::Uuid::new_v4());
+    let bob = User::builder()
+        .localname(format!("bob-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![registration::Room::builder()
+            .alias(Some(alias.clone()))
+            .members(vec![bob.localname.clone()])
+            .moderators(vec![bob.localname.clone()])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-race-state".into())
+        .users(vec![alice.clone(), bob.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let room_id = resolve_alias(&tester, &alice.localname, &alias).await;
+    helpers::race_state(&[], &room_id, helpers::StateRace::Topic, 1)
+        .await
+        .expect_err("Cannot race without users");
+    let clients = vec![
+        tester.client(&alice.localname).unwrap().clone(),
+        tester.client(&bob.localname).unwrap().clone(),
+    ];
+    let topic = helpers::race_state(&clients, &room_id, helpers::StateRace::Topic, 5)
+        .await
+        .expect("Could not race");
+    assert!(
+        topic["topic"] == "Topic 4 by racer 0" || topic["topic"] == "Topic 4 by racer 1",
+        "{}",
+        topic
+    );
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: members of a space may join the restricted rooms of the space, others
+/// may knock on knock rooms.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_join_rules() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let space = format!("space-{}", uuid::Uuid::new_v4());
+    let restricted = format!("restricted-{}", uuid::Uuid::new_v4());
+    let knock = format!("knock-{}", uuid::Uuid::new_v4());
+    let bob = User::builder()
+        .localname(format!("bob-{}", uuid::Uuid::new_v4()))
+        .build();
+    let carol = User::builder()
+        .localname(format!("carol-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .rooms(vec![
+            registration::Room::builder()
+                .alias(Some(space.clone()))
+                .space(true)
+                .members(vec![bob.localname.clone()])
+                .build(),
+            registration::Room::builder()
+                .alias(Some(restricted.clone()))
+                .join_rule(Some(registration::JoinRule::Restricted))
+                .allow(vec![space.clone()])
+                .build(),
+            registration::Room::builder()
+                .alias(Some(knock.clone()))
+                .join_rule(Some(registration::JoinRule::Knock))
+                .build(),
+        ])
+        .build();
+    let config = Config::builder()
+        .name("test-join-rules".into())
+        .users(vec![alice.clone(), bob.clone(), carol.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let bob_client = tester.client(&bob.localname).unwrap();
+    let carol_client = tester.client(&carol.localname).unwrap();
+    let restricted_id = resolve_alias(&tester, &alice.localname, &restricted).await;
+    carol_client
+        .join_room_by_id(&restricted_id)
+        .await
+        .expect_err("Carol is not a member of the space");
+    bob_client
+        .join_room_by_id(&restricted_id)
+        .await
+        .expect("Bob is a member of the space");
+
+    let knock_id = helpers::knock(
+        carol_client,
+        &format!("#{}:{}", knock, tester.config().homeserver.server_name),
+        Some("Let me in"),
+    )
+    .await
+    .expect("Could not knock");
+    assert_eq!(
+        knock_id,
+        resolve_alias(&tester, &alice.localname, &knock).await
+    );
+    helpers::approve_knock(
+        tester.client(&alice.localname).unwrap(),
+        &knock_id,
+        carol_client.user_id().unwrap(),
+    )
+    .await
+    .expect("Could not approve knock");
+    carol_client
+        .join_room_by_id(&knock_id)
+        .await
+        .expect("Carol was invited");
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: with `on: failure-only`, artifacts are only uploaded once a test has failed.
+///
+/// This replaces the `aws` command-line tool with a script recording its arguments.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_artifacts_upload() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let bin = std::env::temp_dir().join(format!("mx-tester-aws-{}", uuid::Uuid::new_v4()));
+    let log = bin.join("aws.log");
+    std::fs::create_dir_all(&bin).unwrap();
+    let aws = bin.join("aws");
+    std::fs::write(
+        &aws,
+        format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&aws, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::env::set_var(
+        "PATH",
+        format!(
+            "{}:{}",
+            bin.display(),
+            std::env::var("PATH").unwrap_or_default()
+        ),
+    );
+    let uploads = || std::fs::read_to_string(&log).unwrap_or_default();
+
+    let config = Config::builder()
+        .name("test-artifacts-upload".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .artifacts(
+            artifacts::ArtifactsConfig::builder()
+                .upload(Some(
+                    artifacts::UploadConfig::builder()
+                        .url("s3://bucket/mx-tester/".to_string())
+                        .on(artifacts::UploadWhen::FailureOnly)
+                        .build(),
+                ))
+                .build(),
+        )
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    mx_tester::down(&docker, &config, Status::Success)
+        .await
+        .expect("Failed in step `down`");
+    assert_eq!(uploads(), "");
+
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    mx_tester::down(&docker, &config, Status::Failure)
+        .await
+        .expect("Failed in step `down`");
+    let uploads = uploads();
+    let lines: Vec<&str> = uploads.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", uploads);
+    assert!(
+        lines[0].starts_with(&format!(
+            "s3 cp --only-show-errors --recursive {} s3://bucket/mx-tester/test-artifacts-upload/",
+            config.logs_dir().display()
+        )),
+        "{}",
+        uploads
+    );
+    assert!(lines[0].ends_with("/logs"), "{}", uploads);
+    assert!(lines[1].ends_with("/manifest.json"), "{}", uploads);
+    let _ = std::fs::remove_dir_all(&bin);
+}
+
+/// Test: `down` leaves nothing behind, resources that remain are an error with
+/// `strict_leaks` and `clean` removes them.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leaks() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-leaks".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .strict_leaks(true)
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    assert!(leaks::Leaks::detect(&docker, &config)
+        .await
+        .expect("Could not detect leaks")
+        .is_empty());
+
+    // Simulate a resource that `down` doesn't know about.
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    let network = format!("net-mx-tester-leak-{}", uuid::Uuid::new_v4());
+    docker
+        .create_network(bollard::network::CreateNetworkOptions {
+            name: network.clone(),
+            labels: config.docker_labels(),
+            ..Default::default()
+        })
+        .await
+        .expect("Could not create network");
+    let err = mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect_err("The network should be reported as a leak");
+    assert!(
+        format!("{:?}", err).contains("Resources left behind by the test"),
+        "{:?}",
+        err
+    );
+    assert_eq!(
+        leaks::Leaks::detect(&docker, &config)
+            .await
+            .expect("Could not detect leaks")
+            .networks,
+        vec![network]
+    );
+
+    mx_tester::clean(&docker, &config, false)
+        .await
+        .expect("Failed in step `clean`");
+    assert!(leaks::Leaks::detect(&docker, &config)
+        .await
+        .expect("Could not detect leaks")
+        .is_empty());
+}
+
+/// Test: `up` gives up with the logs of Synapse if it doesn't accept requests
+/// within `docker.readiness_timeout_sec`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_readiness_timeout() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let mut config = Config::builder()
+        .name("test-readiness-timeout".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    // Synapse takes a few seconds to start, so it can't be ready after 1s.
+    config.docker.readiness_timeout_sec = 1;
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("Synapse should not be ready yet");
+    let err = format!("{:?}", err);
+    assert!(err.contains("Synapse did not become ready"), "{}", err);
+    assert!(err.contains("still not ready after 1s"), "{}", err);
+    assert!(err.contains("Last lines of logs"), "{}", err);
+    mx_tester::down(&docker, &config, Status::Failure)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: Docker checks the health of Synapse, and commands refuse to operate
+/// on an unhealthy Synapse.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_healthcheck() {
+    use bollard::models::HealthStatusEnum;
+
+    async fn wait_for_health(docker: &bollard::Docker, name: &str, expected: &HealthStatusEnum) {
+        for _ in 0..60 {
+            let health = docker
+                .inspect_container(name, None)
+                .await
+                .expect("Could not inspect container")
+                .state
+                .and_then(|state| state.health)
+                .and_then(|health| health.status);
+            if health.as_ref() == Some(expected) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        panic!("Synapse did not become {:?}", expected);
+    }
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-healthcheck".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let container_name = config.run_container_name();
+    let healthcheck = docker
+        .inspect_container(&container_name, None)
+        .await
+        .expect("Could not inspect container")
+        .config
+        .and_then(|config| config.healthcheck)
+        .and_then(|healthcheck| healthcheck.test)
+        .expect("Missing healthcheck");
+    assert!(
+        healthcheck
+            .join(" ")
+            .contains("http://localhost:8008/health"),
+        "{:?}",
+        healthcheck
+    );
+    wait_for_health(&docker, &container_name, &HealthStatusEnum::HEALTHY).await;
+
+    // Break the healthcheck, without stopping Synapse.
+    let sh = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+    assert_eq!(
+        shell::exec(
+            &docker,
+            &config,
+            &sh("which curl > /tmp/curl.path && mv $(cat /tmp/curl.path) /tmp/curl")
+        )
+        .await
+        .expect("Could not exec"),
+        0
+    );
+    wait_for_health(&docker, &container_name, &HealthStatusEnum::UNHEALTHY).await;
+    let err = admin::reload_config(&docker, &config, &[])
+        .await
+        .expect_err("Synapse is unhealthy");
+    assert!(err.to_string().contains("is unhealthy"), "{}", err);
+
+    assert_eq!(
+        shell::exec(&docker, &config, &sh("mv /tmp/curl $(cat /tmp/curl.path)"))
+            .await
+            .expect("Could not exec"),
+        0
+    );
+    wait_for_health(&docker, &container_name, &HealthStatusEnum::HEALTHY).await;
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: after a failed `run`, `down` still runs, with the failure path.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_down_after_failure() {
+    use mx_tester::commands::{Command, Progress};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let log = std::env::temp_dir().join(format!("mx-tester-down-{}.log", uuid::Uuid::new_v4()));
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-down-after-failure
+run:
+  - exit 1
+down:
+  success:
+    - echo success >> {log}
+  failure:
+    - echo failure >> {log}
+"#,
+        log = log.display()
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+
+    let mut progress = Progress::default();
+    for command in [Command::Build, Command::Up, Command::Run, Command::Down] {
+        assert!(
+            !progress.skips(command),
+            "{:?} should not be skipped",
+            command
+        );
+        let result = match command {
+            Command::Build => mx_tester::build(&docker, &config).await,
+            Command::Up => mx_tester::up(&docker, &config).await,
+            Command::Run => mx_tester::run(&docker, &config).await,
+            Command::Down => mx_tester::down(&docker, &config, progress.down_status()).await,
+        };
+        progress.record(command, result);
+    }
+    let failed: Vec<Command> = progress
+        .failures
+        .iter()
+        .map(|(command, _)| *command)
+        .collect();
+    assert_eq!(failed, vec![Command::Run]);
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap_or_default(),
+        "failure\n"
+    );
+    let _ = std::fs::remove_file(&log);
+}
+
+/// Test: `up` fails early if a host port is already in use.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_port_conflict() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-port-conflict".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+
+    let listener = std::net::TcpListener::bind(("0.0.0.0", config.homeserver.host_port as u16))
+        .expect("Could not bind port");
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("The port is in use");
+    assert!(
+        err.to_string().contains(&format!(
+            "Host port {} is already in use",
+            config.homeserver.host_port
+        )),
+        "{}",
+        err
+    );
+    drop(listener);
+
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    // A second `up` finds the container of the first one.
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("The port is in use");
+    assert!(
+        err.to_string().contains(&format!(
+            "by container {} from a previous `mx-tester up`",
+            config.run_container_name()
+        )),
+        "{}",
+        err
+    );
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `connect` honors the configuration of the connection to the daemon.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let mut config = Config::builder()
+        .name("test-connect".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build();
+    config.docker.timeout_sec = 30;
+    config.docker.connect_retries = 2;
+    config.docker.connect_backoff_ms = 10;
+    let docker = mx_tester::connect(&config)
+        .await
+        .expect("Could not connect to the daemon");
+    docker
+        .ping()
+        .await
+        .expect("Could not use the connection to the daemon");
+
+    // SSL requires a server.
+    config.docker.ssl = DockerSsl::Always;
+    let err = mx_tester::connect(&config)
+        .await
+        .expect_err("SSL without a server should fail");
+    let err = format!("{:?}", err);
+    assert!(err.contains("requires a server address"), "{}", err);
+}
+
+/// Test: registering extra users from test code with `register_user`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_register_user() {
+    use registration::{register_user, Registration, RegistrationError};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-register-user".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let localname = format!("bot-{}", uuid::Uuid::new_v4());
+    let registration = Registration::builder()
+        .localname(localname.clone())
+        .displayname("The Bot".to_string())
+        .user_type("bot".to_string())
+        .build();
+    let registered = register_user(
+        &config.homeserver.public_baseurl,
+        &config.homeserver.registration_shared_secret,
+        &registration,
+    )
+    .await
+    .expect("Could not register user");
+    assert_eq!(
+        registered.user_id.as_str(),
+        format!("@{}:{}", localname, config.homeserver.server_name)
+    );
+    assert!(!registered.access_token.is_empty());
+
+    // The user has the display name and type we asked for.
+    let profile: serde_json::Value = reqwest::get(format!(
+        "{}/_matrix/client/v3/profile/{}/displayname",
+        config.homeserver.public_baseurl, registered.user_id
+    ))
+    .await
+    .expect("Could not get display name")
+    .json()
+    .await
+    .expect("Invalid display name");
+    assert_eq!(profile["displayname"], "The Bot");
+    let admin = registration::admin_client(&config)
+        .await
+        .expect("Could not login as admin");
+    let details: serde_json::Value = reqwest::Client::new()
+        .get(format!(
+            "{}/_synapse/admin/v2/users/{}",
+            config.homeserver.public_baseurl, registered.user_id
+        ))
+        .bearer_auth(admin.access_token().expect("Admin is not logged in"))
+        .send()
+        .await
+        .expect("Could not get user details")
+        .json()
+        .await
+        .expect("Invalid user details");
+    assert_eq!(details["user_type"], "bot");
+
+    // Registering the same user again is an error that callers may inspect.
+    let err = register_user(
+        &config.homeserver.public_baseurl,
+        &config.homeserver.registration_shared_secret,
+        &registration,
+    )
+    .await
+    .expect_err("The user already exists");
+    match err.downcast_ref::<RegistrationError>() {
+        Some(RegistrationError { errcode, .. }) => assert_eq!(errcode, "M_USER_IN_USE"),
+        None => panic!("Unexpected error {:?}", err),
+    }
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `up` creates users of `users` with their `user_type`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_user_type() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alice = User::builder()
+        .localname(format!("alice-{}", uuid::Uuid::new_v4()))
+        .build();
+    let bot = User::builder()
+        .localname(format!("bot-{}", uuid::Uuid::new_v4()))
+        .user_type(Some("bot".to_string()))
+        .build();
+    let config = Config::builder()
+        .name("test-user-type".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .users(vec![alice.clone(), bot.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let admin = registration::admin_client(&config)
+        .await
+        .expect("Could not login as admin");
+    for (user, expected) in [(&alice, serde_json::Value::Null), (&bot, "bot".into())] {
+        let details: serde_json::Value = reqwest::Client::new()
+            .get(format!(
+                "{}/_synapse/admin/v2/users/@{}:{}",
+                config.homeserver.public_baseurl, user.localname, config.homeserver.server_name
+            ))
+            .bearer_auth(admin.access_token().expect("Admin is not logged in"))
+            .send()
+            .await
+            .expect("Could not get user details")
+            .json()
+            .await
+            .expect("Invalid user details");
+        assert_eq!(details["user_type"], expected, "{}", user.localname);
+    }
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: registering users that already exist, e.g. from a previous run.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_existing_users() {
+    use registration::{handle_user_registration, register_user, Registration};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let mut config = Config::builder()
+        .name("test-existing-users".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    // Alice already exists with another password.
+    let localname = format!("alice-{}", uuid::Uuid::new_v4());
+    register_user(
+        &config.homeserver.public_baseurl,
+        &config.homeserver.registration_shared_secret,
+        &Registration::builder()
+            .localname(localname.clone())
+            .password("old password".to_string())
+            .build(),
+    )
+    .await
+    .expect("Could not register user");
+    config.users = vec![User::builder()
+        .localname(localname.clone())
+        .password("new password".to_string())
+        .build()];
+    let err = handle_user_registration(&config)
+        .await
+        .expect_err("The password of Alice doesn't match");
+    let err = format!("{:?}", err);
+    assert!(
+        err.contains("already exists with a different password, set `reset_password: true`"),
+        "{}",
+        err
+    );
+
+    // With `reset_password`, the password is reset, and registering again is a no-op.
+    config.users[0].reset_password = true;
+    for _ in 0..2 {
+        handle_user_registration(&config)
+            .await
+            .expect("Could not register existing user");
+    }
+    registration::user_client(&config, &localname)
+        .await
+        .expect("Could not login with the new password");
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `up` waits and retries when Synapse rate-limits logins.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rate_limited_up() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let localnames: Vec<String> = (0..3)
+        .map(|i| format!("user-{}-{}", i, uuid::Uuid::new_v4()))
+        .collect();
+    // At most one login every 2s, so logging in the admin and the users
+    // is rate-limited.
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-rate-limited-up
+homeserver:
+  rc_login:
+    address:
+      per_second: 0.5
+      burst_count: 1
+    account:
+      per_second: 0.5
+      burst_count: 1
+users:
+  - localname: {}
+  - localname: {}
+  - localname: {}
+"#,
+        localnames[0], localnames[1], localnames[2]
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    for localname in &localnames {
+        registration::user_client(&config, localname)
+            .await
+            .expect("Could not login once rate-limited");
+    }
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `moderators` of a room are promoted once the room is created, whether
+/// or not they are members.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_room_moderators() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let alias = format!("moderated-{}", uuid::Uuid::new_v4());
+    let bob = User::builder()
+        .localname(format!("bob-{}", uuid::Uuid::new_v4()))
+        .build();
+    let bot = User::builder()
+        .localname(format!("bot-{}", uuid::Uuid::new_v4()))
+        .build();
+    let alice_localname = format!("alice-{}", uuid::Uuid::new_v4());
+    // Alice is a moderator of her own room, which must not demote her.
+    let alice = User::builder()
+        .localname(alice_localname.clone())
+        .rooms(vec![registration::Room::builder()
+            .alias(Some(alias.clone()))
+            .members(vec![bob.localname.clone()])
+            .moderators(vec![
+                alice_localname,
+                bob.localname.clone(),
+                bot.localname.clone(),
+            ])
+            .build()])
+        .build();
+    let config = Config::builder()
+        .name("test-room-moderators".into())
+        .users(vec![alice.clone(), bob.clone(), bot.clone()])
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    let mut tester = Tester::new(docker, config);
+    tester.build().await.expect("Failed in step `build`");
+    tester.up().await.expect("Failed in step `up`");
+
+    let room_id = resolve_alias(&tester, &alice.localname, &alias).await;
+    let power_levels = helpers::get_state_event(
+        tester.client(&alice.localname).unwrap(),
+        &room_id,
+        "m.room.power_levels",
+        "",
+    )
+    .await
+    .expect("Missing power levels");
+    let server_name = &tester.config().homeserver.server_name;
+    for (user, level) in [(&alice, 100), (&bob, 50), (&bot, 50)] {
+        assert_eq!(
+            power_levels["users"][format!("@{}:{}", user.localname, server_name)],
+            level,
+            "{}",
+            power_levels
+        );
+    }
+
+    tester
+        .down(Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: bots run in their own container from `up` to `down`, with the
+/// credentials of their user.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bots() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let localname = format!("bot-{}", uuid::Uuid::new_v4());
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-bots
+users:
+  - localname: {localname}
+bots:
+  - name: echo
+    image: alpine:latest
+    command:
+      - sh
+      - -c
+      - echo "user=$MX_TEST_BOT_USER_ID extra=$EXTRA" && wget -qO- $MX_TEST_HOMESERVER_URL/health && echo && sleep 3600
+    env:
+      EXTRA: extra
+    user: {localname}
+"#,
+        localname = localname
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let container_name = config.bot_container_name(&config.bots[0]);
+    let state = docker
+        .inspect_container(&container_name, None)
+        .await
+        .expect("Missing bot container")
+        .state
+        .unwrap_or_default();
+    assert_eq!(state.running, Some(true));
+
+    // The bot reaches Synapse on the Docker network, as its user.
+    let logs_path = config.logs_dir().join("docker").join("bot-echo.log");
+    let expected = format!(
+        "user=@{}:{} extra=extra",
+        localname, config.homeserver.server_name
+    );
+    let mut logs = String::new();
+    for _ in 0..30 {
+        logs = std::fs::read_to_string(&logs_path).unwrap_or_default();
+        if logs.contains("OK") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    assert!(logs.contains(&expected), "{}", logs);
+    assert!(logs.contains("OK"), "{}", logs);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    docker
+        .inspect_container(&container_name, None)
+        .await
+        .expect_err("The bot should have been removed");
+}
+
+/// Test: `up` registers the ghosts of bridges and lets them double-puppet users.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bridge_preset() {
+    use mx_tester::appservice::{load_double_puppet_registration, register_ghosts};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let ghost = format!("ghost-{}", uuid::Uuid::new_v4());
+    let localname = format!("bob-{}", uuid::Uuid::new_v4());
+    // Nothing listens at `url`, which Synapse only needs to push events.
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-bridge-preset
+users:
+  - localname: {localname}
+appservices:
+  - id: bridge
+    url: http://host.docker.internal:9001
+    sender_localpart: _bridge_bot
+    bridge:
+      prefix: telegram_
+      ghosts: [{ghost}]
+"#,
+        localname = localname,
+        ghost = ghost
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    // The ghost exists, and registering it again is a no-op.
+    let response = reqwest::get(format!(
+        "{}/_matrix/client/v3/profile/@telegram_{}:{}",
+        config.homeserver.public_baseurl, ghost, config.homeserver.server_name
+    ))
+    .await
+    .expect("Could not get profile of ghost");
+    assert!(response.status().is_success(), "{:?}", response);
+    register_ghosts(&config)
+        .await
+        .expect("Could not register ghosts again");
+
+    // The double-puppeting registration may act as any user.
+    let double_puppet = load_double_puppet_registration(&config, &config.appservices[0])
+        .expect("Could not load double-puppeting registration")
+        .expect("Missing double-puppeting registration");
+    let user_id = format!("@{}:{}", localname, config.homeserver.server_name);
+    let whoami: serde_json::Value = reqwest::Client::new()
+        .get(format!(
+            "{}/_matrix/client/v3/account/whoami",
+            config.homeserver.public_baseurl
+        ))
+        .query(&[("user_id", &user_id)])
+        .bearer_auth(&double_puppet.as_token)
+        .send()
+        .await
+        .expect("Could not call whoami")
+        .json()
+        .await
+        .expect("Invalid whoami");
+    assert_eq!(whoami["user_id"], user_id.as_str(), "{}", whoami);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: clients discover the homeserver through `/.well-known/matrix/client`,
+/// as recorded in the manifest.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_discovery() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-discovery".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let discovery = manifest::Manifest::load(&config)
+        .expect("Could not load manifest")
+        .discovery
+        .expect("Missing discovery");
+    assert_eq!(discovery, manifest::Discovery::new(&config));
+    let well_known: serde_json::Value = reqwest::get(&discovery.well_known_url)
+        .await
+        .expect("Could not get well-known file")
+        .json()
+        .await
+        .expect("Invalid well-known file");
+    assert_eq!(
+        well_known["m.homeserver"]["base_url"]
+            .as_str()
+            .map(|url| url.trim_end_matches('/')),
+        Some(discovery.base_url.as_str()),
+        "{}",
+        well_known
+    );
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: with `postgres.host`, Synapse uses an already-running PostgreSQL
+/// server, whose database is reset before `up`.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_external_postgres() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let postgres_port = std::net::TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Could not find a free port")
+        .port();
+
+    // The PostgreSQL server of another test, published on the host.
+    let mut server: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-postgres-server
+postgres:
+  host_port: {}
+"#,
+        postgres_port
+    ))
+    .expect("Invalid config");
+    server.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let server = server.assign_port();
+    let _ = Cleanup::new(&server);
+    mx_tester::build(&docker, &server)
+        .await
+        .expect("Failed in step `build` of the server");
+    mx_tester::up(&docker, &server)
+        .await
+        .expect("Failed in step `up` of the server");
+
+    // Use database `postgres` of this server, which is not used by its own Synapse.
+    let external = |password: &str| -> Config {
+        let mut config: Config = serde_yaml::from_str(&format!(
+            r#"
+name: test-external-postgres
+postgres:
+  host: host.docker.internal
+  port: {}
+  database: postgres
+  password: {}
+  reset: true
+"#,
+            postgres_port, password
+        ))
+        .expect("Invalid config");
+        config.synapse = SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        };
+        config.assign_port()
+    };
+    let reset_container_name = "mx-tester-postgres-reset-test-external-postgres";
+
+    // Failing to reset the database fails `up`.
+    let config = external("not the password");
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("The password is wrong");
+    let err = format!("{:?}", err);
+    assert!(err.contains("Could not reset database postgres"), "{}", err);
+    docker
+        .inspect_container(reset_container_name, None)
+        .await
+        .expect_err("The reset container should have been removed");
+    mx_tester::down(&docker, &config, Status::Failure)
+        .await
+        .expect("Failed in step `down`");
+
+    let config = external("password");
+    let _ = Cleanup::new(&config);
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    let logs = std::fs::read_to_string(config.logs_dir().join("docker").join("postgres-reset.log"))
+        .expect("Missing logs of the reset");
+    assert!(logs.contains("CREATE SCHEMA"), "{}", logs);
+    docker
+        .inspect_container(reset_container_name, None)
+        .await
+        .expect_err("The reset container should have been removed");
+    registration::admin_client(&config)
+        .await
+        .expect("Synapse does not work with the external database");
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+
+    // `down` leaves the external server alone.
+    let state = docker
+        .inspect_container(&postgres::container_name(&server), None)
+        .await
+        .expect("The external server should still exist")
+        .state
+        .unwrap_or_default();
+    assert_eq!(state.running, Some(true));
+    mx_tester::down(&docker, &server, Status::Manual)
+        .await
+        .expect("Failed in step `down` of the server");
+}
+
+/// Test: `shell` runs commands in the container of the homeserver, from `/data`,
+/// as the requested user.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_shell() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-shell".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let sh = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    let err = shell::shell(&docker, &config, &sh("true"), None)
+        .await
+        .expect_err("Synapse is not up yet");
+    assert!(
+        err.to_string().contains("please run `mx-tester up` first"),
+        "{}",
+        err
+    );
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    // The exit code of the command is returned.
+    let code = shell::shell(
+        &docker,
+        &config,
+        &sh("test \"$(pwd)\" = /data && exit 3"),
+        None,
+    )
+    .await
+    .expect("Could not run command");
+    assert_eq!(code, 3);
+    let code = shell::shell(
+        &docker,
+        &config,
+        &sh("test \"$(id -un)\" = nobody"),
+        Some("nobody"),
+    )
+    .await
+    .expect("Could not run command as nobody");
+    assert_eq!(code, 0);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `exec` runs commands in the container of the homeserver with the
+/// environment of mx-tester, capturing their output in the logs.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_exec() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-exec".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let sh = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    shell::exec(&docker, &config, &[])
+        .await
+        .expect_err("A command is required");
+    let code = shell::exec(
+        &docker,
+        &config,
+        &sh("echo \"url=$MX_TEST_HOMESERVER_URL\"; echo \"cwd=$(pwd)\"; echo oops >&2; exit 2"),
+    )
+    .await
+    .expect("Could not execute command");
+    assert_eq!(code, 2);
+
+    // Within the container, the homeserver is reached on its own port.
+    let stdout = std::fs::read_to_string(config.scripts_logs_dir().join("exec.out"))
+        .expect("Missing stdout capture");
+    assert!(stdout.contains("url=http://localhost:8008"), "{}", stdout);
+    assert!(stdout.contains("cwd=/data"), "{}", stdout);
+    assert!(!stdout.contains("oops"), "{}", stdout);
+    let stderr = std::fs::read_to_string(config.scripts_logs_dir().join("exec.log"))
+        .expect("Missing stderr capture");
+    assert!(stderr.contains("oops"), "{}", stderr);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: with `wait_for_background_updates`, `up` returns once Synapse has
+/// completed its background updates, and test code may wait again later.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wait_for_background_updates() {
+    use mx_tester::synapse_admin::{background_updates_status, start_background_job};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let mut config = Config::builder()
+        .name("test-wait-for-background-updates".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    config.wait_for_background_updates = true;
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let admin = registration::admin_client(&config)
+        .await
+        .expect("Could not login as admin");
+    let status = admin
+        .send(background_updates_status::Request::new(), None)
+        .await
+        .expect("Could not get status of background updates");
+    assert!(status.current_updates.is_empty(), "{:?}", status);
+
+    // Start more background work, then wait for it.
+    admin
+        .send(
+            start_background_job::Request::new("regenerate_directory"),
+            None,
+        )
+        .await
+        .expect("Could not start background job");
+    admin::wait_for_background_updates(&config)
+        .await
+        .expect("Background updates did not complete");
+    let status = admin
+        .send(background_updates_status::Request::new(), None)
+        .await
+        .expect("Could not get status of background updates");
+    assert!(status.current_updates.is_empty(), "{:?}", status);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: `clean --all` only removes the leftovers of the tests of this project,
+/// i.e. of the same test directory.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_clean_all() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let project = |name: &str| -> Config {
+        let mut config = Config::builder().name(name.into()).build();
+        config.directories.root =
+            std::env::temp_dir().join(format!("mx-tester-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&config.directories.root).expect("Could not create test root");
+        config
+    };
+    let create_network = |labels: std::collections::HashMap<String, String>| {
+        let docker = docker.clone();
+        async move {
+            let name = format!("net-mx-tester-clean-all-{}", uuid::Uuid::new_v4());
+            docker
+                .create_network(bollard::network::CreateNetworkOptions {
+                    name: name.clone(),
+                    labels,
+                    ..Default::default()
+                })
+                .await
+                .expect("Could not create network");
+            name
+        }
+    };
+    let ours = project("test-clean-all");
+    let theirs = project("test-clean-all");
+    let ours_network = create_network(ours.docker_labels()).await;
+    let theirs_network = create_network(theirs.docker_labels()).await;
+
+    // Older versions of mx-tester didn't record the test root, so we rely on
+    // the directory of the test.
+    let legacy = format!("test-clean-all-legacy-{}", uuid::Uuid::new_v4());
+    std::fs::create_dir_all(ours.directories.root.join(&legacy))
+        .expect("Could not create test directory");
+    let legacy_network =
+        create_network(std::iter::once((LABEL_TEST_NAME.to_string(), legacy.clone())).collect())
+            .await;
+    let unknown_network = create_network(
+        std::iter::once((
+            LABEL_TEST_NAME.to_string(),
+            format!("test-clean-all-unknown-{}", uuid::Uuid::new_v4()),
+        ))
+        .collect(),
+    )
+    .await;
+
+    let mut networks = leaks::Leaks::detect_all(&docker, &ours.directories.root)
+        .await
+        .expect("Could not detect leaks")
+        .networks;
+    networks.sort();
+    let mut expected = vec![ours_network.clone(), legacy_network.clone()];
+    expected.sort();
+    assert_eq!(networks, expected);
+
+    mx_tester::clean_all(&docker, &ours, false)
+        .await
+        .expect("Failed in step `clean --all`");
+    for network in [&ours_network, &legacy_network] {
+        docker
+            .inspect_network::<&str>(network, None)
+            .await
+            .expect_err("The network should have been removed");
+    }
+    for network in [&theirs_network, &unknown_network] {
+        docker
+            .inspect_network::<&str>(network, None)
+            .await
+            .expect("The network of another project should have been left alone");
+        docker
+            .remove_network(network)
+            .await
+            .expect("Could not remove network");
+    }
+    for config in [&ours, &theirs] {
+        let _ = std::fs::remove_dir_all(&config.directories.root);
+    }
+}
+
+/// Test: `up` checks the port of the recording proxy along with the other
+/// host ports.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_recording_port_conflict() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let listener = std::net::TcpListener::bind(("0.0.0.0", 0)).expect("Could not bind port");
+    let port = listener.local_addr().expect("No local address").port();
+    let mut config = Config::builder()
+        .name("test-recording-port-conflict".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    config.recording = Some(
+        mx_tester::recording::RecordingConfig::builder()
+            .port(port)
+            .build(),
+    );
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("The recording port is in use");
+    assert!(
+        err.to_string()
+            .contains(&format!("Host port {} is already in use", port)),
+        "{}",
+        err
+    );
+    drop(listener);
+
+    // Sharing a port with the homeserver is caught before binding anything.
+    config.recording = Some(
+        mx_tester::recording::RecordingConfig::builder()
+            .port(config.homeserver.host_port as u16)
+            .build(),
+    );
+    let err = mx_tester::up(&docker, &config)
+        .await
+        .expect_err("The recording port is the homeserver port");
+    assert!(
+        err.to_string().contains(&format!(
+            "Host port {} is mapped several times, by homeserver, recording",
+            config.homeserver.host_port
+        )),
+        "{}",
+        err
+    );
+}
+
+/// Test: `snapshot` and `restore` dump and load PostgreSQL by executing
+/// commands in its container.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_snapshot_postgres() {
+    use registration::{register_user, Registration};
+
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let mut config: Config = serde_yaml::from_str(
+        r#"
+name: test-snapshot-postgres
+postgres: {}
+"#,
+    )
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    mx_tester::snapshot::snapshot(&docker, &config, "base")
+        .await
+        .expect("Could not take snapshot");
+    let dir = mx_tester::snapshot::snapshot_dir(&config, "base").expect("Invalid snapshot name");
+    let dump = std::fs::read_to_string(dir.join("database.sql")).expect("Missing dump");
+    assert!(dump.contains("CREATE TABLE"), "{}", dump);
+
+    // A user registered after the snapshot disappears once it is restored,
+    // so it can be registered again.
+    let registration = Registration::builder()
+        .localname(format!("user-{}", uuid::Uuid::new_v4()))
+        .build();
+    let register = || {
+        register_user(
+            &config.homeserver.public_baseurl,
+            &config.homeserver.registration_shared_secret,
+            &registration,
+        )
+    };
+    register().await.expect("Could not register user");
+    mx_tester::snapshot::restore(&docker, &config, "base")
+        .await
+        .expect("Could not restore snapshot");
+    register()
+        .await
+        .expect("The user should not exist after `restore`");
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Simple test: `watch` reinstalls a module once its source changes, and the
+/// outputs of its `build` script don't trigger another rebuild.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let source = std::env::temp_dir().join(format!("mx-tester-watch-{}", uuid::Uuid::new_v4()));
+    let log = std::env::temp_dir().join(format!("mx-tester-watch-{}.log", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(source.join("watched_module")).unwrap();
+    std::fs::write(
+        source.join("setup.py"),
+        "from setuptools import setup\nsetup(name='watched_module', version='0.1', packages=['watched_module'])\n",
+    )
+    .unwrap();
+    let write_module = |version: &str| {
+        std::fs::write(
+            source.join("watched_module").join("__init__.py"),
+            format!(
+                "VERSION = '{}'\n\nclass Module:\n    def __init__(self, config, api):\n        pass\n",
+                version
+            ),
+        )
+        .unwrap()
+    };
+    write_module("before");
+    // The build script writes into the source directory, as e.g. `python -m build` does.
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-watch
+modules:
+  - name: watched_module
+    build:
+      - echo build >> {log}
+      - mkdir -p {source}/build && date +%s%N > {source}/build/stamp
+      - cp -r {source}/. $MX_TEST_MODULE_DIR
+    path: {source}
+    config:
+      module: watched_module.Module
+      config: {{}}
+"#,
+        source = source.display(),
+        log = log.display()
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "build\n");
+
+    // `watch` runs until interrupted, so give it time to reinstall, restart
+    // Synapse, then (wrongly) notice its own build outputs.
+    let change = async {
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        write_module("after");
+    };
+    let (watched, ()) = tokio::join!(
+        tokio::time::timeout(
+            std::time::Duration::from_secs(90),
+            watch::watch(&docker, &config)
+        ),
+        change
+    );
+    assert!(watched.is_err(), "`watch` stopped: {:?}", watched);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "build\nbuild\n");
+    let version_is_after = vec![
+        "/usr/local/bin/python".to_string(),
+        "-c".to_string(),
+        "import watched_module; assert watched_module.VERSION == 'after'".to_string(),
+    ];
+    assert_eq!(
+        shell::exec(&docker, &config, &version_is_after)
+            .await
+            .unwrap(),
+        0
+    );
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    let _ = std::fs::remove_dir_all(&source);
+    let _ = std::fs::remove_file(&log);
+}
+
+/// Simple test: in workers mode, `up` waits for the main process, checked
+/// from within the container, and workers can then be scaled.
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(feature = "workers")]
+async fn test_workers_main_process() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-workers-main-process".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .workers(
+            WorkersConfig::builder()
+                .enabled(true)
+                .types(vec!["synchrotron".to_string()])
+                .build(),
+        )
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    // The main process listens on port 8080, which is not published on the host.
+    let main_process_health = vec![
+        "curl".to_string(),
+        "-fsS".to_string(),
+        "http://localhost:8080/health".to_string(),
+    ];
+    assert_eq!(
+        shell::exec(&docker, &config, &main_process_health)
+            .await
+            .unwrap(),
+        0
+    );
+
+    let synchrotrons = |config: &Config| {
+        workers::workers(config)
+            .expect("Could not list workers")
+            .iter()
+            .filter(|worker| worker.worker_type() == "synchrotron")
+            .count()
+    };
+    assert_eq!(synchrotrons(&config), 1);
+    workers::scale(
+        &docker,
+        &config,
+        &["synchrotron=2".parse().expect("Invalid change")],
+    )
+    .await
+    .expect("Could not scale workers");
+    assert_eq!(synchrotrons(&config), 2);
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+}
+
+/// Test: with `jaeger`, `up` starts Jaeger on the network of Synapse, which
+/// reports its traces there, and `down` removes it.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_jaeger() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let ui_port = std::net::TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Could not find a free port")
+        .port();
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-jaeger
+jaeger:
+  host_port: {}
+  aliases:
+    - tracing
+"#,
+        ui_port
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let container_name = jaeger::container_name(&config);
+    let inspect = docker
+        .inspect_container(&container_name, None)
+        .await
+        .expect("Missing Jaeger container");
+    assert_eq!(inspect.state.unwrap_or_default().running, Some(true));
+    let aliases = inspect
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .and_then(|mut networks| networks.remove(&config.network()))
+        .and_then(|network| network.aliases)
+        .unwrap_or_default();
+    assert!(aliases.contains(&"tracing".to_string()), "{:?}", aliases);
+    assert!(config.logs_dir().join("docker").join("jaeger.log").exists());
+
+    // Synapse reports the requests it handles to Jaeger.
+    let ui_url = config.jaeger.as_ref().unwrap().ui_url();
+    let mut services = serde_json::Value::Null;
+    for _ in 0..60 {
+        let _ = reqwest::get(format!(
+            "{}/_matrix/client/versions",
+            config.homeserver.public_baseurl
+        ))
+        .await;
+        if let Ok(response) = reqwest::get(format!("{}/api/services", ui_url)).await {
+            services = response.json().await.unwrap_or_default();
+            if services["data"]
+                .as_array()
+                .map(|data| !data.is_empty())
+                .unwrap_or(false)
+            {
+                break;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    assert!(
+        services
+            .to_string()
+            .contains(&config.homeserver.server_name),
+        "{}",
+        services
+    );
+
+    // Starting again replaces the container rather than failing on its name.
+    jaeger::start(&docker, &config)
+        .await
+        .expect("Could not restart Jaeger");
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    docker
+        .inspect_container(&container_name, None)
+        .await
+        .expect_err("Jaeger should have been removed");
+}
+
+/// Test: `up` starts sidecars on the network of Synapse, labelled as part of
+/// the test, and `down` removes them, even if they are already gone.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sidecars() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let email_port = std::net::TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Could not find a free port")
+        .port();
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-sidecars
+email:
+  host_port: {}
+turn:
+  aliases:
+    - turn.test
+"#,
+        email_port
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+
+    let containers = [
+        (email::container_name(&config), "mailhog.log"),
+        (turn::container_name(&config), "turn.log"),
+    ];
+    for (container_name, log_name) in &containers {
+        let inspect = docker
+            .inspect_container(container_name, None)
+            .await
+            .expect("Missing sidecar container");
+        assert_eq!(
+            inspect.state.unwrap_or_default().running,
+            Some(true),
+            "{}",
+            container_name
+        );
+        let labels = inspect
+            .config
+            .and_then(|container| container.labels)
+            .unwrap_or_default();
+        for (key, value) in config.docker_labels() {
+            assert_eq!(labels.get(&key), Some(&value), "{}", container_name);
+        }
+        assert!(
+            config.logs_dir().join("docker").join(log_name).exists(),
+            "{}",
+            log_name
+        );
+    }
+    let aliases = docker
+        .inspect_container(&turn::container_name(&config), None)
+        .await
+        .expect("Missing coturn container")
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .and_then(|mut networks| networks.remove(&config.network()))
+        .and_then(|network| network.aliases)
+        .unwrap_or_default();
+    assert!(aliases.contains(&"turn.test".to_string()), "{:?}", aliases);
+    // The API of MailHog is published on the host.
+    assert!(email::emails(&config)
+        .await
+        .expect("Could not list emails")
+        .is_empty());
+
+    // A sidecar that disappeared doesn't prevent `down`.
+    docker
+        .remove_container(
+            &email::container_name(&config),
+            Some(bollard::container::RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("Could not remove MailHog");
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    for (container_name, _) in &containers {
+        docker
+            .inspect_container(container_name, None)
+            .await
+            .expect_err("The sidecar should have been removed");
+    }
+}
+
+/// Simple test: `build` records the version of Synapse found in the image.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_image_info() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let config = Config::builder()
+        .name("test-image-info".into())
+        .synapse(SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        })
+        .build()
+        .assign_port();
+    let _ = Cleanup::new(&config);
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    let image = manifest::Manifest::load(&config)
+        .expect("Could not load manifest")
+        .image
+        .expect("`build` did not record the image");
+    assert_eq!(image.tag, config.tag());
+    assert_eq!(image.base_image, SYNAPSE_VERSION);
+    assert!(image.modules.is_empty());
+    let synapse = image
+        .packages
+        .get("matrix-synapse")
+        .expect("Synapse is not installed in the image");
+    assert_eq!(image.synapse_version.as_ref(), Some(&synapse.version));
+}
+
+/// Simple test: a module installed in editable mode runs from its source
+/// directory on the host.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_module_editable() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let docker = DOCKER.clone();
+    let source = std::env::temp_dir().join(format!("mx-tester-editable-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(source.join("editable_module")).unwrap();
+    std::fs::write(
+        source.join("setup.py"),
+        "from setuptools import setup\nsetup(name='editable_module', version='0.1', packages=['editable_module'])\n",
+    )
+    .unwrap();
+    let write_module = |version: &str| {
+        std::fs::write(
+            source.join("editable_module").join("__init__.py"),
+            format!(
+                "VERSION = '{}'\n\nclass Module:\n    def __init__(self, config, api):\n        pass\n",
+                version
+            ),
+        )
+        .unwrap()
+    };
+    write_module("before");
+    let mut config: Config = serde_yaml::from_str(&format!(
+        r#"
+name: test-module-editable
+modules:
+  - name: editable_module
+    build:
+      - cp -r {source}/. $MX_TEST_MODULE_DIR
+    install_mode: editable
+    path: {source}
+    config:
+      module: editable_module.Module
+      config: {{}}
+"#,
+        source = source.display()
+    ))
+    .expect("Invalid config");
+    config.synapse = SynapseVersion::Docker {
+        tag: SYNAPSE_VERSION.into(),
+    };
+    let config = config.assign_port();
+    let _ = Cleanup::new(&config);
+    let version_is = |version: &str| {
+        vec![
+            "/usr/local/bin/python".to_string(),
+            "-c".to_string(),
+            format!(
+                "import editable_module; assert editable_module.VERSION == '{}'",
+                version
+            ),
+        ]
+    };
+    mx_tester::build(&docker, &config)
+        .await
+        .expect("Failed in step `build`");
+    mx_tester::up(&docker, &config)
+        .await
+        .expect("Failed in step `up`");
+    assert_eq!(
+        shell::exec(&docker, &config, &version_is("before"))
+            .await
+            .unwrap(),
+        0
+    );
+
+    // Changes on the host are visible in the container without a rebuild.
+    write_module("after");
+    assert_eq!(
+        shell::exec(&docker, &config, &version_is("after"))
+            .await
+            .unwrap(),
+        0
+    );
+    admin::restart(&docker, &config)
+        .await
+        .expect("Could not restart Synapse");
+
+    mx_tester::down(&docker, &config, Status::Manual)
+        .await
+        .expect("Failed in step `down`");
+    let _ = std::fs::remove_dir_all(&source);
+}
+
+/// Simple test: handles on a shared environment bring it up once and tear it
+/// down once the last handle is dropped.
+///
+/// `SharedTester` runs its own runtime, so this is a regular `#[test]`.
+#[test]
+fn test_shared_lifecycle() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let log = std::env::temp_dir().join(format!("mx-tester-shared-{}.log", uuid::Uuid::new_v4()));
+    let config = || -> Config {
+        let mut config: Config = serde_yaml::from_str(&format!(
+            r#"
+name: test-shared-lifecycle
+up:
+  before:
+    - echo up >> {log}
+down:
+  finally:
+    - echo down >> {log}
+"#,
+            log = log.display()
+        ))
+        .expect("Invalid config");
+        config.synapse = SynapseVersion::Docker {
+            tag: SYNAPSE_VERSION.into(),
+        };
+        config
+    };
+    let steps = || std::fs::read_to_string(&log).unwrap_or_default();
+
+    let first = SharedTester::get_or_init(config().assign_port()).expect("Could not bring up");
+    let second = SharedTester::get_or_init(config()).expect("Could not share");
+    assert_eq!(
+        first.config().homeserver.host_port,
+        second.config().homeserver.host_port
+    );
+    assert_ne!(first.namespace(), second.namespace());
+    assert_eq!(steps(), "up\n");
+
+    drop(first);
+    assert_eq!(steps(), "up\n");
+
+    drop(second);
+    assert_eq!(steps(), "up\ndown\n");
+    let _ = std::fs::remove_file(&log);
+}
+
 /// Simple test: repeat numerous times up/down, to increase the
 /// chances of hitting one the cases in which Synapse fails
 /// during startup.